@@ -0,0 +1,176 @@
+//! Generates the `api` models from the Firecracker API specs vendored under `api/`.
+//!
+//! Each `fc-<major>_<minor>` feature generates the models of a release into
+//! `$OUT_DIR/api_v<major>_<minor>.rs`, which `src/api.rs` includes. The specs are the swagger
+//! files of the Firecracker repository (`src/firecracker/swagger/firecracker.yaml`), vendored as
+//! `api/firecracker-v<major>.<minor>.yaml` from the release tag listed in [`VERSIONS`].
+
+use std::{env, fmt::Write, fs, path::Path};
+
+use serde_json::{Map, Value};
+
+/// Property names that need to be raw identifiers.
+const KEYWORDS: &[&str] = &[
+    "type", "ref", "match", "move", "use", "mod", "struct", "enum",
+];
+
+/// The releases the specs are vendored for, with the tag of the Firecracker repository they are
+/// taken from.
+const VERSIONS: &[(&str, &str)] = &[("1.7", "v1.7.0"), ("1.10", "v1.10.1")];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let out_dir = env::var_os("OUT_DIR").expect("OUT_DIR not set");
+
+    for (version, tag) in VERSIONS {
+        let module = version.replace('.', "_");
+        if env::var_os(format!("CARGO_FEATURE_FC_{module}")).is_none() {
+            continue;
+        }
+
+        let spec_path = format!("api/firecracker-v{version}.yaml");
+        println!("cargo:rerun-if-changed={spec_path}");
+        let spec = fs::read_to_string(&spec_path)
+            .unwrap_or_else(|e| panic!("failed to read {spec_path}: {e}"));
+        let spec: Value = serde_yaml::from_str(&spec)
+            .unwrap_or_else(|e| panic!("failed to parse {spec_path}: {e}"));
+        let spec_version = spec["info"]["version"].as_str().unwrap_or_default();
+        if tag.strip_prefix('v') != Some(spec_version) {
+            panic!("{spec_path} is the spec of {spec_version}, not of {tag}");
+        }
+        let definitions = spec["definitions"]
+            .as_object()
+            .unwrap_or_else(|| panic!("no definitions in {spec_path}"));
+
+        let mut code = format!(
+            "/// The Firecracker release the models are generated from.\n\
+             pub const SPEC_TAG: &str = \"{tag}\";\n"
+        );
+        code.push_str(&generate(definitions));
+        fs::write(Path::new(&out_dir).join(format!("api_v{module}.rs")), code)
+            .expect("failed to write the generated models");
+    }
+}
+
+/// Generate the models of the swagger `definitions`.
+fn generate(definitions: &Map<String, Value>) -> String {
+    let mut code = String::from("\nuse serde::{Deserialize, Serialize};\n");
+    for (name, schema) in definitions {
+        match schema.get("enum") {
+            Some(values) => generate_enum(&mut code, name, schema, values),
+            None => generate_struct(&mut code, name, schema),
+        }
+    }
+
+    code
+}
+
+fn generate_struct(code: &mut String, name: &str, schema: &Value) {
+    let required: Vec<_> = schema["required"]
+        .as_array()
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    let empty = Map::new();
+    let properties = schema["properties"].as_object().unwrap_or(&empty);
+
+    // Inline enums get a type of their own, named after the struct and the field.
+    let mut enums = String::new();
+    let mut fields = String::new();
+    for (field, property) in properties {
+        let ty = match property.get("enum") {
+            Some(values) => {
+                let enum_name = format!("{name}{}", camel_case(field));
+                generate_enum(&mut enums, &enum_name, property, values);
+                enum_name
+            }
+            None => rust_type(property),
+        };
+
+        fields.push('\n');
+        doc_comment(&mut fields, "    ", property);
+        let ident = if KEYWORDS.contains(&field.as_str()) {
+            format!("r#{field}")
+        } else {
+            field.clone()
+        };
+        if required.contains(&field.as_str()) {
+            writeln!(fields, "    pub {ident}: {ty},").unwrap();
+        } else {
+            fields.push_str("    #[serde(default, skip_serializing_if = \"Option::is_none\")]\n");
+            writeln!(fields, "    pub {ident}: Option<{ty}>,").unwrap();
+        }
+    }
+
+    code.push('\n');
+    doc_comment(code, "", schema);
+    let default = if required.is_empty() { ", Default" } else { "" };
+    writeln!(
+        code,
+        "#[derive(Debug, Clone, PartialEq{default}, Serialize, Deserialize)]"
+    )
+    .unwrap();
+    code.push_str("#[serde(deny_unknown_fields)]\n");
+    writeln!(code, "pub struct {name} {{{fields}}}").unwrap();
+    code.push_str(&enums);
+}
+
+fn generate_enum(code: &mut String, name: &str, schema: &Value, values: &Value) {
+    code.push('\n');
+    doc_comment(code, "", schema);
+    code.push_str("#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]\n");
+    writeln!(code, "pub enum {name} {{").unwrap();
+    for value in values
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+    {
+        let mut variant = camel_case(value);
+        if variant.starts_with(|c: char| c.is_ascii_digit()) {
+            variant.insert(0, '_');
+        }
+        if variant != value {
+            writeln!(code, "    #[serde(rename = \"{value}\")]").unwrap();
+        }
+        writeln!(code, "    {variant},").unwrap();
+    }
+    code.push_str("}\n");
+}
+
+/// The Rust type of a property that isn't an enum.
+fn rust_type(property: &Value) -> String {
+    if let Some(reference) = property["$ref"].as_str() {
+        return reference.trim_start_matches("#/definitions/").to_owned();
+    }
+
+    match property["type"].as_str() {
+        Some("string") => "String".to_owned(),
+        Some("integer") => "i64".to_owned(),
+        Some("number") => "f64".to_owned(),
+        Some("boolean") => "bool".to_owned(),
+        Some("array") => format!("Vec<{}>", rust_type(&property["items"])),
+        _ => "serde_json::Value".to_owned(),
+    }
+}
+
+fn doc_comment(code: &mut String, indent: &str, schema: &Value) {
+    if let Some(description) = schema["description"].as_str() {
+        for line in description.lines() {
+            writeln!(code, "{indent}/// {line}").unwrap();
+        }
+    }
+}
+
+/// `snake_case` or `Not started` to `CamelCase`.
+fn camel_case(s: &str) -> String {
+    s.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect()
+}
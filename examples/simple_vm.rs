@@ -110,7 +110,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build()
         // Determine where the socket will be handled
         .socket_path(Path::new("./tmp/firec-simple_vm.socket"))
-        .build();
+        .build()?;
     let mut machine = Machine::create(config).await?;
 
     println!("Booting the VM");
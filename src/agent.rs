@@ -0,0 +1,517 @@
+//! Guest agent protocol over vsock.
+//!
+//! Some operations, such as [`crate::Machine::push_file`] and [`crate::Machine::pull_file`],
+//! talk to an agent in the guest, listening on vsock port [`crate::config::Config::agent_port`].
+//! Every operation uses its own connection and starts with a request line from the host:
+//!
+//! * `PUT <length> <guest path>`: followed by `<length>` bytes of file content and the hex
+//!   SHA-256 of the content on its own line. The agent writes the file and answers `OK` once the
+//!   checksum is verified.
+//! * `GET <guest path>`: the agent answers `OK <length>`, followed by `<length>` bytes of file
+//!   content and the hex SHA-256 of the content on its own line.
+//! * `EXEC <json>`: runs a command described by a JSON object with the `cmd` (string), `args`
+//!   (array of strings), `env` (object of strings) and `timeout_ms` (number) fields. Once the
+//!   command exits, the agent answers `OK <exit code> <stdout length> <stderr length>`,
+//!   followed by the captured stdout and stderr. The agent kills commands running for longer
+//!   than `timeout_ms`.
+//!
+//! The agent answers `ERR <message>` to requests it can't fulfill. All lines end with `\n`.
+
+use std::{collections::BTreeMap, ffi::OsString, path::Path, time::Duration};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+};
+use tracing::{debug, instrument};
+
+use crate::{vsock, AgentError, Error};
+
+/// The default vsock port of the guest agent.
+pub const DEFAULT_AGENT_PORT: u32 = 10000;
+
+/// The default maximum size of the stdout and stderr of a command run in the guest, see
+/// [`crate::config::Builder::agent_max_output`].
+pub const DEFAULT_AGENT_MAX_OUTPUT: usize = 16 * 1024 * 1024;
+
+/// Size of the chunks files are streamed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Maximum length of a line from the agent, newline included.
+const MAX_LINE_LEN: usize = 4096;
+
+/// The output of a command run in the guest, see [`crate::Machine::exec`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecOutput {
+    /// The exit code of the command.
+    pub exit_code: i32,
+    /// The captured stdout.
+    pub stdout: Vec<u8>,
+    /// The captured stderr.
+    pub stderr: Vec<u8>,
+}
+
+impl ExecOutput {
+    /// If the command exited successfully.
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ExecRequest {
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub timeout_ms: u64,
+}
+
+/// A connection to the guest agent.
+pub(crate) type Connection = BufReader<UnixStream>;
+
+/// Connect to the agent and send the request line `request`.
+pub(crate) async fn request(
+    uds_path: &Path,
+    port: u32,
+    request: &str,
+) -> Result<Connection, Error> {
+    if request.contains('\n') {
+        return Err(AgentError::InvalidRequest(request.to_owned()).into());
+    }
+    let mut conn = BufReader::new(vsock::connect(uds_path, port).await?);
+    conn.write_all(format!("{request}\n").as_bytes()).await?;
+
+    Ok(conn)
+}
+
+/// Read a response line, returning what follows `OK`.
+pub(crate) async fn response(conn: &mut Connection) -> Result<String, Error> {
+    let line = read_line(conn).await?;
+    match line.split_once(' ').unwrap_or((&line, "")) {
+        ("OK", rest) => Ok(rest.to_owned()),
+        ("ERR", message) => Err(AgentError::Remote(message.to_owned()).into()),
+        _ => Err(AgentError::UnexpectedResponse(line).into()),
+    }
+}
+
+async fn read_line(conn: &mut Connection) -> Result<String, Error> {
+    let mut line = String::new();
+    // The guest is untrusted, don't buffer an endless line.
+    let len = (&mut *conn)
+        .take(MAX_LINE_LEN as u64)
+        .read_line(&mut line)
+        .await?;
+    if len == 0 {
+        return Err(AgentError::ConnectionClosed.into());
+    }
+    if len == MAX_LINE_LEN && !line.ends_with('\n') {
+        return Err(AgentError::LineTooLong(MAX_LINE_LEN).into());
+    }
+
+    Ok(line.trim_end_matches('\n').to_owned())
+}
+
+/// Copy `host_path` to `guest_path`, returning the number of bytes copied.
+#[instrument(skip_all, fields(guest_path = guest_path))]
+pub(crate) async fn push(
+    uds_path: &Path,
+    port: u32,
+    host_path: &Path,
+    guest_path: &str,
+) -> Result<u64, Error> {
+    let mut file = File::open(host_path).await?;
+    let len = file.metadata().await?.len();
+    let mut conn = request(uds_path, port, &format!("PUT {len} {guest_path}")).await?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut sent = 0;
+    while sent < len {
+        let max = buf.len().min((len - sent) as usize);
+        let n = file.read(&mut buf[..max]).await?;
+        if n == 0 {
+            return Err(AgentError::Truncated(host_path.to_owned()).into());
+        }
+        hasher.update(&buf[..n]);
+        conn.write_all(&buf[..n]).await?;
+        sent += n as u64;
+    }
+    conn.write_all(format!("{:x}\n", hasher.finalize()).as_bytes())
+        .await?;
+    conn.flush().await?;
+    response(&mut conn).await?;
+    debug!(len, "File pushed");
+
+    Ok(len)
+}
+
+/// Copy `guest_path` to `host_path`, returning the number of bytes copied.
+///
+/// The content is written to a temporary file next to `host_path`, which is only renamed to
+/// `host_path` once the checksum is verified.
+#[instrument(skip_all, fields(guest_path = guest_path))]
+pub(crate) async fn pull(
+    uds_path: &Path,
+    port: u32,
+    guest_path: &str,
+    host_path: &Path,
+) -> Result<u64, Error> {
+    let mut conn = request(uds_path, port, &format!("GET {guest_path}")).await?;
+    let len = response(&mut conn).await?;
+    let len: u64 = len
+        .parse()
+        .map_err(|_| AgentError::InvalidResponse(len.clone()))?;
+
+    let mut tmp_path = OsString::from(host_path);
+    tmp_path.push(".part");
+    let result = async {
+        let mut file = File::create(&tmp_path).await?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut received = 0;
+        while received < len {
+            let max = buf.len().min((len - received) as usize);
+            let n = conn.read(&mut buf[..max]).await?;
+            if n == 0 {
+                return Err(AgentError::ConnectionClosed.into());
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n]).await?;
+            received += n as u64;
+        }
+        if read_line(&mut conn).await? != format!("{:x}", hasher.finalize()) {
+            return Err(Error::ChecksumMismatch(host_path.to_owned()));
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, host_path).await?;
+
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+    result?;
+    debug!(len, "File pulled");
+
+    Ok(len)
+}
+
+/// Run a command in the guest, waiting for at most `timeout` for it to exit.
+///
+/// Fails without reading the output if the agent announces a stdout or stderr larger than
+/// `max_output` bytes.
+#[instrument(skip_all, fields(cmd = %request.cmd))]
+pub(crate) async fn exec(
+    uds_path: &Path,
+    port: u32,
+    request: ExecRequest,
+    timeout: Duration,
+    max_output: usize,
+) -> Result<ExecOutput, Error> {
+    let run = async {
+        let mut conn = self::request(
+            uds_path,
+            port,
+            &format!("EXEC {}", serde_json::to_string(&request)?),
+        )
+        .await?;
+        let response = response(&mut conn).await?;
+        let invalid = || AgentError::InvalidResponse(response.clone());
+        let mut fields = response.split(' ');
+        let mut next = || fields.next().ok_or_else(invalid);
+        let exit_code: i32 = next()?.parse().map_err(|_| invalid())?;
+        let stdout_len: usize = next()?.parse().map_err(|_| invalid())?;
+        let stderr_len: usize = next()?.parse().map_err(|_| invalid())?;
+        if stdout_len > max_output || stderr_len > max_output {
+            return Err(AgentError::OutputTooLarge {
+                stdout: stdout_len,
+                stderr: stderr_len,
+                max: max_output,
+            }
+            .into());
+        }
+
+        let mut stdout = vec![0; stdout_len];
+        conn.read_exact(&mut stdout).await?;
+        let mut stderr = vec![0; stderr_len];
+        conn.read_exact(&mut stderr).await?;
+        debug!(exit_code, stdout_len, stderr_len, "Command exited");
+
+        Ok(ExecOutput {
+            exit_code,
+            stdout,
+            stderr,
+        })
+    };
+
+    tokio::time::timeout(timeout, run)
+        .await
+        .map_err(|_| Error::ExecTimedOut(timeout))?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, path::PathBuf};
+
+    use tokio::{net::UnixListener, task::JoinHandle};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn temp_path(suffix: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("firec-agent-{}{suffix}", Uuid::new_v4()))
+    }
+
+    /// A fake Firecracker vsock socket with an agent behind it, running `agent` on the connection
+    /// once Firecracker accepted it.
+    fn fake_agent_with<F, Fut, T>(agent: F) -> (PathBuf, JoinHandle<T>)
+    where
+        F: FnOnce(Connection) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let uds_path = temp_path(".sock");
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        let agent = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(stream);
+            let mut connect = String::new();
+            conn.read_line(&mut connect).await.unwrap();
+            assert_eq!(connect, format!("CONNECT {DEFAULT_AGENT_PORT}\n"));
+            conn.write_all(b"OK 1073741824\n").await.unwrap();
+
+            agent(conn).await
+        });
+
+        (uds_path, agent)
+    }
+
+    /// A fake agent answering `response` to the first request and returning the request line.
+    fn fake_agent(response: Vec<u8>) -> (PathBuf, JoinHandle<String>) {
+        fake_agent_with(|mut conn| async move {
+            let request = read_line(&mut conn).await.unwrap();
+            conn.write_all(&response).await.unwrap();
+
+            request
+        })
+    }
+
+    /// A `GET` response for `content`, with the checksum `sha256`.
+    fn get_response(content: &[u8], sha256: &str) -> Vec<u8> {
+        let mut response = format!("OK {}\n", content.len()).into_bytes();
+        response.extend_from_slice(content);
+        response.extend_from_slice(format!("{sha256}\n").as_bytes());
+        response
+    }
+
+    fn sha256(content: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(content))
+    }
+
+    fn part_path(host_path: &Path) -> PathBuf {
+        let mut part_path = OsString::from(host_path);
+        part_path.push(".part");
+        part_path.into()
+    }
+
+    #[tokio::test]
+    async fn push_round_trip() {
+        let host_path = temp_path(".txt");
+        std::fs::write(&host_path, b"hello world").unwrap();
+        let (uds_path, agent) = fake_agent_with(|mut conn| async move {
+            let request = read_line(&mut conn).await.unwrap();
+            let mut content = vec![0; 11];
+            conn.read_exact(&mut content).await.unwrap();
+            let checksum = read_line(&mut conn).await.unwrap();
+            conn.write_all(b"OK\n").await.unwrap();
+
+            (request, content, checksum)
+        });
+
+        let len = push(&uds_path, DEFAULT_AGENT_PORT, &host_path, "/etc/motd")
+            .await
+            .unwrap();
+        assert_eq!(len, 11);
+        let (request, content, checksum) = agent.await.unwrap();
+        assert_eq!(request, "PUT 11 /etc/motd");
+        assert_eq!(content, b"hello world");
+        assert_eq!(checksum, sha256(b"hello world"));
+
+        // The agent reports checksum mismatches.
+        let (uds_path_err, agent) = fake_agent_with(|mut conn| async move {
+            let mut request = vec![0; "PUT 11 /etc/motd\n".len() + 11 + 65];
+            conn.read_exact(&mut request).await.unwrap();
+            conn.write_all(b"ERR checksum mismatch\n").await.unwrap();
+        });
+        let err = push(&uds_path_err, DEFAULT_AGENT_PORT, &host_path, "/etc/motd")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Agent(AgentError::Remote(message)) if message == "checksum mismatch"),
+            "{err:?}"
+        );
+        agent.await.unwrap();
+
+        for path in [host_path, uds_path, uds_path_err] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn pull_round_trip() {
+        let host_path = temp_path(".txt");
+        let (uds_path, agent) = fake_agent(get_response(b"hello", &sha256(b"hello")));
+
+        let len = pull(&uds_path, DEFAULT_AGENT_PORT, "/etc/hostname", &host_path)
+            .await
+            .unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(agent.await.unwrap(), "GET /etc/hostname");
+        assert_eq!(std::fs::read(&host_path).unwrap(), b"hello");
+        assert!(!part_path(&host_path).exists());
+
+        std::fs::remove_file(&host_path).unwrap();
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pull_checksum_mismatch() {
+        let host_path = temp_path(".txt");
+        let (uds_path, agent) = fake_agent(get_response(b"hello", &sha256(b"world")));
+
+        let err = pull(&uds_path, DEFAULT_AGENT_PORT, "/etc/hostname", &host_path)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::ChecksumMismatch(path) if *path == host_path),
+            "{err:?}"
+        );
+        agent.await.unwrap();
+        // Nothing is left behind, not even the partial file.
+        assert!(!host_path.exists());
+        assert!(!part_path(&host_path).exists());
+
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pull_truncated() {
+        let host_path = temp_path(".txt");
+        // The agent closes the connection after 5 of the 10 announced bytes.
+        let (uds_path, agent) = fake_agent(b"OK 10\nhello".to_vec());
+
+        let err = pull(&uds_path, DEFAULT_AGENT_PORT, "/etc/hostname", &host_path)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::Agent(AgentError::ConnectionClosed)),
+            "{err:?}"
+        );
+        agent.await.unwrap();
+        assert!(!host_path.exists());
+        assert!(!part_path(&host_path).exists());
+
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        // Request lines can't smuggle other requests.
+        let err = request(
+            Path::new("/nonexistent"),
+            DEFAULT_AGENT_PORT,
+            "GET /a\nGET /b",
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(err, Error::Agent(AgentError::InvalidRequest(_))),
+            "{err:?}"
+        );
+
+        // Firecracker refuses the connection when no agent listens on the port.
+        let uds_path = temp_path(".sock");
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        let firecracker = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(stream);
+            let mut connect = String::new();
+            conn.read_line(&mut connect).await.unwrap();
+            conn.write_all(b"ERR no listener\n").await.unwrap();
+        });
+        let err = request(&uds_path, DEFAULT_AGENT_PORT, "GET /etc/hostname")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Io(e) if e.kind() == std::io::ErrorKind::ConnectionRefused),
+            "{err:?}"
+        );
+        firecracker.await.unwrap();
+        std::fs::remove_file(&uds_path).unwrap();
+
+        // Unknown responses are rejected.
+        let (uds_path, agent) = fake_agent(b"MAYBE\n".to_vec());
+        let mut conn = request(&uds_path, DEFAULT_AGENT_PORT, "GET /etc/hostname")
+            .await
+            .unwrap();
+        let err = response(&mut conn).await.unwrap_err();
+        assert!(
+            matches!(&err, Error::Agent(AgentError::UnexpectedResponse(line)) if line == "MAYBE"),
+            "{err:?}"
+        );
+        agent.await.unwrap();
+        std::fs::remove_file(&uds_path).unwrap();
+
+        // So are endless lines.
+        let (uds_path, agent) = fake_agent(vec![b'x'; MAX_LINE_LEN * 2]);
+        let mut conn = request(&uds_path, DEFAULT_AGENT_PORT, "GET /etc/hostname")
+            .await
+            .unwrap();
+        let err = response(&mut conn).await.unwrap_err();
+        assert!(
+            matches!(err, Error::Agent(AgentError::LineTooLong(MAX_LINE_LEN))),
+            "{err:?}"
+        );
+        agent.await.unwrap();
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    fn exec_request() -> ExecRequest {
+        ExecRequest {
+            cmd: "uname".to_owned(),
+            args: vec!["-r".to_owned()],
+            env: BTreeMap::new(),
+            timeout_ms: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_output_limit() {
+        let timeout = Duration::from_secs(5);
+
+        let (uds_path, agent) = fake_agent(b"OK 0 6 3\n6.1.0\nerr".to_vec());
+        let output = exec(&uds_path, DEFAULT_AGENT_PORT, exec_request(), timeout, 6)
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"6.1.0\n");
+        assert_eq!(output.stderr, b"err");
+        assert!(agent.await.unwrap().starts_with("EXEC {\"cmd\":\"uname\""));
+        std::fs::remove_file(&uds_path).unwrap();
+
+        // The lengths come from the guest, which mustn't get the host to allocate arbitrary
+        // amounts of memory.
+        let (uds_path, agent) = fake_agent(format!("OK 0 {} 0\n", usize::MAX).into_bytes());
+        let err = exec(&uds_path, DEFAULT_AGENT_PORT, exec_request(), timeout, 6)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::Agent(AgentError::OutputTooLarge { max: 6, .. })),
+            "{err:?}"
+        );
+        agent.await.unwrap();
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+}
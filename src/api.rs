@@ -0,0 +1,132 @@
+//! Firecracker API models, generated from the API specs of Firecracker releases.
+//!
+//! The swagger spec of each supported release (`src/firecracker/swagger/firecracker.yaml` in the
+//! Firecracker repository) is vendored under `api/`, and the `fc-<major>_<minor>` features
+//! generate the models of a release in the matching module at build time. The models follow the
+//! spec field by field and reject unknown fields like Firecracker does. When the configuration
+//! pins a release whose models are built in (see [`crate::config::Builder::firecracker_version`]),
+//! the request bodies sent to the VMM are serialized through them, and a body that doesn't match
+//! the spec fails with [`crate::Error::ApiSpecMismatch`] instead of reaching the VMM.
+//!
+//! Supporting a new release means vendoring its spec, adding it and its tag to the versions of
+//! `build.rs`, adding its feature to `Cargo.toml` and its module here.
+
+/// Models of the Firecracker 1.7 API.
+#[cfg(feature = "fc-1_7")]
+#[allow(missing_docs)]
+pub mod v1_7 {
+    include!(concat!(env!("OUT_DIR"), "/api_v1_7.rs"));
+}
+
+/// Models of the Firecracker 1.10 API.
+#[cfg(feature = "fc-1_10")]
+#[allow(missing_docs)]
+pub mod v1_10 {
+    include!(concat!(env!("OUT_DIR"), "/api_v1_10.rs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::Value;
+
+    use crate::{
+        config::{network::Interface, Config, MmdsVersion},
+        plan::PlannedOperation,
+        snapshot::Snapshot,
+        version::FirecrackerVersion,
+        Machine, StartOptions,
+    };
+
+    /// The bodies of the API calls starting, or restoring, a VM pinned to `version`.
+    async fn payloads(version: FirecrackerVersion, restore: bool) -> Vec<(String, Value)> {
+        let config = Config::builder(None, Path::new("/tmp/kernel.bin"))
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .build()
+            .firecracker_version(version)
+            .log_path(Path::new("/tmp/firecracker.log"))
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .add_network_interface(Interface::new("tap0", "eth0", Some("06:00:00:00:00:01")))
+            .mmds_cfg(MmdsVersion::V2, ["eth0"], None::<&str>)
+            .vsock_cfg(3, Path::new("/tmp/vsock.sock"))
+            .balloon_cfg(64, true, 1)
+            .build()
+            .unwrap();
+        let mut options = StartOptions::default();
+        if restore {
+            options = options.restore(Snapshot::in_chroot(&config, "snap"));
+        }
+
+        Machine::plan(&config, &options)
+            .await
+            .unwrap()
+            .start
+            .into_iter()
+            .filter_map(|op| match op {
+                PlannedOperation::ApiCall {
+                    path,
+                    body: Some(body),
+                    ..
+                } => Some((path, body)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Check that the payloads sent to a VM pinned to `$version` match the models of `$api`.
+    macro_rules! check_payloads {
+        ($api:ident, $version:expr) => {{
+            use super::$api as api;
+
+            let mut paths = Vec::new();
+            for restore in [false, true] {
+                for (path, body) in payloads($version, restore).await {
+                    let checked = match path.split('/').nth(1).unwrap() {
+                        "actions" => {
+                            serde_json::from_value::<api::InstanceActionInfo>(body).map(drop)
+                        }
+                        "balloon" => serde_json::from_value::<api::Balloon>(body).map(drop),
+                        "boot-source" => serde_json::from_value::<api::BootSource>(body).map(drop),
+                        "drives" => serde_json::from_value::<api::Drive>(body).map(drop),
+                        "logger" => serde_json::from_value::<api::Logger>(body).map(drop),
+                        "machine-config" => {
+                            serde_json::from_value::<api::MachineConfiguration>(body).map(drop)
+                        }
+                        "mmds" => serde_json::from_value::<api::MmdsConfig>(body).map(drop),
+                        "network-interfaces" => {
+                            serde_json::from_value::<api::NetworkInterface>(body).map(drop)
+                        }
+                        "snapshot" => {
+                            serde_json::from_value::<api::SnapshotLoadParams>(body).map(drop)
+                        }
+                        "vsock" => serde_json::from_value::<api::Vsock>(body).map(drop),
+                        _ => panic!("no model for {path}"),
+                    };
+                    if let Err(e) = checked {
+                        panic!("payload of {path} doesn't match the spec: {e}");
+                    }
+                    paths.push(path);
+                }
+            }
+            assert!(paths.iter().any(|path| path == "/snapshot/load"));
+            assert!(paths.iter().any(|path| path == "/drives/root"));
+        }};
+    }
+
+    #[cfg(feature = "fc-1_7")]
+    #[tokio::test]
+    async fn payloads_v1_7() {
+        check_payloads!(v1_7, FirecrackerVersion::new(1, 7, 0));
+    }
+
+    #[cfg(feature = "fc-1_10")]
+    #[tokio::test]
+    async fn payloads_v1_10() {
+        check_payloads!(v1_10, FirecrackerVersion::new(1, 10, 1));
+    }
+}
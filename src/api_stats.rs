@@ -0,0 +1,217 @@
+//! Latency and payload size statistics of Firecracker API calls.
+//!
+//! Every API call of a machine is recorded per endpoint (see [`crate::Machine::api_stats`]): its
+//! latency in a [`Histogram`] and the sizes of its request and response bodies. This helps
+//! finding what slows a start down, e.g drive configuration dominating the boot time. Endpoints
+//! of collections are grouped, e.g all the drives under `PUT /drives/{id}`.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::Method;
+use serde::Serialize;
+
+/// Upper bounds of the latency buckets of [`Histogram`], in microseconds.
+pub const LATENCY_BUCKETS_US: [u64; 14] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 5_000_000,
+];
+
+/// Collections whose members are grouped under a single endpoint.
+const COLLECTIONS: [&str; 2] = ["/drives/", "/network-interfaces/"];
+
+/// A latency histogram, with the buckets of [`LATENCY_BUCKETS_US`] and an overflow bucket.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Histogram {
+    /// The number of calls of each bucket, the last one counting calls slower than all bounds.
+    pub buckets: [u64; LATENCY_BUCKETS_US.len() + 1],
+    /// The number of calls.
+    pub count: u64,
+    /// The total latency.
+    pub sum: Duration,
+    /// The highest latency.
+    pub max: Duration,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros();
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| us <= u128::from(bound))
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// The mean latency, if any call was recorded.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum / self.count as u32)
+    }
+
+    /// An upper bound of the `q` quantile (between 0 and 1) of the latency, if any call was
+    /// recorded: the bound of its bucket, or the highest latency for the overflow bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * q.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = LATENCY_BUCKETS_US
+                    .get(bucket)
+                    .map(|&us| Duration::from_micros(us))
+                    .unwrap_or(self.max);
+                return Some(bound.min(self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+}
+
+/// The statistics of an endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EndpointStats {
+    /// The number of failed calls, i.e. without a successful response.
+    pub errors: u64,
+    /// The latency of the calls, failed or not.
+    pub latency: Histogram,
+    /// The total size of the request bodies, in bytes.
+    pub request_bytes: u64,
+    /// The total size of the response bodies, in bytes.
+    pub response_bytes: u64,
+    /// The largest request body, in bytes.
+    pub max_request_bytes: u64,
+    /// The largest response body, in bytes.
+    pub max_response_bytes: u64,
+}
+
+/// The statistics of the API calls of a machine, by endpoint (e.g `PUT /drives/{id}`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ApiStats {
+    /// The statistics of each endpoint.
+    pub endpoints: BTreeMap<String, EndpointStats>,
+}
+
+impl ApiStats {
+    /// The endpoints, those the most time was spent on first.
+    pub fn by_total_time(&self) -> Vec<(&str, &EndpointStats)> {
+        let mut endpoints: Vec<_> = self
+            .endpoints
+            .iter()
+            .map(|(endpoint, stats)| (endpoint.as_str(), stats))
+            .collect();
+        endpoints.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.latency.sum));
+
+        endpoints
+    }
+
+    /// The total time spent in API calls.
+    pub fn total_time(&self) -> Duration {
+        self.endpoints.values().map(|stats| stats.latency.sum).sum()
+    }
+}
+
+/// The recorded API call statistics, shared by the clones of an API client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ApiStatsRecorder(Arc<Mutex<ApiStats>>);
+
+impl ApiStatsRecorder {
+    /// Record a call.
+    pub(crate) fn record(&self, call: &Call<'_>) {
+        let mut stats = self.0.lock().unwrap();
+        let stats = stats
+            .endpoints
+            .entry(endpoint(call.method, call.path))
+            .or_default();
+        if !call.success {
+            stats.errors += 1;
+        }
+        stats.latency.record(call.latency);
+        stats.request_bytes += call.request_bytes;
+        stats.response_bytes += call.response_bytes;
+        stats.max_request_bytes = stats.max_request_bytes.max(call.request_bytes);
+        stats.max_response_bytes = stats.max_response_bytes.max(call.response_bytes);
+    }
+
+    pub(crate) fn snapshot(&self) -> ApiStats {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.0.lock().unwrap() = ApiStats::default();
+    }
+}
+
+/// An API call, as recorded.
+#[derive(Debug)]
+pub(crate) struct Call<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub latency: Duration,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub success: bool,
+}
+
+/// The endpoint of a `method` call to `path`.
+fn endpoint(method: &Method, path: &str) -> String {
+    let path = path.split('?').next().unwrap_or(path);
+    match COLLECTIONS
+        .iter()
+        .find(|collection| path.starts_with(*collection))
+    {
+        Some(collection) => format!("{method} {collection}{{id}}"),
+        None => format!("{method} {path}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recording() {
+        let recorder = ApiStatsRecorder::default();
+        let call = |path, latency_ms, request_bytes, success| Call {
+            method: &Method::PUT,
+            path,
+            latency: Duration::from_millis(latency_ms),
+            request_bytes,
+            response_bytes: 0,
+            success,
+        };
+        recorder.record(&call("/drives/rootfs", 40, 120, true));
+        recorder.record(&call("/drives/data", 60, 200, false));
+        recorder.record(&call("/machine-config", 1, 50, true));
+
+        let stats = recorder.snapshot();
+        let (endpoint, drives) = stats.by_total_time()[0];
+        assert_eq!(endpoint, "PUT /drives/{id}");
+        assert_eq!(drives.errors, 1);
+        assert_eq!(drives.request_bytes, 320);
+        assert_eq!(drives.max_request_bytes, 200);
+        assert_eq!(drives.latency.count, 2);
+        assert_eq!(drives.latency.mean(), Some(Duration::from_millis(50)));
+        assert_eq!(
+            drives.latency.quantile(0.5),
+            Some(Duration::from_millis(50))
+        );
+        assert_eq!(
+            drives.latency.quantile(1.0),
+            Some(Duration::from_millis(60))
+        );
+        assert_eq!(stats.total_time(), Duration::from_millis(101));
+
+        recorder.reset();
+        assert!(recorder.snapshot().endpoints.is_empty());
+    }
+}
@@ -0,0 +1,329 @@
+//! Audit log of privileged host operations.
+//!
+//! When enabled through [`crate::config::Builder::audit_log_dir`], the privileged operations
+//! performed on the host to set up, provision and tear down a VM are appended as JSON lines to
+//! [`crate::config::Config::audit_log_path`]: writes to the chroot, the VM directory and mounted
+//! images (through the [`crate::fs::ChrootFs`]), device nodes, commands like the jailer,
+//! `iptables`, `dmsetup` or `mount` (through the [`crate::spawner::ProcessSpawner`]), the vsock
+//! listener sockets handed to the VMM and signals sent to the VMM process.
+//!
+//! Files read or written on behalf of the caller aren't recorded: the jailer output and rotated
+//! logs, saved state, recordings, files pulled from the guest and snapshots moved by
+//! [`crate::storage`].
+//!
+//! Operations are recorded before they're performed, and aren't performed if they can't be
+//! recorded. The log is kept when the VM is deleted, so it must be outside of the VM directory.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, task};
+
+use crate::{
+    config::VmId,
+    fs::ChrootFs,
+    spawner::{ChildProcess, ProcessSpawner},
+    Error,
+};
+
+/// A recorded privileged operation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Time of the operation, in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u128,
+    /// The VM the operation was performed for.
+    pub vm_id: VmId,
+    /// The operation.
+    pub action: AuditAction,
+}
+
+/// A privileged operation, see [`AuditEntry`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditAction {
+    /// A directory was created, along with its parents.
+    CreateDir {
+        /// The host path of the directory.
+        path: PathBuf,
+    },
+    /// A file was copied.
+    Copy {
+        /// The host path of the source.
+        src: PathBuf,
+        /// The host path of the copy.
+        dest: PathBuf,
+    },
+    /// A file was hard linked.
+    HardLink {
+        /// The host path of the linked file.
+        src: PathBuf,
+        /// The host path of the link.
+        dest: PathBuf,
+    },
+    /// A block device node was created.
+    MakeBlockDevice {
+        /// The host path of the node.
+        path: PathBuf,
+        /// The major number of the device.
+        major: u32,
+        /// The minor number of the device.
+        minor: u32,
+    },
+    /// A file was written.
+    WriteFile {
+        /// The host path of the file.
+        path: PathBuf,
+        /// The size of the content in bytes.
+        size: u64,
+    },
+    /// The owner and permissions of a file were changed.
+    SetOwner {
+        /// The host path of the file.
+        path: PathBuf,
+        /// The user ID.
+        uid: u32,
+        /// The group ID.
+        gid: u32,
+        /// The permission bits.
+        mode: u32,
+    },
+    /// A file was removed.
+    RemoveFile {
+        /// The host path of the file.
+        path: PathBuf,
+    },
+    /// A directory was removed, along with its contents.
+    RemoveDir {
+        /// The host path of the directory.
+        path: PathBuf,
+    },
+    /// A command was run, e.g the jailer or `iptables`.
+    Command {
+        /// The command line.
+        command: Vec<String>,
+    },
+    /// A signal was sent to a process.
+    Signal {
+        /// The ID of the process.
+        pid: u32,
+        /// The name of the signal, e.g `SIGKILL`.
+        signal: String,
+    },
+}
+
+/// The audit log of a VM, shared by the clones of its audited filesystem and spawner.
+#[derive(Debug, Clone)]
+pub(crate) struct AuditLog {
+    vm_id: VmId,
+    path: PathBuf,
+    // Serializes the appends, so that lines are never interleaved.
+    lock: Arc<Mutex<()>>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(vm_id: VmId, path: PathBuf) -> Self {
+        Self {
+            vm_id,
+            path,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Append `action` to the log, on the blocking thread pool.
+    pub(crate) async fn record(&self, action: AuditAction) -> io::Result<()> {
+        let log = self.clone();
+        task::spawn_blocking(move || log.record_blocking(action))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Append `action` to the log, blocking the current thread.
+    ///
+    /// Only for callers that can't await, like [`ProcessSpawner::spawn`].
+    pub(crate) fn record_blocking(&self, action: AuditAction) -> io::Result<()> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let entry = AuditEntry {
+            timestamp_ms,
+            vm_id: self.vm_id.clone(),
+            action,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().unwrap();
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)
+    }
+}
+
+/// Read the entries of the audit log at `path`.
+pub async fn read(path: &Path) -> Result<Vec<AuditEntry>, Error> {
+    let content = tokio::fs::read_to_string(path).await?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(Into::into))
+        .collect()
+}
+
+/// A [`ChrootFs`] recording the changes it makes to an [`AuditLog`].
+#[derive(Debug)]
+pub(crate) struct AuditedFs {
+    pub(crate) inner: Arc<dyn ChrootFs>,
+    pub(crate) log: AuditLog,
+}
+
+impl AuditedFs {
+    fn audited<'a, T: Send + 'a>(
+        &'a self,
+        action: AuditAction,
+        op: BoxFuture<'a, io::Result<T>>,
+    ) -> BoxFuture<'a, io::Result<T>> {
+        async move {
+            self.log.record(action).await?;
+            op.await
+        }
+        .boxed()
+    }
+}
+
+impl ChrootFs for AuditedFs {
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::CreateDir {
+            path: path.to_owned(),
+        };
+        self.audited(action, self.inner.create_dir_all(path))
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<bool>> {
+        self.inner.exists(path)
+    }
+
+    fn file_len<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        self.inner.file_len(path)
+    }
+
+    fn copy<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        let action = AuditAction::Copy {
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+        };
+        self.audited(action, self.inner.copy(src, dest))
+    }
+
+    fn same_contents<'a>(
+        &'a self,
+        src: &'a Path,
+        dest: &'a Path,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        self.inner.same_contents(src, dest)
+    }
+
+    fn hard_link<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::HardLink {
+            src: src.to_owned(),
+            dest: dest.to_owned(),
+        };
+        self.audited(action, self.inner.hard_link(src, dest))
+    }
+
+    fn make_block_device<'a>(
+        &'a self,
+        path: &'a Path,
+        major: u32,
+        minor: u32,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::MakeBlockDevice {
+            path: path.to_owned(),
+            major,
+            minor,
+        };
+        self.audited(action, self.inner.make_block_device(path, major, minor))
+    }
+
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        self.inner.read(path)
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::WriteFile {
+            path: path.to_owned(),
+            size: contents.len() as u64,
+        };
+        self.audited(action, self.inner.write(path, contents))
+    }
+
+    fn set_owner<'a>(
+        &'a self,
+        path: &'a Path,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::SetOwner {
+            path: path.to_owned(),
+            uid,
+            gid,
+            mode,
+        };
+        self.audited(action, self.inner.set_owner(path, uid, gid, mode))
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::RemoveFile {
+            path: path.to_owned(),
+        };
+        self.audited(action, self.inner.remove_file(path))
+    }
+
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        let action = AuditAction::RemoveDir {
+            path: path.to_owned(),
+        };
+        self.audited(action, self.inner.remove_dir_all(path))
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        self.inner.read_dir(path)
+    }
+
+    fn disk_usage<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        self.inner.disk_usage(path)
+    }
+}
+
+/// A [`ProcessSpawner`] recording the commands it runs to an [`AuditLog`].
+#[derive(Debug)]
+pub(crate) struct AuditedSpawner {
+    pub(crate) inner: Arc<dyn ProcessSpawner>,
+    pub(crate) log: AuditLog,
+}
+
+impl ProcessSpawner for AuditedSpawner {
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Box<dyn ChildProcess>> {
+        let std_cmd = cmd.as_std();
+        let command = std::iter::once(std_cmd.get_program())
+            .chain(std_cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        self.log.record_blocking(AuditAction::Command { command })?;
+
+        self.inner.spawn(cmd)
+    }
+}
@@ -0,0 +1,230 @@
+//! Balloon device statistics and memory autoscaling.
+
+use std::time::Duration;
+
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{client::ApiClient, task::TaskHandle, Error};
+
+/// Statistics reported by the balloon device.
+///
+/// Memory sizes are in bytes. Statistics not reported by the guest are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalloonStats {
+    /// Target number of pages the balloon should hold.
+    pub target_pages: u32,
+    /// Number of pages the balloon actually holds.
+    pub actual_pages: u32,
+    /// Target size of the balloon in MiB.
+    pub target_mib: u32,
+    /// Actual size of the balloon in MiB.
+    pub actual_mib: u32,
+    /// Amount of memory swapped in.
+    pub swap_in: Option<u64>,
+    /// Amount of memory swapped out.
+    pub swap_out: Option<u64>,
+    /// Number of major page faults.
+    pub major_faults: Option<u64>,
+    /// Number of minor page faults.
+    pub minor_faults: Option<u64>,
+    /// Memory not in use by the guest.
+    pub free_memory: Option<u64>,
+    /// Total memory available to the guest.
+    pub total_memory: Option<u64>,
+    /// Memory available for new allocations without swapping, as estimated by the guest.
+    pub available_memory: Option<u64>,
+    /// Memory used by the guest's disk caches.
+    pub disk_caches: Option<u64>,
+    /// Number of successful hugetlb page allocations.
+    pub hugetlb_allocations: Option<u64>,
+    /// Number of failed hugetlb page allocations.
+    pub hugetlb_failures: Option<u64>,
+}
+
+impl BalloonStats {
+    /// The memory available to the guest in MiB, if reported.
+    ///
+    /// Uses `available_memory`, falling back to `free_memory`.
+    pub fn available_mib(&self) -> Option<u64> {
+        self.available_memory
+            .or(self.free_memory)
+            .map(|bytes| bytes >> 20)
+    }
+}
+
+/// Policy for [`crate::Machine::autoscale_memory`].
+///
+/// Every `interval`, the balloon is inflated by `step_mib` if the guest has more than
+/// `max_available_mib` available, and deflated by `step_mib` if it has less than
+/// `min_available_mib`, keeping the available guest memory within that band.
+#[derive(Debug, Clone)]
+pub struct AutoscalePolicy {
+    pub(crate) min_available_mib: u64,
+    pub(crate) max_available_mib: u64,
+    pub(crate) step_mib: u32,
+    pub(crate) max_balloon_mib: Option<u32>,
+    pub(crate) interval: Duration,
+}
+
+impl AutoscalePolicy {
+    /// Create a policy keeping the available guest memory between the given bounds, in MiB.
+    pub fn new(min_available_mib: u64, max_available_mib: u64) -> Self {
+        Self {
+            min_available_mib,
+            max_available_mib,
+            step_mib: 64,
+            max_balloon_mib: None,
+            interval: Duration::from_secs(5),
+        }
+    }
+
+    /// Set by how much the balloon is inflated or deflated at once.
+    ///
+    /// Defaults to 64 MiB.
+    pub fn step_mib(mut self, step_mib: u32) -> Self {
+        self.step_mib = step_mib;
+        self
+    }
+
+    /// Set the maximum size of the balloon.
+    ///
+    /// Defaults to the memory size of the machine.
+    pub fn max_balloon_mib(mut self, max_balloon_mib: u32) -> Self {
+        self.max_balloon_mib = Some(max_balloon_mib);
+        self
+    }
+
+    /// Set how often the balloon statistics are checked.
+    ///
+    /// Defaults to 5 seconds. There's no point in making this shorter than the statistics
+    /// polling interval of the balloon.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// The balloon size to aim for, given its current statistics.
+    fn target_mib(&self, stats: &BalloonStats, max_balloon_mib: u32) -> Option<u32> {
+        let available_mib = stats.available_mib()?;
+        let target_mib = if available_mib > self.max_available_mib {
+            stats
+                .target_mib
+                .saturating_add(self.step_mib)
+                .min(max_balloon_mib)
+        } else if available_mib < self.min_available_mib {
+            stats.target_mib.saturating_sub(self.step_mib)
+        } else {
+            return None;
+        };
+
+        (target_mib != stats.target_mib).then_some(target_mib)
+    }
+}
+
+pub(crate) async fn stats(client: &ApiClient) -> Result<BalloonStats, Error> {
+    let body = client
+        .send(Method::GET, "/balloon/statistics", None)
+        .await?
+        .unwrap_or_default();
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+pub(crate) async fn update(client: &ApiClient, amount_mib: u32) -> Result<(), Error> {
+    let json = serde_json::to_string(&serde_json::json!({ "amount_mib": amount_mib }))?;
+    client.send(Method::PATCH, "/balloon", Some(json)).await?;
+
+    Ok(())
+}
+
+pub(crate) async fn update_stats_interval(
+    client: &ApiClient,
+    stats_polling_interval_s: u32,
+) -> Result<(), Error> {
+    let json = serde_json::to_string(&serde_json::json!({
+        "stats_polling_interval_s": stats_polling_interval_s,
+    }))?;
+    client
+        .send(Method::PATCH, "/balloon/statistics", Some(json))
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) fn autoscale(
+    client: ApiClient,
+    policy: AutoscalePolicy,
+    mem_size_mib: u32,
+) -> TaskHandle {
+    let max_balloon_mib = policy.max_balloon_mib.unwrap_or(mem_size_mib);
+
+    TaskHandle::new(tokio::spawn(run_autoscaler(
+        client,
+        policy,
+        max_balloon_mib,
+    )))
+}
+
+#[instrument(skip_all)]
+async fn run_autoscaler(client: ApiClient, policy: AutoscalePolicy, max_balloon_mib: u32) {
+    info!(
+        min_available_mib = policy.min_available_mib,
+        max_available_mib = policy.max_available_mib,
+        "Starting memory autoscaler"
+    );
+    loop {
+        sleep(policy.interval).await;
+
+        let stats = match stats(&client).await {
+            Ok(stats) => stats,
+            Err(err) => {
+                warn!(error = %err, "Failed to get balloon statistics");
+                continue;
+            }
+        };
+        let target_mib = match policy.target_mib(&stats, max_balloon_mib) {
+            Some(target_mib) => target_mib,
+            None => continue,
+        };
+        debug!(
+            available_mib = stats.available_mib(),
+            from_mib = stats.target_mib,
+            to_mib = target_mib,
+            "Resizing balloon"
+        );
+        if let Err(err) = update(&client, target_mib).await {
+            warn!(error = %err, "Failed to resize balloon");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn autoscale_target() {
+        let policy = AutoscalePolicy::new(256, 1024).step_mib(128);
+        let stats = |target_mib, available_mib: u64| BalloonStats {
+            target_mib,
+            available_memory: Some(available_mib << 20),
+            ..Default::default()
+        };
+
+        // Within the band.
+        assert_eq!(policy.target_mib(&stats(512, 512), 2048), None);
+        // Too much available memory, inflate up to the maximum.
+        assert_eq!(policy.target_mib(&stats(512, 2048), 2048), Some(640));
+        assert_eq!(policy.target_mib(&stats(2000, 2048), 2048), Some(2048));
+        assert_eq!(policy.target_mib(&stats(2048, 2048), 2048), None);
+        // Too little available memory, deflate down to nothing.
+        assert_eq!(policy.target_mib(&stats(512, 128), 2048), Some(384));
+        assert_eq!(policy.target_mib(&stats(64, 128), 2048), Some(0));
+        assert_eq!(policy.target_mib(&stats(0, 128), 2048), None);
+        // No statistics reported yet.
+        assert_eq!(policy.target_mib(&BalloonStats::default(), 2048), None);
+    }
+}
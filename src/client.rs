@@ -0,0 +1,250 @@
+//! HTTP client for the Firecracker API.
+
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{http::request, Body, Client, Method, Request, Response};
+use hyperlocal::{UnixConnector, Uri};
+use tracing::{field, instrument, trace, warn, Span};
+
+use crate::{
+    api_stats::{self, ApiStats, ApiStatsRecorder},
+    compat,
+    config::{Config, VmId},
+    recording::{self, ApiCall},
+    transport::ApiTransport,
+    version::FirecrackerVersion,
+    Error,
+};
+
+/// How long an idle connection to the API socket is kept open.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Maximum number of idle connections kept open per API socket.
+const POOL_MAX_IDLE_PER_SOCKET: usize = 8;
+
+/// Create a client for the Firecracker API.
+///
+/// Connections are kept alive and pooled per socket, so repeated calls against the same VMM
+/// (e.g metrics polling) reuse an already established connection instead of reconnecting for
+/// every request. As pooled connections are tied to a VMM process, a new client should be
+/// created whenever the VMM is (re)started.
+pub(crate) fn api_client() -> Client<UnixConnector> {
+    Client::builder()
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .pool_max_idle_per_host(POOL_MAX_IDLE_PER_SOCKET)
+        .build(UnixConnector)
+}
+
+/// Client for the API of a single Firecracker process.
+///
+/// Cheap to clone, clones share the connection pool.
+#[derive(Debug, Clone)]
+pub(crate) struct ApiClient {
+    client: Client<UnixConnector>,
+    vm_id: VmId,
+    socket_path: PathBuf,
+    record_path: Option<PathBuf>,
+    version: Option<FirecrackerVersion>,
+    transport: Option<Arc<dyn ApiTransport>>,
+    stats: ApiStatsRecorder,
+}
+
+impl ApiClient {
+    pub(crate) fn new(config: &Config<'_>) -> Self {
+        Self {
+            client: api_client(),
+            vm_id: config.vm_id().clone(),
+            socket_path: config.host_socket_path(),
+            record_path: config.record_api_calls().then(|| config.api_record_path()),
+            version: config.firecracker_version(),
+            transport: config.api_transport().cloned(),
+            stats: ApiStatsRecorder::default(),
+        }
+    }
+
+    /// The statistics of the calls made so far, see [`crate::api_stats`].
+    pub(crate) fn stats(&self) -> ApiStats {
+        self.stats.snapshot()
+    }
+
+    /// Forget the statistics of the calls made so far.
+    pub(crate) fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// Check if the API is up, without recording the call.
+    pub(crate) async fn ping(&self) -> Result<(), Error> {
+        let request = self
+            .request_builder(Method::GET, "/version")?
+            .header("Accept", "application/json")
+            .body(Body::empty())?;
+        let status = self.request(request).await?.status();
+        if !status.is_success() {
+            return Err(Error::FirecrackerAPIError { status, body: None });
+        }
+
+        Ok(())
+    }
+
+    fn request_builder(&self, method: Method, path: &str) -> Result<request::Builder, Error> {
+        let builder = Request::builder().method(method);
+        Ok(match self.transport {
+            None => builder.uri(Uri::new(&self.socket_path, path)),
+            // The request is sent as is over the stream, so it must be in origin form.
+            Some(_) => builder.uri(path).header("Host", "localhost"),
+        })
+    }
+
+    async fn request(&self, request: Request<Body>) -> Result<Response<Body>, Error> {
+        let transport = match &self.transport {
+            Some(transport) => transport,
+            None => {
+                return self
+                    .client
+                    .request(request)
+                    .await
+                    .map_err(|e| self.connect_error(e))
+            }
+        };
+        let stream = transport.connect(&self.socket_path).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                trace!(error = %e, "API connection failed");
+            }
+        });
+
+        Ok(sender.send_request(request).await?)
+    }
+
+    /// Map errors connecting to the API socket to dedicated variants.
+    fn connect_error(&self, e: hyper::Error) -> Error {
+        let kind = if e.is_connect() {
+            let mut source = std::error::Error::source(&e);
+            loop {
+                match source {
+                    Some(err) => match err.downcast_ref::<io::Error>() {
+                        Some(err) => break Some(err.kind()),
+                        None => source = err.source(),
+                    },
+                    None => break None,
+                }
+            }
+        } else {
+            None
+        };
+
+        match kind {
+            Some(io::ErrorKind::NotFound) => Error::SocketNotFound {
+                path: self.socket_path.clone(),
+            },
+            Some(io::ErrorKind::ConnectionRefused) => Error::VmmNotListening {
+                path: self.socket_path.clone(),
+            },
+            _ => e.into(),
+        }
+    }
+
+    /// Send a request to `path`, returning the response body, if any.
+    #[instrument(
+        skip_all,
+        fields(
+            vm_id = %self.vm_id,
+            endpoint = path,
+            status = field::Empty,
+            duration_ms = field::Empty,
+            request_bytes = field::Empty,
+            response_bytes = field::Empty,
+        )
+    )]
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        self.try_send(method, path, body)
+            .await
+            .map_err(|e| e.at_endpoint(&self.vm_id, path))
+    }
+
+    async fn try_send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<Option<String>, Error> {
+        let body = match (self.version, body) {
+            (Some(version), Some(body)) => {
+                let body = compat::adapt(version, &method, path, body);
+                Some(compat::conform(version, &method, path, body)?)
+            }
+            (_, body) => body,
+        };
+        trace!(%method, body = body.as_deref(), "Sending request");
+
+        let recorded_body = self.record_path.as_ref().and_then(|_| body.clone());
+        let request_bytes = body.as_ref().map_or(0, |body| body.len() as u64);
+        let request = self
+            .request_builder(method.clone(), path)?
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .body(body.map(Body::from).unwrap_or_else(Body::empty))?;
+
+        let start = Instant::now();
+        let span = Span::current();
+        span.record("request_bytes", request_bytes);
+        let mut call = api_stats::Call {
+            method: &method,
+            path,
+            latency: Duration::ZERO,
+            request_bytes,
+            response_bytes: 0,
+            success: false,
+        };
+        let resp = async {
+            let resp = self.request(request).await?;
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await?;
+
+            Ok::<_, Error>((status, body))
+        }
+        .await;
+        call.latency = start.elapsed();
+        span.record("duration_ms", call.latency.as_millis() as u64);
+        let (status, body) = match resp {
+            Ok(resp) => resp,
+            Err(e) => {
+                self.stats.record(&call);
+                return Err(e);
+            }
+        };
+        call.response_bytes = body.len() as u64;
+        call.success = status.is_success();
+        self.stats.record(&call);
+        span.record("status", status.as_u16());
+        span.record("response_bytes", call.response_bytes);
+        let body = (!body.is_empty()).then(|| String::from_utf8_lossy(&body).into_owned());
+        if let Some(record_path) = &self.record_path {
+            let call = ApiCall::new(&method, path, recorded_body, status.as_u16(), body.clone());
+            if let Err(e) = recording::append(record_path, &call).await {
+                warn!(error = %e, "Failed to record API call");
+            }
+        }
+        if status.is_success() {
+            trace!("Request successful");
+        } else {
+            match &body {
+                Some(body) => trace!(%body, "Request failed"),
+                None => trace!("Request failed"),
+            }
+            return Err(Error::FirecrackerAPIError { status, body });
+        }
+
+        Ok(body)
+    }
+}
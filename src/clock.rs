@@ -0,0 +1,80 @@
+//! Guest clock management across pauses and snapshot restores.
+//!
+//! The guest clock stops while the VM is paused or snapshotted, and a restored guest resumes with
+//! the time of the snapshot, unless it re-syncs it itself (e.g through NTP). firec records when
+//! machines are resumed and for how long they were suspended (see
+//! [`crate::Machine::last_resume`]), so callers can compensate, and can set the guest clock to
+//! the host time through the guest agent (see [`crate::Machine::sync_guest_clock`] and
+//! [`crate::StartOptions::sync_clock`]).
+//!
+//! The clock is set with `date -u -s @<seconds since the epoch>`, run by the agent, so it has a
+//! one second precision and requires a `date` supporting the `@` syntax (GNU coreutils,
+//! busybox).
+
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::agent::ExecRequest;
+
+/// The timeout of the command setting the guest clock.
+pub const CLOCK_SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When and how a machine was last resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeInfo {
+    /// When the machine was resumed.
+    pub resumed_at: SystemTime,
+    /// When the guest clock stopped, if known: when the machine was paused (by the same
+    /// [`crate::Machine`]) or when the restored snapshot was written.
+    pub suspended_at: Option<SystemTime>,
+    /// If the machine was restored from a snapshot, rather than resumed after a pause.
+    pub restored: bool,
+}
+
+impl ResumeInfo {
+    pub(crate) fn new(suspended_at: Option<SystemTime>, restored: bool) -> Self {
+        Self {
+            resumed_at: SystemTime::now(),
+            suspended_at,
+            restored,
+        }
+    }
+
+    /// How long the guest clock was stopped, i.e. how far behind it is unless re-synced.
+    pub fn suspended_for(&self) -> Option<Duration> {
+        self.resumed_at.duration_since(self.suspended_at?).ok()
+    }
+}
+
+/// The agent request setting the guest clock to `now`.
+pub(crate) fn sync_request(now: SystemTime) -> ExecRequest {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    ExecRequest {
+        cmd: "date".to_owned(),
+        args: vec!["-u".to_owned(), "-s".to_owned(), format!("@{secs}")],
+        env: BTreeMap::new(),
+        timeout_ms: CLOCK_SYNC_TIMEOUT.as_millis() as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_info() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let info = ResumeInfo {
+            resumed_at: now,
+            suspended_at: Some(now - Duration::from_secs(90)),
+            restored: true,
+        };
+        assert_eq!(info.suspended_for(), Some(Duration::from_secs(90)));
+
+        let request = sync_request(now + Duration::from_millis(400));
+        assert_eq!(request.args, ["-u", "-s", "@1700000000"]);
+    }
+}
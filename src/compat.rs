@@ -0,0 +1,282 @@
+//! Adaptation of API payloads to the Firecracker version in use.
+//!
+//! Payloads are built for the latest supported Firecracker API. When a configuration pins an
+//! older or newer version (see [`crate::config::Builder::firecracker_version`]), the fields that
+//! were renamed, restructured or added across versions are adjusted before the request is sent,
+//! so VMMs of different versions can be driven side by side.
+//!
+//! With the `fc-*` feature of the release in use, payloads are then serialized through the
+//! models generated from its API spec (see [`crate::api`]), so fields and values the release
+//! doesn't know about are caught before reaching the VMM.
+
+use hyper::Method;
+use serde_json::{Map, Value};
+
+use crate::{version::FirecrackerVersion, Error};
+
+const V1_0: FirecrackerVersion = FirecrackerVersion::new(1, 0, 0);
+const V1_1: FirecrackerVersion = FirecrackerVersion::new(1, 1, 0);
+const V1_8: FirecrackerVersion = FirecrackerVersion::new(1, 8, 0);
+
+/// Adapt the JSON `body` of a `method` request to `path` to `version`.
+///
+/// Bodies that aren't JSON objects or need no change are returned as is.
+pub(crate) fn adapt(
+    version: FirecrackerVersion,
+    method: &Method,
+    path: &str,
+    body: String,
+) -> String {
+    if *method != Method::PUT && *method != Method::PATCH {
+        return body;
+    }
+    let mut object = match serde_json::from_str::<Value>(&body) {
+        Ok(Value::Object(object)) => object,
+        _ => return body,
+    };
+    let changed = match path {
+        "/machine-config" => adapt_machine_config(version, &mut object),
+        "/snapshot/load" => adapt_snapshot_load(version, &mut object),
+        "/mmds/config" if version < V1_0 => object.remove("version").is_some(),
+        _ if path.starts_with("/drives/") && version < V1_0 => object.remove("io_engine").is_some(),
+        _ => false,
+    };
+    if !changed {
+        return body;
+    }
+
+    Value::Object(object).to_string()
+}
+
+/// Serialize the JSON `body` sent to `$endpoint` through its model in the `$api` module, if any.
+///
+/// `$endpoint` is the method, the resource and the rest of the path, if any.
+#[cfg(any(feature = "fc-1_7", feature = "fc-1_10"))]
+macro_rules! through_model {
+    ($api:path, $endpoint:expr, $body:expr) => {{
+        use $api as api;
+
+        match $endpoint {
+            ("PUT", "actions", None) => Some(through::<api::InstanceActionInfo>($body)),
+            ("PUT", "balloon", None) => Some(through::<api::Balloon>($body)),
+            ("PATCH", "balloon", None) => Some(through::<api::BalloonUpdate>($body)),
+            ("PATCH", "balloon", Some("statistics")) => {
+                Some(through::<api::BalloonStatsUpdate>($body))
+            }
+            ("PUT", "boot-source", None) => Some(through::<api::BootSource>($body)),
+            ("PUT", "drives", Some(_)) => Some(through::<api::Drive>($body)),
+            ("PATCH", "drives", Some(_)) => Some(through::<api::PartialDrive>($body)),
+            ("PUT", "entropy", None) => Some(through::<api::EntropyDevice>($body)),
+            ("PUT", "logger", None) => Some(through::<api::Logger>($body)),
+            ("PUT", "machine-config", None) => Some(through::<api::MachineConfiguration>($body)),
+            ("PUT", "metrics", None) => Some(through::<api::Metrics>($body)),
+            ("PUT", "mmds", Some("config")) => Some(through::<api::MmdsConfig>($body)),
+            ("PUT", "network-interfaces", Some(_)) => Some(through::<api::NetworkInterface>($body)),
+            ("PATCH", "network-interfaces", Some(_)) => {
+                Some(through::<api::PartialNetworkInterface>($body))
+            }
+            ("PUT", "snapshot", Some("create")) => {
+                Some(through::<api::SnapshotCreateParams>($body))
+            }
+            ("PUT", "snapshot", Some("load")) => Some(through::<api::SnapshotLoadParams>($body)),
+            ("PATCH", "vm", None) => Some(through::<api::Vm>($body)),
+            ("PUT", "vsock", None) => Some(through::<api::Vsock>($body)),
+            _ => None,
+        }
+    }};
+}
+
+/// Deserialize `body` into the model `T`, rejecting unknown fields, and serialize it back.
+#[cfg(any(feature = "fc-1_7", feature = "fc-1_10"))]
+fn through<T>(body: &str) -> Result<String, serde_json::Error>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    serde_json::to_string(&serde_json::from_str::<T>(body)?)
+}
+
+/// Serialize the JSON `body` of a `method` request to `path` through the model of the API of
+/// `version`.
+///
+/// Bodies are returned as is when the models of the release aren't built in, or the endpoint
+/// takes arbitrary JSON (e.g `/mmds`).
+#[cfg(any(feature = "fc-1_7", feature = "fc-1_10"))]
+pub(crate) fn conform(
+    version: FirecrackerVersion,
+    method: &Method,
+    path: &str,
+    body: String,
+) -> Result<String, Error> {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let endpoint = (
+        method.as_str(),
+        segments.next().unwrap_or_default(),
+        segments.next(),
+    );
+    let conformed = match (version.major, version.minor) {
+        #[cfg(feature = "fc-1_7")]
+        (1, 7) => through_model!(crate::api::v1_7, endpoint, &body),
+        #[cfg(feature = "fc-1_10")]
+        (1, 10) => through_model!(crate::api::v1_10, endpoint, &body),
+        _ => None,
+    };
+
+    match conformed {
+        Some(conformed) => conformed.map_err(|source| Error::ApiSpecMismatch {
+            path: path.to_owned(),
+            version,
+            source,
+        }),
+        None => Ok(body),
+    }
+}
+
+/// Serialize the JSON `body` of a request through the model of the API of the Firecracker
+/// version in use.
+///
+/// No models are built in without the `fc-*` features, so the body is returned as is.
+#[cfg(not(any(feature = "fc-1_7", feature = "fc-1_10")))]
+pub(crate) fn conform(
+    _version: FirecrackerVersion,
+    _method: &Method,
+    _path: &str,
+    body: String,
+) -> Result<String, Error> {
+    Ok(body)
+}
+
+fn adapt_machine_config(version: FirecrackerVersion, object: &mut Map<String, Value>) -> bool {
+    // Renamed in 1.0.
+    version < V1_0 && rename(object, "smt", "ht_enabled")
+}
+
+fn adapt_snapshot_load(version: FirecrackerVersion, object: &mut Map<String, Value>) -> bool {
+    let mut changed = false;
+    // `mem_backend` replaced `mem_file_path` in 1.1.
+    if version < V1_1 {
+        if let Some(Value::Object(mut backend)) = object.remove("mem_backend") {
+            if let Some(path) = backend.remove("backend_path") {
+                object.insert("mem_file_path".to_owned(), path);
+            }
+            changed = true;
+        }
+    }
+    // `track_dirty_pages` replaced `enable_diff_snapshots` in 1.8.
+    if version >= V1_8 {
+        changed |= rename(object, "enable_diff_snapshots", "track_dirty_pages");
+    }
+
+    changed
+}
+
+fn rename(object: &mut Map<String, Value>, from: &str, to: &str) -> bool {
+    match object.remove(from) {
+        Some(value) => {
+            object.insert(to.to_owned(), value);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn payloads() {
+        let adapt_json = |version, path, body: Value| -> Value {
+            let body = adapt(version, &Method::PUT, path, body.to_string());
+            serde_json::from_str(&body).unwrap()
+        };
+        let v0_25 = FirecrackerVersion::new(0, 25, 2);
+        let v1_10 = FirecrackerVersion::new(1, 10, 1);
+
+        let machine_config = json!({"smt": false, "vcpu_count": 2});
+        assert_eq!(
+            adapt_json(v0_25, "/machine-config", machine_config.clone()),
+            json!({"ht_enabled": false, "vcpu_count": 2})
+        );
+        assert_eq!(
+            adapt_json(v1_10, "/machine-config", machine_config.clone()),
+            machine_config
+        );
+
+        let load = json!({
+            "snapshot_path": "vm.snap",
+            "mem_backend": {"backend_type": "File", "backend_path": "vm.mem"},
+            "enable_diff_snapshots": false,
+            "resume_vm": true,
+        });
+        assert_eq!(
+            adapt_json(v0_25, "/snapshot/load", load.clone()),
+            json!({
+                "snapshot_path": "vm.snap",
+                "mem_file_path": "vm.mem",
+                "enable_diff_snapshots": false,
+                "resume_vm": true,
+            })
+        );
+        assert_eq!(
+            adapt_json(v1_10, "/snapshot/load", load),
+            json!({
+                "snapshot_path": "vm.snap",
+                "mem_backend": {"backend_type": "File", "backend_path": "vm.mem"},
+                "track_dirty_pages": false,
+                "resume_vm": true,
+            })
+        );
+
+        let drive = json!({"drive_id": "rootfs", "io_engine": "Async"});
+        assert_eq!(
+            adapt_json(v0_25, "/drives/rootfs", drive),
+            json!({"drive_id": "rootfs"})
+        );
+    }
+
+    #[cfg(all(feature = "fc-1_7", feature = "fc-1_10"))]
+    #[test]
+    fn models() {
+        let v1_7 = FirecrackerVersion::new(1, 7, 0);
+        let v1_10 = FirecrackerVersion::new(1, 10, 1);
+        let conform_json = |version, method, path, body: &Value| {
+            conform(version, &method, path, body.to_string())
+                .map(|body| serde_json::from_str::<Value>(&body).unwrap())
+        };
+
+        let drive = json!({"drive_id": "root", "is_root_device": true, "path_on_host": "rootfs"});
+        assert_eq!(
+            conform_json(v1_10, Method::PUT, "/drives/root", &drive).unwrap(),
+            drive
+        );
+        // Updates only take the fields that can be changed.
+        let err = conform_json(v1_10, Method::PATCH, "/drives/root", &drive).unwrap_err();
+        assert!(
+            matches!(&err, Error::ApiSpecMismatch { path, .. } if path == "/drives/root"),
+            "{err:?}"
+        );
+
+        // `track_dirty_pages` only exists from 1.8.
+        let load = json!({"snapshot_path": "vm.snap", "track_dirty_pages": true});
+        assert_eq!(
+            conform_json(v1_10, Method::PUT, "/snapshot/load", &load).unwrap(),
+            load
+        );
+        conform_json(v1_7, Method::PUT, "/snapshot/load", &load).unwrap_err();
+
+        // Arbitrary JSON, and releases without models, are left alone.
+        let mmds = json!({"latest": {"meta-data": {}}});
+        assert_eq!(
+            conform_json(v1_10, Method::PUT, "/mmds", &mmds).unwrap(),
+            mmds
+        );
+        let v1_9 = FirecrackerVersion::new(1, 9, 0);
+        let bogus = json!({"drive_id": "root", "bogus": true});
+        assert_eq!(
+            conform_json(v1_9, Method::PUT, "/drives/root", &bogus).unwrap(),
+            bogus
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Balloon device configuration.
+///
+/// For details on the balloon device, please refer to the relevant [Firecracker documentation].
+///
+/// [Firecracker documentation]: https://github.com/firecracker-microvm/firecracker/blob/main/docs/ballooning.md
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Balloon {
+    pub(crate) amount_mib: u32,
+    pub(crate) deflate_on_oom: bool,
+    pub(crate) stats_polling_interval_s: u32,
+}
+
+impl Balloon {
+    /// The initial target size of the balloon, in MiB.
+    pub fn amount_mib(&self) -> u32 {
+        self.amount_mib
+    }
+
+    /// If the balloon deflates when the guest is out of memory.
+    pub fn deflate_on_oom(&self) -> bool {
+        self.deflate_on_oom
+    }
+
+    /// The interval in seconds at which the guest reports balloon statistics.
+    ///
+    /// Statistics are disabled if 0.
+    pub fn stats_polling_interval_s(&self) -> u32 {
+        self.stats_polling_interval_s
+    }
+}
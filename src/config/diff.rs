@@ -0,0 +1,442 @@
+//! Comparing the desired configuration of a VM with its live configuration.
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::{network, Balloon, Builder, Config, Drive, Machine, RateLimiter, VSock};
+use crate::Error;
+
+/// The live configuration of a VM, as returned by Firecracker's `GET /vm/config`.
+///
+/// See [`crate::Machine::live_config`].
+#[derive(Debug, Deserialize)]
+pub struct VmConfig {
+    /// The machine configuration.
+    #[serde(rename = "machine-config")]
+    pub machine_config: Option<Machine<'static>>,
+    /// The boot source.
+    #[serde(rename = "boot-source")]
+    pub boot_source: Option<LiveBootSource>,
+    /// The drives.
+    #[serde(default)]
+    pub drives: Vec<Drive<'static>>,
+    /// The network interfaces.
+    #[serde(rename = "network-interfaces", default)]
+    pub network_interfaces: Vec<network::Interface<'static>>,
+    /// The balloon device.
+    pub balloon: Option<Balloon>,
+    /// The vsock device.
+    pub vsock: Option<VSock<'static>>,
+}
+
+impl VmConfig {
+    /// Configure the VM of `builder` from the live configuration, on a best-effort basis, see
+    /// [`crate::Machine::adopt`].
+    ///
+    /// The jailer must be configured, as the kernel, initrd and drives are the files in its chroot.
+    pub(crate) fn configure(self, mut builder: Builder<'_>) -> Builder<'_> {
+        let workspace_dir = builder.0.jailer().workspace_dir().to_owned();
+        // Firecracker only sees the chroot, so the files are at the same paths under the workspace.
+        let relative = |path: &Path| path.strip_prefix("/").unwrap_or(path).to_owned();
+        if let Some(boot_source) = self.boot_source {
+            let kernel = relative(&boot_source.kernel_image_path);
+            builder.0.src_kernel_image_path = workspace_dir.join(&kernel).into();
+            builder.0.kernel_image_name = kernel.to_string_lossy().into_owned().into();
+            if let Some(initrd) = boot_source.initrd_path.as_deref().map(relative) {
+                builder.0.src_initrd_path = Some(workspace_dir.join(&initrd).into());
+                builder.0.initrd_name = Some(initrd.to_string_lossy().into_owned().into());
+            }
+            builder.0.kernel_args = boot_source.boot_args.map(Into::into);
+        }
+        if let Some(machine_cfg) = self.machine_config {
+            builder.0.machine_cfg = machine_cfg;
+        }
+        builder.0.drives = self
+            .drives
+            .into_iter()
+            .map(|drive| drive.adopted(&workspace_dir))
+            .collect();
+        builder.0.network_interfaces = self.network_interfaces;
+        builder.0.balloon_cfg = self.balloon;
+        builder.0.vsock_cfg = self.vsock;
+
+        builder
+    }
+}
+
+/// The boot source of a running VM, see [`VmConfig`].
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiveBootSource {
+    /// The kernel image path, inside the chroot.
+    pub kernel_image_path: PathBuf,
+    /// The kernel command line.
+    pub boot_args: Option<String>,
+    /// The initrd path, inside the chroot.
+    pub initrd_path: Option<PathBuf>,
+}
+
+/// A difference between the desired and the live configuration of a VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigChange {
+    /// The vCPU count, memory size, SMT, dirty page tracking or CPU template.
+    Machine,
+    /// The kernel, initrd or kernel command line.
+    BootSource,
+    /// A drive, by ID, to attach.
+    DriveAdded(String),
+    /// A drive, by ID, to detach.
+    DriveRemoved(String),
+    /// A drive, by ID, with a different backing file or options.
+    DriveChanged(String),
+    /// A drive, by ID, with a different rate limiter.
+    DriveRateLimiter(String),
+    /// A network interface, by ID, to attach.
+    NetworkInterfaceAdded(String),
+    /// A network interface, by ID, to detach.
+    NetworkInterfaceRemoved(String),
+    /// A network interface, by ID, with a different host device or MAC address.
+    NetworkInterfaceChanged(String),
+    /// The balloon device to attach or detach, or with a different `deflate_on_oom`, or with
+    /// statistics to enable or disable.
+    Balloon,
+    /// A different balloon target size, in MiB.
+    BalloonAmount(u32),
+    /// A different balloon statistics interval, in seconds.
+    BalloonStatsInterval(u32),
+    /// The vsock device.
+    Vsock,
+}
+
+impl ConfigChange {
+    /// If the change can be applied to the running VM, see [`crate::Machine::apply`].
+    ///
+    /// Other changes require a restart.
+    pub fn is_hot_applicable(&self) -> bool {
+        matches!(
+            self,
+            Self::DriveRateLimiter(_) | Self::BalloonAmount(_) | Self::BalloonStatsInterval(_)
+        )
+    }
+}
+
+/// The changes between the desired and the live configuration of a VM, see [`Config::diff`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    changes: Vec<ConfigChange>,
+}
+
+impl ConfigDiff {
+    /// All the changes.
+    pub fn changes(&self) -> &[ConfigChange] {
+        &self.changes
+    }
+
+    /// If the configurations are the same.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// If some changes can't be applied without restarting the VM.
+    pub fn requires_restart(&self) -> bool {
+        self.changes
+            .iter()
+            .any(|change| !change.is_hot_applicable())
+    }
+
+    /// The changes that can be applied to the running VM.
+    pub fn hot_applicable(&self) -> impl Iterator<Item = &ConfigChange> {
+        self.changes
+            .iter()
+            .filter(|change| change.is_hot_applicable())
+    }
+
+    /// The changes that require a restart.
+    pub fn restart_required(&self) -> impl Iterator<Item = &ConfigChange> {
+        self.changes
+            .iter()
+            .filter(|change| !change.is_hot_applicable())
+    }
+}
+
+/// The outcome of [`crate::Machine::apply`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// The changes applied to the running VM.
+    pub applied: Vec<ConfigChange>,
+    /// The changes that take effect on the next restart.
+    pub restart_required: Vec<ConfigChange>,
+}
+
+impl ApplyReport {
+    /// If the VM needs to be restarted to fully match the applied configuration.
+    pub fn requires_restart(&self) -> bool {
+        !self.restart_required.is_empty()
+    }
+}
+
+impl Config<'_> {
+    /// Compare the configuration with the `live` configuration of the VM.
+    ///
+    /// Only what firec configures through the API is compared, so host side settings (e.g the
+    /// jailer or NAT) are not.
+    pub fn diff(&self, live: &VmConfig) -> Result<ConfigDiff, Error> {
+        let mut changes = Vec::new();
+
+        if live
+            .machine_config
+            .as_ref()
+            .is_none_or(|machine| !same_machine(self.machine_cfg(), machine))
+        {
+            changes.push(ConfigChange::Machine);
+        }
+
+        let boot_source = self.boot_source()?;
+        let same_boot_source = live.boot_source.as_ref().is_some_and(|live| {
+            same_path(&boot_source.kernel_image_path, &live.kernel_image_path)
+                && boot_source.boot_args == live.boot_args.as_deref()
+                && match (&boot_source.initrd_path, &live.initrd_path) {
+                    (Some(desired), Some(live)) => same_path(desired, live),
+                    (desired, live) => desired.is_none() && live.is_none(),
+                }
+        });
+        if !same_boot_source {
+            changes.push(ConfigChange::BootSource);
+        }
+
+        for drive in &self.drives {
+            let id = drive.drive_id();
+            let live = match live.drives.iter().find(|live| live.drive_id() == id) {
+                Some(live) => live,
+                None => {
+                    changes.push(ConfigChange::DriveAdded(id.to_owned()));
+                    continue;
+                }
+            };
+            if drive.is_read_only() != live.is_read_only()
+                || drive.is_root_device() != live.is_root_device()
+                || drive.part_uuid() != live.part_uuid()
+                || !same_path(Path::new(&self.drive_name(drive)?), live.src_path())
+            {
+                changes.push(ConfigChange::DriveChanged(id.to_owned()));
+            }
+            if effective(drive.rate_limiter()) != effective(live.rate_limiter()) {
+                changes.push(ConfigChange::DriveRateLimiter(id.to_owned()));
+            }
+        }
+        changes.extend(
+            live.drives
+                .iter()
+                .filter(|live| self.drives.iter().all(|d| d.drive_id() != live.drive_id()))
+                .map(|live| ConfigChange::DriveRemoved(live.drive_id().to_owned())),
+        );
+
+        let interfaces = self.network_interfaces();
+        for interface in interfaces {
+            let id = interface.vm_if_name();
+            match live
+                .network_interfaces
+                .iter()
+                .find(|live| live.vm_if_name() == id)
+            {
+                None => changes.push(ConfigChange::NetworkInterfaceAdded(id.to_owned())),
+                Some(live)
+                    if interface.host_if_name() != live.host_if_name()
+                        || interface
+                            .vm_mac_address()
+                            .is_some_and(|mac| Some(mac) != live.vm_mac_address()) =>
+                {
+                    changes.push(ConfigChange::NetworkInterfaceChanged(id.to_owned()))
+                }
+                Some(_) => (),
+            }
+        }
+        changes.extend(
+            live.network_interfaces
+                .iter()
+                .filter(|live| {
+                    interfaces
+                        .iter()
+                        .all(|i| i.vm_if_name() != live.vm_if_name())
+                })
+                .map(|live| ConfigChange::NetworkInterfaceRemoved(live.vm_if_name().to_owned())),
+        );
+
+        match (self.balloon_cfg(), &live.balloon) {
+            (None, None) => (),
+            (Some(desired), Some(live))
+                if desired.deflate_on_oom() == live.deflate_on_oom()
+                    && (desired.stats_polling_interval_s() == 0)
+                        == (live.stats_polling_interval_s() == 0) =>
+            {
+                if desired.amount_mib() != live.amount_mib() {
+                    changes.push(ConfigChange::BalloonAmount(desired.amount_mib()));
+                }
+                if desired.stats_polling_interval_s() != live.stats_polling_interval_s() {
+                    changes.push(ConfigChange::BalloonStatsInterval(
+                        desired.stats_polling_interval_s(),
+                    ));
+                }
+            }
+            _ => changes.push(ConfigChange::Balloon),
+        }
+
+        let same_vsock = match (self.vsock_cfg(), &live.vsock) {
+            (Some(desired), Some(live)) => {
+                desired.guest_cid() == live.guest_cid()
+                    && same_path(desired.uds_path(), live.uds_path())
+            }
+            (desired, live) => desired.is_none() && live.is_none(),
+        };
+        if !same_vsock {
+            changes.push(ConfigChange::Vsock);
+        }
+
+        Ok(ConfigDiff { changes })
+    }
+}
+
+fn same_machine(desired: &Machine<'_>, live: &Machine<'_>) -> bool {
+    desired.vcpu_count() == live.vcpu_count()
+        && desired.mem_size_mib() == live.mem_size_mib()
+        && desired.smt() == live.smt()
+        && desired.track_dirty_pages() == live.track_dirty_pages()
+        && cpu_template(desired) == cpu_template(live)
+}
+
+fn cpu_template<'a>(machine: &'a Machine<'_>) -> Option<&'a str> {
+    // Firecracker reports the absence of a CPU template as `None`.
+    machine
+        .cpu_template()
+        .filter(|template| *template != "None")
+}
+
+/// Compare paths inside the chroot, which Firecracker may report as absolute or relative.
+fn same_path(desired: &Path, live: &Path) -> bool {
+    let relative = |path: &Path| path.strip_prefix("/").unwrap_or(path).to_owned();
+
+    relative(desired) == relative(live)
+}
+
+/// A rate limiter without any limit is the same as no rate limiter.
+fn effective(rate_limiter: Option<&RateLimiter>) -> Option<&RateLimiter> {
+    rate_limiter.filter(|rl| rl.bandwidth.is_some() || rl.ops.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TokenBucket;
+
+    #[test]
+    fn diff() {
+        let config = Config::builder(None, Path::new("/tmp/kernel.bin"))
+            .kernel_args("console=ttyS0")
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .add_drive("data", Path::new("/tmp/data.ext4"))
+            .rate_limiter(Some(RateLimiter {
+                bandwidth: None,
+                ops: Some(TokenBucket {
+                    size: 100,
+                    one_time_burst: None,
+                    refill_time_ms: 1000,
+                }),
+            }))
+            .build()
+            .balloon_cfg(256, true, 1)
+            .build()
+            .unwrap();
+        let live: VmConfig = serde_json::from_value(serde_json::json!({
+            "machine-config": {
+                "vcpu_count": 1,
+                "mem_size_mib": 1024,
+                "smt": false,
+                "track_dirty_pages": false,
+                "cpu_template": "None",
+            },
+            "boot-source": {
+                "kernel_image_path": "/kernel",
+                "boot_args": "console=ttyS0",
+            },
+            "drives": [
+                {
+                    "drive_id": "root",
+                    "path_on_host": "rootfs.ext4",
+                    "is_root_device": true,
+                    "is_read_only": false,
+                },
+                {
+                    "drive_id": "data",
+                    "path_on_host": "data.ext4",
+                    "is_root_device": false,
+                    "is_read_only": false,
+                    "rate_limiter": null,
+                },
+                {
+                    "drive_id": "scratch",
+                    "path_on_host": "scratch.ext4",
+                    "is_root_device": false,
+                    "is_read_only": false,
+                },
+            ],
+            "network-interfaces": [],
+            "balloon": {
+                "amount_mib": 128,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 1,
+            },
+        }))
+        .unwrap();
+
+        let diff = config.diff(&live).unwrap();
+        assert_eq!(
+            diff.changes(),
+            [
+                ConfigChange::DriveRateLimiter("data".to_owned()),
+                ConfigChange::DriveRemoved("scratch".to_owned()),
+                ConfigChange::BalloonAmount(256),
+            ]
+        );
+        assert!(diff.requires_restart());
+        assert_eq!(diff.hot_applicable().count(), 2);
+    }
+
+    #[test]
+    fn adopt() {
+        let live: VmConfig = serde_json::from_value(serde_json::json!({
+            "machine-config": {
+                "vcpu_count": 2,
+                "mem_size_mib": 512,
+                "smt": false,
+                "track_dirty_pages": false,
+            },
+            "boot-source": {
+                "kernel_image_path": "/vmlinux",
+                "boot_args": "console=ttyS0",
+            },
+            "drives": [{
+                "drive_id": "root",
+                "path_on_host": "/rootfs.ext4",
+                "is_root_device": true,
+                "is_read_only": false,
+            }],
+        }))
+        .unwrap();
+        let builder = Config::builder(None, Path::new("/unknown"))
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("firecracker"))
+            .build();
+        let config = live.configure(builder).build().unwrap();
+
+        let root = config.jailer().workspace_dir();
+        assert_eq!(config.kernel_image_path(), root.join("vmlinux"));
+        assert_eq!(config.kernel_args(), Some("console=ttyS0"));
+        assert_eq!(config.machine_cfg().vcpu_count(), 2);
+        let drive = &config.drives()[0];
+        assert_eq!(drive.src_path(), root.join("rootfs.ext4"));
+        assert_eq!(config.drive_path(drive).unwrap(), root.join("rootfs.ext4"));
+    }
+}
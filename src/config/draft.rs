@@ -0,0 +1,68 @@
+//! Non-consuming configuration builder.
+
+use std::{borrow::Cow, path::Path};
+
+use super::{Builder, Config, DriveBuilder, JailerBuilder, MachineBuilder};
+use crate::Error;
+
+/// A configuration under construction, edited through `&mut self` methods.
+///
+/// This wraps [`Builder`] for configurations assembled across conditional branches and loops,
+/// where threading the consuming builders through is awkward. Start one with
+/// [`Builder::into_draft`].
+#[derive(Debug)]
+pub struct ConfigDraft<'c>(Option<Builder<'c>>);
+
+impl<'c> ConfigDraft<'c> {
+    /// Apply `f` to the underlying builder.
+    pub fn update<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(Builder<'c>) -> Builder<'c>,
+    {
+        let builder = self.0.take().expect("draft builder missing");
+        self.0 = Some(f(builder));
+        self
+    }
+
+    /// Add a drive, configured by `f`.
+    pub fn add_drive<I, P, F>(&mut self, drive_id: I, src_path: P, f: F) -> &mut Self
+    where
+        I: Into<Cow<'c, str>>,
+        P: Into<Cow<'c, Path>>,
+        F: FnOnce(DriveBuilder<'c>) -> DriveBuilder<'c>,
+    {
+        self.update(|builder| f(builder.add_drive(drive_id, src_path)).build())
+    }
+
+    /// Update the jailer configuration with `f`, starting from the current one if any.
+    pub fn jailer<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(JailerBuilder<'c>) -> JailerBuilder<'c>,
+    {
+        self.update(|builder| f(builder.jailer_cfg()).build())
+    }
+
+    /// Update the machine configuration with `f`, starting from the current one.
+    pub fn machine<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(MachineBuilder<'c>) -> MachineBuilder<'c>,
+    {
+        self.update(|builder| f(builder.machine_cfg()).build())
+    }
+
+    /// Build the configuration, see [`Builder::build`].
+    pub fn build(self) -> Result<Config<'c>, Error> {
+        self.into_builder().build()
+    }
+
+    /// Get back the consuming builder.
+    pub fn into_builder(self) -> Builder<'c> {
+        self.0.expect("draft builder missing")
+    }
+}
+
+impl<'c> From<Builder<'c>> for ConfigDraft<'c> {
+    fn from(builder: Builder<'c>) -> Self {
+        Self(Some(builder))
+    }
+}
@@ -1,4 +1,7 @@
-use std::{borrow::Cow, path::Path};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -19,7 +22,7 @@ pub enum IOEngineType {
 ///
 /// More info here:
 /// https://github.com/firecracker-microvm/firecracker/blob/main/src/rate_limiter/src/lib.rs
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct TokenBucket {
     /// Size of bucker
     pub size: u64,
@@ -35,7 +38,7 @@ pub struct TokenBucket {
 /// Is set up for each drive separatelly.
 ///
 /// Specifiyng 0 as `size` or `refill_time_ms` of a rate limiter is the same as passing None.
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct RateLimiter {
     /// Limit in bytes
     pub bandwidth: Option<TokenBucket>,
@@ -43,6 +46,50 @@ pub struct RateLimiter {
     pub ops: Option<TokenBucket>,
 }
 
+/// How the file of a drive is provided in the chroot by [`crate::Machine::create`].
+///
+/// Device-mapper provisioning creates a per-VM copy-on-write block device instead of copying the
+/// source file, so large base images are cloned instantly. The device is exposed in the chroot as
+/// a block device node, in place of the drive file, and is removed by [`crate::Machine::delete`].
+/// It requires `dmsetup` (and `losetup` for [`DriveProvisioning::DmSnapshot`], `cryptsetup` for
+/// [`DriveProvisioning::Luks`]) on the host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DriveProvisioning {
+    /// Copy the source file into the chroot.
+    #[default]
+    Copy,
+    /// Create a dm-thin snapshot of the source thin volume.
+    ///
+    /// The source path of the drive must be the active thin volume to snapshot, e.g
+    /// `/dev/mapper/base`. It's suspended while the snapshot is taken.
+    DmThin {
+        /// The thin pool device, e.g `/dev/mapper/pool`.
+        pool: PathBuf,
+        /// The thin device ID of the source volume in the pool.
+        origin_id: u32,
+        /// The thin device ID of the snapshot, which must be unused in the pool.
+        device_id: u32,
+    },
+    /// Create a dm-snapshot of the source file, over loop devices.
+    ///
+    /// The source file is only read. Writes go to a sparse copy-on-write file in the VM directory
+    /// (see [`super::Config::vm_dir`]).
+    DmSnapshot {
+        /// The size of the copy-on-write file, which bounds how much the guest can write.
+        cow_size: u64,
+    },
+    /// Copy the source file into a dm-crypt device, encrypted at rest with LUKS2.
+    ///
+    /// The encrypted volume is backed by a file in the VM directory (see
+    /// [`super::Config::vm_dir`]) and has the size of the source file. On delete, its LUKS
+    /// key slots are erased before the file is removed, so the data can't be recovered even from
+    /// the blocks the file occupied.
+    Luks {
+        /// The key file of the volume on the host, e.g on a tmpfs.
+        key_file: PathBuf,
+    },
+}
+
 /// Drive configuration.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Drive<'d> {
@@ -57,6 +104,12 @@ pub struct Drive<'d> {
     io_engine: Option<IOEngineType>,
     #[serde(skip_serializing_if = "Option::is_none")]
     rate_limiter: Option<RateLimiter>,
+    #[serde(skip)]
+    dest_name: Option<Cow<'d, str>>,
+    #[serde(skip)]
+    provisioning: DriveProvisioning,
+    #[serde(skip)]
+    pub(crate) guest_device_index: usize,
 }
 
 impl<'d> Drive<'d> {
@@ -80,6 +133,11 @@ impl<'d> Drive<'d> {
         self.part_uuid.as_deref()
     }
 
+    /// The IO rate limiter.
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
     /// The source path for the guest drive.
     ///
     /// This is the path given by the application. The drive is transfered to the chroot directory
@@ -87,6 +145,56 @@ impl<'d> Drive<'d> {
     pub fn src_path(&self) -> &Path {
         &self.src_path
     }
+
+    /// The name of the drive file inside the chroot, if set explicitly.
+    ///
+    /// Use [`super::Config::drive_name`] to get the effective name.
+    pub fn dest_name(&self) -> Option<&str> {
+        self.dest_name.as_deref()
+    }
+
+    /// The name of the block device of the drive in a Linux guest, e.g `vda`, to generate
+    /// `fstab` entries or kernel arguments.
+    ///
+    /// Firecracker attaches the root device first (`vda`), then the other drives in configuration
+    /// order.
+    pub fn guest_device_name(&self) -> String {
+        // `vda` to `vdz`, then `vdaa`, `vdab`, ...
+        let mut suffix = Vec::new();
+        let mut n = self.guest_device_index + 1;
+        while n > 0 {
+            n -= 1;
+            suffix.push(char::from(b'a' + (n % 26) as u8));
+            n /= 26;
+        }
+
+        format!("vd{}", suffix.iter().rev().collect::<String>())
+    }
+
+    /// How the drive is provided in the chroot.
+    pub fn provisioning(&self) -> &DriveProvisioning {
+        &self.provisioning
+    }
+
+    /// The drive of a running VM (see [`super::VmConfig`]), whose backing file is in the chroot
+    /// `workspace_dir`.
+    pub(crate) fn adopted(mut self, workspace_dir: &Path) -> Self {
+        let chroot_path = self
+            .src_path
+            .strip_prefix("/")
+            .unwrap_or(&self.src_path)
+            .to_owned();
+        self.dest_name = Some(chroot_path.to_string_lossy().into_owned().into());
+        self.src_path = workspace_dir.join(chroot_path).into();
+        self
+    }
+
+    /// Back the drive with a copy of `src_path` named `dest_name` in the chroot, see
+    /// [`crate::Machine::swap_drive`].
+    pub(crate) fn swap(&mut self, src_path: PathBuf, dest_name: String) {
+        self.src_path = src_path.into();
+        self.dest_name = Some(dest_name.into());
+    }
 }
 
 /// Builder for `Drive`.
@@ -112,6 +220,9 @@ impl<'d> DriveBuilder<'d> {
                 src_path: src_path.into(),
                 io_engine: None,
                 rate_limiter: None,
+                dest_name: None,
+                provisioning: DriveProvisioning::Copy,
+                guest_device_index: 0,
             },
         }
     }
@@ -151,6 +262,25 @@ impl<'d> DriveBuilder<'d> {
         self
     }
 
+    /// Set the name of the drive file inside the chroot.
+    ///
+    /// Defaults to the filename of the source drive.
+    pub fn dest_name<N>(mut self, dest_name: N) -> Self
+    where
+        N: Into<Cow<'d, str>>,
+    {
+        self.drive.dest_name = Some(dest_name.into());
+        self
+    }
+
+    /// Set how the drive is provided in the chroot.
+    ///
+    /// Defaults to [`DriveProvisioning::Copy`].
+    pub fn provisioning(mut self, provisioning: DriveProvisioning) -> Self {
+        self.drive.provisioning = provisioning;
+        self
+    }
+
     /// Build the `Drive`.
     ///
     /// Returns the main configuration builder with the new drive added to it.
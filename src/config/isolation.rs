@@ -0,0 +1,163 @@
+use std::{borrow::Cow, ops::Range, path::Path};
+
+use super::{Config, Seccomp};
+use crate::Error;
+
+/// The network namespace policy of an [`IsolationProfile`].
+#[derive(Debug, Clone, Default)]
+pub enum NetnsPolicy<'p> {
+    /// Leave the network namespace as configured, see [`super::Builder::net_ns`].
+    #[default]
+    Unchanged,
+    /// Join the network namespace with the given handle path, shared by all the VMs of the
+    /// profile.
+    Shared(Cow<'p, Path>),
+    /// Join a network namespace per VM, with the handle `<dir>/<vm_id>`, e.g `/var/run/netns`.
+    ///
+    /// The namespaces must be created beforehand, e.g with `ip netns add`.
+    PerVm(Cow<'p, Path>),
+}
+
+/// A bundle of isolation settings, shared by the VMs of a tenant.
+///
+/// Set with [`super::Builder::isolation_profile`] and applied by [`super::Builder::build`]: the
+/// seccomp filter, network namespace and cgroup arguments of the jailer are set from the
+/// profile, and the jailer uid and gid must be in the range of the profile (see
+/// [`crate::uid_pool::UidGidPool`] to allocate them). The jailer must be configured and
+/// cgroups must not be set through [`super::JailerBuilder::extra_jailer_args`].
+#[derive(Debug, Clone)]
+pub struct IsolationProfile<'p> {
+    pub(crate) name: Cow<'p, str>,
+    pub(crate) uid_range: Option<Range<u32>>,
+    pub(crate) cgroup_version: Option<u8>,
+    pub(crate) parent_cgroup: Option<Cow<'p, str>>,
+    pub(crate) cgroups: Vec<Cow<'p, str>>,
+    pub(crate) seccomp: Option<Seccomp<'p>>,
+    pub(crate) netns: NetnsPolicy<'p>,
+}
+
+impl<'p> IsolationProfile<'p> {
+    /// Create a profile named `name`, which doesn't change anything until configured.
+    pub fn new<N>(name: N) -> Self
+    where
+        N: Into<Cow<'p, str>>,
+    {
+        Self {
+            name: name.into(),
+            uid_range: None,
+            cgroup_version: None,
+            parent_cgroup: None,
+            cgroups: Vec::new(),
+            seccomp: None,
+            netns: NetnsPolicy::default(),
+        }
+    }
+
+    /// The name of the profile.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Require the jailer uid and gid to be in `range`.
+    pub fn uid_range(mut self, range: Range<u32>) -> Self {
+        self.uid_range = Some(range);
+        self
+    }
+
+    /// Set the cgroup version the jailer uses (`--cgroup-version`).
+    pub fn cgroup_version(mut self, version: u8) -> Self {
+        self.cgroup_version = Some(version);
+        self
+    }
+
+    /// Set the parent cgroup of the VM cgroups (`--parent-cgroup`).
+    pub fn parent_cgroup<C>(mut self, parent_cgroup: C) -> Self
+    where
+        C: Into<Cow<'p, str>>,
+    {
+        self.parent_cgroup = Some(parent_cgroup.into());
+        self
+    }
+
+    /// Add a cgroup limit, as `<file>=<value>` (`--cgroup`), e.g `cpu.max=50000`.
+    pub fn cgroup<C>(mut self, cgroup: C) -> Self
+    where
+        C: Into<Cow<'p, str>>,
+    {
+        self.cgroups.push(cgroup.into());
+        self
+    }
+
+    /// Set the seccomp filter.
+    pub fn seccomp(mut self, seccomp: Seccomp<'p>) -> Self {
+        self.seccomp = Some(seccomp);
+        self
+    }
+
+    /// Set the network namespace policy.
+    pub fn netns(mut self, netns: NetnsPolicy<'p>) -> Self {
+        self.netns = netns;
+        self
+    }
+
+    /// Apply the profile to `config`.
+    pub(crate) fn apply(&self, config: &mut Config<'p>) -> Result<(), Error> {
+        let vm_id = config.vm_id().to_string();
+        let jailer = config
+            .jailer_cfg
+            .as_mut()
+            .ok_or_else(|| self.violation("the jailer is not configured"))?;
+        if let Some(range) = &self.uid_range {
+            if !range.contains(&jailer.uid()) || !range.contains(&jailer.gid()) {
+                return Err(self.violation(format!(
+                    "uid {} and gid {} must be in {range:?}",
+                    jailer.uid(),
+                    jailer.gid()
+                )));
+            }
+        }
+        let cgroup_args = ["--cgroup", "--cgroup-version", "--parent-cgroup"];
+        if cgroup_args
+            .iter()
+            .any(|arg| jailer.jailer_arg(arg).is_some())
+        {
+            return Err(self.violation("cgroups must only be set by the profile"));
+        }
+
+        if let Some(version) = self.cgroup_version {
+            jailer.extra_jailer_args.push("--cgroup-version".into());
+            jailer.extra_jailer_args.push(version.to_string().into());
+        }
+        if let Some(parent_cgroup) = &self.parent_cgroup {
+            jailer.extra_jailer_args.push("--parent-cgroup".into());
+            jailer.extra_jailer_args.push(parent_cgroup.clone());
+        }
+        for cgroup in &self.cgroups {
+            jailer.extra_jailer_args.push("--cgroup".into());
+            jailer.extra_jailer_args.push(cgroup.clone());
+        }
+        if let Some(seccomp) = &self.seccomp {
+            config.seccomp = seccomp.clone();
+        }
+        let net_ns = match &self.netns {
+            NetnsPolicy::Unchanged => None,
+            NetnsPolicy::Shared(path) => Some(path.to_string_lossy().into_owned()),
+            NetnsPolicy::PerVm(dir) => Some(dir.join(vm_id).to_string_lossy().into_owned()),
+        };
+        if let Some(net_ns) = net_ns {
+            config.net_ns = Some(net_ns.into());
+        }
+
+        Ok(())
+    }
+
+    fn violation<R>(&self, reason: R) -> Error
+    where
+        R: Into<String>,
+    {
+        Error::IsolationProfileViolation {
+            profile: self.name.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
@@ -1,9 +1,13 @@
 //! API to configure and interact with jailer.
 
 use derivative::Derivative;
-use std::{borrow::Cow, path::Path};
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
-use super::Builder;
+use super::{Builder, VmId};
 
 /// Jailer specific configuration needed to execute the jailer.
 #[derive(Debug)]
@@ -14,9 +18,17 @@ pub struct Jailer<'j> {
     exec_file: Cow<'j, Path>,
     jailer_binary: Cow<'j, Path>,
     chroot_base_dir: Cow<'j, Path>,
+    workspace_layout: WorkspaceLayout<'j>,
+    vm_dir: Cow<'j, Path>,
     workspace_dir: Cow<'j, Path>,
+    chroot_dir: Cow<'j, Path>,
     pub(crate) mode: JailerMode<'j>,
-    // TODO: We need an equivalent of ChrootStrategy.
+    capture_daemon_output: bool,
+    workspace_quota: Option<WorkspaceQuota<'j>>,
+    chown_artifacts: bool,
+    security_label: Option<SecurityLabel<'j>>,
+    pub(crate) extra_jailer_args: Vec<Cow<'j, str>>,
+    extra_vmm_args: Vec<Cow<'j, str>>,
 }
 
 impl<'j> Jailer<'j> {
@@ -51,14 +63,185 @@ impl<'j> Jailer<'j> {
     }
 
     /// The mode of the jailer process.
-    pub fn mode(&self) -> &JailerMode {
+    pub fn mode(&self) -> &JailerMode<'j> {
         &self.mode
     }
 
+    /// If the output of the jailer is captured in [`JailerMode::Daemon`].
+    pub fn capture_daemon_output(&self) -> bool {
+        self.capture_daemon_output
+    }
+
+    /// Where the workspace is on the host.
+    pub fn workspace_layout(&self) -> &WorkspaceLayout<'j> {
+        &self.workspace_layout
+    }
+
+    /// The VM directory, see [`WorkspaceLayout`].
+    pub fn vm_dir(&self) -> &Path {
+        &self.vm_dir
+    }
+
     /// The path to the jailer workspace.
     pub fn workspace_dir(&self) -> &Path {
         &self.workspace_dir
     }
+
+    /// The directory the jailer chroots into, `<chroot base dir>/<exec file name>/<VM ID>/root`.
+    ///
+    /// The workspace is bind-mounted there if it's elsewhere, see [`WorkspaceLayout`].
+    pub fn chroot_dir(&self) -> &Path {
+        &self.chroot_dir
+    }
+
+    /// The limit on the size of the jailer workspace.
+    pub fn workspace_quota(&self) -> Option<&WorkspaceQuota<'j>> {
+        self.workspace_quota.as_ref()
+    }
+
+    /// If the artifacts copied into the chroot are owned by [`Jailer::uid`]/[`Jailer::gid`].
+    pub fn chown_artifacts(&self) -> bool {
+        self.chown_artifacts
+    }
+
+    /// The MAC label the jailer is executed with.
+    pub fn security_label(&self) -> Option<&SecurityLabel<'j>> {
+        self.security_label.as_ref()
+    }
+
+    /// Additional arguments passed to the jailer.
+    pub fn extra_jailer_args(&self) -> &[Cow<'j, str>] {
+        &self.extra_jailer_args
+    }
+
+    /// Additional arguments passed to the Firecracker binary.
+    pub fn extra_vmm_args(&self) -> &[Cow<'j, str>] {
+        &self.extra_vmm_args
+    }
+
+    /// The PID file the jailer writes in the chroot before exec-ing the target binary.
+    pub(crate) fn pid_file_path(&self) -> Option<PathBuf> {
+        let exec_name = self.exec_file.file_name()?.to_str()?;
+
+        Some(self.workspace_dir.join(format!("{exec_name}.pid")))
+    }
+
+    /// The `cgroup.procs` file of the cgroup the jailer moves the process of `vm_id` to, if
+    /// cgroups are set up through `--cgroup` arguments (see [`Jailer::extra_jailer_args`]).
+    pub(crate) fn cgroup_procs_path(&self, vm_id: &VmId) -> Option<PathBuf> {
+        let cgroup = self.jailer_arg("--cgroup")?;
+        let parent = match self.jailer_arg("--parent-cgroup") {
+            Some(parent) => parent,
+            None => self.exec_file.file_name()?.to_str()?,
+        };
+        let mut path = PathBuf::from("/sys/fs/cgroup");
+        // cgroup v1 has a hierarchy per controller, named after the prefix of the cgroup files.
+        if self.jailer_arg("--cgroup-version") != Some("2") {
+            path.push(cgroup.split('.').next()?);
+        }
+        path.extend([parent, vm_id.as_str(), "cgroup.procs"]);
+
+        Some(path)
+    }
+
+    /// The value of the first `name` argument in [`Jailer::extra_jailer_args`], given either as
+    /// `name value` or `name=value`.
+    pub(crate) fn jailer_arg(&self, name: &str) -> Option<&str> {
+        self.extra_jailer_args
+            .iter()
+            .enumerate()
+            .find_map(|(i, arg)| {
+                if arg == name {
+                    self.extra_jailer_args.get(i + 1).map(AsRef::as_ref)
+                } else {
+                    arg.strip_prefix(name)?.strip_prefix('=')
+                }
+            })
+    }
+
+    /// The program and arguments executing the jailer binary, wrapped according to
+    /// [`Jailer::security_label`].
+    pub(crate) fn program(&self) -> Vec<OsString> {
+        let mut program: Vec<OsString> = match &self.security_label {
+            Some(SecurityLabel::Selinux(context)) => {
+                vec!["runcon".into(), context.as_ref().into()]
+            }
+            Some(SecurityLabel::AppArmor(profile)) => vec![
+                "aa-exec".into(),
+                "-p".into(),
+                profile.as_ref().into(),
+                "--".into(),
+            ],
+            None => Vec::new(),
+        };
+        program.push(self.jailer_binary.as_os_str().to_owned());
+
+        program
+    }
+}
+
+/// A mandatory access control label to confine the jailer, and the Firecracker process it execs,
+/// with.
+///
+/// The jailer is executed through the wrapper command of the security module, which must be
+/// installed on the host. The label must allow the jailer to set up the chroot and the cgroups.
+#[derive(Debug, Clone)]
+pub enum SecurityLabel<'j> {
+    /// An SELinux security context (e.g `system_u:system_r:firecracker_t:s0`), applied with
+    /// `runcon`.
+    Selinux(Cow<'j, str>),
+    /// An AppArmor profile, applied with `aa-exec`.
+    AppArmor(Cow<'j, str>),
+}
+
+/// Limits how much the jailer workspace can grow on the host filesystem.
+///
+/// This keeps a guest filling up its writable drives (or Firecracker filling up its log file) from
+/// exhausting the host filesystem.
+#[derive(Debug, Clone)]
+pub enum WorkspaceQuota<'j> {
+    /// Mount an ext4 filesystem image of the given size as the workspace.
+    ///
+    /// The image is created next to the workspace directory, using `truncate`, `mkfs.ext4` and
+    /// `mount`, and unmounted again on [`crate::Machine::delete`].
+    LoopFile {
+        /// The size of the image in bytes.
+        size: u64,
+    },
+    /// Assign the workspace directory to a filesystem project with a hard block limit.
+    ///
+    /// The quota is set up with `xfs_quota`, so the filesystem at `mount_point` must be mounted
+    /// with project quotas enabled (`prjquota`).
+    Project {
+        /// The project ID, which must be unique to the VM.
+        id: u32,
+        /// The hard limit in bytes.
+        size: u64,
+        /// Mount point of the filesystem containing the chroot base directory.
+        mount_point: Cow<'j, Path>,
+    },
+}
+
+/// Where the workspace of a VM is on the host, see [`JailerBuilder::workspace_layout`].
+///
+/// The jailer always chroots into `<chroot base dir>/<exec file name>/<VM ID>/root` (see
+/// [`Jailer::chroot_dir`]). With the other layouts, the workspace is bind-mounted there by
+/// [`crate::Machine::create`], and unmounted by [`crate::Machine::delete`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum WorkspaceLayout<'j> {
+    /// The layout of the jailer: the VM directory is `<chroot base dir>/<exec file name>/<VM ID>`,
+    /// and the workspace its `root` subdirectory.
+    #[default]
+    Jailer,
+    /// A directory per VM: the VM directory is `<dir>/<VM ID>`, and the workspace its `root`
+    /// subdirectory. [`crate::list`] only finds these VMs if `dir` is passed to
+    /// [`crate::Filter::flat_dir`].
+    Flat(Cow<'j, Path>),
+    /// An existing directory as the workspace, e.g one provisioned by the deployment.
+    ///
+    /// The VM directory is the one of the jailer layout. The directory itself is left in place,
+    /// with its contents, when the VM is deleted.
+    External(Cow<'j, Path>),
 }
 
 /// The mode of the jailer process.
@@ -97,19 +280,34 @@ pub struct JailerBuilder<'j> {
 }
 
 impl<'j> JailerBuilder<'j> {
-    pub(crate) fn new(config_builder: Builder<'j>) -> Self {
-        Self {
-            config_builder,
-            jailer: Jailer {
+    pub(crate) fn new(mut config_builder: Builder<'j>) -> Self {
+        let jailer = config_builder
+            .0
+            .jailer_cfg
+            .take()
+            .unwrap_or_else(|| Jailer {
                 gid: users::get_effective_gid(),
                 uid: users::get_effective_uid(),
                 numa_node: None,
                 exec_file: Path::new("/usr/bin/firecracker").into(),
                 jailer_binary: Path::new("jailer").into(),
                 chroot_base_dir: Path::new("/srv/jailer").into(),
+                workspace_layout: WorkspaceLayout::default(),
+                vm_dir: Path::new("/srv/jailer/firecracker").into(),
                 workspace_dir: Path::new("/srv/jailer/firecracker/root").into(),
+                chroot_dir: Path::new("/srv/jailer/firecracker/root").into(),
                 mode: JailerMode::default(),
-            },
+                capture_daemon_output: false,
+                workspace_quota: None,
+                chown_artifacts: true,
+                security_label: None,
+                extra_jailer_args: Vec::new(),
+                extra_vmm_args: Vec::new(),
+            });
+
+        Self {
+            config_builder,
+            jailer,
         }
     }
 
@@ -131,6 +329,12 @@ impl<'j> JailerBuilder<'j> {
         self
     }
 
+    /// Set or clear the NUMA node the process gets assigned to, see [`JailerBuilder::numa_node`].
+    pub fn numa_node_opt(mut self, numa_node: Option<i32>) -> Self {
+        self.jailer.numa_node = numa_node;
+        self
+    }
+
     /// The path to the Firecracker binary that will be exec-ed by the jailer.
     ///
     /// The user can provide a path to any binary, but the interaction
@@ -143,6 +347,16 @@ impl<'j> JailerBuilder<'j> {
         self
     }
 
+    /// Use the binaries of a downloaded `release`, pinning its version (see
+    /// [`Builder::firecracker_version`]).
+    #[cfg(feature = "download")]
+    pub fn release(mut self, release: &crate::download::Release) -> Self {
+        self.jailer.exec_file = release.firecracker.clone().into();
+        self.jailer.jailer_binary = release.jailer.clone().into();
+        self.config_builder = self.config_builder.firecracker_version(release.version);
+        self
+    }
+
     /// Specifies the jailer binary to be used for setting up the Firecracker VM jail.
     ///
     /// If the value contains no path separators, it will use the PATH environment variable to get
@@ -176,6 +390,88 @@ impl<'j> JailerBuilder<'j> {
         self
     }
 
+    /// Capture the output of the jailer in [`JailerMode::Daemon`].
+    ///
+    /// The jailer only redirects its stdio to `/dev/null` once it daemonizes, so errors happening
+    /// before that (e.g an invalid uid or missing KVM access) are written to the files at
+    /// [`super::Config::jailer_stdout_path`] and [`super::Config::jailer_stderr_path`]. Their
+    /// content is then included in the error if the jailer fails to start.
+    pub fn capture_daemon_output(mut self, capture_daemon_output: bool) -> Self {
+        self.jailer.capture_daemon_output = capture_daemon_output;
+        self
+    }
+
+    /// Place the workspace according to `workspace_layout`, instead of the layout of the jailer.
+    pub fn workspace_layout(mut self, workspace_layout: WorkspaceLayout<'j>) -> Self {
+        self.jailer.workspace_layout = workspace_layout;
+        self
+    }
+
+    /// Limit the size of the jailer workspace.
+    pub fn workspace_quota(mut self, workspace_quota: WorkspaceQuota<'j>) -> Self {
+        self.jailer.workspace_quota = Some(workspace_quota);
+        self
+    }
+
+    /// Set or clear the limit on the size of the jailer workspace, see
+    /// [`JailerBuilder::workspace_quota`].
+    pub fn workspace_quota_opt(mut self, workspace_quota: Option<WorkspaceQuota<'j>>) -> Self {
+        self.jailer.workspace_quota = workspace_quota;
+        self
+    }
+
+    /// Give the artifacts copied into the chroot (kernel, initrd, drives, etc.) to the jailer
+    /// uid/gid, with minimal permissions.
+    ///
+    /// Firecracker runs as the jailer uid/gid and can't open root-owned files otherwise.
+    /// Read-only artifacts are made readable by their owner only (`0400`), writable drives and
+    /// the log file readable and writable (`0600`). Enabled by default.
+    pub fn chown_artifacts(mut self, chown_artifacts: bool) -> Self {
+        self.jailer.chown_artifacts = chown_artifacts;
+        self
+    }
+
+    /// Execute the jailer with a MAC label, see [`SecurityLabel`].
+    pub fn security_label(mut self, security_label: SecurityLabel<'j>) -> Self {
+        self.jailer.security_label = Some(security_label);
+        self
+    }
+
+    /// Set or clear the MAC label the jailer is executed with, see
+    /// [`JailerBuilder::security_label`].
+    pub fn security_label_opt(mut self, security_label: Option<SecurityLabel<'j>>) -> Self {
+        self.jailer.security_label = security_label;
+        self
+    }
+
+    /// Add arguments passed to the jailer, after the ones set by the crate.
+    ///
+    /// This allows using jailer flags the crate doesn't support (yet).
+    pub fn extra_jailer_args<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<Cow<'j, str>>,
+    {
+        self.jailer
+            .extra_jailer_args
+            .extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add arguments passed to the Firecracker binary, after the ones set by the crate.
+    ///
+    /// This allows using Firecracker flags the crate doesn't support (yet).
+    pub fn extra_vmm_args<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<Cow<'j, str>>,
+    {
+        self.jailer
+            .extra_vmm_args
+            .extend(args.into_iter().map(Into::into));
+        self
+    }
+
     /// Build the `Jailer` instance.
     ///
     /// Returns the main configuration builder with new jailer.
@@ -188,13 +484,19 @@ impl<'j> JailerBuilder<'j> {
             // have a proper filename here.
             .expect("invalid jailer exec file path");
         let id_str = self.config_builder.0.vm_id().to_string();
-        self.jailer.workspace_dir = self
+        let jailer_vm_dir = self
             .jailer
             .chroot_base_dir()
             .join(exec_file_base)
-            .join(id_str)
-            .join("root")
-            .into();
+            .join(&id_str);
+        self.jailer.chroot_dir = jailer_vm_dir.join("root").into();
+        let (vm_dir, workspace_dir) = match &self.jailer.workspace_layout {
+            WorkspaceLayout::Jailer => (jailer_vm_dir.clone(), jailer_vm_dir.join("root")),
+            WorkspaceLayout::Flat(dir) => (dir.join(&id_str), dir.join(&id_str).join("root")),
+            WorkspaceLayout::External(dir) => (jailer_vm_dir, dir.to_path_buf()),
+        };
+        self.jailer.vm_dir = vm_dir.into();
+        self.jailer.workspace_dir = workspace_dir.into();
         self.config_builder.0.jailer_cfg = Some(self.jailer);
 
         self.config_builder
@@ -1,9 +1,63 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, fmt, str::FromStr};
 
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 
 use super::Builder;
+use crate::Error;
+
+/// Maximum number of vCPUs of a VM.
+pub const MAX_VCPU_COUNT: usize = 32;
+
+/// A CPU architecture supported by Firecracker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Arch {
+    /// x86_64.
+    X86_64,
+    /// aarch64.
+    Aarch64,
+}
+
+impl Arch {
+    /// The architecture of the host, if supported by Firecracker.
+    pub fn host() -> Option<Self> {
+        std::env::consts::ARCH.parse().ok()
+    }
+
+    /// If simultaneous multithreading can be enabled.
+    pub fn supports_smt(&self) -> bool {
+        *self == Self::X86_64
+    }
+
+    /// The CPU templates available, besides `None`.
+    pub fn cpu_templates(&self) -> &'static [&'static str] {
+        match self {
+            Self::X86_64 => &["C3", "T2", "T2S", "T2CL", "T2A"],
+            Self::Aarch64 => &["V1N1"],
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::X86_64 => "x86_64",
+            Self::Aarch64 => "aarch64",
+        })
+    }
+}
+
+impl FromStr for Arch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" => Ok(Self::X86_64),
+            "aarch64" => Ok(Self::Aarch64),
+            _ => Err(Error::UnsupportedArch(s.to_owned())),
+        }
+    }
+}
 
 /// Machine configuration.
 #[derive(Derivative, Debug, Serialize, Deserialize)]
@@ -42,6 +96,35 @@ impl<'m> Machine<'m> {
     pub fn cpu_template(&self) -> Option<&str> {
         self.cpu_template.as_deref()
     }
+
+    /// Check the options against what Firecracker supports on `arch`.
+    ///
+    /// Only architecture independent checks are done if `arch` is `None`.
+    pub(crate) fn validate(&self, arch: Option<Arch>) -> Result<(), Error> {
+        if self.vcpu_count == 0
+            || self.vcpu_count > MAX_VCPU_COUNT
+            || (self.smt && self.vcpu_count > 1 && !self.vcpu_count.is_multiple_of(2))
+        {
+            return Err(Error::InvalidVcpuCount(self.vcpu_count));
+        }
+        let arch = match arch {
+            Some(arch) => arch,
+            None => return Ok(()),
+        };
+        if self.smt && !arch.supports_smt() {
+            return Err(Error::SmtUnsupported(arch));
+        }
+        if let Some(template) = self.cpu_template() {
+            if template != "None" && !arch.cpu_templates().contains(&template) {
+                return Err(Error::UnsupportedCpuTemplate {
+                    template: template.to_owned(),
+                    arch,
+                });
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl Default for Machine<'_> {
@@ -65,10 +148,12 @@ pub struct MachineBuilder<'m> {
 
 impl<'m> MachineBuilder<'m> {
     /// Create a new `MachineBuilder` instance.
-    pub(crate) fn new(config_builder: Builder<'m>) -> Self {
+    pub(crate) fn new(mut config_builder: Builder<'m>) -> Self {
+        let machine = std::mem::take(&mut config_builder.0.machine_cfg);
+
         Self {
             config_builder,
-            machine: Machine::default(),
+            machine,
         }
     }
 
@@ -95,7 +180,7 @@ impl<'m> MachineBuilder<'m> {
         self
     }
 
-    /// Number of vCPUs (either 1 or an even number).
+    /// Number of vCPUs (either 1 or an even number if SMT is enabled).
     ///
     /// Maximum: 32
     /// Minimum: 1
@@ -105,6 +190,8 @@ impl<'m> MachineBuilder<'m> {
     }
 
     /// cpu template.
+    ///
+    /// See [`Arch::cpu_templates`] for the templates available.
     pub fn cpu_template(mut self, cpu_template: Cow<'m, str>) -> Self {
         self.machine.cpu_template = Some(cpu_template);
         self
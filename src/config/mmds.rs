@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+
+use crate::version::FirecrackerVersion;
+
+/// Name of the MMDS metadata file inside the chroot.
+pub const METADATA_FILE_NAME: &str = "metadata.json";
+
+/// The default IPv4 address of MMDS, as seen by the guest.
+pub const MMDS_DEFAULT_IPV4_ADDRESS: &str = "169.254.169.254";
+
+/// The path of the MMDS V2 session token endpoint.
+pub const MMDS_TOKEN_PATH: &str = "/latest/api/token";
+
+/// The header a guest sets on a `PUT` to [`MMDS_TOKEN_PATH`] to request a session token, with
+/// the token lifetime in seconds (1 to 21600) as value.
+pub const MMDS_TOKEN_TTL_HEADER: &str = "X-metadata-token-ttl-seconds";
+
+/// The header a guest sets on MMDS V2 requests, with the session token as value.
+pub const MMDS_TOKEN_HEADER: &str = "X-metadata-token";
+
+/// MMDS configuration.
+///
+/// For details on MMDS, please refer to the relevant [Firecracker documentation].
+///
+/// [Firecracker documentation]: https://github.com/firecracker-microvm/firecracker/blob/main/docs/mmds/mmds-user-guide.md
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mmds<'m> {
+    pub(crate) version: MmdsVersion,
+    pub(crate) network_interfaces: Vec<Cow<'m, str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) ipv4_address: Option<Cow<'m, str>>,
+}
+
+impl<'m> Mmds<'m> {
+    /// The MMDS version.
+    pub fn version(&self) -> MmdsVersion {
+        self.version
+    }
+
+    /// The IDs of the network interfaces MMDS is reachable from.
+    pub fn network_interfaces(&self) -> &[Cow<'m, str>] {
+        &self.network_interfaces
+    }
+
+    /// The IPv4 address of MMDS, if not [`MMDS_DEFAULT_IPV4_ADDRESS`].
+    pub fn ipv4_address(&self) -> Option<&str> {
+        self.ipv4_address.as_deref()
+    }
+}
+
+/// The version of MMDS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MmdsVersion {
+    /// Requests are served without a session token.
+    #[default]
+    V1,
+    /// Requests require a session token.
+    ///
+    /// The guest first gets a token with a `PUT` request to [`MMDS_TOKEN_PATH`], setting
+    /// [`MMDS_TOKEN_TTL_HEADER`], and then passes it in [`MMDS_TOKEN_HEADER`] on every request
+    /// (see [`MmdsVersion::guest_fetch_command`]).
+    V2,
+}
+
+impl MmdsVersion {
+    /// The first Firecracker version supporting this MMDS version.
+    pub fn min_firecracker_version(&self) -> FirecrackerVersion {
+        match self {
+            Self::V1 => FirecrackerVersion::new(0, 0, 0),
+            Self::V2 => FirecrackerVersion::new(1, 0, 0),
+        }
+    }
+
+    /// A shell command a guest can run to fetch `path` from MMDS at the given address.
+    ///
+    /// With V2, the command requests a session token valid for `token_ttl_secs` seconds first.
+    pub fn guest_fetch_command(
+        &self,
+        ipv4_address: &str,
+        path: &str,
+        token_ttl_secs: u32,
+    ) -> String {
+        let url = format!("http://{ipv4_address}/{}", path.trim_start_matches('/'));
+        match self {
+            Self::V1 => format!("curl -s '{url}'"),
+            Self::V2 => format!(
+                "TOKEN=$(curl -s -X PUT 'http://{ipv4_address}{MMDS_TOKEN_PATH}' \
+                 -H '{MMDS_TOKEN_TTL_HEADER}: {token_ttl_secs}') && \
+                 curl -s '{url}' -H \"{MMDS_TOKEN_HEADER}: $TOKEN\""
+            ),
+        }
+    }
+}
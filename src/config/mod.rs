@@ -2,44 +2,89 @@
 
 use std::{
     borrow::Cow,
+    collections::{BTreeMap, HashSet},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use derivative::Derivative;
 use serde::{Deserialize, Serialize};
 
+mod balloon;
+mod diff;
+mod draft;
 mod drive;
+mod isolation;
 mod jailer;
 mod machine;
+mod mmds;
+mod nat;
 /// Network configuration.
 pub mod network;
+mod seccomp;
+mod socket;
+mod vm_id;
 mod vsock;
+mod workspace;
 
+pub use balloon::*;
+pub use diff::*;
+pub use draft::*;
 pub use drive::*;
+pub use isolation::*;
 pub use jailer::*;
 pub use machine::*;
+pub use mmds::*;
+pub use nat::*;
+pub use seccomp::*;
+pub use socket::*;
+pub use vm_id::*;
 pub use vsock::*;
+pub use workspace::*;
 
-use uuid::Uuid;
+use crate::{
+    audit::{AuditAction, AuditLog, AuditedFs, AuditedSpawner},
+    fs::{ChrootFs, LocalFs},
+    spawner::{LocalSpawner, ProcessSpawner},
+    transport::ApiTransport,
+    version::FirecrackerVersion,
+    Error,
+};
 
-use crate::Error;
+/// Maximum length of a Unix domain socket path, excluding the terminating NUL byte.
+pub const UNIX_SOCKET_PATH_MAX_LEN: usize = 107;
 
-// FIXME: Hardcoding for now. This should come from ChrootStrategy enum, when we've that.
-const KERNEL_IMAGE_FILENAME: &str = "kernel";
+/// Length of the longest `_PORT` suffix Firecracker appends to the vsock UDS path for
+/// guest-initiated connections.
+const VSOCK_PORT_SUFFIX_MAX_LEN: usize = "_4294967295".len();
+
+/// The default name of the kernel image inside the chroot.
+pub const DEFAULT_KERNEL_IMAGE_NAME: &str = "kernel";
 
 /// VMM configuration.
 #[derive(Debug)]
 pub struct Config<'c> {
     pub(crate) socket_path: Cow<'c, Path>,
+    api_socket_permissions: Option<SocketPermissions>,
     log_path: Option<Cow<'c, Path>>,
     log_fifo: Option<Cow<'c, Path>>,
     log_level: Option<LogLevel>,
+    log_module: Option<Cow<'c, str>>,
+    log_show_level: bool,
+    log_show_origin: bool,
     metrics_path: Option<Cow<'c, Path>>,
     metrics_fifo: Option<Cow<'c, Path>>,
     pub(crate) src_kernel_image_path: Cow<'c, Path>,
+    kernel_image_name: Cow<'c, str>,
+    decompress_kernel: bool,
     pub(crate) src_initrd_path: Option<Cow<'c, Path>>,
+    initrd_name: Option<Cow<'c, str>>,
     kernel_args: Option<Cow<'c, str>>,
     pub(crate) drives: Vec<Drive<'c>>,
+    check_root_filesystem: bool,
+    artifact_refresh: ArtifactRefresh,
+    admission_check: Option<f64>,
+    firecracker_version: Option<FirecrackerVersion>,
 
     // FIXME: Can't use trait object here because it's make `Config` non-Send, which is problematic
     // for async/await.
@@ -48,10 +93,28 @@ pub struct Config<'c> {
     //pub fifo_log_writer: Option<Box<dyn AsyncWrite>>,
     machine_cfg: Machine<'c>,
     pub(crate) jailer_cfg: Option<Jailer<'c>>,
-    vm_id: Uuid,
+    isolation_profile: Option<IsolationProfile<'c>>,
+    vm_id: VmId,
     net_ns: Option<Cow<'c, str>>,
     network_interfaces: Vec<network::Interface<'c>>,
     vsock_cfg: Option<VSock<'c>>,
+    vsock_socket_permissions: Option<SocketPermissions>,
+    balloon_cfg: Option<Balloon>,
+    vcpu_affinity: Vec<u32>,
+    labels: BTreeMap<String, String>,
+    seccomp: Seccomp<'c>,
+    mmds_metadata: Option<serde_json::Value>,
+    mmds_cfg: Option<Mmds<'c>>,
+    nat: Option<Nat<'c>>,
+    record_api_calls: bool,
+    audit_log_dir: Option<Cow<'c, Path>>,
+    audit_log: Option<AuditLog>,
+    agent_port: u32,
+    agent_max_output: usize,
+    target_arch: Option<Arch>,
+    pub(crate) spawner: Arc<dyn ProcessSpawner>,
+    api_transport: Option<Arc<dyn ApiTransport>>,
+    pub(crate) fs: Arc<dyn ChrootFs>,
     /* TODO:
 
 
@@ -60,56 +123,157 @@ pub struct Config<'c> {
     */
 }
 
+/// The kernel command line of the presets, see [`Config::micro`].
+pub const PRESET_KERNEL_ARGS: &str = "console=ttyS0 reboot=k panic=1 pci=off";
+
+/// The log path inside the chroot of the presets, see [`Config::micro`].
+pub const PRESET_LOG_PATH: &str = "/firecracker.log";
+
 impl<'c> Config<'c> {
+    /// Create a `Builder` prefilled for a small VM, with 1 vCPU and 128 MiB of memory.
+    ///
+    /// The VM boots the `src_kernel_image_path` kernel with [`PRESET_KERNEL_ARGS`] from the
+    /// read-write `rootfs` root drive at `src_rootfs_path`, and logs at [`LogLevel::Info`] to
+    /// [`PRESET_LOG_PATH`]. All of these can be overridden with the builder, and the jailer still
+    /// needs to be configured.
+    pub fn micro<K, R>(
+        vm_id: Option<VmId>,
+        src_kernel_image_path: K,
+        src_rootfs_path: R,
+    ) -> Builder<'c>
+    where
+        K: Into<Cow<'c, Path>>,
+        R: Into<Cow<'c, Path>>,
+    {
+        Self::preset(vm_id, src_kernel_image_path, src_rootfs_path, 1, 128)
+    }
+
+    /// Create a `Builder` prefilled for a standard VM, with 2 vCPUs and 1 GiB of memory.
+    ///
+    /// See [`Config::micro`] for the other settings.
+    pub fn standard<K, R>(
+        vm_id: Option<VmId>,
+        src_kernel_image_path: K,
+        src_rootfs_path: R,
+    ) -> Builder<'c>
+    where
+        K: Into<Cow<'c, Path>>,
+        R: Into<Cow<'c, Path>>,
+    {
+        Self::preset(vm_id, src_kernel_image_path, src_rootfs_path, 2, 1024)
+    }
+
+    fn preset<K, R>(
+        vm_id: Option<VmId>,
+        src_kernel_image_path: K,
+        src_rootfs_path: R,
+        vcpu_count: usize,
+        mem_size_mib: i64,
+    ) -> Builder<'c>
+    where
+        K: Into<Cow<'c, Path>>,
+        R: Into<Cow<'c, Path>>,
+    {
+        Self::builder(vm_id, src_kernel_image_path)
+            .machine_cfg()
+            .vcpu_count(vcpu_count)
+            .mem_size_mib(mem_size_mib)
+            .build()
+            .kernel_args(PRESET_KERNEL_ARGS)
+            .add_drive("rootfs", src_rootfs_path)
+            .is_root_device(true)
+            .build()
+            .log_path(Path::new(PRESET_LOG_PATH))
+            .log_level(LogLevel::Info)
+    }
+
     /// Create a new `Builder` instance.
     ///
     /// # Arguments
     ///
     /// `vm_id` - The ID of the VM. It's used as the Firecracker's instance ID. Pass `None` to
-    ///           generate a random ID.
+    ///           generate a random UUID-based ID.
     /// `src_kernel_image_path`: The path to the kernel image, that must be an uncompressed ELF image.
-    pub fn builder<P>(vm_id: Option<Uuid>, src_kernel_image_path: P) -> Builder<'c>
+    pub fn builder<P>(vm_id: Option<VmId>, src_kernel_image_path: P) -> Builder<'c>
     where
         P: Into<Cow<'c, Path>>,
     {
         Builder(Self {
             socket_path: Path::new("/run/firecracker.socket").into(),
+            api_socket_permissions: None,
             log_path: None,
             log_fifo: None,
             log_level: None,
+            log_module: None,
+            log_show_level: false,
+            log_show_origin: false,
             metrics_path: None,
             metrics_fifo: None,
             src_kernel_image_path: src_kernel_image_path.into(),
+            kernel_image_name: DEFAULT_KERNEL_IMAGE_NAME.into(),
+            decompress_kernel: false,
             src_initrd_path: None,
+            initrd_name: None,
             kernel_args: None,
             drives: Vec::new(),
+            check_root_filesystem: false,
+            artifact_refresh: ArtifactRefresh::IfMissing,
+            admission_check: None,
+            firecracker_version: None,
             machine_cfg: Machine::default(),
             jailer_cfg: None,
-            vm_id: vm_id.unwrap_or_else(Uuid::new_v4),
+            isolation_profile: None,
+            vm_id: vm_id.unwrap_or_else(VmId::random),
             net_ns: None,
             network_interfaces: Vec::new(),
             vsock_cfg: None,
+            vsock_socket_permissions: None,
+            balloon_cfg: None,
+            vcpu_affinity: Vec::new(),
+            labels: BTreeMap::new(),
+            seccomp: Seccomp::default(),
+            mmds_metadata: None,
+            mmds_cfg: None,
+            nat: None,
+            record_api_calls: false,
+            audit_log_dir: None,
+            audit_log: None,
+            agent_port: crate::agent::DEFAULT_AGENT_PORT,
+            agent_max_output: crate::agent::DEFAULT_AGENT_MAX_OUTPUT,
+            target_arch: None,
+            spawner: Arc::new(LocalSpawner),
+            api_transport: None,
+            fs: Arc::new(LocalFs),
+        })
+    }
+
+    /// Create the logger configuration from `self`, if logging is configured.
+    pub(crate) fn logger(&self) -> Option<Logger<'_>> {
+        let log_path = self.log_fifo().or_else(|| self.log_path());
+        if log_path.is_none() && self.log_level.is_none() && self.log_module.is_none() {
+            return None;
+        }
+
+        Some(Logger {
+            log_path,
+            level: self.log_level,
+            show_level: self.log_show_level,
+            show_log_origin: self.log_show_origin,
+            module: self.log_module(),
         })
     }
 
     /// Create boot source from `self`.
-    pub(crate) fn boot_source(&self) -> Result<BootSource, Error> {
-        let relative_kernel_image_path = Path::new("/").join(KERNEL_IMAGE_FILENAME);
-
-        let relative_initrd_path: Result<Option<PathBuf>, Error> =
-            match self.src_initrd_path.as_ref() {
-                Some(initrd_path) => {
-                    let initrd_filename =
-                        initrd_path.file_name().ok_or(Error::InvalidInitrdPath)?;
-                    Ok(Some(Path::new("/").join(initrd_filename)))
-                }
-                None => Ok(None),
-            };
+    pub(crate) fn boot_source(&self) -> Result<BootSource<'_>, Error> {
+        let relative_kernel_image_path = Path::new("/").join(self.kernel_image_name());
+        let relative_initrd_path = self
+            .initrd_name()?
+            .map(|initrd_name| Path::new("/").join(initrd_name));
 
         Ok(BootSource {
             kernel_image_path: relative_kernel_image_path,
-            initrd_path: relative_initrd_path?,
-            boot_args: self.kernel_args.as_ref().map(AsRef::as_ref).map(Into::into),
+            initrd_path: relative_initrd_path,
+            boot_args: self.kernel_args.as_ref().map(AsRef::as_ref),
         })
     }
 
@@ -118,13 +282,94 @@ impl<'c> Config<'c> {
         self.socket_path.as_ref()
     }
 
+    /// The permissions of the API socket, if set.
+    pub fn api_socket_permissions(&self) -> Option<SocketPermissions> {
+        self.api_socket_permissions
+    }
+
     /// The socket path in chroot location.
     pub fn host_socket_path(&self) -> PathBuf {
-        let socket_path = self.socket_path.as_ref();
-        let relative_path = socket_path.strip_prefix("/").unwrap_or(socket_path);
+        self.host_path(self.socket_path())
+    }
+
+    /// The vsock Unix socket path in chroot location.
+    pub fn host_vsock_uds_path(&self) -> Option<PathBuf> {
+        self.vsock_cfg()
+            .map(|vsock| self.host_path(vsock.uds_path()))
+    }
+
+    /// The host path of `path`, as seen by Firecracker inside the chroot.
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        let relative_path = path.strip_prefix("/").unwrap_or(path);
         self.jailer().workspace_dir().join(relative_path)
     }
 
+    /// The VM directory, with the record and diagnostics of the VM and, unless it's external, the
+    /// jailer workspace (see [`WorkspaceLayout`]).
+    pub fn vm_dir(&self) -> &Path {
+        self.jailer().vm_dir()
+    }
+
+    /// The record of the VM used by [`crate::list`].
+    pub fn record_path(&self) -> PathBuf {
+        self.vm_dir().join(crate::discovery::RECORD_FILE_NAME)
+    }
+
+    /// The record of the commands removing the NAT rules of the VM.
+    pub(crate) fn nat_rules_path(&self) -> PathBuf {
+        self.vm_dir().join(crate::nat::NAT_RULES_FILE_NAME)
+    }
+
+    /// The path of the block device of the drive with the given ID in a Linux guest, e.g
+    /// `/dev/vdb`, see [`Drive::guest_device_name`].
+    pub fn drive_guest_device(&self, drive_id: &str) -> Option<String> {
+        self.drives
+            .iter()
+            .find(|drive| drive.drive_id() == drive_id)
+            .map(|drive| format!("/dev/{}", drive.guest_device_name()))
+    }
+
+    /// The copy-on-write file of `drive` with [`DriveProvisioning::DmSnapshot`].
+    pub(crate) fn drive_cow_path(&self, drive: &Drive<'_>) -> PathBuf {
+        self.vm_dir().join(format!("{}.cow", drive.drive_id()))
+    }
+
+    /// The encrypted file of `drive` with [`DriveProvisioning::Luks`].
+    pub(crate) fn drive_luks_path(&self, drive: &Drive<'_>) -> PathBuf {
+        self.vm_dir().join(format!("{}.luks", drive.drive_id()))
+    }
+
+    /// The filesystem image backing the jailer workspace with [`WorkspaceQuota::LoopFile`].
+    pub fn workspace_image_path(&self) -> PathBuf {
+        self.vm_dir().join("root.ext4")
+    }
+
+    /// The host-side layout of the jailer workspace.
+    pub fn workspace(&self) -> Result<Workspace, Error> {
+        let drives = self
+            .drives
+            .iter()
+            .map(|drive| Ok((drive.drive_id().to_owned(), self.drive_path(drive)?)))
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Workspace {
+            vm_dir: self.vm_dir().to_owned(),
+            root: self.jailer().workspace_dir().to_owned(),
+            diagnostics_dir: self.diagnostics_dir(),
+            kernel_image: self.kernel_image_path(),
+            initrd: self.initrd_path()?,
+            seccomp_filter: self.seccomp_filter_path(),
+            mmds_metadata: self.mmds_metadata_path(),
+            drives,
+            api_socket: self.host_socket_path(),
+            vsock_uds: self.host_vsock_uds_path(),
+            log: self.log_path().map(|path| self.host_path(path)),
+            log_fifo: self.log_fifo().map(|path| self.host_path(path)),
+            metrics: self.metrics_path().map(|path| self.host_path(path)),
+            metrics_fifo: self.metrics_fifo().map(|path| self.host_path(path)),
+        })
+    }
+
     /// The log path.
     pub fn log_path(&self) -> Option<&Path> {
         self.log_path.as_ref().map(AsRef::as_ref)
@@ -135,6 +380,26 @@ impl<'c> Config<'c> {
         self.log_fifo.as_ref().map(AsRef::as_ref)
     }
 
+    /// The verbosity of Firecracker logging.
+    pub fn log_level(&self) -> Option<LogLevel> {
+        self.log_level
+    }
+
+    /// The module Firecracker logging is restricted to.
+    pub fn log_module(&self) -> Option<&str> {
+        self.log_module.as_deref()
+    }
+
+    /// If the level is included in Firecracker log lines.
+    pub fn log_show_level(&self) -> bool {
+        self.log_show_level
+    }
+
+    /// If the file and line of origin are included in Firecracker log lines.
+    pub fn log_show_origin(&self) -> bool {
+        self.log_show_origin
+    }
+
     /// The metrics path.
     pub fn metrics_path(&self) -> Option<&Path> {
         self.metrics_path.as_ref().map(AsRef::as_ref)
@@ -154,9 +419,19 @@ impl<'c> Config<'c> {
         self.src_kernel_image_path.as_ref()
     }
 
+    /// The name of the kernel image inside the chroot.
+    pub fn kernel_image_name(&self) -> &str {
+        &self.kernel_image_name
+    }
+
+    /// If compressed kernel images are decompressed when copied to the chroot.
+    pub fn decompress_kernel(&self) -> bool {
+        self.decompress_kernel
+    }
+
     /// The kernel image path in chroot location.
     pub fn kernel_image_path(&self) -> PathBuf {
-        self.jailer().workspace_dir().join(KERNEL_IMAGE_FILENAME)
+        self.jailer().workspace_dir().join(self.kernel_image_name())
     }
 
     /// The source initrd path.
@@ -168,18 +443,89 @@ impl<'c> Config<'c> {
         self.src_initrd_path.as_ref().map(AsRef::as_ref)
     }
 
+    /// The name of the initrd inside the chroot.
+    ///
+    /// Unless set explicitly, this is the filename of the source initrd.
+    pub fn initrd_name(&self) -> Result<Option<&str>, Error> {
+        let src_initrd_path = match self.src_initrd_path() {
+            Some(src_initrd_path) => src_initrd_path,
+            None => return Ok(None),
+        };
+        match self.initrd_name.as_deref() {
+            Some(initrd_name) => Ok(Some(initrd_name)),
+            None => src_initrd_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(Some)
+                .ok_or(Error::InvalidInitrdPath),
+        }
+    }
+
     /// The initrd path in chroot location.
     pub fn initrd_path(&self) -> Result<Option<PathBuf>, Error> {
-        match self.src_initrd_path.as_ref() {
-            Some(initrd_path) => {
-                let initrd_filename = initrd_path
-                    .file_name()
-                    .ok_or(Error::InvalidInitrdPath)?
-                    .to_owned();
-                Ok(Some(self.jailer().workspace_dir().join(initrd_filename)))
-            }
-            None => Ok(None),
+        Ok(self
+            .initrd_name()?
+            .map(|initrd_name| self.jailer().workspace_dir().join(initrd_name)))
+    }
+
+    /// The seccomp filter.
+    pub fn seccomp(&self) -> &Seccomp<'c> {
+        &self.seccomp
+    }
+
+    /// The path to the custom seccomp filter in the chroot, if any.
+    pub fn seccomp_filter_path(&self) -> Option<PathBuf> {
+        match self.seccomp {
+            Seccomp::Custom(_) => Some(self.jailer().workspace_dir().join(SECCOMP_FILTER_NAME)),
+            Seccomp::Default | Seccomp::Disabled => None,
+        }
+    }
+
+    /// The initial contents of the MMDS data store.
+    pub fn mmds_metadata(&self) -> Option<&serde_json::Value> {
+        self.mmds_metadata.as_ref()
+    }
+
+    /// The MMDS configuration.
+    pub fn mmds_cfg(&self) -> Option<&Mmds<'c>> {
+        self.mmds_cfg.as_ref()
+    }
+
+    /// The path to the MMDS metadata file in the chroot, if any.
+    pub fn mmds_metadata_path(&self) -> Option<PathBuf> {
+        self.mmds_metadata
+            .as_ref()
+            .map(|_| self.jailer().workspace_dir().join(METADATA_FILE_NAME))
+    }
+
+    /// The arguments passed to the Firecracker binary.
+    pub(crate) fn vmm_args(&self) -> Result<Vec<String>, Error> {
+        let mut args = vec![
+            "--api-sock".to_owned(),
+            self.socket_path
+                .to_str()
+                .ok_or(Error::InvalidSocketPath)?
+                .to_owned(),
+        ];
+        match self.seccomp {
+            Seccomp::Default => (),
+            Seccomp::Disabled => args.push("--no-seccomp".to_owned()),
+            Seccomp::Custom(_) => args.extend([
+                "--seccomp-filter".to_owned(),
+                format!("/{SECCOMP_FILTER_NAME}"),
+            ]),
+        }
+        if self.mmds_metadata.is_some() {
+            args.extend(["--metadata".to_owned(), format!("/{METADATA_FILE_NAME}")]);
         }
+        args.extend(
+            self.jailer()
+                .extra_vmm_args()
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+
+        Ok(args)
     }
 
     /// The kernel arguments.
@@ -192,6 +538,157 @@ impl<'c> Config<'c> {
         &self.drives
     }
 
+    /// The name of the drive file inside the chroot.
+    ///
+    /// Unless set explicitly through [`DriveBuilder::dest_name`], this is the filename of the
+    /// source drive. If several drives share the same source filename, their names are prefixed
+    /// with `<drive_id>-` to keep them apart.
+    pub fn drive_name(&self, drive: &Drive<'_>) -> Result<String, Error> {
+        if let Some(dest_name) = drive.dest_name() {
+            return Ok(dest_name.to_owned());
+        }
+        let src_name = drive
+            .src_path()
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or(Error::InvalidDrivePath)?;
+        let shared = self
+            .drives
+            .iter()
+            .filter(|d| {
+                d.dest_name().is_none() && d.src_path().file_name() == Some(src_name.as_ref())
+            })
+            .count()
+            > 1;
+
+        if shared {
+            Ok(format!("{}-{src_name}", drive.drive_id()))
+        } else {
+            Ok(src_name.to_owned())
+        }
+    }
+
+    /// If the root drive image is checked by [`crate::Machine::create`].
+    pub fn check_root_filesystem(&self) -> bool {
+        self.check_root_filesystem
+    }
+
+    /// When the artifacts are copied to the chroot.
+    pub fn artifact_refresh(&self) -> ArtifactRefresh {
+        self.artifact_refresh
+    }
+
+    /// The overhead factor of the host resource check done by [`crate::Machine::start`], if
+    /// enabled.
+    pub fn admission_check(&self) -> Option<f64> {
+        self.admission_check
+    }
+
+    /// The Firecracker version the configuration is pinned to, if any.
+    pub fn firecracker_version(&self) -> Option<FirecrackerVersion> {
+        self.firecracker_version
+    }
+
+    /// The drive file path in chroot location.
+    pub fn drive_path(&self, drive: &Drive<'_>) -> Result<PathBuf, Error> {
+        Ok(self.jailer().workspace_dir().join(self.drive_name(drive)?))
+    }
+
+    /// Validate the configuration.
+    fn validate(&self) -> Result<(), Error> {
+        self.machine_cfg.validate(self.target_arch())?;
+        self.validate_drives()?;
+        self.validate_artifact_names()?;
+        if self.jailer_cfg.is_some() {
+            self.validate_socket_paths()?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the sockets are placed inside the chroot and their host paths fit in the
+    /// `AF_UNIX` address limit.
+    fn validate_socket_paths(&self) -> Result<(), Error> {
+        let escapes_chroot = |path: &Path| {
+            path.components()
+                .any(|c| matches!(c, std::path::Component::ParentDir))
+        };
+        if escapes_chroot(self.socket_path()) {
+            return Err(Error::InvalidSocketPath);
+        }
+        let host_socket_path = self.host_socket_path();
+        if host_socket_path.as_os_str().len() > UNIX_SOCKET_PATH_MAX_LEN {
+            return Err(Error::SocketPathTooLong {
+                path: host_socket_path,
+            });
+        }
+
+        if let Some(vsock) = self.vsock_cfg() {
+            if escapes_chroot(vsock.uds_path()) {
+                return Err(Error::InvalidSocketPath);
+            }
+        }
+        if let Some(uds_path) = self.host_vsock_uds_path() {
+            // Leave room for the port suffix of guest-initiated connections.
+            let len = uds_path.as_os_str().len() + VSOCK_PORT_SUFFIX_MAX_LEN;
+            if len > UNIX_SOCKET_PATH_MAX_LEN {
+                return Err(Error::SocketPathTooLong { path: uds_path });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that a single drive is the root device, unless booting from an initrd, and that only
+    /// the root device has a partition UUID.
+    fn validate_drives(&self) -> Result<(), Error> {
+        match self.drives.iter().filter(|d| d.is_root_device()).count() {
+            0 if self.src_initrd_path.is_none() => return Err(Error::NoRootDrive),
+            0 | 1 => {}
+            _ => return Err(Error::MultipleRootDrives),
+        }
+        if let Some(drive) = self
+            .drives
+            .iter()
+            .find(|d| d.part_uuid().is_some() && !d.is_root_device())
+        {
+            return Err(Error::PartUuidOnNonRootDrive(drive.drive_id().to_owned()));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the names of the artifacts in the chroot are valid and unique.
+    fn validate_artifact_names(&self) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        let initrd_name = self.initrd_name()?;
+        let drive_names = self
+            .drives
+            .iter()
+            .map(|drive| self.drive_name(drive))
+            .collect::<Result<Vec<_>, _>>()?;
+        let seccomp_name = match self.seccomp {
+            Seccomp::Custom(_) => Some(SECCOMP_FILTER_NAME),
+            Seccomp::Default | Seccomp::Disabled => None,
+        };
+        let metadata_name = self.mmds_metadata.as_ref().map(|_| METADATA_FILE_NAME);
+        let all_names = std::iter::once(self.kernel_image_name())
+            .chain(initrd_name)
+            .chain(seccomp_name)
+            .chain(metadata_name)
+            .chain(drive_names.iter().map(String::as_str));
+        for name in all_names {
+            if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+                return Err(Error::InvalidArtifactName(name.to_owned()));
+            }
+            if !names.insert(name) {
+                return Err(Error::DuplicateArtifactName(name.to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+
     /// The machine configuration.
     pub fn machine_cfg(&self) -> &Machine<'c> {
         &self.machine_cfg
@@ -203,10 +700,15 @@ impl<'c> Config<'c> {
     }
 
     /// The VM ID.
-    pub fn vm_id(&self) -> &Uuid {
+    pub fn vm_id(&self) -> &VmId {
         &self.vm_id
     }
 
+    /// The isolation profile the configuration was built with, if any.
+    pub fn isolation_profile(&self) -> Option<&IsolationProfile<'c>> {
+        self.isolation_profile.as_ref()
+    }
+
     /// The network namespace path.
     pub fn net_ns(&self) -> Option<&str> {
         self.net_ns.as_ref().map(AsRef::as_ref)
@@ -222,7 +724,116 @@ impl<'c> Config<'c> {
         self.vsock_cfg.as_ref()
     }
 
-    pub(crate) fn jailer(&self) -> &Jailer {
+    /// The permissions of the vsock Unix socket, if set.
+    pub fn vsock_socket_permissions(&self) -> Option<SocketPermissions> {
+        self.vsock_socket_permissions
+    }
+
+    /// The balloon device configuration.
+    pub fn balloon_cfg(&self) -> Option<&Balloon> {
+        self.balloon_cfg.as_ref()
+    }
+
+    /// The host NAT configuration.
+    pub fn nat(&self) -> Option<&Nat<'c>> {
+        self.nat.as_ref()
+    }
+
+    /// The host CPUs the vCPU threads are pinned to.
+    pub fn vcpu_affinity(&self) -> &[u32] {
+        &self.vcpu_affinity
+    }
+
+    /// The labels of the VM.
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    /// If Firecracker API calls are recorded.
+    pub fn record_api_calls(&self) -> bool {
+        self.record_api_calls
+    }
+
+    /// The audit log of privileged host operations, if enabled.
+    ///
+    /// See [`crate::audit`] for details.
+    pub fn audit_log_path(&self) -> Option<PathBuf> {
+        self.audit_log_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.jsonl", self.vm_id)))
+    }
+
+    /// Record `action` to the audit log, if enabled.
+    pub(crate) async fn audit(&self, action: AuditAction) -> Result<(), Error> {
+        if let Some(log) = &self.audit_log {
+            log.record(action).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The vsock port of the guest agent, see [`crate::agent`].
+    pub fn agent_port(&self) -> u32 {
+        self.agent_port
+    }
+
+    /// The maximum size of the stdout and stderr of commands run through the guest agent.
+    pub fn agent_max_output(&self) -> usize {
+        self.agent_max_output
+    }
+
+    /// The architecture the machine options are validated for.
+    ///
+    /// Defaults to the host architecture, see [`Arch::host`].
+    pub fn target_arch(&self) -> Option<Arch> {
+        self.target_arch.or_else(Arch::host)
+    }
+
+    /// The host directory holding diagnostics data of the VM.
+    ///
+    /// It lives next to the jailer workspace (and hence outside the chroot) and is removed along
+    /// with the VM on [`crate::Machine::delete`].
+    pub fn diagnostics_dir(&self) -> PathBuf {
+        self.vm_dir().join("diagnostics")
+    }
+
+    /// The file where Firecracker API calls are recorded, if enabled.
+    ///
+    /// See [`crate::recording`] for details.
+    pub fn api_record_path(&self) -> PathBuf {
+        self.diagnostics_dir().join("api.jsonl")
+    }
+
+    /// The file capturing the standard output of the jailer.
+    ///
+    /// See [`JailerBuilder::capture_daemon_output`].
+    pub fn jailer_stdout_path(&self) -> PathBuf {
+        self.diagnostics_dir().join("jailer.stdout")
+    }
+
+    /// The file capturing the standard error of the jailer.
+    ///
+    /// See [`JailerBuilder::capture_daemon_output`].
+    pub fn jailer_stderr_path(&self) -> PathBuf {
+        self.diagnostics_dir().join("jailer.stderr")
+    }
+
+    /// The process spawner.
+    pub fn spawner(&self) -> &dyn ProcessSpawner {
+        self.spawner.as_ref()
+    }
+
+    /// The transport of the Firecracker API, if not the local API socket.
+    pub fn api_transport(&self) -> Option<&Arc<dyn ApiTransport>> {
+        self.api_transport.as_ref()
+    }
+
+    /// The chroot filesystem.
+    pub fn fs(&self) -> &dyn ChrootFs {
+        self.fs.as_ref()
+    }
+
+    pub(crate) fn jailer(&self) -> &Jailer<'c> {
         // FIXME: Assuming jailer for now.
         self.jailer_cfg.as_ref().expect("no jailer config")
     }
@@ -240,10 +851,28 @@ pub struct BootSource<'b> {
     pub initrd_path: Option<PathBuf>,
 }
 
+/// The logger configuration of Firecracker.
+///
+/// These are all the fields of `PUT /logger` up to Firecracker 1.10, which has no option for a
+/// profile directory. Firecracker rejects unknown fields, so none are sent speculatively.
+#[derive(Debug, Serialize)]
+pub(crate) struct Logger<'l> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_path: Option<&'l Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<LogLevel>,
+    pub show_level: bool,
+    pub show_log_origin: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<&'l str>,
+}
+
 /// defines the verbosity of Firecracker logging.
-#[derive(Derivative)]
+#[derive(Derivative, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[derivative(Debug, Default)]
 pub enum LogLevel {
+    /// Logging disabled.
+    Off,
     /// Error level logging.
     Error,
     /// Warning level logging.
@@ -253,6 +882,26 @@ pub enum LogLevel {
     Info,
     /// Debug level logging.
     Debug,
+    /// Trace level logging.
+    Trace,
+}
+
+/// When [`crate::Machine::create`] copies the artifacts (kernel, initrd, seccomp filter and drives)
+/// to the chroot, if they're already there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArtifactRefresh {
+    /// Only copy missing artifacts.
+    #[default]
+    IfMissing,
+    /// Copy missing artifacts and artifacts whose source changed, see
+    /// [`crate::fs::ChrootFs::same_contents`].
+    ///
+    /// Read-write drives written to by the guest are changed too, and get overwritten. Kernels
+    /// decompressed when copied (see [`Builder::decompress_kernel`]) are compared through the
+    /// SHA-256 of their source, recorded next to them in the chroot.
+    IfChanged,
+    /// Always copy the artifacts.
+    Always,
 }
 
 /// Configuration builder.
@@ -269,6 +918,13 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set the permissions of the API socket, so that other users of the host can't call the
+    /// Firecracker API.
+    pub fn api_socket_permissions(mut self, permissions: SocketPermissions) -> Self {
+        self.0.api_socket_permissions = Some(permissions);
+        self
+    }
+
     /// Set the Firecracker log path.
     pub fn log_path<P>(mut self, log_path: P) -> Self
     where
@@ -278,6 +934,15 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the Firecracker log path, see [`Builder::log_path`].
+    pub fn log_path_opt<P>(mut self, log_path: Option<P>) -> Self
+    where
+        P: Into<Cow<'c, Path>>,
+    {
+        self.0.log_path = log_path.map(Into::into);
+        self
+    }
+
     /// Set the Firecracker log named-pipe path.
     pub fn log_fifo<P>(mut self, log_fifo: P) -> Self
     where
@@ -287,12 +952,57 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the Firecracker log named-pipe path, see [`Builder::log_fifo`].
+    pub fn log_fifo_opt<P>(mut self, log_fifo: Option<P>) -> Self
+    where
+        P: Into<Cow<'c, Path>>,
+    {
+        self.0.log_fifo = log_fifo.map(Into::into);
+        self
+    }
+
     /// Set the verbosity of Firecracker logging.
     pub fn log_level(mut self, log_level: LogLevel) -> Self {
         self.0.log_level = Some(log_level);
         self
     }
 
+    /// Set or clear the verbosity of Firecracker logging, see [`Builder::log_level`].
+    pub fn log_level_opt(mut self, log_level: Option<LogLevel>) -> Self {
+        self.0.log_level = log_level;
+        self
+    }
+
+    /// Restrict Firecracker logging to the given module, e.g `vmm::vmm_config`.
+    pub fn log_module<M>(mut self, log_module: M) -> Self
+    where
+        M: Into<Cow<'c, str>>,
+    {
+        self.0.log_module = Some(log_module.into());
+        self
+    }
+
+    /// Set or clear the module Firecracker logging is restricted to, see [`Builder::log_module`].
+    pub fn log_module_opt<M>(mut self, log_module: Option<M>) -> Self
+    where
+        M: Into<Cow<'c, str>>,
+    {
+        self.0.log_module = log_module.map(Into::into);
+        self
+    }
+
+    /// Include the level in Firecracker log lines.
+    pub fn log_show_level(mut self, log_show_level: bool) -> Self {
+        self.0.log_show_level = log_show_level;
+        self
+    }
+
+    /// Include the file and line of origin in Firecracker log lines.
+    pub fn log_show_origin(mut self, log_show_origin: bool) -> Self {
+        self.0.log_show_origin = log_show_origin;
+        self
+    }
+
     /// Set the Firecracker metrics path.
     pub fn metrics_path<P>(mut self, metrics_path: P) -> Self
     where
@@ -302,6 +1012,15 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the Firecracker metrics path, see [`Builder::metrics_path`].
+    pub fn metrics_path_opt<P>(mut self, metrics_path: Option<P>) -> Self
+    where
+        P: Into<Cow<'c, Path>>,
+    {
+        self.0.metrics_path = metrics_path.map(Into::into);
+        self
+    }
+
     /// Set the Firecracker metrics named-pipe path.
     pub fn metrics_fifo<P>(mut self, metrics_fifo: P) -> Self
     where
@@ -311,6 +1030,15 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the Firecracker metrics named-pipe path, see [`Builder::metrics_fifo`].
+    pub fn metrics_fifo_opt<P>(mut self, metrics_fifo: Option<P>) -> Self
+    where
+        P: Into<Cow<'c, Path>>,
+    {
+        self.0.metrics_fifo = metrics_fifo.map(Into::into);
+        self
+    }
+
     /// Set the initrd image path.
     pub fn initrd_path<P>(mut self, initrd_path: P) -> Self
     where
@@ -320,6 +1048,55 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the initrd image path, see [`Builder::initrd_path`].
+    pub fn initrd_path_opt<P>(mut self, initrd_path: Option<P>) -> Self
+    where
+        P: Into<Cow<'c, Path>>,
+    {
+        self.0.src_initrd_path = initrd_path.map(Into::into);
+        self
+    }
+
+    /// Set the name of the kernel image inside the chroot.
+    ///
+    /// Defaults to [`DEFAULT_KERNEL_IMAGE_NAME`].
+    pub fn kernel_image_name<N>(mut self, kernel_image_name: N) -> Self
+    where
+        N: Into<Cow<'c, str>>,
+    {
+        self.0.kernel_image_name = kernel_image_name.into();
+        self
+    }
+
+    /// Decompress the kernel image when copying it to the chroot, if it's compressed, see
+    /// [`crate::kernel::decompress`].
+    ///
+    /// Defaults to `false`, in which case compressed images are rejected.
+    pub fn decompress_kernel(mut self, decompress_kernel: bool) -> Self {
+        self.0.decompress_kernel = decompress_kernel;
+        self
+    }
+
+    /// Set the name of the initrd inside the chroot.
+    ///
+    /// Defaults to the filename of the source initrd.
+    pub fn initrd_name<N>(mut self, initrd_name: N) -> Self
+    where
+        N: Into<Cow<'c, str>>,
+    {
+        self.0.initrd_name = Some(initrd_name.into());
+        self
+    }
+
+    /// Set or clear the name of the initrd inside the chroot, see [`Builder::initrd_name`].
+    pub fn initrd_name_opt<N>(mut self, initrd_name: Option<N>) -> Self
+    where
+        N: Into<Cow<'c, str>>,
+    {
+        self.0.initrd_name = initrd_name.map(Into::into);
+        self
+    }
+
     /// Set the command-line arguments that should be passed to the kernel.
     pub fn kernel_args<P>(mut self, kernel_args: P) -> Self
     where
@@ -329,6 +1106,52 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the kernel command-line arguments, see [`Builder::kernel_args`].
+    pub fn kernel_args_opt<P>(mut self, kernel_args: Option<P>) -> Self
+    where
+        P: Into<Cow<'c, str>>,
+    {
+        self.0.kernel_args = kernel_args.map(Into::into);
+        self
+    }
+
+    /// Check the root drive image before copying it to the chroot, see
+    /// [`crate::images::check_root_filesystem`].
+    ///
+    /// Defaults to `false`.
+    pub fn check_root_filesystem(mut self, check_root_filesystem: bool) -> Self {
+        self.0.check_root_filesystem = check_root_filesystem;
+        self
+    }
+
+    /// Set when the artifacts are copied to the chroot, if they're already there.
+    ///
+    /// Defaults to [`ArtifactRefresh::IfMissing`].
+    pub fn artifact_refresh(mut self, artifact_refresh: ArtifactRefresh) -> Self {
+        self.0.artifact_refresh = artifact_refresh;
+        self
+    }
+
+    /// Check that the host has enough free memory and CPUs for the VM when it's started by
+    /// [`crate::Machine::start`], failing with [`Error::InsufficientHostResources`] otherwise.
+    ///
+    /// The guest memory and vCPU count are multiplied by `overhead_factor` (e.g `1.1`), to account
+    /// for the VMM. Free CPUs are the CPUs available to this process minus the load average.
+    pub fn admission_check(mut self, overhead_factor: f64) -> Self {
+        self.0.admission_check = Some(overhead_factor);
+        self
+    }
+
+    /// Pin the version of the Firecracker binary (see [`JailerBuilder::exec_file`]).
+    ///
+    /// API payloads are adapted to the version, so VMs of different Firecracker versions can be
+    /// driven by the same process, and [`crate::Machine::start`] fails if the started binary has
+    /// another version.
+    pub fn firecracker_version(mut self, version: FirecrackerVersion) -> Self {
+        self.0.firecracker_version = Some(version);
+        self
+    }
+
     /// Add a drive.
     pub fn add_drive<I, P>(self, drive_id: I, src_path: P) -> DriveBuilder<'c>
     where
@@ -339,18 +1162,28 @@ impl<'c> Builder<'c> {
     }
 
     /// Set the Firecracker microVM process configuration builder.
+    ///
+    /// The builder starts from the current configuration, e.g from a preset.
     pub fn machine_cfg(self) -> MachineBuilder<'c> {
         MachineBuilder::new(self)
     }
 
     /// Create the jailer process configuration builder.
+    ///
+    /// The builder starts from the current jailer configuration, if any.
     pub fn jailer_cfg(self) -> JailerBuilder<'c> {
         JailerBuilder::new(self)
     }
 
+    /// Apply the isolation `profile` when building the configuration, see [`IsolationProfile`].
+    pub fn isolation_profile(mut self, profile: IsolationProfile<'c>) -> Self {
+        self.0.isolation_profile = Some(profile);
+        self
+    }
+
     /// Set the path to a network namespace handle.
     ///
-    /// If specified, the application will use this to join the associated network namespace.
+    /// If specified, the jailer joins the associated network namespace (`--netns`).
     pub fn net_ns<N>(mut self, net_ns: N) -> Self
     where
         N: Into<Cow<'c, str>>,
@@ -359,6 +1192,15 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set or clear the network namespace handle path, see [`Builder::net_ns`].
+    pub fn net_ns_opt<N>(mut self, net_ns: Option<N>) -> Self
+    where
+        N: Into<Cow<'c, str>>,
+    {
+        self.0.net_ns = net_ns.map(Into::into);
+        self
+    }
+
     /// Add a network interface.
     ///
     /// Add a tap device that should be made available to the microVM.
@@ -382,22 +1224,302 @@ impl<'c> Builder<'c> {
         self
     }
 
+    /// Set the permissions of the vsock Unix socket, so that other users of the host can't
+    /// connect to the guest.
+    pub fn vsock_socket_permissions(mut self, permissions: SocketPermissions) -> Self {
+        self.0.vsock_socket_permissions = Some(permissions);
+        self
+    }
+
+    /// Set the balloon device configuration.
+    ///
+    /// Set `stats_polling_interval_s` to a non-zero value to enable
+    /// [`crate::Machine::balloon_stats`] and [`crate::Machine::autoscale_memory`].
+    pub fn balloon_cfg(
+        mut self,
+        amount_mib: u32,
+        deflate_on_oom: bool,
+        stats_polling_interval_s: u32,
+    ) -> Self {
+        self.0.balloon_cfg = Some(Balloon {
+            amount_mib,
+            deflate_on_oom,
+            stats_polling_interval_s,
+        });
+        self
+    }
+
+    /// Set up host NAT and port forwards for the guest network.
+    pub fn nat(mut self, nat: Nat<'c>) -> Self {
+        self.0.nat = Some(nat);
+        self
+    }
+
+    /// Set or clear host NAT, see [`Builder::nat`].
+    pub fn nat_opt(mut self, nat: Option<Nat<'c>>) -> Self {
+        self.0.nat = nat;
+        self
+    }
+
+    /// Pin the vCPU threads to the given host CPUs.
+    ///
+    /// vCPU `n` is pinned to `cpus[n % cpus.len()]` with `taskset` once the VM has booted. To
+    /// confine the whole VMM process instead, use a cpuset cgroup.
+    pub fn vcpu_affinity<I>(mut self, cpus: I) -> Self
+    where
+        I: IntoIterator<Item = u32>,
+    {
+        self.0.vcpu_affinity = cpus.into_iter().collect();
+        self
+    }
+
+    /// Set the seccomp filter.
+    ///
+    /// Defaults to [`Seccomp::Default`].
+    pub fn seccomp(mut self, seccomp: Seccomp<'c>) -> Self {
+        self.0.seccomp = seccomp;
+        self
+    }
+
+    /// Set the initial contents of the MMDS data store.
+    ///
+    /// The value is written into the chroot by [`crate::Machine::create`] and passed to
+    /// Firecracker with `--metadata`, so it's available from the very first guest instruction.
+    /// MMDS still needs to be enabled on a network interface for the guest to access it.
+    pub fn mmds_metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.0.mmds_metadata = Some(metadata);
+        self
+    }
+
+    /// Set or clear the initial contents of the MMDS data store, see [`Builder::mmds_metadata`].
+    pub fn mmds_metadata_opt(mut self, mmds_metadata: Option<serde_json::Value>) -> Self {
+        self.0.mmds_metadata = mmds_metadata;
+        self
+    }
+
+    /// Set the MMDS configuration.
+    ///
+    /// MMDS is made reachable from the network interfaces with the given IDs, at `ipv4_address`
+    /// or [`MMDS_DEFAULT_IPV4_ADDRESS`] if `None`. [`MmdsVersion::V2`] requires Firecracker 1.0
+    /// or later, which is checked when the machine is started.
+    pub fn mmds_cfg<I, N, A>(
+        mut self,
+        version: MmdsVersion,
+        network_interfaces: I,
+        ipv4_address: Option<A>,
+    ) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<Cow<'c, str>>,
+        A: Into<Cow<'c, str>>,
+    {
+        self.0.mmds_cfg = Some(Mmds {
+            version,
+            network_interfaces: network_interfaces.into_iter().map(Into::into).collect(),
+            ipv4_address: ipv4_address.map(Into::into),
+        });
+        self
+    }
+
+    /// Add a label to the VM.
+    ///
+    /// Labels are arbitrary key/value pairs, e.g a tenant or image version. They are persisted
+    /// in the VM record, so they can be used to filter VMs in [`crate::list`].
+    pub fn label<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.0.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Record all Firecracker API calls to [`Config::api_record_path`].
+    ///
+    /// Disabled by default.
+    pub fn record_api_calls(mut self, record_api_calls: bool) -> Self {
+        self.0.record_api_calls = record_api_calls;
+        self
+    }
+
+    /// Record the privileged host operations performed for the VM to
+    /// [`Config::audit_log_path`], a file named after the VM ID in `dir`.
+    ///
+    /// `dir` must be outside of the VM directory, as the log is kept when the VM is deleted.
+    /// Disabled by default. See [`crate::audit`] for details.
+    pub fn audit_log_dir<P>(mut self, dir: P) -> Self
+    where
+        P: Into<Cow<'c, Path>>,
+    {
+        self.0.audit_log_dir = Some(dir.into());
+        self
+    }
+
+    /// Set the architecture the machine options are validated for.
+    ///
+    /// Defaults to the host architecture.
+    pub fn target_arch(mut self, target_arch: Arch) -> Self {
+        self.0.target_arch = Some(target_arch);
+        self
+    }
+
+    /// Set or clear the architecture the machine options are validated for, see
+    /// [`Builder::target_arch`].
+    pub fn target_arch_opt(mut self, target_arch: Option<Arch>) -> Self {
+        self.0.target_arch = target_arch;
+        self
+    }
+
+    /// Set the vsock port of the guest agent, see [`crate::agent`].
+    ///
+    /// Defaults to [`crate::agent::DEFAULT_AGENT_PORT`].
+    pub fn agent_port(mut self, agent_port: u32) -> Self {
+        self.0.agent_port = agent_port;
+        self
+    }
+
+    /// Set the maximum size of the stdout and of the stderr of commands run through the guest
+    /// agent, see [`crate::Machine::exec`]. Larger outputs fail the command.
+    ///
+    /// Defaults to [`crate::agent::DEFAULT_AGENT_MAX_OUTPUT`].
+    pub fn agent_max_output(mut self, max_output: usize) -> Self {
+        self.0.agent_max_output = max_output;
+        self
+    }
+
+    /// Set the process spawner used to launch the jailer (and tmux, if used).
+    ///
+    /// Defaults to [`LocalSpawner`].
+    pub fn process_spawner<S>(mut self, spawner: S) -> Self
+    where
+        S: ProcessSpawner + 'static,
+    {
+        self.0.spawner = Arc::new(spawner);
+        self
+    }
+
+    /// Drive the Firecracker API over `transport`, instead of the local API socket.
+    ///
+    /// See [`crate::transport`].
+    pub fn api_transport<T>(mut self, transport: T) -> Self
+    where
+        T: ApiTransport + 'static,
+    {
+        self.0.api_transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// Set the filesystem used to prepare and clean up the chroot.
+    ///
+    /// Defaults to [`LocalFs`].
+    pub fn chroot_fs<F>(mut self, fs: F) -> Self
+    where
+        F: ChrootFs + 'static,
+    {
+        self.0.fs = Arc::new(fs);
+        self
+    }
+
+    /// Continue with `&mut self` methods, see [`ConfigDraft`].
+    pub fn into_draft(self) -> ConfigDraft<'c> {
+        self.into()
+    }
+
     /// Build the configuration.
-    pub fn build(self) -> Config<'c> {
-        self.0
+    ///
+    /// Fails if the configuration is invalid, e.g if the host socket path is too long.
+    pub fn build(mut self) -> Result<Config<'c>, Error> {
+        if let Some(profile) = self.0.isolation_profile.clone() {
+            profile.apply(&mut self.0)?;
+        }
+        self.0.validate()?;
+        // Firecracker attaches the root device first, then the others in order.
+        let (root, others): (Vec<_>, Vec<_>) = self
+            .0
+            .drives
+            .iter_mut()
+            .partition(|drive| drive.is_root_device());
+        for (index, drive) in root.into_iter().chain(others).enumerate() {
+            drive.guest_device_index = index;
+        }
+        if let Some(path) = self.0.audit_log_path() {
+            let log = AuditLog::new(self.0.vm_id.clone(), path);
+            self.0.fs = Arc::new(AuditedFs {
+                inner: self.0.fs.clone(),
+                log: log.clone(),
+            });
+            self.0.spawner = Arc::new(AuditedSpawner {
+                inner: self.0.spawner.clone(),
+                log: log.clone(),
+            });
+            self.0.audit_log = Some(log);
+        }
+
+        Ok(self.0)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use Uuid;
+    use uuid::Uuid;
+
+    #[test]
+    fn presets() {
+        let config = Config::standard(
+            None,
+            Path::new("/tmp/kernel"),
+            Path::new("/tmp/rootfs.ext4"),
+        )
+        .jailer_cfg()
+        .chroot_base_dir(Path::new("/chroot"))
+        .exec_file(Path::new("/usr/bin/firecracker"))
+        .build()
+        .machine_cfg()
+        .mem_size_mib(2048)
+        .build()
+        .build()
+        .unwrap();
+
+        assert_eq!(config.machine_cfg().vcpu_count(), 2);
+        assert_eq!(config.machine_cfg().mem_size_mib(), 2048);
+        assert_eq!(config.kernel_args(), Some(PRESET_KERNEL_ARGS));
+        assert!(config.drives()[0].is_root_device());
+        assert_eq!(config.log_level(), Some(LogLevel::Info));
+    }
+
+    #[test]
+    fn arch_validation() {
+        let builder = |arch, smt, template: &'static str| {
+            Config::micro(
+                None,
+                Path::new("/tmp/kernel"),
+                Path::new("/tmp/rootfs.ext4"),
+            )
+            .target_arch(arch)
+            .machine_cfg()
+            .smt(smt)
+            .cpu_template(template.into())
+            .build()
+            .build()
+        };
+
+        assert!(builder(Arch::X86_64, true, "T2").is_ok());
+        assert!(matches!(
+            builder(Arch::Aarch64, true, "None"),
+            Err(Error::SmtUnsupported(Arch::Aarch64))
+        ));
+        assert!(matches!(
+            builder(Arch::Aarch64, false, "T2"),
+            Err(Error::UnsupportedCpuTemplate { .. })
+        ));
+    }
 
     #[test]
     fn config_host_values() {
         let id = Uuid::new_v4();
 
-        let config = Config::builder(Some(id), Path::new("/tmp/kernel.path"))
+        let config = Config::builder(Some(id.into()), Path::new("/tmp/kernel.path"))
             .jailer_cfg()
             .chroot_base_dir(Path::new("/chroot"))
             .exec_file(Path::new("/usr/bin/firecracker"))
@@ -409,7 +1531,8 @@ mod tests {
             .build()
             .socket_path(Path::new("/firecracker.socket"))
             .vsock_cfg(3, Path::new("/vsock.sock"))
-            .build();
+            .build()
+            .unwrap();
 
         assert_eq!(
             config.src_initrd_path.as_ref().unwrap().as_os_str(),
@@ -456,4 +1579,288 @@ mod tests {
         assert_eq!(boot_source.kernel_image_path.as_os_str(), "/kernel");
         assert_eq!(boot_source.initrd_path.unwrap().as_os_str(), "/initrd.img");
     }
+
+    #[test]
+    fn artifact_names() {
+        let config = Config::builder(None, Path::new("/tmp/vmlinux"))
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .build()
+            .kernel_image_name("vmlinux")
+            .add_drive("a", Path::new("/images/a/disk.img"))
+            .is_root_device(true)
+            .build()
+            .add_drive("b", Path::new("/images/b/disk.img"))
+            .build()
+            .add_drive("c", Path::new("/images/data.img"))
+            .dest_name("scratch.img")
+            .build()
+            .build()
+            .unwrap();
+
+        assert!(config.kernel_image_path().ends_with("root/vmlinux"));
+        let names: Vec<_> = config
+            .drives()
+            .iter()
+            .map(|drive| config.drive_name(drive).unwrap())
+            .collect();
+        assert_eq!(names, ["a-disk.img", "b-disk.img", "scratch.img"]);
+
+        let config = Config::builder(None, Path::new("/tmp/vmlinux"))
+            .jailer_cfg()
+            .build()
+            .add_drive("root", Path::new("/images/kernel"))
+            .is_root_device(true)
+            .build()
+            .build();
+        assert!(matches!(
+            config,
+            Err(Error::DuplicateArtifactName(name)) if name == "kernel"
+        ));
+    }
+
+    #[test]
+    fn drive_validation() {
+        let builder = || {
+            Config::builder(None, Path::new("/tmp/vmlinux"))
+                .add_drive("data", Path::new("/images/data.ext4"))
+                .build()
+        };
+
+        assert!(matches!(builder().build(), Err(Error::NoRootDrive)));
+        builder()
+            .initrd_path(Path::new("/tmp/initrd.img"))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            builder()
+                .add_drive("root", Path::new("/images/root.ext4"))
+                .is_root_device(true)
+                .build()
+                .add_drive("other", Path::new("/images/other.ext4"))
+                .is_root_device(true)
+                .build()
+                .build(),
+            Err(Error::MultipleRootDrives)
+        ));
+        assert!(matches!(
+            Config::builder(None, Path::new("/tmp/vmlinux"))
+                .add_drive("root", Path::new("/images/root.ext4"))
+                .is_root_device(true)
+                .build()
+                .add_drive("data", Path::new("/images/data.ext4"))
+                .part_uuid("uuid")
+                .build()
+                .build(),
+            Err(Error::PartUuidOnNonRootDrive(id)) if id == "data"
+        ));
+
+        let mut builder = builder()
+            .add_drive("root", Path::new("/images/root.ext4"))
+            .is_root_device(true)
+            .build();
+        for i in 0..26 {
+            builder = builder
+                .add_drive(format!("data{i}"), Path::new("/images/data.ext4"))
+                .build();
+        }
+        let config = builder.build().unwrap();
+        let names: Vec<_> = config
+            .drives()
+            .iter()
+            .map(|drive| drive.guest_device_name())
+            .collect();
+        assert_eq!(&names[..3], ["vdb", "vda", "vdc"]);
+        assert_eq!(names[25..], ["vdz", "vdaa", "vdab"]);
+        assert_eq!(
+            config.drive_guest_device("data0").as_deref(),
+            Some("/dev/vdc")
+        );
+        assert_eq!(config.drive_guest_device("missing"), None);
+    }
+
+    #[test]
+    fn isolation_profiles() {
+        let profile = IsolationProfile::new("tenant-a")
+            .uid_range(10_000..20_000)
+            .cgroup_version(2)
+            .cgroup("cpu.max=50000")
+            .seccomp(Seccomp::Disabled)
+            .netns(NetnsPolicy::PerVm(Path::new("/var/run/netns").into()));
+        let builder = |uid: u32| {
+            Config::builder(Some(VmId::new("vm").unwrap()), Path::new("/tmp/vmlinux"))
+                .jailer_cfg()
+                .uid(uid)
+                .gid(uid)
+                .build()
+                .initrd_path(Path::new("/tmp/initrd"))
+                .isolation_profile(profile.clone())
+        };
+
+        let config = builder(10_000).build().unwrap();
+        assert_eq!(
+            config.jailer().extra_jailer_args(),
+            ["--cgroup-version", "2", "--cgroup", "cpu.max=50000"]
+        );
+        assert!(matches!(config.seccomp(), Seccomp::Disabled));
+        assert_eq!(config.net_ns(), Some("/var/run/netns/vm"));
+        assert_eq!(config.isolation_profile().unwrap().name(), "tenant-a");
+        assert!(matches!(
+            builder(1000).build(),
+            Err(Error::IsolationProfileViolation { .. })
+        ));
+        assert!(matches!(
+            builder(10_000)
+                .jailer_cfg()
+                .extra_jailer_args(["--cgroup", "cpu.weight=1"])
+                .build()
+                .build(),
+            Err(Error::IsolationProfileViolation { .. })
+        ));
+    }
+
+    #[test]
+    fn jail_pid_paths() {
+        let id = VmId::new("vm").unwrap();
+        let builder = || {
+            Config::builder(Some(id.clone()), Path::new("/tmp/vmlinux"))
+                .jailer_cfg()
+                .chroot_base_dir(Path::new("/chroot"))
+        };
+
+        let config = builder()
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .build()
+            .unwrap();
+        let jailer = config.jailer();
+        assert_eq!(
+            jailer.pid_file_path().unwrap(),
+            Path::new("/chroot/firecracker/vm/root/firecracker.pid")
+        );
+        assert_eq!(jailer.cgroup_procs_path(&id), None);
+
+        let config = builder()
+            .extra_jailer_args(["--cgroup", "cpuset.cpus=0"])
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.jailer().cgroup_procs_path(&id).unwrap(),
+            Path::new("/sys/fs/cgroup/cpuset/firecracker/vm/cgroup.procs")
+        );
+
+        let config = builder()
+            .extra_jailer_args([
+                "--cgroup-version=2",
+                "--cgroup=cpu.max=10000",
+                "--parent-cgroup",
+                "vms",
+            ])
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.jailer().cgroup_procs_path(&id).unwrap(),
+            Path::new("/sys/fs/cgroup/vms/vm/cgroup.procs")
+        );
+    }
+
+    #[test]
+    fn socket_path_validation() {
+        let builder = |chroot_base_dir: &'static str, socket_path: &'static str| {
+            Config::builder(None, Path::new("/tmp/vmlinux"))
+                .jailer_cfg()
+                .chroot_base_dir(Path::new(chroot_base_dir))
+                .build()
+                .socket_path(Path::new(socket_path))
+                .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+                .is_root_device(true)
+                .build()
+        };
+
+        builder("/srv/jailer", "/run/firecracker.socket")
+            .build()
+            .unwrap();
+
+        let deep_dir = "/var/lib/some/very/deeply/nested/directory/for/jails";
+        match builder(deep_dir, "/run/firecracker.socket").build() {
+            Err(Error::SocketPathTooLong { path }) => {
+                assert!(path.starts_with(deep_dir));
+                assert!(path.as_os_str().len() > UNIX_SOCKET_PATH_MAX_LEN);
+            }
+            res => panic!("unexpected result: {res:?}"),
+        }
+
+        assert!(matches!(
+            builder("/srv/jailer", "/../../firecracker.socket").build(),
+            Err(Error::InvalidSocketPath)
+        ));
+        assert!(matches!(
+            builder("/srv/jailer", "/run/firecracker.socket")
+                .vsock_cfg(3, Path::new(&format!("/{}.sock", "v".repeat(40))))
+                .build(),
+            Err(Error::SocketPathTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn draft() {
+        let mut draft = Config::builder(None, Path::new("/tmp/kernel")).into_draft();
+        draft
+            .jailer(|jailer| jailer.chroot_base_dir(Path::new("/chroot")).uid(123))
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"), |drive| {
+                drive.is_root_device(true)
+            });
+        for i in 0..2 {
+            draft.add_drive(format!("data{i}"), Path::new("/tmp/data.ext4"), |drive| {
+                drive
+            });
+        }
+        draft.jailer(|jailer| jailer.gid(456));
+        let config = draft.build().unwrap();
+
+        // Updating the jailer configuration keeps the previous settings.
+        let jailer = config.jailer();
+        assert_eq!((jailer.uid(), jailer.gid()), (123, 456));
+        assert!(jailer.workspace_dir().starts_with("/chroot"));
+        let drive_ids: Vec<_> = config.drives.iter().map(|d| d.drive_id()).collect();
+        assert_eq!(drive_ids, ["root", "data0", "data1"]);
+    }
+
+    #[test]
+    fn workspace_layouts() {
+        let id = Uuid::new_v4();
+        let config = |layout| {
+            Config::builder(Some(id.into()), Path::new("/tmp/kernel"))
+                .jailer_cfg()
+                .chroot_base_dir(Path::new("/chroot"))
+                .exec_file(Path::new("/usr/bin/firecracker"))
+                .workspace_layout(layout)
+                .build()
+                .initrd_path(Path::new("/tmp/initrd"))
+                .build()
+                .unwrap()
+        };
+        let jailer_vm_dir = PathBuf::from(format!("/chroot/firecracker/{id}"));
+
+        let default = config(WorkspaceLayout::Jailer);
+        assert_eq!(default.vm_dir(), jailer_vm_dir);
+        assert_eq!(default.jailer().workspace_dir(), jailer_vm_dir.join("root"));
+        assert_eq!(default.jailer().chroot_dir(), jailer_vm_dir.join("root"));
+
+        let flat = config(WorkspaceLayout::Flat(Path::new("/var/lib/vms").into()));
+        let vm_dir = PathBuf::from(format!("/var/lib/vms/{id}"));
+        assert_eq!(flat.vm_dir(), vm_dir);
+        assert_eq!(flat.jailer().workspace_dir(), vm_dir.join("root"));
+        assert_eq!(flat.jailer().chroot_dir(), jailer_vm_dir.join("root"));
+        assert_eq!(flat.record_path().parent(), Some(vm_dir.as_path()));
+
+        let external = config(WorkspaceLayout::External(Path::new("/mnt/vm").into()));
+        assert_eq!(external.vm_dir(), jailer_vm_dir);
+        assert_eq!(external.jailer().workspace_dir(), Path::new("/mnt/vm"));
+        assert!(external.host_socket_path().starts_with("/mnt/vm"));
+    }
 }
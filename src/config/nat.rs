@@ -0,0 +1,119 @@
+use std::{borrow::Cow, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+/// Host-side NAT for the guest network.
+///
+/// Guest traffic from `guest_subnet` is masqueraded out of the host interface, and host ports
+/// can be forwarded to guests. The rules are set up by [`crate::Machine::create`] and removed by
+/// [`crate::Machine::delete`]. IP forwarding (`net.ipv4.ip_forward`) must be enabled on the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Nat<'n> {
+    pub(crate) firewall: Firewall,
+    pub(crate) guest_subnet: Cow<'n, str>,
+    pub(crate) host_interface: Cow<'n, str>,
+    pub(crate) port_forwards: Vec<PortForward>,
+}
+
+impl<'n> Nat<'n> {
+    /// NAT guest traffic from `guest_subnet` (in CIDR notation) out of `host_interface`, with
+    /// `firewall` rules.
+    pub fn new<S, I>(firewall: Firewall, guest_subnet: S, host_interface: I) -> Self
+    where
+        S: Into<Cow<'n, str>>,
+        I: Into<Cow<'n, str>>,
+    {
+        Self {
+            firewall,
+            guest_subnet: guest_subnet.into(),
+            host_interface: host_interface.into(),
+            port_forwards: Vec::new(),
+        }
+    }
+
+    /// Forward a host port to a guest.
+    pub fn port_forward(mut self, port_forward: PortForward) -> Self {
+        self.port_forwards.push(port_forward);
+        self
+    }
+
+    /// The firewall the rules are added to.
+    pub fn firewall(&self) -> Firewall {
+        self.firewall
+    }
+
+    /// The guest subnet, in CIDR notation.
+    pub fn guest_subnet(&self) -> &str {
+        &self.guest_subnet
+    }
+
+    /// The host interface guest traffic goes out of.
+    pub fn host_interface(&self) -> &str {
+        &self.host_interface
+    }
+
+    /// The port forwards.
+    pub fn port_forwards(&self) -> &[PortForward] {
+        &self.port_forwards
+    }
+}
+
+/// The host firewall NAT rules are added to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Firewall {
+    /// Rules are added to the builtin `iptables` chains, tagged with the VM ID.
+    Iptables,
+    /// Rules are added to a dedicated `nft` table per VM.
+    Nftables,
+}
+
+/// A transport protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Protocol {
+    /// TCP.
+    Tcp,
+    /// UDP.
+    Udp,
+}
+
+impl Protocol {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+}
+
+/// A forward of a host port to a guest port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortForward {
+    /// The protocol.
+    pub protocol: Protocol,
+    /// The host port.
+    pub host_port: u16,
+    /// The IP address of the guest.
+    pub guest_ip: Ipv4Addr,
+    /// The guest port.
+    pub guest_port: u16,
+}
+
+impl PortForward {
+    /// Forward TCP `host_port` to `guest_ip:guest_port`.
+    pub fn tcp(host_port: u16, guest_ip: Ipv4Addr, guest_port: u16) -> Self {
+        Self {
+            protocol: Protocol::Tcp,
+            host_port,
+            guest_ip,
+            guest_port,
+        }
+    }
+
+    /// Forward UDP `host_port` to `guest_ip:guest_port`.
+    pub fn udp(host_port: u16, guest_ip: Ipv4Addr, guest_port: u16) -> Self {
+        Self {
+            protocol: Protocol::Udp,
+            ..Self::tcp(host_port, guest_ip, guest_port)
+        }
+    }
+}
@@ -1,6 +1,13 @@
 use std::borrow::Cow;
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use super::VmId;
+
+/// Maximum length of Linux interface names.
+const IF_NAME_MAX_LEN: usize = 15;
 
 /// Network configuration.
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -11,6 +18,8 @@ pub struct Interface<'i> {
     vm_if_name: Cow<'i, str>,
     #[serde(rename = "guest_mac", skip_serializing_if = "Option::is_none")]
     vm_mac_address: Option<Cow<'i, str>>,
+    #[serde(skip)]
+    tap_mode: TapMode<'i>,
 }
 
 impl<'i> Interface<'i> {
@@ -25,9 +34,25 @@ impl<'i> Interface<'i> {
             host_if_name: host_if_name.into(),
             vm_if_name: vm_if_name.into(),
             vm_mac_address: vm_mac_address.map(Into::into),
+            tap_mode: TapMode::default(),
         }
     }
 
+    /// Create the host TAP device and attach it to `bridge`.
+    ///
+    /// If `create_bridge` is set, the bridge is created if it doesn't exist yet. See
+    /// [`TapMode::Bridge`].
+    pub fn attach_to_bridge<B>(mut self, bridge: B, create_bridge: bool) -> Self
+    where
+        B: Into<Cow<'i, str>>,
+    {
+        self.tap_mode = TapMode::Bridge {
+            bridge: bridge.into(),
+            create_bridge,
+        };
+        self
+    }
+
     /// The name of the host interface.
     pub fn host_if_name(&self) -> &str {
         &self.host_if_name
@@ -42,6 +67,69 @@ impl<'i> Interface<'i> {
     pub fn vm_mac_address(&self) -> Option<&str> {
         self.vm_mac_address.as_deref()
     }
+
+    /// How the host TAP device is set up.
+    pub fn tap_mode(&self) -> &TapMode<'i> {
+        &self.tap_mode
+    }
+
+    /// Give the interface a new identity for the VM `vm_id`, e.g for a clone of a VM (see
+    /// [`crate::Machine::fork`]): the host TAP device is renamed to [`tap_name`] and the MAC
+    /// address replaced by a [`random_mac_address`].
+    ///
+    /// `index` tells the interfaces of the same VM apart.
+    pub fn reidentify(mut self, vm_id: &VmId, index: usize) -> Self {
+        self.host_if_name = tap_name(vm_id, index).into();
+        self.vm_mac_address = Some(random_mac_address().into());
+        self
+    }
+}
+
+/// A random, locally administered unicast MAC address.
+pub fn random_mac_address() -> String {
+    let bytes = Uuid::new_v4().into_bytes();
+    // Set the locally administered bit and clear the multicast bit.
+    let first = (bytes[0] | 0x02) & !0x01;
+
+    std::iter::once(first)
+        .chain(bytes[1..6].iter().copied())
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A host TAP device name for the interface `index` of the VM `vm_id`.
+///
+/// The name is derived from a hash of the VM ID, as VM IDs are longer than interface names can
+/// be.
+pub fn tap_name(vm_id: &VmId, index: usize) -> String {
+    let suffix = format!("-{index}");
+    let hash: String = Sha256::digest(vm_id.as_str().as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let hash_len = IF_NAME_MAX_LEN.saturating_sub("fc".len() + suffix.len());
+
+    format!("fc{}{suffix}", &hash[..hash_len])
+}
+
+/// How the host TAP device of an [`Interface`] is set up.
+#[derive(Debug, Clone, Default)]
+pub enum TapMode<'t> {
+    /// The TAP device already exists, e.g. for routed or NAT (see [`crate::config::Nat`]) setups.
+    #[default]
+    Existing,
+    /// The TAP device is created by [`crate::Machine::create`], owned by the jailer user, and
+    /// attached to a Linux bridge. It's removed by [`crate::Machine::delete`].
+    ///
+    /// The TAP device and bridge are created in the host network namespace. Created bridges
+    /// are never removed, as other VMs might be attached to them.
+    Bridge {
+        /// The name of the bridge.
+        bridge: Cow<'t, str>,
+        /// If the bridge is created when it doesn't exist.
+        create_bridge: bool,
+    },
 }
 
 #[cfg(test)]
@@ -54,4 +142,19 @@ mod tests {
         // Different types are fine, as long as they've the same lifetime.
         let _ = super::Interface::new("host_if_name".to_string(), "vm_if_name", None::<String>);
     }
+
+    #[test]
+    fn reidentify() {
+        let vm_id = super::VmId::new("clone-1").unwrap();
+        let interface =
+            super::Interface::new("tap0", "eth0", Some("AA:FC:00:00:00:01")).reidentify(&vm_id, 0);
+        assert_eq!(interface.host_if_name(), super::tap_name(&vm_id, 0));
+        assert_eq!(interface.host_if_name().len(), super::IF_NAME_MAX_LEN);
+        assert_ne!(super::tap_name(&vm_id, 0), super::tap_name(&vm_id, 1));
+
+        let mac = interface.vm_mac_address().unwrap();
+        assert_ne!(mac, "AA:FC:00:00:00:01");
+        let first = u8::from_str_radix(&mac[..2], 16).unwrap();
+        assert_eq!(first & 0x03, 0x02);
+    }
 }
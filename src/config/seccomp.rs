@@ -0,0 +1,24 @@
+use std::{borrow::Cow, path::Path};
+
+use derivative::Derivative;
+
+/// Name of a custom seccomp filter inside the chroot.
+pub const SECCOMP_FILTER_NAME: &str = "seccomp.bpf";
+
+/// The seccomp filter Firecracker installs on its threads.
+#[derive(Derivative, Clone)]
+#[derivative(Debug, Default)]
+pub enum Seccomp<'s> {
+    /// The filter built into Firecracker.
+    #[derivative(Default)]
+    Default,
+    /// No filter at all (`--no-seccomp`).
+    ///
+    /// This is not meant for production use.
+    Disabled,
+    /// A custom filter, compiled to BPF with `seccompiler-bin` (`--seccomp-filter`).
+    ///
+    /// The file is copied into the chroot as [`SECCOMP_FILTER_NAME`] by
+    /// [`crate::Machine::create`].
+    Custom(Cow<'s, Path>),
+}
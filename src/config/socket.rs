@@ -0,0 +1,34 @@
+/// Permissions of a Unix socket created by Firecracker in the chroot.
+///
+/// Firecracker creates its sockets with its default permissions, which may let other users of
+/// the host connect to them. The permissions are applied right after the socket is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketPermissions {
+    mode: u32,
+    owner: Option<(u32, u32)>,
+}
+
+impl SocketPermissions {
+    /// Set the mode of the socket to `mode`, e.g `0o600`.
+    ///
+    /// The socket is owned by the jailer uid and gid, unless an owner is set.
+    pub fn new(mode: u32) -> Self {
+        Self { mode, owner: None }
+    }
+
+    /// Set the owner of the socket.
+    pub fn owner(mut self, uid: u32, gid: u32) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+
+    /// The mode of the socket.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// The uid and gid of the owner of the socket, if set.
+    pub fn owner_ids(&self) -> Option<(u32, u32)> {
+        self.owner
+    }
+}
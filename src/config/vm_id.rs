@@ -0,0 +1,102 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::Error;
+
+/// Maximum length of a VM ID, as accepted by the jailer.
+pub const VM_ID_MAX_LEN: usize = 64;
+
+/// The ID of a VM.
+///
+/// It's used as the Firecracker instance ID and as the name of the VM's jail directory. The jailer
+/// only accepts IDs of at most [`VM_ID_MAX_LEN`] characters, consisting of alphanumeric characters
+/// and hyphens. A random UUID is used if no ID is given to [`super::Config::builder`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct VmId(String);
+
+impl VmId {
+    /// Create a new `VmId` from a custom string, validating it.
+    pub fn new<S>(id: S) -> Result<Self, Error>
+    where
+        S: Into<String>,
+    {
+        let id = id.into();
+        if id.is_empty()
+            || id.len() > VM_ID_MAX_LEN
+            || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return Err(Error::InvalidVmId(id));
+        }
+
+        Ok(Self(id))
+    }
+
+    /// Generate a random, UUID-based `VmId`.
+    pub fn random() -> Self {
+        Uuid::new_v4().into()
+    }
+
+    /// The ID as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Uuid> for VmId {
+    fn from(uuid: Uuid) -> Self {
+        Self(uuid.to_string())
+    }
+}
+
+impl FromStr for VmId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for VmId {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new(s)
+    }
+}
+
+impl From<VmId> for String {
+    fn from(id: VmId) -> Self {
+        id.0
+    }
+}
+
+impl AsRef<str> for VmId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for VmId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert_eq!(VmId::new("my-vm-01").unwrap().as_str(), "my-vm-01");
+        assert!(VmId::new("a".repeat(VM_ID_MAX_LEN)).is_ok());
+
+        assert!(VmId::new("").is_err());
+        assert!(VmId::new("a".repeat(VM_ID_MAX_LEN + 1)).is_err());
+        assert!(VmId::new("my_vm").is_err());
+        assert!(VmId::new("../escape").is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+/// Host-side layout of a VM's jailer workspace.
+///
+/// All paths are host paths, as seen from outside of the chroot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    /// The VM directory, containing the chroot and the diagnostics directory.
+    pub vm_dir: PathBuf,
+    /// The chroot directory (jailer workspace).
+    pub root: PathBuf,
+    /// The diagnostics directory.
+    pub diagnostics_dir: PathBuf,
+    /// The kernel image.
+    pub kernel_image: PathBuf,
+    /// The initrd, if any.
+    pub initrd: Option<PathBuf>,
+    /// The custom seccomp filter, if any.
+    pub seccomp_filter: Option<PathBuf>,
+    /// The MMDS metadata file, if any.
+    pub mmds_metadata: Option<PathBuf>,
+    /// The drive files, as `(drive_id, path)` pairs in configuration order.
+    pub drives: Vec<(String, PathBuf)>,
+    /// The Firecracker API socket.
+    pub api_socket: PathBuf,
+    /// The vsock Unix socket, if vsock is configured.
+    pub vsock_uds: Option<PathBuf>,
+    /// The Firecracker log file, if configured.
+    pub log: Option<PathBuf>,
+    /// The Firecracker log named pipe, if configured.
+    pub log_fifo: Option<PathBuf>,
+    /// The Firecracker metrics file, if configured.
+    pub metrics: Option<PathBuf>,
+    /// The Firecracker metrics named pipe, if configured.
+    pub metrics_fifo: Option<PathBuf>,
+}
@@ -0,0 +1,386 @@
+//! Device-mapper drive provisioning, see [`crate::config::DriveProvisioning`].
+
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use rustix::fs::{major, minor};
+use tokio::{fs, process::Command};
+use tracing::{instrument, trace, warn};
+
+use crate::{
+    config::{Config, Drive, DriveProvisioning},
+    machine::{command_output, run_command},
+    DeleteSummary, Error,
+};
+
+const SECTOR_SIZE: u64 = 512;
+/// Chunk size of the dm-snapshot copy-on-write files, in sectors.
+const SNAPSHOT_CHUNK_SECTORS: u64 = 8;
+/// The default size of the LUKS2 header, in bytes.
+const LUKS2_HEADER_SIZE: u64 = 16 << 20;
+
+/// Create the device-mapper device of `drive`, unless it already exists, and expose it in the
+/// chroot at the drive path.
+#[instrument(skip_all, fields(drive_id = drive.drive_id()))]
+pub(crate) async fn setup(config: &Config<'_>, drive: &Drive<'_>) -> Result<(), Error> {
+    let name = device_name(config, drive);
+    let device = device_path(&name);
+    if !fs::try_exists(&device).await? {
+        match drive.provisioning() {
+            DriveProvisioning::Copy => return Ok(()),
+            DriveProvisioning::DmThin {
+                pool,
+                origin_id,
+                device_id,
+            } => create_thin(config, drive, &name, pool, *origin_id, *device_id).await?,
+            DriveProvisioning::DmSnapshot { cow_size } => {
+                create_snapshot(config, drive, &name, *cow_size).await?
+            }
+            DriveProvisioning::Luks { key_file } => {
+                create_luks(config, drive, &name, key_file).await?
+            }
+        }
+    }
+
+    let dest = config.drive_path(drive)?;
+    let chroot_fs = config.fs();
+    if !chroot_fs.exists(&dest).await? {
+        let (major, minor) = device_number(&device).await?;
+        trace!(
+            major,
+            minor,
+            "Creating block device at `{}`",
+            dest.display()
+        );
+        chroot_fs.make_block_device(&dest, major, minor).await?;
+    }
+
+    Ok(())
+}
+
+/// Remove the device-mapper devices created by [`setup`].
+#[instrument(skip_all)]
+pub(crate) async fn teardown(config: &Config<'_>, summary: &mut DeleteSummary) {
+    for drive in config.drives() {
+        match remove(config, drive).await {
+            Ok(true) => summary
+                .drive_devices_removed
+                .push(drive.drive_id().to_owned()),
+            Ok(false) => {}
+            Err(err) => summary.warn(
+                format!(
+                    "Failed to remove the device of drive `{}`",
+                    drive.drive_id()
+                ),
+                &err,
+            ),
+        }
+    }
+}
+
+async fn create_thin(
+    config: &Config<'_>,
+    drive: &Drive<'_>,
+    name: &str,
+    pool: &Path,
+    origin_id: u32,
+    device_id: u32,
+) -> Result<(), Error> {
+    let origin = drive.src_path();
+    let sectors = sectors(origin).await?;
+    trace!(origin_id, device_id, "Creating thin snapshot");
+
+    // The origin must not be written to while it's snapshotted.
+    dmsetup(config, [OsStr::new("suspend"), origin.as_os_str()]).await?;
+    let message = format!("create_snap {device_id} {origin_id}");
+    let result = dm_message(config, pool, &message).await;
+    dmsetup(config, [OsStr::new("resume"), origin.as_os_str()]).await?;
+    result?;
+
+    let table = format!("0 {sectors} thin {} {device_id}", pool.display());
+    if let Err(e) = dmsetup(config, ["create", name, "--table", &table]).await {
+        delete_thin(config, pool, device_id).await;
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+async fn create_snapshot(
+    config: &Config<'_>,
+    drive: &Drive<'_>,
+    name: &str,
+    cow_size: u64,
+) -> Result<(), Error> {
+    let src = drive.src_path();
+    let sectors = sectors(src).await?;
+    let cow_path = config.drive_cow_path(drive);
+    trace!(
+        cow_size,
+        "Creating copy-on-write file at `{}`",
+        cow_path.display()
+    );
+    create_sparse_file(config, &cow_path, cow_size).await?;
+
+    let mut loop_devices = Vec::new();
+    let result = async {
+        let origin = attach_loop(config, src, true).await?;
+        loop_devices.push(origin.clone());
+        let cow = attach_loop(config, &cow_path, false).await?;
+        loop_devices.push(cow.clone());
+
+        let table = format!(
+            "0 {sectors} snapshot {} {} P {SNAPSHOT_CHUNK_SECTORS}",
+            origin.display(),
+            cow.display()
+        );
+        dmsetup(config, ["create", name, "--table", &table]).await
+    }
+    .await;
+    if let Err(e) = result {
+        for loop_device in loop_devices {
+            detach_loop(config, &loop_device).await;
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Create a LUKS2 volume in a file of the VM directory and fill it with the source file.
+async fn create_luks(
+    config: &Config<'_>,
+    drive: &Drive<'_>,
+    name: &str,
+    key_file: &Path,
+) -> Result<(), Error> {
+    let src = drive.src_path();
+    let size = fs::metadata(src).await?.len();
+    let luks_path = config.drive_luks_path(drive);
+    trace!(
+        size,
+        "Creating encrypted volume at `{}`",
+        luks_path.display()
+    );
+    create_sparse_file(config, &luks_path, size + LUKS2_HEADER_SIZE).await?;
+
+    let result = async {
+        run_command(
+            config,
+            Command::new("cryptsetup")
+                .args([
+                    "luksFormat",
+                    "--batch-mode",
+                    "--type",
+                    "luks2",
+                    "--key-file",
+                ])
+                .arg(key_file)
+                .arg(&luks_path),
+        )
+        .await?;
+        run_command(
+            config,
+            Command::new("cryptsetup")
+                .args(["open", "--type", "luks2", "--key-file"])
+                .arg(key_file)
+                .arg(&luks_path)
+                .arg(name),
+        )
+        .await?;
+
+        let device = device_path(name);
+        trace!("Copying `{}` to `{}`", src.display(), device.display());
+        let mut input = OsString::from("if=");
+        input.push(src);
+        let mut output = OsString::from("of=");
+        output.push(&device);
+        run_command(
+            config,
+            Command::new("dd")
+                .arg(input)
+                .arg(output)
+                .args(["bs=1M", "conv=fsync", "status=none"]),
+        )
+        .await
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = run_command(config, Command::new("cryptsetup").args(["close", name])).await;
+        let _ = config.fs().remove_file(&luks_path).await;
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Close the encrypted volume of `drive` and erase its keys, returning `false` if both were
+/// already done.
+async fn remove_luks(config: &Config<'_>, drive: &Drive<'_>, name: &str) -> Result<bool, Error> {
+    let mut removed = false;
+    if fs::try_exists(device_path(name)).await? {
+        trace!("Closing encrypted volume `{name}`");
+        run_command(config, Command::new("cryptsetup").args(["close", name])).await?;
+        removed = true;
+    }
+    let luks_path = config.drive_luks_path(drive);
+    if fs::try_exists(&luks_path).await? {
+        trace!("Erasing the keys of `{}`", luks_path.display());
+        run_command(
+            config,
+            Command::new("cryptsetup")
+                .args(["luksErase", "--batch-mode"])
+                .arg(&luks_path),
+        )
+        .await?;
+        removed = true;
+    }
+
+    Ok(removed)
+}
+
+/// Remove the device of `drive`, returning `false` if there was none.
+async fn remove(config: &Config<'_>, drive: &Drive<'_>) -> Result<bool, Error> {
+    let name = device_name(config, drive);
+    if let DriveProvisioning::Luks { .. } = drive.provisioning() {
+        return remove_luks(config, drive, &name).await;
+    }
+    let device = device_path(&name);
+    if *drive.provisioning() == DriveProvisioning::Copy || !fs::try_exists(&device).await? {
+        return Ok(false);
+    }
+
+    trace!("Removing device `{name}`");
+    // The loop devices of a dm-snapshot.
+    let loop_devices = slaves(&device).await?;
+    dmsetup(config, ["remove", &name]).await?;
+    match drive.provisioning() {
+        DriveProvisioning::DmThin {
+            pool, device_id, ..
+        } => delete_thin(config, pool, *device_id).await,
+        DriveProvisioning::DmSnapshot { .. } => {
+            for loop_device in loop_devices {
+                detach_loop(config, &loop_device).await;
+            }
+        }
+        DriveProvisioning::Copy | DriveProvisioning::Luks { .. } => {}
+    }
+
+    Ok(true)
+}
+
+async fn delete_thin(config: &Config<'_>, pool: &Path, device_id: u32) {
+    let message = format!("delete {device_id}");
+    if let Err(err) = dm_message(config, pool, &message).await {
+        warn!(device_id, error = %err, "Failed to delete thin device");
+    }
+}
+
+/// Create an empty sparse file of `size` bytes at `path`, replacing any existing file.
+async fn create_sparse_file(config: &Config<'_>, path: &Path, size: u64) -> Result<(), Error> {
+    config.fs().write(path, Vec::new()).await?;
+    run_command(
+        config,
+        Command::new("truncate")
+            .arg("-s")
+            .arg(size.to_string())
+            .arg(path),
+    )
+    .await
+}
+
+/// Attach `file` to a free loop device, returning the device path.
+async fn attach_loop(config: &Config<'_>, file: &Path, read_only: bool) -> Result<PathBuf, Error> {
+    let mut cmd = Command::new("losetup");
+    cmd.args(["--find", "--show"]);
+    if read_only {
+        cmd.arg("--read-only");
+    }
+    let output = command_output(config, cmd.arg(file)).await?;
+
+    Ok(PathBuf::from(output.trim()))
+}
+
+async fn detach_loop(config: &Config<'_>, loop_device: &Path) {
+    trace!("Detaching loop device `{}`", loop_device.display());
+    if let Err(err) = run_command(config, Command::new("losetup").arg("-d").arg(loop_device)).await
+    {
+        warn!(loop_device = %loop_device.display(), error = %err, "Failed to detach loop device");
+    }
+}
+
+async fn dmsetup<I, S>(config: &Config<'_>, args: I) -> Result<(), Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    run_command(config, Command::new("dmsetup").args(args)).await
+}
+
+/// Send `message` to the thin `pool`.
+async fn dm_message(config: &Config<'_>, pool: &Path, message: &str) -> Result<(), Error> {
+    dmsetup(
+        config,
+        [
+            OsStr::new("message"),
+            pool.as_os_str(),
+            "0".as_ref(),
+            message.as_ref(),
+        ],
+    )
+    .await
+}
+
+/// The device-mapper name of the device of `drive`.
+fn device_name(config: &Config<'_>, drive: &Drive<'_>) -> String {
+    format!("firec-{}-{}", config.vm_id(), drive.drive_id())
+}
+
+fn device_path(name: &str) -> PathBuf {
+    Path::new("/dev/mapper").join(name)
+}
+
+async fn device_number(device: &Path) -> Result<(u32, u32), Error> {
+    let rdev = fs::metadata(device).await?.rdev();
+
+    Ok((major(rdev), minor(rdev)))
+}
+
+/// The sysfs directory of the block `device`.
+async fn sysfs_dir(device: &Path) -> Result<PathBuf, Error> {
+    let (major, minor) = device_number(device).await?;
+
+    Ok(PathBuf::from(format!("/sys/dev/block/{major}:{minor}")))
+}
+
+/// The size of the block device or file at `path`, in sectors.
+async fn sectors(path: &Path) -> Result<u64, Error> {
+    let metadata = fs::metadata(path).await?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(metadata.len() / SECTOR_SIZE);
+    }
+
+    let size = fs::read_to_string(sysfs_dir(path).await?.join("size")).await?;
+    size.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid size of `{}`: {size}", path.display()),
+        )
+        .into()
+    })
+}
+
+/// The devices underlying the device-mapper `device`.
+async fn slaves(device: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut slaves = Vec::new();
+    let mut entries = fs::read_dir(sysfs_dir(device).await?.join("slaves")).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        slaves.push(Path::new("/dev").join(entry.file_name()));
+    }
+
+    Ok(slaves)
+}
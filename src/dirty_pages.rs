@@ -0,0 +1,140 @@
+//! Guest dirty page rate estimation.
+//!
+//! With dirty page tracking enabled (see [`crate::config::MachineBuilder::track_dirty_pages`]),
+//! Firecracker writes only the pages dirtied since the previous snapshot to the memory file of a
+//! diff snapshot, leaving the rest of the file sparse. Taking diff snapshots at a regular interval
+//! and measuring the space allocated to their memory file tells how fast the guest dirties its
+//! memory, which bounds how quickly it can be migrated or snapshotted incrementally.
+//!
+//! Every sample pauses the VM for the time it takes to write the dirty pages. The snapshot files
+//! are removed once measured.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use tokio::{sync::watch, time::interval};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    client::ApiClient,
+    config::VmId,
+    fs::ChrootFs,
+    snapshot::{self, Snapshot, SnapshotType},
+    task::TaskHandle,
+    Error,
+};
+
+/// Name of the diff snapshots taken to measure the dirty page rate.
+pub const SAMPLE_SNAPSHOT_NAME: &str = "dirty-pages";
+
+/// How much guest memory was dirtied over an interval.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyPageRate {
+    /// Bytes of guest memory dirtied over the interval.
+    pub dirty_bytes: u64,
+    /// The interval.
+    pub interval: Duration,
+}
+
+impl DirtyPageRate {
+    /// The dirty page rate, in bytes per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.dirty_bytes as f64 / self.interval.as_secs_f64()
+    }
+}
+
+/// Periodically estimates the dirty page rate of a VM, see [`crate::Machine::observe_dirty_pages`].
+///
+/// Sampling stops when the observer is dropped.
+#[derive(Debug)]
+pub struct DirtyPageObserver {
+    rate: watch::Receiver<Option<DirtyPageRate>>,
+    _task: TaskHandle,
+}
+
+impl DirtyPageObserver {
+    /// The latest estimate, if any.
+    pub fn latest(&self) -> Option<DirtyPageRate> {
+        *self.rate.borrow()
+    }
+
+    /// Wait for the next estimate.
+    ///
+    /// Returns `None` if sampling stopped, e.g because the VM is gone.
+    pub async fn next(&mut self) -> Option<DirtyPageRate> {
+        self.rate.changed().await.ok()?;
+        *self.rate.borrow_and_update()
+    }
+}
+
+/// Everything needed to take a sample, owned so it can be moved to a task.
+#[derive(Debug, Clone)]
+pub(crate) struct Sampler {
+    pub(crate) vm_id: VmId,
+    pub(crate) client: ApiClient,
+    pub(crate) fs: Arc<dyn ChrootFs>,
+    pub(crate) snapshot: Snapshot,
+    pub(crate) chroot_paths: (PathBuf, PathBuf),
+}
+
+impl Sampler {
+    /// Take a diff snapshot, returning the bytes dirtied since the previous one.
+    pub(crate) async fn sample(&self) -> Result<u64, Error> {
+        // Run in its own task so that dropping the caller can't leave the VM paused.
+        let sampler = self.clone();
+        tokio::spawn(async move { sampler.take_snapshot().await }).await??;
+
+        let dirty_bytes = self.fs.disk_usage(&self.snapshot.memory_path).await?;
+        for path in [&self.snapshot.state_path, &self.snapshot.memory_path] {
+            self.fs.remove_file(path).await?;
+        }
+
+        Ok(dirty_bytes)
+    }
+
+    async fn take_snapshot(&self) -> Result<(), Error> {
+        snapshot::set_vm_state(&self.client, "Paused").await?;
+        let (state_path, mem_file_path) = &self.chroot_paths;
+        let result =
+            snapshot::create(&self.client, SnapshotType::Diff, state_path, mem_file_path).await;
+        snapshot::set_vm_state(&self.client, "Resumed").await?;
+
+        result
+    }
+}
+
+pub(crate) fn observe(sampler: Sampler, every: Duration) -> DirtyPageObserver {
+    let (tx, rate) = watch::channel(None);
+    let task = tokio::spawn(run_observer(sampler, every, tx));
+
+    DirtyPageObserver {
+        rate,
+        _task: TaskHandle::new(task),
+    }
+}
+
+#[instrument(skip_all, fields(vm_id = %sampler.vm_id))]
+async fn run_observer(sampler: Sampler, every: Duration, tx: watch::Sender<Option<DirtyPageRate>>) {
+    let mut ticks = interval(every);
+    // The first snapshot holds everything dirtied since boot or the previous snapshot.
+    ticks.tick().await;
+    if let Err(err) = sampler.sample().await {
+        warn!(error = %err, "Failed to take baseline snapshot");
+        return;
+    }
+    loop {
+        ticks.tick().await;
+        match sampler.sample().await {
+            Ok(dirty_bytes) => {
+                debug!(dirty_bytes, "Dirty pages sampled");
+                let _ = tx.send(Some(DirtyPageRate {
+                    dirty_bytes,
+                    interval: every,
+                }));
+            }
+            Err(err) => {
+                warn!(error = %err, "Failed to sample dirty pages");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Discovery of the VMs created by firec on the host.
+//!
+//! [`crate::Machine::create`] writes a record of the VM next to its jailer workspace (see
+//! [`crate::config::Config::record_path`]), which [`list`] reads back and matches with the
+//! running Firecracker processes.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sysinfo::{PidExt, ProcessExt, ProcessRefreshKind, RefreshKind, System, SystemExt};
+use tokio::task;
+use tracing::{trace, warn};
+
+use crate::{
+    config::{Config, VmId},
+    machine::root_dir,
+    Error, MachineState,
+};
+
+/// Name of the VM record file in the VM directory.
+pub(crate) const RECORD_FILE_NAME: &str = "firec.json";
+
+/// What's persisted about a VM.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VmRecord {
+    pub(crate) vm_id: VmId,
+    pub(crate) mem_size_mib: i64,
+    pub(crate) vcpu_count: usize,
+    #[serde(default)]
+    pub(crate) labels: BTreeMap<String, String>,
+}
+
+impl VmRecord {
+    pub(crate) fn new(config: &Config<'_>) -> Self {
+        Self {
+            vm_id: config.vm_id().clone(),
+            mem_size_mib: config.machine_cfg().mem_size_mib(),
+            vcpu_count: config.machine_cfg().vcpu_count(),
+            labels: config.labels().clone(),
+        }
+    }
+
+    pub(crate) async fn write(&self, config: &Config<'_>) -> Result<(), Error> {
+        let json = serde_json::to_vec(self)?;
+        config.fs().write(&config.record_path(), json).await?;
+
+        Ok(())
+    }
+
+    async fn read(path: &Path) -> Result<Self, Error> {
+        Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+    }
+}
+
+/// Filter for [`list`].
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub(crate) chroot_base_dir: PathBuf,
+    pub(crate) flat_dirs: Vec<PathBuf>,
+    pub(crate) state: Option<MachineState>,
+    pub(crate) labels: Vec<(String, String)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            chroot_base_dir: PathBuf::from("/srv/jailer"),
+            flat_dirs: Vec::new(),
+            state: None,
+            labels: Vec::new(),
+        }
+    }
+}
+
+impl Filter {
+    /// Set the base folder where chroot jails are built.
+    ///
+    /// The default is `/srv/jailer`.
+    pub fn chroot_base_dir<P>(mut self, chroot_base_dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.chroot_base_dir = chroot_base_dir.into();
+        self
+    }
+
+    /// Also list the VMs with their VM directory in `dir`, i.e with the
+    /// [`crate::config::WorkspaceLayout::Flat`] layout in `dir`.
+    ///
+    /// Can be called multiple times.
+    pub fn flat_dir<P>(mut self, dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.flat_dirs.push(dir.into());
+        self
+    }
+
+    /// Only list VMs in the given state.
+    pub fn state(mut self, state: MachineState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Only list VMs with the given label.
+    ///
+    /// Can be called multiple times, in which case VMs must have all of the labels.
+    pub fn label<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    fn matches(&self, vm: &VmSummary) -> bool {
+        self.state.is_none_or(|state| vm.state == state)
+            && self
+                .labels
+                .iter()
+                .all(|(key, value)| vm.labels.get(key) == Some(value))
+    }
+}
+
+/// Summary of a VM found by [`list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmSummary {
+    /// The ID of the VM.
+    pub vm_id: VmId,
+    /// The VM directory.
+    pub vm_dir: PathBuf,
+    /// The state of the VM.
+    pub state: MachineState,
+    /// The PID of the Firecracker process, if running.
+    pub pid: Option<u32>,
+    /// How long the Firecracker process has been running.
+    pub uptime: Option<Duration>,
+    /// Memory size of the VM.
+    pub mem_size_mib: i64,
+    /// Number of vCPUs of the VM.
+    pub vcpu_count: usize,
+    /// The labels of the VM.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// List the VMs created by firec under the chroot base directory of `filter`, and in its flat
+/// layout directories (see [`Filter::flat_dir`]).
+///
+/// VMs are sorted by ID. VM directories without a readable record are skipped. VMs are only
+/// running if their Firecracker process is jailed under the chroot base directory of `filter`.
+pub async fn list(filter: &Filter) -> Result<Vec<VmSummary>, Error> {
+    let mut vm_dirs = Vec::new();
+    for exec_dir in read_dirs(&filter.chroot_base_dir).await? {
+        vm_dirs.extend(read_dirs(&exec_dir).await?);
+    }
+    for dir in &filter.flat_dirs {
+        vm_dirs.extend(read_dirs(dir).await?);
+    }
+    let mut records = Vec::new();
+    for vm_dir in vm_dirs {
+        let path = vm_dir.join(RECORD_FILE_NAME);
+        match VmRecord::read(&path).await {
+            Ok(record) => records.push((vm_dir, record)),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                trace!("Skipping `{}` without a VM record", vm_dir.display());
+            }
+            Err(e) => warn!(error = %e, "Failed to read VM record at `{}`", path.display()),
+        }
+    }
+
+    let chroot_base_dir = filter.chroot_base_dir.clone();
+    let processes = task::spawn_blocking(move || {
+        let chroot_base_dir = canonical(&chroot_base_dir);
+        firecracker_processes()
+            .into_iter()
+            .filter(|process| process.jailed_under(&chroot_base_dir))
+            .collect::<Vec<_>>()
+    })
+    .await?;
+    let mut vms: Vec<_> = records
+        .into_iter()
+        .map(|(vm_dir, record)| {
+            let process = processes
+                .iter()
+                .find(|process| process.vm_id == record.vm_id.as_str());
+            VmSummary {
+                state: if process.is_some() {
+                    MachineState::RUNNING
+                } else {
+                    MachineState::SHUTOFF
+                },
+                pid: process.map(|process| process.pid),
+                uptime: process.map(|process| process.uptime),
+                vm_id: record.vm_id,
+                vm_dir,
+                mem_size_mib: record.mem_size_mib,
+                vcpu_count: record.vcpu_count,
+                labels: record.labels,
+            }
+        })
+        .filter(|vm| filter.matches(vm))
+        .collect();
+    vms.sort_by(|a, b| a.vm_id.cmp(&b.vm_id));
+
+    Ok(vms)
+}
+
+/// The PID of the running Firecracker process of `vm_id` jailed in `chroot_dir`, if any.
+pub(crate) async fn firecracker_pid(vm_id: &VmId, chroot_dir: &Path) -> Result<Option<u32>, Error> {
+    let chroot_dir = chroot_dir.to_owned();
+    let processes = task::spawn_blocking(move || {
+        let chroot_dir = canonical(&chroot_dir);
+        firecracker_processes()
+            .into_iter()
+            .filter(|process| process.jailed_in(&chroot_dir))
+            .collect::<Vec<_>>()
+    })
+    .await?;
+
+    Ok(processes
+        .into_iter()
+        .find(|process| process.vm_id == vm_id.as_str())
+        .map(|process| process.pid))
+}
+
+/// `path` without symbolic links, or as is if it can't be resolved.
+fn canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// The subdirectories of `path`, or none if it doesn't exist.
+async fn read_dirs(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut dirs = Vec::new();
+    let mut entries = match tokio::fs::read_dir(path).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+
+    Ok(dirs)
+}
+
+struct FirecrackerProcess {
+    vm_id: String,
+    pid: u32,
+    uptime: Duration,
+    // The root directory of the process, i.e its chroot, if it can be read.
+    root: Option<PathBuf>,
+}
+
+impl FirecrackerProcess {
+    /// If the process is jailed in `chroot_dir`, or its root can't be read.
+    fn jailed_in(&self, chroot_dir: &Path) -> bool {
+        self.root.as_ref().is_none_or(|root| root == chroot_dir)
+    }
+
+    /// If the process is jailed in its `<chroot base dir>/<exec file name>/<VM ID>/root` chroot
+    /// under `chroot_base_dir`, or its root can't be read.
+    ///
+    /// The `--id` argument might be in the command line of unrelated processes, or of VMs jailed
+    /// under another base directory.
+    fn jailed_under(&self, chroot_base_dir: &Path) -> bool {
+        self.root.as_ref().is_none_or(|root| {
+            root.ends_with(Path::new(&self.vm_id).join("root"))
+                && root.ancestors().nth(3) == Some(chroot_base_dir)
+        })
+    }
+}
+
+/// The running processes with an `--id <vm_id>` argument, as passed to Firecracker by the jailer.
+fn firecracker_processes() -> Vec<FirecrackerProcess> {
+    let mut sys = System::new();
+    sys.refresh_specifics(RefreshKind::new().with_processes(ProcessRefreshKind::everything()));
+
+    sys.processes()
+        .values()
+        .filter(|process| process.status() != sysinfo::ProcessStatus::Zombie)
+        .filter_map(|process| {
+            let cmd = process.cmd();
+            let vm_id = cmd
+                .windows(2)
+                .find(|args| args[0] == "--id")
+                .map(|args| args[1].clone())?;
+            // The jailer itself execs Firecracker, so this is the Firecracker process.
+            Some(FirecrackerProcess {
+                vm_id,
+                pid: process.pid().as_u32(),
+                uptime: Duration::from_secs(process.run_time()),
+                root: root_dir(process.pid().as_u32()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::WorkspaceLayout;
+
+    #[test]
+    fn jailed_processes() {
+        let process = |root: Option<&str>| FirecrackerProcess {
+            vm_id: "vm".to_owned(),
+            pid: 1,
+            uptime: Duration::ZERO,
+            root: root.map(PathBuf::from),
+        };
+        let base = Path::new("/srv/jailer");
+
+        assert!(process(Some("/srv/jailer/firecracker/vm/root")).jailed_under(base));
+        assert!(process(None).jailed_under(base));
+        for root in [
+            "/",
+            "/srv/jailer/firecracker/other/root",
+            "/srv/other/firecracker/vm/root",
+            "/srv/jailer/vm/root",
+        ] {
+            assert!(!process(Some(root)).jailed_under(base), "{root}");
+        }
+        assert!(process(Some("/srv/jailer/firecracker/vm/root"))
+            .jailed_in(Path::new("/srv/jailer/firecracker/vm/root")));
+        assert!(!process(Some("/")).jailed_in(Path::new("/srv/jailer/firecracker/vm/root")));
+    }
+
+    #[tokio::test]
+    async fn flat_layout() {
+        // Short, so that the API socket path fits in a Unix socket address.
+        let id = Uuid::new_v4().simple().to_string();
+        let base = std::env::temp_dir().join(format!("firec-{}", &id[..8]));
+        let flat_dir = base.join("vms");
+        let vm_id = VmId::new("vm").unwrap();
+        let config = Config::builder(Some(vm_id), Path::new("/tmp/kernel.bin"))
+            .jailer_cfg()
+            .chroot_base_dir(base.join("jailer"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .workspace_layout(WorkspaceLayout::Flat(flat_dir.clone().into()))
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .build()
+            .unwrap();
+        tokio::fs::create_dir_all(config.vm_dir()).await.unwrap();
+        VmRecord::new(&config).write(&config).await.unwrap();
+
+        let filter = Filter::default().chroot_base_dir(base.join("jailer"));
+        assert!(list(&filter).await.unwrap().is_empty());
+        let vms = list(&filter.flat_dir(&flat_dir)).await.unwrap();
+        assert_eq!(vms.len(), 1);
+        assert_eq!(&vms[0].vm_id, config.vm_id());
+        assert_eq!(vms[0].vm_dir, config.vm_dir());
+        assert_eq!(vms[0].state, MachineState::SHUTOFF);
+
+        tokio::fs::remove_dir_all(&base).await.unwrap();
+    }
+}
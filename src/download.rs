@@ -0,0 +1,312 @@
+//! Download of Firecracker releases.
+//!
+//! [`ReleaseManager`] downloads the release archive of a pinned Firecracker version for the host
+//! architecture from GitHub, verifies it against its published SHA-256 checksum (and against the
+//! checksums of the binaries it contains) and caches the Firecracker and jailer binaries, so a
+//! host or CI environment can be bootstrapped without installing them:
+//!
+//! * [`Release::firecracker`] goes to [`crate::config::JailerBuilder::exec_file`].
+//! * [`Release::jailer`] goes to [`crate::config::JailerBuilder::jailer_binary`].
+//!
+//! [`crate::config::JailerBuilder::release`] does both and pins the version of the configuration.
+//!
+//! Requires the `download` feature.
+
+use std::{
+    collections::HashMap,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
+use tokio::{fs, task};
+use tracing::{debug, info, instrument};
+use uuid::Uuid;
+
+use crate::{version::FirecrackerVersion, Error};
+
+/// The release downloads of the Firecracker repository.
+pub const GITHUB_RELEASES: &str = "https://github.com/firecracker-microvm/firecracker/releases";
+
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// The cached binaries of a Firecracker release.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    /// The version of the release.
+    pub version: FirecrackerVersion,
+    /// The path to the Firecracker binary.
+    pub firecracker: PathBuf,
+    /// The path to the jailer binary.
+    pub jailer: PathBuf,
+}
+
+/// Downloads and caches Firecracker releases, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct ReleaseManager {
+    cache_dir: PathBuf,
+    base_url: String,
+    arch: String,
+    pinned_checksums: HashMap<FirecrackerVersion, String>,
+}
+
+impl ReleaseManager {
+    /// Cache the releases under `cache_dir`, one directory per version and architecture.
+    pub fn new<P>(cache_dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            cache_dir: cache_dir.into(),
+            base_url: GITHUB_RELEASES.to_owned(),
+            arch: std::env::consts::ARCH.to_owned(),
+            pinned_checksums: HashMap::new(),
+        }
+    }
+
+    /// Download from a mirror of [`GITHUB_RELEASES`], with the same layout.
+    pub fn base_url<U>(mut self, base_url: U) -> Self
+    where
+        U: Into<String>,
+    {
+        self.base_url = base_url.into().trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Download the releases for `arch` (`x86_64` or `aarch64`) instead of the host architecture.
+    pub fn arch<A>(mut self, arch: A) -> Self
+    where
+        A: Into<String>,
+    {
+        self.arch = arch.into();
+        self
+    }
+
+    /// Require the release archive of `version` to have the SHA-256 checksum `sha256`, in hex.
+    ///
+    /// Without it, the archive is checked against the checksum published next to it, which only
+    /// guards against corrupted downloads.
+    pub fn pin_checksum<S>(mut self, version: FirecrackerVersion, sha256: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.pinned_checksums
+            .insert(version, sha256.into().to_ascii_lowercase());
+        self
+    }
+
+    /// The binaries of `version`, downloaded unless already cached.
+    #[instrument(skip_all, fields(version = %version, arch = %self.arch))]
+    pub async fn fetch(&self, version: FirecrackerVersion) -> Result<Release, Error> {
+        let release = self.cached(version);
+        if fs::metadata(&release.firecracker).await.is_ok()
+            && fs::metadata(&release.jailer).await.is_ok()
+        {
+            debug!("Release already cached");
+            return Ok(release);
+        }
+
+        fs::create_dir_all(&self.cache_dir).await?;
+        // Download next to the final directory, so that concurrent fetches never see a partial
+        // release.
+        let tmp_dir = self.cache_dir.join(format!(
+            ".{}-{}",
+            self.release_name(version),
+            Uuid::new_v4()
+        ));
+        fs::create_dir(&tmp_dir).await?;
+        let res = self.download(version, &tmp_dir).await;
+        let res = match res {
+            Ok(()) => {
+                let dir = release.firecracker.parent().expect("cached in a directory");
+                match fs::rename(&tmp_dir, dir).await {
+                    Ok(()) => Ok(release),
+                    // Another fetch won the race.
+                    Err(_) if fs::metadata(&release.firecracker).await.is_ok() => Ok(release),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_dir_all(&tmp_dir).await;
+        if res.is_ok() {
+            info!("Release downloaded");
+        }
+
+        res
+    }
+
+    /// The paths of the cached binaries of `version`, whether downloaded or not.
+    pub fn cached(&self, version: FirecrackerVersion) -> Release {
+        let dir = self.cache_dir.join(self.release_name(version));
+
+        Release {
+            version,
+            firecracker: dir.join("firecracker"),
+            jailer: dir.join("jailer"),
+        }
+    }
+
+    fn release_name(&self, version: FirecrackerVersion) -> String {
+        format!("v{version}-{}", self.arch)
+    }
+
+    async fn download(&self, version: FirecrackerVersion, dir: &Path) -> Result<(), Error> {
+        let archive_name = format!("firecracker-{}.tgz", self.release_name(version));
+        let url = format!("{}/download/v{version}/{archive_name}", self.base_url);
+
+        let published = get(&format!("{url}.sha256.txt")).await?;
+        let published = String::from_utf8_lossy(&published)
+            .split_whitespace()
+            .next()
+            .map(str::to_ascii_lowercase)
+            .ok_or_else(|| Error::EmptyReleaseChecksum(archive_name.clone()))?;
+        let archive = get(&url).await?;
+        let checksum = hex_sha256(&archive);
+        let expected = self.pinned_checksums.get(&version).unwrap_or(&published);
+        if checksum != *expected || checksum != published {
+            return Err(Error::ChecksumMismatch(archive_name.into()));
+        }
+        debug!(len = archive.len(), "Release archive verified");
+
+        let release_name = self.release_name(version);
+        let dir = dir.to_owned();
+        task::spawn_blocking(move || extract(&archive, &release_name, &dir)).await?
+    }
+}
+
+async fn get(url: &str) -> Result<Vec<u8>, Error> {
+    debug!(url, "Downloading");
+    let body = async { reqwest::get(url).await?.error_for_status()?.bytes().await }
+        .await
+        .map_err(|source| Error::Download {
+            url: url.to_owned(),
+            source,
+        })?;
+
+    Ok(body.to_vec())
+}
+
+/// Extract the binaries of the release archive `archive` into `dir`, checking them against the
+/// checksums file of the archive if any.
+fn extract(archive: &[u8], release_name: &str, dir: &Path) -> Result<(), Error> {
+    let binaries = [
+        (format!("firecracker-{release_name}"), "firecracker"),
+        (format!("jailer-{release_name}"), "jailer"),
+    ];
+    let mut checksums = None;
+    let mut extracted = HashMap::new();
+    for entry in tar::Archive::new(GzDecoder::new(archive)).entries()? {
+        let mut entry = entry?;
+        let name = match entry.path()?.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
+        if name == CHECKSUMS_FILE {
+            let mut content = String::new();
+            io::Read::read_to_string(&mut entry, &mut content)?;
+            checksums = Some(content);
+            continue;
+        }
+        let Some((_, dest)) = binaries.iter().find(|(binary, _)| *binary == name) else {
+            continue;
+        };
+        let mut content = Vec::new();
+        io::Read::read_to_end(&mut entry, &mut content)?;
+        extracted.insert(name, (dir.join(dest), content));
+    }
+
+    let checksums = checksums.unwrap_or_default();
+    let checksums: HashMap<_, _> = checksums
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .map(|(checksum, name)| (name.trim_start_matches([' ', '*']), checksum))
+        .collect();
+    for (binary, _) in &binaries {
+        let (path, content) = extracted
+            .get(binary)
+            .ok_or_else(|| Error::MissingReleaseBinary(binary.clone()))?;
+        if let Some(expected) = checksums.get(binary.as_str()) {
+            if !expected.eq_ignore_ascii_case(&hex_sha256(content)) {
+                return Err(Error::ChecksumMismatch(path.clone()));
+            }
+        }
+        std::fs::write(path, content)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *content).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn extraction() {
+        let dir = std::env::temp_dir().join(format!("firec-download-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let sums = format!(
+            "{}  firecracker-v1.7.0-x86_64\n{}  jailer-v1.7.0-x86_64\n",
+            hex_sha256(b"fc"),
+            hex_sha256(b"jailer"),
+        );
+        let good = archive(&[
+            ("release-v1.7.0-x86_64/firecracker-v1.7.0-x86_64", b"fc"),
+            (
+                "release-v1.7.0-x86_64/firecracker-v1.7.0-x86_64.debug",
+                b"debug",
+            ),
+            ("release-v1.7.0-x86_64/jailer-v1.7.0-x86_64", b"jailer"),
+            ("release-v1.7.0-x86_64/SHA256SUMS", sums.as_bytes()),
+        ]);
+        extract(&good, "v1.7.0-x86_64", &dir).unwrap();
+        assert_eq!(std::fs::read(dir.join("firecracker")).unwrap(), b"fc");
+        let mode = std::fs::metadata(dir.join("jailer"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        let tampered = archive(&[
+            ("release-v1.7.0-x86_64/firecracker-v1.7.0-x86_64", b"evil"),
+            ("release-v1.7.0-x86_64/jailer-v1.7.0-x86_64", b"jailer"),
+            ("release-v1.7.0-x86_64/SHA256SUMS", sums.as_bytes()),
+        ]);
+        assert!(matches!(
+            extract(&tampered, "v1.7.0-x86_64", &dir),
+            Err(Error::ChecksumMismatch(_))
+        ));
+
+        let incomplete = archive(&[("release-v1.7.0-x86_64/firecracker-v1.7.0-x86_64", b"fc")]);
+        assert!(matches!(
+            extract(&incomplete, "v1.7.0-x86_64", &dir),
+            Err(Error::MissingReleaseBinary(binary)) if binary == "jailer-v1.7.0-x86_64"
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,20 @@
+use std::{fmt, future::Future, path::PathBuf, process::ExitStatus};
+
 use hyper::StatusCode;
 use thiserror::Error;
 
+use crate::config::VmId;
+
 /// Error type for this crate.
 #[derive(Debug, Error)]
 pub enum Error {
+    /// An operation on a VM failed.
+    ///
+    /// Errors returned by [`crate::Machine`] operations are wrapped in this variant, to tell
+    /// which VM and step failed. Use [`Error::root_cause`] to match on the underlying error.
+    #[error(transparent)]
+    Operation(Box<OperationError>),
+
     /// Failed to generate UUID.
     #[error("Failed to generate UUID: {0}")]
     Uuid(#[from] uuid::Error),
@@ -16,6 +27,20 @@ pub enum Error {
     #[error("Hyper error: {0}")]
     Hyper(#[from] hyper::Error),
 
+    /// The API socket doesn't exist, e.g because the VMM was never started or is gone.
+    #[error("API socket `{}` not found", path.display())]
+    SocketNotFound {
+        /// The host path of the socket.
+        path: PathBuf,
+    },
+
+    /// Nothing is listening on the API socket, e.g because the VMM died.
+    #[error("VMM not listening on API socket `{}`", path.display())]
+    VmmNotListening {
+        /// The host path of the socket.
+        path: PathBuf,
+    },
+
     /// HTTP error.
     #[error("HTTP error: {0}")]
     Http(#[from] hyper::http::Error),
@@ -32,6 +57,10 @@ pub enum Error {
     #[error("Task join error: {0}")]
     JoinError(#[from] tokio::task::JoinError),
 
+    /// Invalid VM ID specified.
+    #[error("Invalid VM ID `{0}`: must be 1 to 64 alphanumeric characters or hyphens")]
+    InvalidVmId(String),
+
     /// Invalid Jailer executable path specified.
     #[error("Invalid Jailer executable path specified")]
     InvalidJailerExecPath,
@@ -44,10 +73,99 @@ pub enum Error {
     #[error("Invalid socket path specified")]
     InvalidSocketPath,
 
+    /// Socket path exceeds the `AF_UNIX` address length limit.
+    #[error("Socket path `{}` exceeds the maximum length of {} bytes", path.display(), crate::config::UNIX_SOCKET_PATH_MAX_LEN)]
+    SocketPathTooLong {
+        /// The offending host path of the socket.
+        path: std::path::PathBuf,
+    },
+
     /// Invalid drive path specified.
     #[error("Invalid drive path specified")]
     InvalidDrivePath,
 
+    /// No filesystem was recognized in the root drive image, see
+    /// [`crate::images::check_root_filesystem`].
+    #[error("No filesystem recognized in root drive `{drive_id}` at `{}`", path.display())]
+    UnrecognizedFilesystem {
+        /// The ID of the drive.
+        drive_id: String,
+        /// The source path of the drive image.
+        path: PathBuf,
+    },
+
+    /// A file can't be injected in a drive image at the given path, see
+    /// [`crate::images::inject`].
+    #[error("Can't inject a file at `{}`: not a regular file or through a symbolic link", .0.display())]
+    InvalidInjectionPath(PathBuf),
+
+    /// The root drive holds a read-only filesystem but isn't read-only.
+    #[error("Root drive `{drive_id}` holds a {filesystem} filesystem and must be read-only")]
+    ReadOnlyFilesystem {
+        /// The ID of the drive.
+        drive_id: String,
+        /// The filesystem.
+        filesystem: crate::images::FilesystemType,
+    },
+
+    /// No drive with the given ID is configured.
+    #[error("No drive with ID `{0}`")]
+    DriveNotFound(String),
+
+    /// The drive of a running VM can only be grown.
+    #[error("Drive `{0}` can't be shrunk while the VM is running")]
+    DriveShrinkWhileRunning(String),
+
+    /// Only drives copied into the chroot can be swapped, see [`crate::Machine::swap_drive`].
+    #[error("Drive `{0}` is provisioned with device-mapper and can't be swapped")]
+    DriveNotSwappable(String),
+
+    /// No drive is the root device, and there is no initrd to boot from.
+    #[error("No root drive configured")]
+    NoRootDrive,
+
+    /// Several drives are the root device.
+    #[error("Several drives are configured as the root device")]
+    MultipleRootDrives,
+
+    /// A drive that isn't the root device has a partition UUID.
+    #[error("Drive `{0}` has a partition UUID but isn't the root device")]
+    PartUuidOnNonRootDrive(String),
+
+    /// The host doesn't meet the requirements of the VM, see [`crate::host::check`].
+    #[error("Host requirements not met: {}", .0.join(", "))]
+    HostRequirementsNotMet(Vec<String>),
+
+    /// The configuration doesn't comply with its isolation profile, see
+    /// [`crate::config::IsolationProfile`].
+    #[error("Isolation profile `{profile}` violated: {reason}")]
+    IsolationProfileViolation {
+        /// The name of the profile.
+        profile: String,
+        /// Why the configuration doesn't comply.
+        reason: String,
+    },
+
+    /// The host doesn't have enough free resources for the VM, see
+    /// [`crate::config::Builder::admission_check`].
+    #[error("Not enough free {resource} on the host: {required} required, {available} available")]
+    InsufficientHostResources {
+        /// The resource, `memory (MiB)` or `CPUs`.
+        resource: &'static str,
+        /// The amount required by the VM.
+        required: u64,
+        /// The amount available on the host.
+        available: u64,
+    },
+
+    /// Invalid name for an artifact in the chroot.
+    #[error("Invalid name `{0}` for an artifact in the chroot")]
+    InvalidArtifactName(String),
+
+    /// Several artifacts share the same name in the chroot.
+    #[error("Several artifacts are named `{0}` in the chroot")]
+    DuplicateArtifactName(String),
+
     /// Invalid chroot base path specified.
     #[error("Invalid chroot base path specified")]
     InvalidChrootBasePath,
@@ -61,13 +179,227 @@ pub enum Error {
         body: Option<String>,
     },
 
-    /// Jailer start timed out
-    #[error("Jailer start timed out")]
-    JailerStartTimedOut,
+    /// Failed to start the VMM.
+    #[error("Failed to start: {0}")]
+    StartFailed(Box<StartFailure>),
+
+    /// A phase of the start timed out.
+    #[error("Start timed out during {phase}")]
+    StartTimedOut {
+        /// The phase that timed out.
+        phase: crate::StartPhase,
+    },
+
+    /// The balloon device is not configured.
+    #[error("Balloon device not configured")]
+    BalloonNotConfigured,
+
+    /// The guest agent failed or violated the protocol, see [`crate::agent`].
+    #[error("Guest agent error: {0}")]
+    Agent(#[from] AgentError),
+
+    /// A command run in the guest didn't exit in time.
+    #[error("Command timed out after {0:?} in the guest")]
+    ExecTimedOut(std::time::Duration),
+
+    /// The checksum of a transferred file doesn't match its content.
+    #[error("Checksum mismatch for `{}`", .0.display())]
+    ChecksumMismatch(PathBuf),
+
+    /// A Firecracker release couldn't be downloaded, see [`crate::download`].
+    #[cfg(feature = "download")]
+    #[error("Failed to download `{url}`: {source}")]
+    Download {
+        /// The URL of the file.
+        url: String,
+        /// The HTTP error.
+        #[source]
+        source: reqwest::Error,
+    },
+
+    /// The checksum published for a release archive is empty, see [`crate::download`].
+    #[cfg(feature = "download")]
+    #[error("Empty checksum file for `{0}`")]
+    EmptyReleaseChecksum(String),
+
+    /// A downloaded release archive lacks a binary, see [`crate::download`].
+    #[cfg(feature = "download")]
+    #[error("`{0}` not found in the release")]
+    MissingReleaseBinary(String),
+
+    /// Neither a metrics file nor a metrics named pipe is configured.
+    #[error("Metrics not configured")]
+    MetricsNotConfigured,
+
+    /// The vsock device is not configured.
+    #[error("Vsock device not configured")]
+    VsockNotConfigured,
+
+    /// No machine with the given ID is managed.
+    #[error("No machine with ID `{0}`")]
+    MachineNotFound(crate::config::VmId),
+
+    /// A machine with the given ID is already managed.
+    #[error("A machine with ID `{0}` already exists")]
+    MachineAlreadyExists(crate::config::VmId),
+
+    /// Creating a machine would exceed the configured capacity.
+    #[error("Capacity exceeded: {0}")]
+    CapacityExceeded(String),
+
+    /// Invalid IPv4 subnet.
+    #[error("Invalid IPv4 subnet `{0}`")]
+    InvalidSubnet(String),
+
+    /// All guest addresses are allocated.
+    #[error("No guest address available")]
+    AddressesExhausted,
+
+    /// The CPU architecture is not supported by Firecracker.
+    #[error("Unsupported CPU architecture `{0}`")]
+    UnsupportedArch(String),
+
+    /// Simultaneous multithreading is not supported on the target architecture.
+    #[error("SMT is not supported on {0}")]
+    SmtUnsupported(crate::config::Arch),
+
+    /// The CPU template is not available on the target architecture.
+    #[error("CPU template `{template}` is not available on {arch}")]
+    UnsupportedCpuTemplate {
+        /// The CPU template.
+        template: String,
+        /// The target architecture.
+        arch: crate::config::Arch,
+    },
+
+    /// Invalid number of vCPUs.
+    #[error(
+        "Invalid vCPU count {0}: must be 1 to {} (and 1 or even with SMT)",
+        crate::config::MAX_VCPU_COUNT
+    )]
+    InvalidVcpuCount(usize),
+
+    /// All jailer uid/gid pairs are allocated.
+    #[error("No jailer uid/gid available")]
+    UidGidExhausted,
+
+    /// Dirty page tracking is not enabled, see [`crate::dirty_pages`].
+    #[error("Dirty page tracking not enabled")]
+    DirtyPageTrackingDisabled,
 
-    /// Failed to start
-    #[error("Failed to start")]
-    FailedToStart,
+    /// The S3 storage backend failed, see `storage::S3Storage`.
+    #[cfg(feature = "s3")]
+    #[error("S3 storage error: {0}")]
+    Storage(#[from] object_store::Error),
+
+    /// An image key would be cached outside of the cache directory, see
+    /// [`crate::storage::ImageCache::get`].
+    #[error("Invalid image key `{0}`")]
+    InvalidImageKey(String),
+
+    /// A cryptographic operation failed, see `storage::SnapshotSeal`.
+    #[cfg(feature = "encryption")]
+    #[error("Cryptographic operation failed")]
+    Crypto(#[source] ring::error::Unspecified),
+
+    /// A snapshot key isn't 256-bit long, see `storage::KeyProvider`.
+    #[cfg(feature = "encryption")]
+    #[error("Snapshot keys must be 256-bit, got {0} bits")]
+    InvalidSnapshotKey(usize),
+
+    /// The signing key isn't a valid Ed25519 key pair, see `storage::SnapshotSeal::sign`.
+    #[cfg(feature = "encryption")]
+    #[error("Invalid signing key")]
+    InvalidSigningKey(#[source] ring::error::KeyRejected),
+
+    /// A pulled snapshot file isn't encrypted, see `storage::SnapshotSeal`.
+    #[cfg(feature = "encryption")]
+    #[error("`{}` is not an encrypted snapshot file", .0.display())]
+    NotEncryptedSnapshot(PathBuf),
+
+    /// A pulled snapshot file was tampered with, or encrypted with another key, see
+    /// `storage::SnapshotSeal`.
+    #[cfg(feature = "encryption")]
+    #[error("`{}` is corrupted or encrypted with another key", .0.display())]
+    SnapshotAuthenticationFailed(PathBuf),
+
+    /// The signature of a pulled snapshot is invalid, see `storage::SnapshotSeal`.
+    #[error("Invalid snapshot signature")]
+    InvalidSignature,
+
+    /// A path expected to be in the chroot of the VM isn't.
+    #[error("`{}` is not in the chroot", .0.display())]
+    OutsideChroot(PathBuf),
+
+    /// Firecracker can't boot the kernel image, see [`crate::kernel`].
+    #[error("Invalid kernel image `{}`: {reason}", path.display())]
+    InvalidKernelFormat {
+        /// The source path of the kernel image.
+        path: PathBuf,
+        /// Why the image can't boot.
+        reason: String,
+    },
+
+    /// The socket to adopt isn't in a jailer workspace, see [`crate::Machine::adopt`].
+    #[error("`{}` is not the API socket of a jailed VM", .0.display())]
+    NotJailedSocket(PathBuf),
+
+    /// A configuration for another VM was applied, see [`crate::Machine::apply`].
+    #[error("Configuration is for VM `{0}`")]
+    VmIdMismatch(VmId),
+
+    /// Invalid Firecracker version.
+    #[error("Invalid Firecracker version `{0}`")]
+    InvalidFirecrackerVersion(String),
+
+    /// A feature is not supported by the Firecracker version in use.
+    #[error("{feature} requires Firecracker {required} or later, but {version} is used")]
+    UnsupportedFeature {
+        /// The feature.
+        feature: String,
+        /// The first Firecracker version supporting the feature.
+        required: crate::version::FirecrackerVersion,
+        /// The Firecracker version in use.
+        version: crate::version::FirecrackerVersion,
+    },
+
+    /// The started Firecracker binary doesn't have the version the configuration pins, see
+    /// [`crate::config::Builder::firecracker_version`].
+    #[error("Firecracker {expected} expected, but {actual} was started")]
+    FirecrackerVersionMismatch {
+        /// The pinned version.
+        expected: crate::version::FirecrackerVersion,
+        /// The version of the started binary.
+        actual: crate::version::FirecrackerVersion,
+    },
+
+    /// A request body doesn't match the API spec of the Firecracker release in use, see
+    /// [`crate::api`].
+    #[cfg(any(feature = "fc-1_7", feature = "fc-1_10"))]
+    #[error("Body of `{path}` doesn't match the Firecracker {version} API: {source}")]
+    ApiSpecMismatch {
+        /// The path of the request.
+        path: String,
+        /// The Firecracker version in use.
+        version: crate::version::FirecrackerVersion,
+        /// The error deserializing the body into the model of the endpoint.
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The start options are incompatible with the configuration, see
+    /// [`crate::StartOptions::skip`].
+    #[error("Invalid start options: {0}")]
+    InvalidStartOptions(String),
+
+    /// A helper command exited unsuccessfully.
+    #[error("Command `{command}` failed with status: {exit_status}")]
+    CommandFailed {
+        /// The command line.
+        command: String,
+        /// The exit status of the command.
+        exit_status: std::process::ExitStatus,
+    },
 
     /// Process already running
     #[error("Process is already running")]
@@ -92,3 +424,216 @@ pub enum Error {
         exit_status: std::process::ExitStatus,
     },
 }
+
+/// Evidence collected when the VMM fails to start.
+#[derive(Debug)]
+pub struct StartFailure {
+    /// Why the start is considered failed.
+    pub reason: StartFailureReason,
+    /// The exit status of the spawned process, if it has exited.
+    pub exit_status: Option<ExitStatus>,
+    /// The last lines of the captured jailer stdout.
+    ///
+    /// Only available when [`crate::config::JailerBuilder::capture_daemon_output`] is enabled.
+    pub stdout_tail: Vec<String>,
+    /// The last lines of the captured jailer stderr.
+    ///
+    /// Only available when [`crate::config::JailerBuilder::capture_daemon_output`] is enabled.
+    pub stderr_tail: Vec<String>,
+    /// Whether the API socket file exists.
+    pub socket_exists: bool,
+    /// The entries of the chroot directory.
+    pub chroot_listing: Vec<PathBuf>,
+}
+
+impl fmt::Display for StartFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)?;
+        match &self.exit_status {
+            Some(exit_status) => write!(f, "; process exited with {exit_status}")?,
+            None => write!(f, "; process still running or already reaped")?,
+        }
+        write!(
+            f,
+            "; API socket {}",
+            if self.socket_exists {
+                "exists"
+            } else {
+                "missing"
+            }
+        )?;
+        let listing: Vec<_> = self
+            .chroot_listing
+            .iter()
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy())
+            .collect();
+        write!(f, "; chroot contents: [{}]", listing.join(", "))?;
+        if !self.stdout_tail.is_empty() {
+            write!(f, "; stdout:\n{}", self.stdout_tail.join("\n"))?;
+        }
+        if !self.stderr_tail.is_empty() {
+            write!(f, "; stderr:\n{}", self.stderr_tail.join("\n"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Why an operation through the guest agent failed, see [`Error::Agent`].
+#[derive(Debug, Error)]
+pub enum AgentError {
+    /// The request line contains a newline, which would start another request.
+    #[error("Invalid request `{0}`")]
+    InvalidRequest(String),
+    /// The agent answered `ERR <message>`, e.g because a pushed file doesn't match its checksum.
+    #[error("{0}")]
+    Remote(String),
+    /// The agent answered neither `OK` nor `ERR`.
+    #[error("Unexpected response `{0}`")]
+    UnexpectedResponse(String),
+    /// The agent answered `OK` followed by something that can't be parsed.
+    #[error("Invalid response `{0}`")]
+    InvalidResponse(String),
+    /// The agent closed the connection before the end of the response.
+    #[error("Connection closed by the agent")]
+    ConnectionClosed,
+    /// A line from the agent doesn't end within the given number of bytes.
+    #[error("Line from the agent exceeds the maximum of {0} bytes")]
+    LineTooLong(usize),
+    /// A pushed file got shorter while it was being sent.
+    #[error("`{}` was truncated while being sent", .0.display())]
+    Truncated(PathBuf),
+    /// The output of a command run in the guest exceeds
+    /// [`crate::config::Config::agent_max_output`].
+    #[error("Command output of {stdout} + {stderr} bytes exceeds the maximum of {max} bytes")]
+    OutputTooLarge {
+        /// The size of stdout.
+        stdout: usize,
+        /// The size of stderr.
+        stderr: usize,
+        /// The maximum size of each.
+        max: usize,
+    },
+    /// A command run in the guest exited unsuccessfully.
+    #[error("Command exited with code {exit_code}: {stderr}")]
+    CommandFailed {
+        /// The exit code of the command.
+        exit_code: i32,
+        /// The stderr of the command, trimmed.
+        stderr: String,
+    },
+}
+
+/// Why the start of the VMM is considered failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartFailureReason {
+    /// The API socket did not become ready in time.
+    TimedOut,
+    /// The spawned process exited unsuccessfully before the API socket became ready.
+    ProcessExited,
+    /// The Firecracker process could not be identified unambiguously.
+    ProcessLookupFailed {
+        /// Number of matching processes found.
+        candidates: usize,
+    },
+}
+
+impl fmt::Display for StartFailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TimedOut => write!(f, "jailer start timed out"),
+            Self::ProcessExited => write!(f, "process exited before the API socket was ready"),
+            Self::ProcessLookupFailed { candidates } => write!(
+                f,
+                "found {candidates} Firecracker processes matching the VM instead of 1"
+            ),
+        }
+    }
+}
+
+/// Context of an [`Error::Operation`].
+#[derive(Debug, Error)]
+pub struct OperationError {
+    /// The ID of the VM.
+    pub vm_id: VmId,
+    /// The operation that failed, e.g `start`.
+    pub operation: Option<&'static str>,
+    /// The Firecracker API endpoint involved, if any.
+    pub endpoint: Option<String>,
+    /// The underlying error.
+    #[source]
+    pub source: Error,
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operation {
+            Some(operation) => write!(f, "Failed to {operation} VM `{}`", self.vm_id)?,
+            None => write!(f, "Operation on VM `{}` failed", self.vm_id)?,
+        }
+        if let Some(endpoint) = &self.endpoint {
+            write!(f, " at `{endpoint}`")?;
+        }
+
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl Error {
+    /// The underlying error, without the context of [`Error::Operation`].
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Self::Operation(e) => e.source.root_cause(),
+            e => e,
+        }
+    }
+
+    /// The ID of the VM the error relates to, if known.
+    pub fn vm_id(&self) -> Option<&VmId> {
+        match self {
+            Self::Operation(e) => Some(&e.vm_id),
+            _ => None,
+        }
+    }
+
+    /// Add the context of an operation on a VM.
+    ///
+    /// The innermost operation is kept, as it's the most specific.
+    pub(crate) fn in_operation(self, vm_id: &VmId, operation: &'static str) -> Self {
+        match self {
+            Self::Operation(mut e) if e.vm_id == *vm_id => {
+                e.operation.get_or_insert(operation);
+                Self::Operation(e)
+            }
+            source => Self::Operation(Box::new(OperationError {
+                vm_id: vm_id.clone(),
+                operation: Some(operation),
+                endpoint: None,
+                source,
+            })),
+        }
+    }
+
+    /// Add the Firecracker API endpoint a request failed on.
+    pub(crate) fn at_endpoint(self, vm_id: &VmId, endpoint: &str) -> Self {
+        Self::Operation(Box::new(OperationError {
+            vm_id: vm_id.clone(),
+            operation: None,
+            endpoint: Some(endpoint.to_owned()),
+            source: self,
+        }))
+    }
+}
+
+/// Run `fut` as the given operation on a VM, adding its context to errors.
+pub(crate) async fn in_operation<T, F>(
+    vm_id: VmId,
+    operation: &'static str,
+    fut: F,
+) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>>,
+{
+    fut.await.map_err(|e| e.in_operation(&vm_id, operation))
+}
@@ -0,0 +1,262 @@
+//! Machine lifecycle events.
+//!
+//! Events serialize with `snake_case` names, the kind of event being in the `type` field, and
+//! timestamps as milliseconds since the Unix epoch, e.g:
+//!
+//! ```json
+//! {"vm_id":"my-vm","timestamp":1686651155123,"type":"stopped","exit_reason":{"type":"clean"}}
+//! ```
+
+use std::{os::unix::process::ExitStatusExt, process::ExitStatus, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::config::VmId;
+
+/// Number of events buffered for each subscriber before it starts lagging.
+const EVENTS_CAPACITY: usize = 64;
+
+/// Exit code of Firecracker when it intercepts a system call forbidden by its seccomp filters.
+const BAD_SYSCALL_EXIT_CODE: i32 = 148;
+/// Exit codes of Firecracker when it intercepts `SIGBUS`, `SIGSEGV`, `SIGXFSZ`, `SIGXCPU`,
+/// `SIGPIPE`, `SIGHUP` and `SIGILL`.
+const FATAL_SIGNAL_EXIT_CODES: [i32; 7] = [149, 150, 151, 154, 155, 156, 157];
+/// Rust panics abort Firecracker.
+const SIGABRT: i32 = 6;
+
+/// A lifecycle event of a machine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineEvent {
+    /// The ID of the machine.
+    pub vm_id: VmId,
+    /// When the event happened.
+    #[serde(with = "unix_millis")]
+    pub timestamp: SystemTime,
+    /// What happened.
+    #[serde(flatten)]
+    pub kind: MachineEventKind,
+}
+
+/// The kind of a [`MachineEvent`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MachineEventKind {
+    /// The machine was created.
+    ///
+    /// Only emitted by [`crate::Orchestrator`], as nobody can subscribe to a machine before it's
+    /// created.
+    Created,
+    /// The machine was started.
+    Started,
+    /// The machine failed to start.
+    StartFailed {
+        /// The error message.
+        error: String,
+    },
+    /// The machine was shut down.
+    Stopped {
+        /// Why the VMM exited, if known.
+        exit_reason: Option<ExitReason>,
+    },
+    /// The machine process was killed.
+    Killed,
+    /// The VMM process disappeared without the machine being stopped.
+    ///
+    /// Only emitted while the machine is watched, see [`crate::Machine::watch`].
+    Crashed {
+        /// Why the VMM is considered gone.
+        reason: String,
+        /// Why the VMM exited, if known.
+        exit_reason: Option<ExitReason>,
+    },
+    /// The machine was deleted.
+    Deleted,
+    /// The guest missed heartbeats.
+    ///
+    /// Only emitted while heartbeats are monitored, see [`crate::Machine::monitor_heartbeat`].
+    Unhealthy {
+        /// When the last heartbeat was received, if any.
+        #[serde(with = "unix_millis::option")]
+        last_heartbeat: Option<SystemTime>,
+    },
+}
+
+/// Why the Firecracker process exited, classified from its exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ExitReason {
+    /// Firecracker exited successfully, e.g after the guest shut down.
+    Clean,
+    /// Firecracker exited with an error, e.g because of an invalid configuration.
+    Error {
+        /// The exit code.
+        code: i32,
+    },
+    /// Firecracker intercepted a system call forbidden by its seccomp filters.
+    BadSyscall,
+    /// Firecracker intercepted a fatal signal, e.g `SIGSEGV` or `SIGBUS`.
+    FatalSignal {
+        /// The exit code, telling which signal was intercepted.
+        code: i32,
+    },
+    /// Firecracker panicked.
+    Panic,
+    /// Firecracker was killed by a signal, e.g by [`crate::Machine::force_shutdown`].
+    Killed {
+        /// The signal.
+        signal: i32,
+    },
+}
+
+impl ExitReason {
+    /// Classify the exit status of a Firecracker process.
+    pub fn from_exit_status(exit_status: ExitStatus) -> Self {
+        match (exit_status.code(), exit_status.signal()) {
+            (Some(0), _) => Self::Clean,
+            (Some(BAD_SYSCALL_EXIT_CODE), _) => Self::BadSyscall,
+            (Some(code), _) if FATAL_SIGNAL_EXIT_CODES.contains(&code) => {
+                Self::FatalSignal { code }
+            }
+            (Some(code), _) => Self::Error { code },
+            (None, Some(SIGABRT)) => Self::Panic,
+            (None, signal) => Self::Killed {
+                signal: signal.unwrap_or_default(),
+            },
+        }
+    }
+}
+
+impl MachineEvent {
+    pub(crate) fn new(vm_id: VmId, kind: MachineEventKind) -> Self {
+        Self {
+            vm_id,
+            timestamp: SystemTime::now(),
+            kind,
+        }
+    }
+}
+
+/// Create the sender of an event channel.
+pub(crate) fn channel() -> broadcast::Sender<MachineEvent> {
+    broadcast::channel(EVENTS_CAPACITY).0
+}
+
+/// (De)serializes timestamps as milliseconds since the Unix epoch.
+mod unix_millis {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let millis = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        serializer.serialize_u64(u64::try_from(millis).unwrap_or(u64::MAX))
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(UNIX_EPOCH + Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+
+    pub(crate) mod option {
+        use std::time::SystemTime;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub(crate) fn serialize<S>(
+            time: &Option<SystemTime>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match time {
+                Some(time) => super::serialize(time, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            struct Millis(#[serde(with = "super")] SystemTime);
+
+            Ok(Option::<Millis>::deserialize(deserializer)?.map(|Millis(time)| time))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_reasons() {
+        let exit_code = |code: i32| ExitStatus::from_raw(code << 8);
+        assert_eq!(
+            ExitReason::from_exit_status(exit_code(0)),
+            ExitReason::Clean
+        );
+        assert_eq!(
+            ExitReason::from_exit_status(exit_code(152)),
+            ExitReason::Error { code: 152 }
+        );
+        assert_eq!(
+            ExitReason::from_exit_status(exit_code(148)),
+            ExitReason::BadSyscall
+        );
+        assert_eq!(
+            ExitReason::from_exit_status(exit_code(150)),
+            ExitReason::FatalSignal { code: 150 }
+        );
+        assert_eq!(
+            ExitReason::from_exit_status(ExitStatus::from_raw(SIGABRT)),
+            ExitReason::Panic
+        );
+        assert_eq!(
+            ExitReason::from_exit_status(ExitStatus::from_raw(9)),
+            ExitReason::Killed { signal: 9 }
+        );
+    }
+
+    #[test]
+    fn serialization() {
+        let event = MachineEvent {
+            vm_id: VmId::new("my-vm").unwrap(),
+            timestamp: SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_686_651_155_123),
+            kind: MachineEventKind::Crashed {
+                reason: "API socket gone".to_owned(),
+                exit_reason: Some(ExitReason::FatalSignal { code: 150 }),
+            },
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"vm_id":"my-vm","timestamp":1686651155123,"type":"crashed","reason":"API socket gone","exit_reason":{"type":"fatal_signal","code":150}}"#
+        );
+        assert_eq!(serde_json::from_str::<MachineEvent>(&json).unwrap(), event);
+
+        let unhealthy = MachineEvent {
+            kind: MachineEventKind::Unhealthy {
+                last_heartbeat: None,
+            },
+            ..event
+        };
+        let json = serde_json::to_string(&unhealthy).unwrap();
+        assert!(json.ends_with(r#""type":"unhealthy","last_heartbeat":null}"#));
+        assert_eq!(
+            serde_json::from_str::<MachineEvent>(&json).unwrap(),
+            unhealthy
+        );
+    }
+}
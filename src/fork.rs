@@ -0,0 +1,102 @@
+//! Cloning running VMs.
+//!
+//! [`crate::Machine::fork`] snapshots a running VM and restores the snapshot into a new machine,
+//! sharing its memory file (see [`crate::snapshot::share`]). As the clone resumes with the
+//! network identity of the original, it's given a new one:
+//!
+//! * On the host, its interfaces are attached to the TAP devices of its configuration, by
+//!   interface ID, e.g renamed with [`crate::config::network::Interface::reidentify`].
+//! * In the guest, the MAC address of each interface is changed to the one of the configuration,
+//!   through the guest agent, and an optional command (see [`ForkOptions::readdress`]) lets the
+//!   guest re-acquire its addresses, e.g with DHCP. New MMDS metadata can also be set for
+//!   guest-side tooling to pick up (see [`ForkOptions::mmds_metadata`]).
+//!
+//! Guest interfaces are found by their MAC address in the original, so the interfaces of the
+//! original VM must have MAC addresses configured for the guest side to be re-identified.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{agent::ExecRequest, StartOptions};
+
+/// Name of the snapshot taken by [`crate::Machine::fork`], in the chroot of both VMs.
+pub const FORK_SNAPSHOT_NAME: &str = "fork";
+
+/// The timeout of each command re-identifying the guest.
+pub const REIDENTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sets the MAC address of the guest interface with the MAC address `$1` to `$2`.
+const SET_MAC_SCRIPT: &str = r#"
+for dev in /sys/class/net/*; do
+    [ "$(cat "$dev/address")" = "$1" ] || continue
+    name="${dev##*/}"
+    ip link set dev "$name" down &&
+        ip link set dev "$name" address "$2" &&
+        ip link set dev "$name" up
+    exit
+done
+echo "no interface with address $1" >&2
+exit 1
+"#;
+
+/// Options for [`crate::Machine::fork`].
+#[derive(Debug, Clone, Default)]
+pub struct ForkOptions {
+    pub(crate) start_options: StartOptions,
+    pub(crate) readdress: Option<(String, Vec<String>)>,
+    pub(crate) mmds_metadata: Option<serde_json::Value>,
+}
+
+impl ForkOptions {
+    /// Start the clone with `start_options`, e.g for timeouts.
+    ///
+    /// The clone is always restored from the fork snapshot, with network overrides.
+    pub fn start_options(mut self, start_options: StartOptions) -> Self {
+        self.start_options = start_options;
+        self
+    }
+
+    /// Run `cmd` with `args` in the guest once its MAC addresses are changed, to re-acquire its
+    /// addresses, e.g `udhcpc -i eth0 -q`.
+    pub fn readdress<C, I, A>(mut self, cmd: C, args: I) -> Self
+    where
+        C: Into<String>,
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        self.readdress = Some((cmd.into(), args.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Set the MMDS metadata of the clone, see [`crate::Machine::set_mmds_metadata`].
+    pub fn mmds_metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.mmds_metadata = Some(metadata);
+        self
+    }
+}
+
+/// The agent request changing the MAC address of a guest interface from `old` to `new`.
+pub(crate) fn set_mac_request(old: &str, new: &str) -> ExecRequest {
+    ExecRequest {
+        cmd: "sh".to_owned(),
+        args: vec![
+            "-c".to_owned(),
+            SET_MAC_SCRIPT.to_owned(),
+            "sh".to_owned(),
+            // Linux shows MAC addresses in lowercase.
+            old.to_ascii_lowercase(),
+            new.to_ascii_lowercase(),
+        ],
+        env: BTreeMap::new(),
+        timeout_ms: REIDENTIFY_TIMEOUT.as_millis() as u64,
+    }
+}
+
+/// The agent request running `cmd` with `args`.
+pub(crate) fn readdress_request(cmd: &str, args: &[String]) -> ExecRequest {
+    ExecRequest {
+        cmd: cmd.to_owned(),
+        args: args.to_vec(),
+        env: BTreeMap::new(),
+        timeout_ms: REIDENTIFY_TIMEOUT.as_millis() as u64,
+    }
+}
@@ -0,0 +1,406 @@
+//! Filesystem abstraction for the chroot.
+//!
+//! All filesystem operations [`crate::Machine`] performs on the jailer workspace go through the
+//! [`ChrootFs`] set on the [`crate::config::Config`]. By default, [`LocalFs`] is used, which
+//! operates on the local host filesystem.
+
+use std::{
+    fmt::Debug,
+    fs::File,
+    io,
+    os::unix::fs::{FileExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use rustix::{
+    fs::{copy_file_range, makedev, mknodat, seek, FileType, Mode, SeekFrom, CWD},
+    io::Errno,
+};
+use sha2::{Digest, Sha256};
+use tokio::{fs, task};
+
+/// Filesystem operations used to prepare and clean up the chroot.
+pub trait ChrootFs: Debug + Send + Sync {
+    /// Recursively create a directory and all of its parent components if they are missing.
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Check if the given path exists.
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// The size of the file at `path`, in bytes.
+    fn file_len<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>>;
+
+    /// Copy the contents of `src` to `dest`, returning the number of bytes copied.
+    ///
+    /// Holes in `src` should be preserved, so that sparse drive images stay sparse. The
+    /// modification time of `src` should be preserved, for [`ChrootFs::same_contents`].
+    fn copy<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<u64>>;
+
+    /// If `dest` has the same contents as `src`.
+    ///
+    /// Files of the same size and modification time are assumed to be the same, otherwise their
+    /// contents are compared.
+    fn same_contents<'a>(
+        &'a self,
+        src: &'a Path,
+        dest: &'a Path,
+    ) -> BoxFuture<'a, io::Result<bool>>;
+
+    /// Create a hard link at `dest` to `src`.
+    fn hard_link<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Create a block device node at `path` for the device with the given major and minor
+    /// numbers.
+    fn make_block_device<'a>(
+        &'a self,
+        path: &'a Path,
+        major: u32,
+        minor: u32,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Read the contents of a file.
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+
+    /// Write `contents` to a file, replacing it if it already exists.
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Change the owner of a file to `uid`/`gid` and its permissions to `mode`.
+    fn set_owner<'a>(
+        &'a self,
+        path: &'a Path,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Remove a file.
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Remove a directory after removing all its contents.
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>>;
+
+    /// List the entries of a directory.
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>>;
+
+    /// The number of bytes allocated on disk for `path`, recursively for directories.
+    ///
+    /// Symlinks are not followed. Returns 0 if the path doesn't exist.
+    fn disk_usage<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>>;
+}
+
+/// Disk space used by a VM, in bytes allocated on the host filesystem.
+///
+/// Sparse files only account for the blocks actually allocated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// The whole VM directory.
+    pub total: u64,
+    /// The kernel image.
+    pub kernel_image: u64,
+    /// The initrd.
+    pub initrd: u64,
+    /// The drive files, as `(drive_id, bytes)` pairs in configuration order.
+    pub drives: Vec<(String, u64)>,
+    /// Firecracker log and metrics files, and the diagnostics directory.
+    pub logs: u64,
+    /// Everything else, e.g snapshot files.
+    pub other: u64,
+}
+
+/// Operates on the local host filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalFs;
+
+impl ChrootFs for LocalFs {
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        fs::create_dir_all(path).boxed()
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            match fs::metadata(path).await {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        .boxed()
+    }
+
+    fn file_len<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        async move { Ok(fs::metadata(path).await?.len()) }.boxed()
+    }
+
+    fn copy<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        let (src, dest) = (src.to_owned(), dest.to_owned());
+        async move {
+            task::spawn_blocking(move || {
+                let copied = blocking_copy_sparse(&src, &dest)?;
+                set_modified(&dest, std::fs::metadata(&src)?.modified()?)?;
+
+                Ok(copied)
+            })
+            .await
+            .map_err(io::Error::other)?
+        }
+        .boxed()
+    }
+
+    fn same_contents<'a>(
+        &'a self,
+        src: &'a Path,
+        dest: &'a Path,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        let (src, dest) = (src.to_owned(), dest.to_owned());
+        async move {
+            task::spawn_blocking(move || blocking_same_contents(&src, &dest))
+                .await
+                .map_err(io::Error::other)?
+        }
+        .boxed()
+    }
+
+    fn hard_link<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        fs::hard_link(src, dest).boxed()
+    }
+
+    fn make_block_device<'a>(
+        &'a self,
+        path: &'a Path,
+        major: u32,
+        minor: u32,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let path = path.to_owned();
+        async move {
+            task::spawn_blocking(move || {
+                let dev = makedev(major, minor);
+                Ok(mknodat(
+                    CWD,
+                    &path,
+                    FileType::BlockDevice,
+                    Mode::RUSR | Mode::WUSR,
+                    dev,
+                )?)
+            })
+            .await
+            .map_err(io::Error::other)?
+        }
+        .boxed()
+    }
+
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        fs::read(path).boxed()
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        fs::write(path, contents).boxed()
+    }
+
+    fn set_owner<'a>(
+        &'a self,
+        path: &'a Path,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        let path = path.to_owned();
+        async move {
+            task::spawn_blocking(move || {
+                std::os::unix::fs::chown(&path, Some(uid), Some(gid))?;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+            })
+            .await
+            .map_err(io::Error::other)?
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        fs::remove_file(path).boxed()
+    }
+
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        fs::remove_dir_all(path).boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        async move {
+            let mut entries = Vec::new();
+            let mut read_dir = fs::read_dir(path).await?;
+            while let Some(entry) = read_dir.next_entry().await? {
+                entries.push(entry.path());
+            }
+            entries.sort();
+
+            Ok(entries)
+        }
+        .boxed()
+    }
+
+    fn disk_usage<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        let path = path.to_owned();
+        async move {
+            task::spawn_blocking(move || blocking_disk_usage(&path))
+                .await
+                .map_err(io::Error::other)?
+        }
+        .boxed()
+    }
+}
+
+/// Copy `src` to `dest`, like [`std::fs::copy`] but leaving holes in `dest` where `src` has
+/// holes, returning the size of the file.
+pub(crate) async fn copy_sparse(src: &Path, dest: &Path) -> io::Result<u64> {
+    let (src, dest) = (src.to_owned(), dest.to_owned());
+    task::spawn_blocking(move || blocking_copy_sparse(&src, &dest))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Only the data segments of `src` (see `SEEK_DATA` in `lseek(2)`) are copied, with
+/// `copy_file_range` when possible. Filesystems without hole support report a single data
+/// segment, so the file is copied whole.
+fn blocking_copy_sparse(src: &Path, dest: &Path) -> io::Result<u64> {
+    let src_file = File::open(src)?;
+    let metadata = src_file.metadata()?;
+    if !metadata.is_file() {
+        return std::fs::copy(src, dest);
+    }
+    let len = metadata.len();
+    let dest_file = File::create(dest)?;
+    dest_file.set_permissions(metadata.permissions())?;
+
+    let mut offset = 0;
+    while offset < len {
+        let data = match seek(&src_file, SeekFrom::Data(offset)) {
+            Ok(data) => data,
+            // Only a hole until the end of the file.
+            Err(Errno::NXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let hole = seek(&src_file, SeekFrom::Hole(data))?.min(len);
+        copy_range(&src_file, &dest_file, data, hole)?;
+        offset = hole;
+    }
+    // Trailing holes.
+    dest_file.set_len(len)?;
+
+    Ok(len)
+}
+
+/// Copy the `start..end` range of `src` to the same range of `dest`.
+fn copy_range(src: &File, dest: &File, start: u64, end: u64) -> io::Result<()> {
+    let (mut off_in, mut off_out) = (start, start);
+    while off_in < end {
+        let len = usize::try_from(end - off_in).unwrap_or(usize::MAX);
+        match copy_file_range(src, Some(&mut off_in), dest, Some(&mut off_out), len) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {}
+            // Not supported for these files, e.g across filesystems on older kernels.
+            Err(Errno::XDEV | Errno::NOSYS | Errno::OPNOTSUPP | Errno::INVAL) => {
+                return copy_range_buffered(src, dest, off_in, end);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_range_buffered(src: &File, dest: &File, mut offset: u64, end: u64) -> io::Result<()> {
+    let mut buf = vec![0; 1 << 20];
+    while offset < end {
+        let len = (end - offset).min(buf.len() as u64) as usize;
+        src.read_exact_at(&mut buf[..len], offset)?;
+        dest.write_all_at(&buf[..len], offset)?;
+        offset += len as u64;
+    }
+
+    Ok(())
+}
+
+fn blocking_same_contents(src: &Path, dest: &Path) -> io::Result<bool> {
+    let (src_metadata, dest_metadata) = (std::fs::metadata(src)?, std::fs::metadata(dest)?);
+    if src_metadata.len() != dest_metadata.len() {
+        return Ok(false);
+    }
+    let modified = src_metadata.modified()?;
+    if modified == dest_metadata.modified()? {
+        return Ok(true);
+    }
+
+    if sha256(src)? != sha256(dest)? {
+        return Ok(false);
+    }
+    // Take the fast path next time.
+    set_modified(dest, modified)?;
+
+    Ok(true)
+}
+
+pub(crate) fn sha256(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+fn set_modified(path: &Path, modified: SystemTime) -> io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(modified)
+}
+
+fn blocking_disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    // `st_blocks` is always in units of 512 bytes.
+    let mut usage = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            usage += blocking_disk_usage(&entry?.path())?;
+        }
+    }
+
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn same_contents() {
+        let dir = std::env::temp_dir().join(format!("firec-fs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let (src, dest) = (dir.join("src"), dir.join("dest"));
+        fs::write(&src, "one").await.unwrap();
+
+        LocalFs.copy(&src, &dest).await.unwrap();
+        assert!(LocalFs.same_contents(&src, &dest).await.unwrap());
+        // Same size, different contents and modification time.
+        fs::write(&src, "two").await.unwrap();
+        set_modified(&src, SystemTime::UNIX_EPOCH).unwrap();
+        assert!(!LocalFs.same_contents(&src, &dest).await.unwrap());
+        // Same contents, different modification time.
+        fs::write(&dest, "two").await.unwrap();
+        assert!(LocalFs.same_contents(&src, &dest).await.unwrap());
+
+        // Holes are preserved.
+        let file = File::create(&src).unwrap();
+        file.set_len(64 << 20).unwrap();
+        file.write_all_at(b"data", 32 << 20).unwrap();
+        LocalFs.copy(&src, &dest).await.unwrap();
+        assert!(LocalFs.same_contents(&src, &dest).await.unwrap());
+        assert_eq!(fs::read(&dest).await.unwrap().len(), 64 << 20);
+        assert!(LocalFs.disk_usage(&dest).await.unwrap() < 1 << 20);
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+}
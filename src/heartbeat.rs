@@ -0,0 +1,146 @@
+//! Guest heartbeats over vsock.
+//!
+//! The protocol is as simple as it gets: an agent in the guest connects to the host (CID 2) on
+//! the heartbeat port and writes a line every [`HeartbeatPolicy::interval`]. Every line counts as
+//! a heartbeat, its content is ignored. The agent may keep the connection open or reconnect for
+//! every beat, e.g:
+//!
+//! ```sh
+//! while true; do echo beat | socat - VSOCK-CONNECT:2:5000; sleep 5; done
+//! ```
+//!
+//! This catches guest hangs that process liveness checks (see [`crate::Machine::watch`]) can't
+//! see.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+    task::JoinSet,
+    time::interval,
+};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::VmId,
+    events::{MachineEvent, MachineEventKind},
+    task::TaskHandle,
+};
+
+/// Time of the last heartbeat, shared with the monitoring task.
+pub(crate) type LastHeartbeat = Arc<Mutex<Option<SystemTime>>>;
+
+/// How guest heartbeats are received and checked.
+#[derive(Debug, Clone)]
+pub struct HeartbeatPolicy {
+    port: u32,
+    interval: Duration,
+    missed_beats: u32,
+}
+
+impl HeartbeatPolicy {
+    /// Expect heartbeats on vsock `port` every `interval`.
+    pub fn new(port: u32, interval: Duration) -> Self {
+        Self {
+            port,
+            interval,
+            missed_beats: 3,
+        }
+    }
+
+    /// Number of missed heartbeats after which the guest is considered unhealthy.
+    ///
+    /// Defaults to 3.
+    pub fn missed_beats(mut self, missed_beats: u32) -> Self {
+        self.missed_beats = missed_beats.max(1);
+        self
+    }
+
+    /// The vsock port heartbeats are received on.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// The expected interval between heartbeats.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+pub(crate) fn monitor(
+    vm_id: VmId,
+    listener: UnixListener,
+    policy: HeartbeatPolicy,
+    last_heartbeat: LastHeartbeat,
+    events: broadcast::Sender<MachineEvent>,
+) -> TaskHandle {
+    TaskHandle::new(tokio::spawn(run_monitor(
+        vm_id,
+        listener,
+        policy,
+        last_heartbeat,
+        events,
+    )))
+}
+
+#[instrument(skip_all, fields(vm_id = %vm_id))]
+async fn run_monitor(
+    vm_id: VmId,
+    listener: UnixListener,
+    policy: HeartbeatPolicy,
+    last_heartbeat: LastHeartbeat,
+    events: broadcast::Sender<MachineEvent>,
+) {
+    let timeout = policy.interval * policy.missed_beats;
+    let started = SystemTime::now();
+    let mut unhealthy = false;
+    let mut ticks = interval(policy.interval);
+    // Dropped (aborting the connections) along with the task.
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    connections.spawn(receive(stream, last_heartbeat.clone()));
+                }
+                Err(err) => warn!(error = %err, "Failed to accept heartbeat connection"),
+            },
+            Some(_) = connections.join_next() => (),
+            _ = ticks.tick() => {
+                let last = *last_heartbeat.lock().unwrap();
+                let silent_for = SystemTime::now()
+                    .duration_since(last.unwrap_or(started))
+                    .unwrap_or_default();
+                match (silent_for > timeout, unhealthy) {
+                    (true, false) => {
+                        warn!(?silent_for, "Guest missed heartbeats");
+                        unhealthy = true;
+                        let _ = events.send(MachineEvent::new(
+                            vm_id.clone(),
+                            MachineEventKind::Unhealthy {
+                                last_heartbeat: last,
+                            },
+                        ));
+                    }
+                    (false, true) => {
+                        debug!("Guest heartbeats resumed");
+                        unhealthy = false;
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+async fn receive(stream: UnixStream, last_heartbeat: LastHeartbeat) {
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(_)) = lines.next_line().await {
+        *last_heartbeat.lock().unwrap() = Some(SystemTime::now());
+    }
+}
@@ -0,0 +1,194 @@
+//! Async callbacks on machine lifecycle events.
+//!
+//! [`EventHooks`] runs callbacks for the events of a machine (see [`crate::Machine::run_hooks`])
+//! or of all the machines of an orchestrator (see [`crate::Orchestrator::run_hooks`]), without
+//! the caller having to manage its own event loop. Callbacks run concurrently, up to a maximum,
+//! and events wait for a free slot beyond it.
+
+use std::{fmt, future::Future, sync::Arc};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::{
+    sync::{broadcast, Semaphore},
+    task::JoinSet,
+};
+use tracing::warn;
+
+use crate::{
+    events::{MachineEvent, MachineEventKind},
+    task::TaskHandle,
+};
+
+type Callback = Arc<dyn Fn(MachineEvent) -> BoxFuture<'static, ()> + Send + Sync>;
+type Filter = fn(&MachineEventKind) -> bool;
+
+/// Callbacks on machine lifecycle events, see the [module documentation](self).
+#[derive(Clone)]
+pub struct EventHooks {
+    hooks: Vec<(Filter, Callback)>,
+    max_concurrency: usize,
+}
+
+impl fmt::Debug for EventHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventHooks")
+            .field("hooks", &self.hooks.len())
+            .field("max_concurrency", &self.max_concurrency)
+            .finish()
+    }
+}
+
+impl EventHooks {
+    /// Create hooks running at most `max_concurrency` callbacks at a time.
+    pub fn new(max_concurrency: usize) -> Self {
+        Self {
+            hooks: Vec::new(),
+            max_concurrency: max_concurrency.max(1),
+        }
+    }
+
+    /// Call `callback` on every event.
+    pub fn on_event<F, Fut>(self, callback: F) -> Self
+    where
+        F: Fn(MachineEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on(|_| true, callback)
+    }
+
+    /// Call `callback` when a machine is started.
+    pub fn on_started<F, Fut>(self, callback: F) -> Self
+    where
+        F: Fn(MachineEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on(|kind| matches!(kind, MachineEventKind::Started), callback)
+    }
+
+    /// Call `callback` when a machine is shut down, see [`MachineEventKind::Stopped`].
+    pub fn on_shutdown<F, Fut>(self, callback: F) -> Self
+    where
+        F: Fn(MachineEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on(
+            |kind| matches!(kind, MachineEventKind::Stopped { .. }),
+            callback,
+        )
+    }
+
+    /// Call `callback` when a machine crashes, see [`MachineEventKind::Crashed`].
+    pub fn on_crashed<F, Fut>(self, callback: F) -> Self
+    where
+        F: Fn(MachineEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on(
+            |kind| matches!(kind, MachineEventKind::Crashed { .. }),
+            callback,
+        )
+    }
+
+    /// Call `callback` when a guest misses heartbeats, see [`MachineEventKind::Unhealthy`].
+    pub fn on_unhealthy<F, Fut>(self, callback: F) -> Self
+    where
+        F: Fn(MachineEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on(
+            |kind| matches!(kind, MachineEventKind::Unhealthy { .. }),
+            callback,
+        )
+    }
+
+    /// Call `callback` on the events whose kind matches `filter`.
+    pub fn on<F, Fut>(mut self, filter: fn(&MachineEventKind) -> bool, callback: F) -> Self
+    where
+        F: Fn(MachineEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks
+            .push((filter, Arc::new(move |event| callback(event).boxed())));
+        self
+    }
+}
+
+/// Run `hooks` on the events of `events` until the returned handle is dropped.
+///
+/// Running callbacks are aborted along with the task.
+pub(crate) fn run(mut events: broadcast::Receiver<MachineEvent>, hooks: EventHooks) -> TaskHandle {
+    TaskHandle::new(tokio::spawn(async move {
+        let slots = Arc::new(Semaphore::new(hooks.max_concurrency));
+        let mut running = JoinSet::new();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Event hooks are lagging, events were skipped");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            for (filter, callback) in &hooks.hooks {
+                if !filter(&event.kind) {
+                    continue;
+                }
+                let slot = slots.clone().acquire_owned().await.expect("never closed");
+                let callback = callback(event.clone());
+                running.spawn(async move {
+                    callback.await;
+                    drop(slot);
+                });
+            }
+            // Reap the finished callbacks.
+            while running.try_join_next().is_some() {}
+        }
+        while running.join_next().await.is_some() {}
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{config::VmId, events};
+
+    #[tokio::test]
+    async fn callbacks() {
+        let crashes = Arc::new(AtomicUsize::new(0));
+        let all = Arc::new(AtomicUsize::new(0));
+        let (task_crashes, task_all) = (crashes.clone(), all.clone());
+        let hooks = EventHooks::new(1)
+            .on_crashed(move |_| {
+                let crashes = task_crashes.clone();
+                async move {
+                    crashes.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .on_event(move |_| {
+                let all = task_all.clone();
+                async move {
+                    all.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+
+        let sender = events::channel();
+        let handle = run(sender.subscribe(), hooks);
+        let vm_id = VmId::new("my-vm").unwrap();
+        for kind in [
+            MachineEventKind::Started,
+            MachineEventKind::Crashed {
+                reason: "gone".to_owned(),
+                exit_reason: None,
+            },
+        ] {
+            sender.send(MachineEvent::new(vm_id.clone(), kind)).unwrap();
+        }
+        drop(sender);
+        handle.join().await;
+
+        assert_eq!(crashes.load(Ordering::SeqCst), 1);
+        assert_eq!(all.load(Ordering::SeqCst), 2);
+    }
+}
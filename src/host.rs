@@ -0,0 +1,211 @@
+//! Host prerequisite checks.
+//!
+//! [`check`] verifies that the host can run a VM with a given configuration before anything is
+//! created, so that missing devices or binaries are reported as such rather than as an obscure
+//! jailer or Firecracker failure.
+//!
+//! The jailer creates the device nodes of the chroot (`/dev/kvm`, `/dev/net/tun`) from the host
+//! devices and gives their ownership to its uid and gid, so the host devices only need to exist.
+//! Firecracker emulates vsock devices in userspace over Unix sockets, so they need no host kernel
+//! module such as `vhost_vsock`.
+
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use sysinfo::{System, SystemExt};
+use tokio::task;
+use tracing::{debug, instrument};
+
+use crate::{config::Config, Error};
+
+const KVM_DEVICE: &str = "/dev/kvm";
+const TUN_DEVICE: &str = "/dev/net/tun";
+
+/// A host requirement of a VM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Requirement {
+    /// The KVM device exists.
+    Kvm,
+    /// The TUN/TAP device exists, for VMs with network interfaces.
+    Tun,
+    /// The jailer binary exists and is executable.
+    JailerBinary(PathBuf),
+    /// The Firecracker binary exists and is executable.
+    FirecrackerBinary(PathBuf),
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvm => write!(f, "KVM device `{KVM_DEVICE}`"),
+            Self::Tun => write!(f, "TUN/TAP device `{TUN_DEVICE}`"),
+            Self::JailerBinary(path) => write!(f, "jailer binary `{}`", path.display()),
+            Self::FirecrackerBinary(path) => write!(f, "Firecracker binary `{}`", path.display()),
+        }
+    }
+}
+
+/// The outcome of checking a [`Requirement`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    /// The requirement.
+    pub requirement: Requirement,
+    /// What's wrong, if the requirement isn't met.
+    pub problem: Option<String>,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.problem {
+            Some(problem) => write!(f, "{}: {problem}", self.requirement),
+            None => write!(f, "{}: ok", self.requirement),
+        }
+    }
+}
+
+/// The outcome of [`check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostReport {
+    /// The checks, in the order they were made.
+    pub checks: Vec<Check>,
+}
+
+impl HostReport {
+    /// If all the requirements are met.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|check| check.problem.is_none())
+    }
+
+    /// The checks of the requirements that aren't met.
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks.iter().filter(|check| check.problem.is_some())
+    }
+
+    /// Turn the report into an [`Error::HostRequirementsNotMet`] if any requirement isn't met.
+    pub fn into_result(self) -> Result<(), Error> {
+        if self.is_ok() {
+            return Ok(());
+        }
+
+        Err(Error::HostRequirementsNotMet(
+            self.failures().map(ToString::to_string).collect(),
+        ))
+    }
+}
+
+/// Check that the host can run a VM with `config`.
+#[instrument(skip_all, fields(vm_id = %config.vm_id()))]
+pub async fn check(config: &Config<'_>) -> HostReport {
+    let jailer = config.jailer();
+    let mut checks = vec![Check {
+        requirement: Requirement::Kvm,
+        problem: char_device_problem(Path::new(KVM_DEVICE)).await,
+    }];
+    if !config.network_interfaces().is_empty() {
+        checks.push(Check {
+            requirement: Requirement::Tun,
+            problem: char_device_problem(Path::new(TUN_DEVICE)).await,
+        });
+    }
+    let binaries = [
+        (
+            Requirement::JailerBinary(jailer.jailer_binary().to_owned()),
+            jailer.jailer_binary(),
+        ),
+        (
+            Requirement::FirecrackerBinary(jailer.exec_file().to_owned()),
+            jailer.exec_file(),
+        ),
+    ];
+    for (requirement, path) in binaries {
+        checks.push(Check {
+            requirement,
+            problem: executable_problem(path).await,
+        });
+    }
+    let report = HostReport { checks };
+    debug!(ok = report.is_ok(), "Host checked");
+
+    report
+}
+
+/// Check that the host has enough free memory and CPUs for the VM of `config`, with its
+/// resources multiplied by `overhead_factor`, see [`crate::config::Builder::admission_check`].
+pub(crate) async fn admit(config: &Config<'_>, overhead_factor: f64) -> Result<(), Error> {
+    let machine = config.machine_cfg();
+    let required_memory_mib = (machine.mem_size_mib() as f64 * overhead_factor).ceil() as u64;
+    let required_cpus = (machine.vcpu_count() as f64 * overhead_factor).ceil() as u64;
+    let (available_memory_mib, available_cpus) = task::spawn_blocking(|| {
+        let mut sys = System::new();
+        sys.refresh_memory();
+        let free_cpus = (available_cpus() as f64 - sys.load_average().one).max(0.0);
+
+        (sys.available_memory() >> 20, free_cpus.floor() as u64)
+    })
+    .await?;
+    debug!(
+        required_memory_mib,
+        available_memory_mib, required_cpus, available_cpus, "Checking host resources"
+    );
+
+    if required_memory_mib > available_memory_mib {
+        return Err(Error::InsufficientHostResources {
+            resource: "memory (MiB)",
+            required: required_memory_mib,
+            available: available_memory_mib,
+        });
+    }
+    if required_cpus > available_cpus {
+        return Err(Error::InsufficientHostResources {
+            resource: "CPUs",
+            required: required_cpus,
+            available: available_cpus,
+        });
+    }
+
+    Ok(())
+}
+
+/// The total memory of the host, in MiB, and the number of CPUs available to this process.
+pub(crate) fn total_resources() -> (u64, usize) {
+    let mut sys = System::new();
+    sys.refresh_memory();
+
+    (sys.total_memory() >> 20, available_cpus())
+}
+
+fn available_cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+async fn char_device_problem(path: &Path) -> Option<String> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_char_device() => None,
+        Ok(_) => Some("not a character device".to_owned()),
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Bare program names, e.g the default jailer binary, are looked up in `PATH`.
+async fn executable_problem(path: &Path) -> Option<String> {
+    let is_bare_name = path.parent() == Some(Path::new(""));
+    let path = match std::env::var_os("PATH") {
+        Some(dirs) if is_bare_name => std::env::split_paths(&dirs)
+            .map(|dir| dir.join(path))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| path.to_owned()),
+        _ => path.to_owned(),
+    };
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if !metadata.is_file() => Some("not a file".to_owned()),
+        Ok(metadata) if metadata.permissions().mode() & 0o111 == 0 => {
+            Some("not executable".to_owned())
+        }
+        Ok(_) => None,
+        Err(e) => Some(e.to_string()),
+    }
+}
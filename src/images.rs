@@ -0,0 +1,396 @@
+//! Utilities to manipulate drive images on the host.
+
+use std::{
+    fmt, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{io::AsyncReadExt, process::Command};
+use tracing::{debug, info, instrument, trace, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, Drive},
+    fs::ChrootFs,
+    machine::{run_command, run_command_with},
+    spawner::ProcessSpawner,
+    Error,
+};
+
+/// Offset of the ext2/3/4 superblock magic.
+const EXT_MAGIC_OFFSET: usize = 0x438;
+const EXT_MAGIC: &[u8] = b"\x53\xef";
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+/// Offset of the btrfs superblock magic.
+const BTRFS_MAGIC_OFFSET: usize = 0x10040;
+const BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+
+/// A filesystem type, as detected from its superblock by [`detect_filesystem`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesystemType {
+    /// ext2, ext3 or ext4.
+    Ext4,
+    /// SquashFS.
+    Squashfs,
+    /// Btrfs.
+    Btrfs,
+}
+
+impl FilesystemType {
+    /// If the filesystem can only be mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        *self == Self::Squashfs
+    }
+}
+
+impl fmt::Display for FilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Ext4 => "ext4",
+            Self::Squashfs => "squashfs",
+            Self::Btrfs => "btrfs",
+        })
+    }
+}
+
+/// Detect the filesystem of the image at `path` from its superblock, if recognized.
+pub async fn detect_filesystem(path: &Path) -> Result<Option<FilesystemType>, Error> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut header = Vec::new();
+    file.take((BTRFS_MAGIC_OFFSET + BTRFS_MAGIC.len()) as u64)
+        .read_to_end(&mut header)
+        .await?;
+    let magic_at =
+        |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+    Ok(if magic_at(0, SQUASHFS_MAGIC) {
+        Some(FilesystemType::Squashfs)
+    } else if magic_at(BTRFS_MAGIC_OFFSET, BTRFS_MAGIC) {
+        Some(FilesystemType::Btrfs)
+    } else if magic_at(EXT_MAGIC_OFFSET, EXT_MAGIC) {
+        Some(FilesystemType::Ext4)
+    } else {
+        None
+    })
+}
+
+/// Check that the image of the root `drive` contains a recognized filesystem, and that the drive
+/// is read-only if the filesystem is.
+///
+/// Drives with a partition table (see [`crate::config::DriveBuilder::part_uuid`]) aren't checked.
+#[instrument(skip_all, fields(drive_id = drive.drive_id()))]
+pub async fn check_root_filesystem(drive: &Drive<'_>) -> Result<(), Error> {
+    if drive.part_uuid().is_some() {
+        return Ok(());
+    }
+
+    let filesystem = detect_filesystem(drive.src_path()).await?.ok_or_else(|| {
+        Error::UnrecognizedFilesystem {
+            drive_id: drive.drive_id().to_owned(),
+            path: drive.src_path().to_owned(),
+        }
+    })?;
+    if filesystem.is_read_only() && !drive.is_read_only() {
+        return Err(Error::ReadOnlyFilesystem {
+            drive_id: drive.drive_id().to_owned(),
+            filesystem,
+        });
+    }
+    debug!(%filesystem, "Root filesystem checked");
+
+    Ok(())
+}
+
+/// Resize the ext4 filesystem image at `path` to `new_size` bytes.
+///
+/// The filesystem is checked with `e2fsck` and resized with `resize2fs`, so both need to be
+/// available on the host. Growing extends the file before resizing the filesystem, shrinking
+/// truncates it afterwards. `new_size` should be a multiple of the filesystem block size.
+///
+/// The commands, including the `truncate` changing the file size, are run through the spawner of
+/// `config` (see [`crate::config::Builder::process_spawner`]). The image must not be in use, e.g by a running
+/// VM. Use [`crate::Machine::resize_drive`] for drives of running VMs.
+#[instrument(skip_all, fields(path = %path.display(), new_size))]
+pub async fn resize_ext4(config: &Config<'_>, path: &Path, new_size: u64) -> Result<(), Error> {
+    let size = config.fs().file_len(path).await?;
+    info!(size, "Resizing ext4 image");
+
+    // `resize2fs` refuses to work on a filesystem that wasn't checked since it was last mounted.
+    // Exit code 1 means errors were found and corrected.
+    match run_command(config, Command::new("e2fsck").args(["-f", "-p"]).arg(path)).await {
+        Err(Error::CommandFailed { exit_status, .. }) if exit_status.code() == Some(1) => {}
+        res => res?,
+    }
+
+    let fs_size = format!("{}K", new_size / 1024);
+    let mut resize2fs = Command::new("resize2fs");
+    resize2fs.arg(path).arg(&fs_size);
+    if new_size >= size {
+        set_len(config, path, new_size).await?;
+        run_command(config, &mut resize2fs).await?;
+    } else {
+        run_command(config, &mut resize2fs).await?;
+        set_len(config, path, new_size).await?;
+    }
+    trace!("Image resized successfully");
+
+    Ok(())
+}
+
+/// A drive image mounted on the host, see [`mount`].
+///
+/// Unmount it with [`MountedImage::unmount`]. If it's dropped while still mounted, it's unmounted
+/// in the background, when dropped in a Tokio runtime, and only left mounted with a warning
+/// otherwise.
+#[derive(Debug)]
+pub struct MountedImage {
+    image_path: PathBuf,
+    mount_point: PathBuf,
+    mounted: bool,
+    spawner: Arc<dyn ProcessSpawner>,
+    fs: Arc<dyn ChrootFs>,
+}
+
+impl MountedImage {
+    /// The path of the mounted image.
+    pub fn image_path(&self) -> &Path {
+        &self.image_path
+    }
+
+    /// The directory the image is mounted on.
+    pub fn mount_point(&self) -> &Path {
+        &self.mount_point
+    }
+
+    /// Unmount the image and remove the mount point.
+    #[instrument(skip_all, fields(path = %self.image_path.display()))]
+    pub async fn unmount(mut self) -> Result<(), Error> {
+        let mut umount = Command::new("umount");
+        umount.arg(&self.mount_point);
+        run_command_with(self.spawner.as_ref(), &mut umount).await?;
+        self.mounted = false;
+        self.fs.remove_dir_all(&self.mount_point).await?;
+        debug!("Image unmounted");
+
+        Ok(())
+    }
+}
+
+impl Drop for MountedImage {
+    fn drop(&mut self) {
+        if !self.mounted {
+            return;
+        }
+
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!(
+                "Image dropped outside of a runtime, leaving it mounted on `{}`",
+                self.mount_point.display()
+            );
+            return;
+        };
+        warn!(
+            "Image dropped while mounted, unmounting `{}` in the background",
+            self.mount_point.display()
+        );
+        let mount_point = self.mount_point.clone();
+        let spawner = self.spawner.clone();
+        let fs = self.fs.clone();
+        runtime.spawn(async move {
+            let mut umount = Command::new("umount");
+            umount.arg(&mount_point);
+            if let Err(err) = run_command_with(spawner.as_ref(), &mut umount).await {
+                warn!(error = %err, "Failed to unmount image");
+                return;
+            }
+            if let Err(err) = fs.remove_dir_all(&mount_point).await {
+                warn!(error = %err, "Failed to remove mount point");
+            }
+        });
+    }
+}
+
+/// Mount the filesystem image at `path` on a new directory under the temporary directory, through
+/// a loop device, e.g to add files to a drive before boot.
+///
+/// Read-only filesystems (see [`FilesystemType::is_read_only`]) are mounted read-only. The image
+/// must not be in use, e.g by a running VM. Mounting needs root privileges and `mount` on the
+/// host. `mount` and `umount` are run through the spawner of `config` (see
+/// [`crate::config::Builder::process_spawner`]), and the mount point is created and removed
+/// through its filesystem (see [`crate::config::Builder::chroot_fs`]).
+#[instrument(skip_all, fields(path = %path.as_ref().display()))]
+pub async fn mount<P>(config: &Config<'_>, path: P) -> Result<MountedImage, Error>
+where
+    P: AsRef<Path>,
+{
+    let image_path = path.as_ref().to_owned();
+    let read_only = detect_filesystem(&image_path)
+        .await?
+        .is_some_and(|filesystem| filesystem.is_read_only());
+    let mount_point = std::env::temp_dir().join(format!("firec-mount-{}", Uuid::new_v4()));
+    config.fs().create_dir_all(&mount_point).await?;
+
+    let options = if read_only { "loop,ro" } else { "loop" };
+    let mut cmd = Command::new("mount");
+    cmd.args(["-o", options]).arg(&image_path).arg(&mount_point);
+    if let Err(e) = run_command(config, &mut cmd).await {
+        if let Err(err) = config.fs().remove_dir_all(&mount_point).await {
+            warn!(error = %err, "Failed to remove mount point");
+        }
+        return Err(e);
+    }
+    debug!(mount_point = %mount_point.display(), "Image mounted");
+
+    Ok(MountedImage {
+        image_path,
+        mount_point,
+        mounted: true,
+        spawner: config.spawner.clone(),
+        fs: config.fs.clone(),
+    })
+}
+
+/// Write files into the filesystem image at `image`, e.g secrets or configuration when neither
+/// MMDS nor cloud-init are available.
+///
+/// `files` are `(path in the image, contents)` pairs. Missing parent directories are created.
+/// Existing files are overwritten, new files are owned by root with mode `0600`. Paths going
+/// through symbolic links in the image are refused, as they could point to the host.
+///
+/// The image is mounted with [`mount`], so the same requirements apply. The files are written
+/// through the filesystem of `config`.
+#[instrument(skip_all, fields(image = %image.as_ref().display()))]
+pub async fn inject<P, I, G, C>(config: &Config<'_>, image: P, files: I) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = (G, C)>,
+    G: AsRef<Path>,
+    C: AsRef<[u8]>,
+{
+    let mounted = mount(config, image).await?;
+    for (guest_path, contents) in files {
+        let guest_path = guest_path.as_ref();
+        let path = host_path(config, mounted.mount_point(), guest_path).await?;
+        trace!("Injecting `{}`", guest_path.display());
+        let fs = config.fs();
+        // New files are restricted before their contents are written.
+        if !fs.exists(&path).await? {
+            fs.write(&path, Vec::new()).await?;
+            fs.set_owner(&path, 0, 0, 0o600).await?;
+        }
+        fs.write(&path, contents.as_ref().to_vec()).await?;
+    }
+    mounted.unmount().await?;
+    info!("Files injected");
+
+    Ok(())
+}
+
+/// The host path of `guest_path` in the image mounted on `mount_point`, creating its missing
+/// parent directories.
+async fn host_path(
+    config: &Config<'_>,
+    mount_point: &Path,
+    guest_path: &Path,
+) -> Result<PathBuf, Error> {
+    let invalid = || Error::InvalidInjectionPath(guest_path.to_owned());
+    let components: Vec<_> = guest_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(Ok(name)),
+            Component::ParentDir => Some(Err(invalid())),
+            _ => None,
+        })
+        .collect::<Result<_, _>>()?;
+    let (file_name, parents) = components.split_last().ok_or_else(invalid)?;
+
+    let mut path = mount_point.to_owned();
+    for name in parents {
+        path.push(name);
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(invalid()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                config.fs().create_dir_all(&path).await?
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    path.push(file_name);
+    match tokio::fs::symlink_metadata(&path).await {
+        Ok(metadata) if !metadata.is_file() => Err(invalid()),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(path),
+    }
+}
+
+/// Set the length of the file at `path`, leaving any new space sparse.
+pub(crate) async fn set_len(config: &Config<'_>, path: &Path, new_size: u64) -> Result<(), Error> {
+    run_command(
+        config,
+        Command::new("truncate")
+            .arg("-s")
+            .arg(new_size.to_string())
+            .arg(path),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn filesystem_detection() {
+        let dir = std::env::temp_dir().join(format!("firec-images-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let mut ext4 = vec![0; 0x1000];
+        ext4[EXT_MAGIC_OFFSET..EXT_MAGIC_OFFSET + 2].copy_from_slice(EXT_MAGIC);
+        let images = [
+            ("rootfs.ext4", ext4, Some(FilesystemType::Ext4)),
+            (
+                "rootfs.squashfs",
+                b"hsqs".to_vec(),
+                Some(FilesystemType::Squashfs),
+            ),
+            ("vmlinux", b"\x7fELF".to_vec(), None),
+        ];
+        for (name, content, filesystem) in images {
+            let path = dir.join(name);
+            tokio::fs::write(&path, content).await.unwrap();
+            assert_eq!(detect_filesystem(&path).await.unwrap(), filesystem);
+        }
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn injection_paths() {
+        let dir = std::env::temp_dir().join(format!("firec-images-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::symlink("/etc", dir.join("etc")).await.unwrap();
+        let config = Config::builder(None, Path::new("/tmp/vmlinux"))
+            .jailer_cfg()
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            host_path(&config, &dir, Path::new("/root/.ssh/authorized_keys"))
+                .await
+                .unwrap(),
+            dir.join("root/.ssh/authorized_keys")
+        );
+        assert!(dir.join("root/.ssh").is_dir());
+        for invalid in ["/etc/hostname", "/root/../etc/hostname", "/root/.ssh", "/"] {
+            assert!(matches!(
+                host_path(&config, &dir, Path::new(invalid)).await,
+                Err(Error::InvalidInjectionPath(_))
+            ));
+        }
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+//! Minimal initramfs images, for VMs booting an appliance without a root drive.
+//!
+//! [`Initramfs`] assembles a gzip-compressed `newc` cpio archive from host files and directories
+//! and an `/init` script, to use as the initrd of a VM (see
+//! [`crate::config::Builder::initrd_path`]). The guest kernel must support initramfs, and the
+//! kernel command line should not set `root=`.
+//!
+//! The archive always contains the `/dev`, `/proc` and `/sys` mount points and the
+//! `/dev/console` device, which the kernel opens before running `/init`. All entries are owned by
+//! root and have a zero modification time, so the same inputs give the same archive.
+
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use tokio::task;
+use tracing::{debug, instrument};
+
+use crate::Error;
+
+/// The placeholder replaced by the command in init script templates, see
+/// [`Initramfs::init_script`].
+pub const COMMAND_PLACEHOLDER: &str = "{command}";
+
+/// An init script template mounting the pseudo filesystems before running the command.
+pub const DEFAULT_INIT_TEMPLATE: &str = "#!/bin/sh
+mount -t devtmpfs devtmpfs /dev
+mount -t proc proc /proc
+mount -t sysfs sysfs /sys
+exec {command}
+";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+
+/// Builder of an initramfs image, see the [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct Initramfs {
+    /// Host paths and their paths in the archive.
+    entries: Vec<(PathBuf, PathBuf)>,
+    init: Option<String>,
+}
+
+impl Initramfs {
+    /// Create an empty initramfs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an initramfs with the content of the host directory `dir` at its root.
+    pub fn from_dir<P>(dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self::new().add(dir, "/")
+    }
+
+    /// Add the host file or directory `src`, recursively, as `dest` in the archive.
+    ///
+    /// Symbolic links are added as is, other special files are skipped. Later entries replace
+    /// earlier ones at the same path.
+    pub fn add<S, D>(mut self, src: S, dest: D) -> Self
+    where
+        S: Into<PathBuf>,
+        D: AsRef<Path>,
+    {
+        self.entries.push((src.into(), archive_path(dest.as_ref())));
+        self
+    }
+
+    /// Set the `/init` script to `template`, with [`COMMAND_PLACEHOLDER`] replaced by `command`.
+    ///
+    /// See [`DEFAULT_INIT_TEMPLATE`]. Without an init script, `/init` must be one of the added
+    /// files.
+    pub fn init_script(mut self, template: &str, command: &str) -> Self {
+        self.init = Some(template.replace(COMMAND_PLACEHOLDER, command));
+        self
+    }
+
+    /// Write the compressed archive to `path`.
+    #[instrument(skip_all, fields(path = %path.as_ref().display()))]
+    pub async fn write<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let initramfs = self.clone();
+        let path = path.as_ref().to_owned();
+        task::spawn_blocking(move || initramfs.write_blocking(&path)).await??;
+        debug!("Initramfs written");
+
+        Ok(())
+    }
+
+    fn write_blocking(&self, path: &Path) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut archive = CpioWriter::new(GzEncoder::new(file, Compression::default()));
+
+        for dir in ["dev", "proc", "sys"] {
+            archive.dir(Path::new(dir), 0o755)?;
+        }
+        archive.entry(Path::new("dev/console"), S_IFCHR | 0o600, (5, 1), 0)?;
+        for (src, dest) in &self.entries {
+            archive.add(src, dest)?;
+        }
+        if let Some(init) = &self.init {
+            archive.entry(Path::new("init"), S_IFREG | 0o755, (0, 0), init.len())?;
+            archive.data(init.as_bytes())?;
+        }
+
+        archive.finish()?.finish()?.into_inner()?.sync_all()
+    }
+}
+
+/// The path of `path` in the archive: relative, without `.` or `..` components.
+fn archive_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Writes a `newc` cpio archive.
+struct CpioWriter<W> {
+    writer: W,
+    ino: u32,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl<W: Write> CpioWriter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            ino: 0,
+            dirs: BTreeSet::new(),
+        }
+    }
+
+    /// Add the host file or directory `src` as `dest`, recursively.
+    fn add(&mut self, src: &Path, dest: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(src)?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            if dest.as_os_str().is_empty() {
+                self.dirs.insert(PathBuf::new());
+            } else {
+                self.dir(dest, mode)?;
+            }
+            let mut children = fs::read_dir(src)?
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            children.sort();
+            for name in children {
+                self.add(&src.join(&name), &dest.join(&name))?;
+            }
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(src)?;
+            let target = target.as_os_str().as_bytes();
+            self.parents(dest)?;
+            self.entry(dest, S_IFLNK | 0o777, (0, 0), target.len())?;
+            self.data(target)?;
+        } else if file_type.is_file() {
+            self.parents(dest)?;
+            self.entry(dest, S_IFREG | mode, (0, 0), metadata.len() as usize)?;
+            let copied = io::copy(&mut File::open(src)?.take(metadata.len()), &mut self.writer)?;
+            if copied != metadata.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("`{}` was truncated while archived", src.display()),
+                ));
+            }
+            self.pad(copied as usize)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the directory `path` and its parents, unless already added.
+    fn dir(&mut self, path: &Path, mode: u32) -> io::Result<()> {
+        self.parents(path)?;
+        if self.dirs.insert(path.to_owned()) {
+            self.entry(path, S_IFDIR | mode, (0, 0), 0)?;
+        }
+
+        Ok(())
+    }
+
+    fn parents(&mut self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => self.dir(parent, 0o755),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the header of an entry, to be followed by `size` bytes of data.
+    fn entry(&mut self, path: &Path, mode: u32, rdev: (u32, u32), size: usize) -> io::Result<()> {
+        self.ino += 1;
+        let name = path.as_os_str().as_bytes();
+        let nlink = if mode & S_IFMT == S_IFDIR { 2 } else { 1 };
+        let size = u32::try_from(size).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}` is too large for a cpio archive", path.display()),
+            )
+        })?;
+        let fields = [
+            self.ino,
+            mode,
+            0, // uid
+            0, // gid
+            nlink,
+            0, // mtime
+            size,
+            0, // devmajor
+            0, // devminor
+            rdev.0,
+            rdev.1,
+            name.len() as u32 + 1,
+            0, // check
+        ];
+        let mut header = String::from("070701");
+        for field in fields {
+            header.push_str(&format!("{field:08x}"));
+        }
+        self.writer.write_all(header.as_bytes())?;
+        self.writer.write_all(name)?;
+        self.writer.write_all(&[0])?;
+        self.pad(header.len() + name.len() + 1)
+    }
+
+    fn data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.pad(data.len())
+    }
+
+    /// Pad `len` written bytes to a multiple of 4.
+    fn pad(&mut self, len: usize) -> io::Result<()> {
+        self.writer.write_all(&[0; 3][..(4 - len % 4) % 4])
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.entry(Path::new("TRAILER!!!"), 0, (0, 0), 0)?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn archive() {
+        let dir = std::env::temp_dir().join(format!("firec-initramfs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("rootfs/bin")).unwrap();
+        fs::write(dir.join("rootfs/bin/app"), "app").unwrap();
+        let path = dir.join("initramfs.cpio.gz");
+
+        Initramfs::from_dir(dir.join("rootfs"))
+            .add(dir.join("rootfs/bin/app"), "/opt/app/app")
+            .init_script(DEFAULT_INIT_TEMPLATE, "/bin/app")
+            .write(&path)
+            .await
+            .unwrap();
+
+        let mut archive = Vec::new();
+        GzDecoder::new(File::open(&path).unwrap())
+            .read_to_end(&mut archive)
+            .unwrap();
+        assert_eq!(archive.len() % 4, 0);
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while offset < archive.len() {
+            let header = std::str::from_utf8(&archive[offset..offset + 110]).unwrap();
+            assert_eq!(&header[..6], "070701");
+            let field = |i: usize| usize::from_str_radix(&header[6 + i * 8..14 + i * 8], 16);
+            let (size, name_size) = (field(6).unwrap(), field(11).unwrap());
+            let name = &archive[offset + 110..offset + 110 + name_size - 1];
+            names.push(std::str::from_utf8(name).unwrap().to_owned());
+            offset += (110 + name_size).next_multiple_of(4) + size.next_multiple_of(4);
+        }
+        assert_eq!(
+            names,
+            [
+                "dev",
+                "proc",
+                "sys",
+                "dev/console",
+                "bin",
+                "bin/app",
+                "opt",
+                "opt/app",
+                "opt/app/app",
+                "init",
+                "TRAILER!!!"
+            ]
+        );
+        assert!(String::from_utf8_lossy(&archive).contains("exec /bin/app\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
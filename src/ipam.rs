@@ -0,0 +1,301 @@
+//! Guest IP address management.
+//!
+//! [`Ipam`] hands out guest addresses from a set of host subnets, one [`Lease`] per VM, and
+//! persists the leases in a state directory. The first host address of each subnet is reserved
+//! for the gateway (the host side of the TAP device).
+//!
+//! A lease feeds the guest kernel command line (see [`Lease::kernel_ip_arg`]) and the host NAT
+//! configuration (see [`Lease::nat`]).
+
+use std::{
+    borrow::Cow,
+    fmt,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, instrument};
+
+use crate::{
+    config::{Firewall, Nat, VmId},
+    state, Error,
+};
+
+/// File name of the leases, in the state directory.
+pub const LEASES_FILE_NAME: &str = "ipam.json";
+
+/// An IPv4 subnet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// Create a subnet of `network/prefix_len`.
+    ///
+    /// The host bits of `network` are ignored. The subnet must have room for the gateway and at
+    /// least one guest, so `prefix_len` must be at most 30.
+    pub fn new(network: Ipv4Addr, prefix_len: u8) -> Result<Self, Error> {
+        if prefix_len > 30 {
+            return Err(Error::InvalidSubnet(format!("{network}/{prefix_len}")));
+        }
+        Ok(Self {
+            network: Ipv4Addr::from(u32::from(network) & mask(prefix_len)),
+            prefix_len,
+        })
+    }
+
+    /// The network address.
+    pub fn network(&self) -> Ipv4Addr {
+        self.network
+    }
+
+    /// The length of the network prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// The netmask.
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(mask(self.prefix_len))
+    }
+
+    /// The gateway address, i.e the first host address.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network) + 1)
+    }
+
+    /// If `addr` belongs to the subnet.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & u32::from(self.netmask()) == u32::from(self.network)
+    }
+
+    /// The addresses available to guests.
+    fn guest_addresses(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let network = u32::from(self.network);
+        let broadcast = network | !u32::from(self.netmask());
+        (network + 2..broadcast).map(Ipv4Addr::from)
+    }
+}
+
+/// The netmask of a `prefix_len` prefix, as an integer.
+fn mask(prefix_len: u8) -> u32 {
+    // Shifting by 32 bits overflows for `/0`.
+    u32::MAX
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidSubnet(s.to_owned());
+        let (network, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+
+        Self::new(
+            network.parse().map_err(|_| invalid())?,
+            prefix_len.parse().map_err(|_| invalid())?,
+        )
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Subnet> for String {
+    fn from(subnet: Subnet) -> Self {
+        subnet.to_string()
+    }
+}
+
+/// The guest address of a VM.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// The ID of the VM.
+    pub vm_id: VmId,
+    /// The guest address.
+    pub ip: Ipv4Addr,
+    /// The subnet the address belongs to.
+    pub subnet: Subnet,
+}
+
+impl Lease {
+    /// The gateway address of the guest.
+    pub fn gateway(&self) -> Ipv4Addr {
+        self.subnet.gateway()
+    }
+
+    /// The `ip=` kernel argument statically configuring `guest_device` (e.g `eth0`) with the
+    /// lease, to add to [`crate::config::Builder::kernel_args`].
+    pub fn kernel_ip_arg(&self, guest_device: &str) -> String {
+        format!(
+            "ip={}::{}:{}::{guest_device}:off",
+            self.ip,
+            self.gateway(),
+            self.subnet.netmask()
+        )
+    }
+
+    /// NAT for the guest subnet out of `host_interface`, see [`crate::config::Builder::nat`].
+    ///
+    /// Port forwards to [`Lease::ip`] can then be added.
+    pub fn nat<'n, I>(&self, firewall: Firewall, host_interface: I) -> Nat<'n>
+    where
+        I: Into<Cow<'n, str>>,
+    {
+        Nat::new(firewall, self.subnet.to_string(), host_interface)
+    }
+}
+
+/// Allocates guest addresses, see the [module documentation](self).
+///
+/// Allocations are serialized within a process. Only a single process should manage a state
+/// directory.
+#[derive(Debug)]
+pub struct Ipam {
+    subnets: Vec<Subnet>,
+    leases_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Ipam {
+    /// Allocate addresses from `subnets`, in order, keeping the leases in `state_dir`.
+    pub fn new<I, P>(subnets: I, state_dir: P) -> Self
+    where
+        I: IntoIterator<Item = Subnet>,
+        P: AsRef<Path>,
+    {
+        Self {
+            subnets: subnets.into_iter().collect(),
+            leases_path: state_dir.as_ref().join(LEASES_FILE_NAME),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Allocate an address to `vm_id`.
+    ///
+    /// Returns the existing lease if the VM already has one.
+    #[instrument(skip_all, fields(vm_id = %vm_id))]
+    pub async fn allocate(&self, vm_id: &VmId) -> Result<Lease, Error> {
+        let _guard = self.lock.lock().await;
+        let mut leases: Vec<Lease> = state::read(&self.leases_path).await?;
+        if let Some(lease) = leases.iter().find(|lease| &lease.vm_id == vm_id) {
+            return Ok(lease.clone());
+        }
+
+        let lease = allocate(&self.subnets, &leases, vm_id).ok_or(Error::AddressesExhausted)?;
+        debug!(ip = %lease.ip, "Allocated guest address");
+        leases.push(lease.clone());
+        state::write(&self.leases_path, &leases).await?;
+
+        Ok(lease)
+    }
+
+    /// Release the address of `vm_id`, returning its lease if it had one.
+    #[instrument(skip_all, fields(vm_id = %vm_id))]
+    pub async fn release(&self, vm_id: &VmId) -> Result<Option<Lease>, Error> {
+        let _guard = self.lock.lock().await;
+        let mut leases: Vec<Lease> = state::read(&self.leases_path).await?;
+        let lease = match leases.iter().position(|lease| &lease.vm_id == vm_id) {
+            Some(i) => leases.remove(i),
+            None => return Ok(None),
+        };
+        debug!(ip = %lease.ip, "Released guest address");
+        state::write(&self.leases_path, &leases).await?;
+
+        Ok(Some(lease))
+    }
+
+    /// The current leases.
+    pub async fn leases(&self) -> Result<Vec<Lease>, Error> {
+        let _guard = self.lock.lock().await;
+        state::read(&self.leases_path).await
+    }
+}
+
+/// Pick the first free address in `subnets`.
+fn allocate(subnets: &[Subnet], leases: &[Lease], vm_id: &VmId) -> Option<Lease> {
+    subnets.iter().find_map(|subnet| {
+        subnet
+            .guest_addresses()
+            .find(|ip| leases.iter().all(|lease| lease.ip != *ip))
+            .map(|ip| Lease {
+                vm_id: vm_id.clone(),
+                ip,
+                subnet: *subnet,
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocation() {
+        let subnets = [
+            "172.16.0.1/30".parse::<Subnet>().unwrap(),
+            "172.16.1.0/29".parse().unwrap(),
+        ];
+        assert_eq!(subnets[0].to_string(), "172.16.0.0/30");
+        assert!("172.16.0.0/31".parse::<Subnet>().is_err());
+
+        let mut leases = Vec::new();
+        for i in 0..6 {
+            let vm_id = VmId::new(format!("vm{i}")).unwrap();
+            leases.push(allocate(&subnets, &leases, &vm_id).unwrap());
+        }
+        let ips: Vec<_> = leases.iter().map(|lease| lease.ip.to_string()).collect();
+        assert_eq!(
+            ips,
+            [
+                "172.16.0.2",
+                "172.16.1.2",
+                "172.16.1.3",
+                "172.16.1.4",
+                "172.16.1.5",
+                "172.16.1.6"
+            ]
+        );
+        assert!(allocate(&subnets, &leases, &VmId::new("vm6").unwrap()).is_none());
+
+        // Released addresses are reused.
+        leases.remove(2);
+        let lease = allocate(&subnets, &leases, &VmId::new("vm6").unwrap()).unwrap();
+        assert_eq!(lease.ip, Ipv4Addr::new(172, 16, 1, 3));
+        assert_eq!(
+            lease.kernel_ip_arg("eth0"),
+            "ip=172.16.1.3::172.16.1.1:255.255.255.248::eth0:off"
+        );
+    }
+
+    #[test]
+    fn netmasks() {
+        let all = "10.1.2.3/0".parse::<Subnet>().unwrap();
+        assert_eq!(all.network(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(all.netmask(), Ipv4Addr::UNSPECIFIED);
+        assert!(all.contains(Ipv4Addr::new(192, 168, 0, 1)));
+
+        let subnet = "10.1.2.3/8".parse::<Subnet>().unwrap();
+        assert_eq!(subnet.network(), Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(subnet.netmask(), Ipv4Addr::new(255, 0, 0, 0));
+        assert!(!subnet.contains(Ipv4Addr::new(11, 0, 0, 1)));
+    }
+}
@@ -0,0 +1,193 @@
+//! Kernel image format validation.
+//!
+//! Firecracker boots uncompressed kernels only: an ELF `vmlinux` on x86_64 and an `Image` on
+//! aarch64, and fails with an opaque error otherwise. [`crate::Machine::create`] checks the
+//! format of the kernel image before copying it to the chroot and, if
+//! [`crate::config::Builder::decompress_kernel`] is set, decompresses gzip-compressed images
+//! (`Image.gz`, and `bzImage`/`vmlinuz` with a gzip payload).
+
+use std::{
+    fmt,
+    io::{self, Read},
+    path::Path,
+};
+
+use flate2::read::GzDecoder;
+use tokio::io::AsyncReadExt;
+
+use crate::{config::Arch, Error};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b\x08";
+/// Magic of arm64 `Image` files, at [`ARM64_MAGIC_OFFSET`].
+const ARM64_MAGIC: &[u8] = b"ARM\x64";
+const ARM64_MAGIC_OFFSET: usize = 0x38;
+/// Magic of the x86 boot protocol header of `bzImage` files, at [`BZIMAGE_MAGIC_OFFSET`].
+const BZIMAGE_MAGIC: &[u8] = b"HdrS";
+const BZIMAGE_MAGIC_OFFSET: usize = 0x202;
+/// Length of the header holding the magics.
+const HEADER_LEN: usize = BZIMAGE_MAGIC_OFFSET + BZIMAGE_MAGIC.len();
+
+/// The format of a kernel image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// An uncompressed ELF `vmlinux`.
+    Elf,
+    /// An uncompressed arm64 `Image`.
+    Arm64Image,
+    /// A gzip-compressed image, e.g `Image.gz`.
+    Gzip,
+    /// An x86 `bzImage`, e.g `vmlinuz`.
+    BzImage,
+    /// Anything else.
+    Unknown,
+}
+
+impl KernelFormat {
+    /// Detect the format of the kernel `image`.
+    pub fn detect(image: &[u8]) -> Self {
+        let magic_at =
+            |offset: usize, magic: &[u8]| image.get(offset..offset + magic.len()) == Some(magic);
+
+        if magic_at(0, ELF_MAGIC) {
+            Self::Elf
+        } else if magic_at(ARM64_MAGIC_OFFSET, ARM64_MAGIC) {
+            Self::Arm64Image
+        } else if magic_at(0, GZIP_MAGIC) {
+            Self::Gzip
+        } else if magic_at(BZIMAGE_MAGIC_OFFSET, BZIMAGE_MAGIC) {
+            Self::BzImage
+        } else {
+            Self::Unknown
+        }
+    }
+
+    /// If Firecracker can boot an image of this format on `arch`.
+    pub fn is_bootable(&self, arch: Arch) -> bool {
+        matches!(
+            (arch, self),
+            (Arch::X86_64, Self::Elf) | (Arch::Aarch64, Self::Arm64Image)
+        )
+    }
+}
+
+impl fmt::Display for KernelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Elf => "ELF",
+            Self::Arm64Image => "arm64 Image",
+            Self::Gzip => "gzip",
+            Self::BzImage => "bzImage",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// Read the header of the kernel image at `path`, enough for [`KernelFormat::detect`] and
+/// [`validate`].
+pub(crate) async fn read_header(path: &Path) -> io::Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut header = Vec::new();
+    file.take(HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .await?;
+
+    Ok(header)
+}
+
+/// Check that Firecracker can boot the kernel `image` at `path` on `arch`.
+pub fn validate(path: &Path, image: &[u8], arch: Arch) -> Result<(), Error> {
+    let format = KernelFormat::detect(image);
+    if format.is_bootable(arch) {
+        return Ok(());
+    }
+
+    Err(invalid(
+        path,
+        match format {
+            KernelFormat::Gzip | KernelFormat::BzImage => {
+                format!("{format} images must be decompressed to boot on {arch}")
+            }
+            _ => format!("{format} images can't boot on {arch}"),
+        },
+    ))
+}
+
+/// Decompress the kernel `image` at `path`, so that it can boot on `arch`.
+///
+/// Bootable images are returned as is. Only gzip compression is supported: `bzImage` files
+/// compressed otherwise (e.g with zstd or xz) must be decompressed beforehand, e.g with the
+/// kernel's `extract-vmlinux` script.
+pub fn decompress(path: &Path, image: Vec<u8>, arch: Arch) -> Result<Vec<u8>, Error> {
+    let decompressed = match KernelFormat::detect(&image) {
+        format if format.is_bootable(arch) => return Ok(image),
+        KernelFormat::Gzip => gunzip(&image)
+            .map_err(|e| invalid(path, format!("failed to decompress gzip image: {e}")))?,
+        // The compressed kernel is embedded in the bzImage, after the decompressor.
+        KernelFormat::BzImage => find_gzip_payload(&image)
+            .ok_or_else(|| invalid(path, "bzImage without a gzip-compressed payload".into()))?,
+        _ => image,
+    };
+    validate(path, &decompressed, arch)?;
+
+    Ok(decompressed)
+}
+
+fn gunzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decompressed)?;
+
+    Ok(decompressed)
+}
+
+/// The first gzip stream in `image` that decompresses to an ELF image.
+fn find_gzip_payload(image: &[u8]) -> Option<Vec<u8>> {
+    image
+        .windows(GZIP_MAGIC.len())
+        .enumerate()
+        .filter(|(_, window)| *window == GZIP_MAGIC)
+        .filter_map(|(offset, _)| gunzip(&image[offset..]).ok())
+        .find(|decompressed| KernelFormat::detect(decompressed) == KernelFormat::Elf)
+}
+
+fn invalid(path: &Path, reason: String) -> Error {
+    Error::InvalidKernelFormat {
+        path: path.to_owned(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let path = Path::new("/tmp/vmlinuz");
+        let elf = b"\x7fELF\x02\x01\x01".to_vec();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&elf).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut bzimage = vec![0; 0x400];
+        bzimage[BZIMAGE_MAGIC_OFFSET..BZIMAGE_MAGIC_OFFSET + 4].copy_from_slice(BZIMAGE_MAGIC);
+        bzimage.extend_from_slice(b"\x1f\x8b\x08garbage");
+        bzimage.extend_from_slice(&gzip);
+
+        assert_eq!(KernelFormat::detect(&elf), KernelFormat::Elf);
+        assert_eq!(KernelFormat::detect(&gzip), KernelFormat::Gzip);
+        assert_eq!(KernelFormat::detect(&bzimage), KernelFormat::BzImage);
+        assert!(validate(path, &elf, Arch::X86_64).is_ok());
+        assert!(matches!(
+            validate(path, &elf, Arch::Aarch64),
+            Err(Error::InvalidKernelFormat { .. })
+        ));
+        assert!(validate(path, &bzimage, Arch::X86_64).is_err());
+        assert_eq!(decompress(path, gzip, Arch::X86_64).unwrap(), elf);
+        assert_eq!(decompress(path, bzimage, Arch::X86_64).unwrap(), elf);
+        assert!(decompress(path, b"garbage".to_vec(), Arch::X86_64).is_err());
+    }
+}
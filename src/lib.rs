@@ -3,12 +3,65 @@
 #![deny(missing_debug_implementations, nonstandard_style)]
 #![warn(missing_docs, rustdoc::missing_doc_code_examples, unreachable_pub)]
 
+pub mod agent;
+#[cfg(any(feature = "fc-1_7", feature = "fc-1_10"))]
+pub mod api;
+pub mod api_stats;
+pub mod audit;
+pub mod balloon;
+mod client;
+pub mod clock;
+mod compat;
 pub mod config;
+mod devmapper;
+pub mod dirty_pages;
+pub mod discovery;
+#[cfg(feature = "download")]
+pub mod download;
 mod error;
+pub mod events;
+pub mod fork;
+pub mod fs;
+pub mod heartbeat;
+pub mod hooks;
+pub mod host;
+pub mod images;
+pub mod initramfs;
+pub mod ipam;
+pub mod kernel;
+pub mod logs;
 mod machine;
+pub mod metrics;
+pub mod migration;
+mod nat;
+pub mod numa;
+mod orchestrator;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+pub mod plan;
+pub mod pool;
+pub mod recording;
+pub mod remote;
+pub mod snapshot;
+pub mod spawner;
+mod start;
+mod state;
+pub mod storage;
+mod tap;
+mod task;
+pub mod transport;
+pub mod uid_pool;
+pub mod version;
+pub mod vsock;
+mod watchdog;
 
+pub use discovery::{list, Filter, VmSummary};
 pub use error::*;
 pub use machine::*;
+pub use migration::migrate;
+pub use orchestrator::*;
+pub use start::{SetupCall, SetupStep, StartOptions, StartPhase};
+pub use task::TaskHandle;
 
 #[cfg(doctest)]
 mod doctests {
@@ -0,0 +1,332 @@
+//! Firecracker log file management and parsing.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    fs,
+    io::{AsyncBufRead, AsyncBufReadExt, Lines},
+    time::sleep,
+};
+use tracing::{info, instrument, warn};
+
+use crate::{config::LogLevel, task::TaskHandle, Error};
+
+/// Policy for [`crate::Machine::rotate_logs`].
+///
+/// Files are rotated by copying them to `<file>.1` (shifting older rotations up to
+/// `<file>.<retention>`) and truncating them in place. As Firecracker and the jailer append to
+/// their files, they keep writing to the truncated file without having to be reconfigured.
+#[derive(Debug, Clone)]
+pub struct LogRotation {
+    pub(crate) max_size: Option<u64>,
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) retention: usize,
+    pub(crate) interval: Duration,
+}
+
+impl LogRotation {
+    /// Create a policy keeping `retention` rotated files.
+    ///
+    /// Files are rotated whenever [`crate::Machine::rotate_logs`] is called, unless a maximum
+    /// size or age is set.
+    pub fn new(retention: usize) -> Self {
+        Self {
+            max_size: None,
+            max_age: None,
+            retention,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Only rotate files larger than `max_size` bytes.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Only rotate files whose last rotation is older than `max_age`.
+    ///
+    /// Files that were never rotated are aged from their creation, or their last modification if
+    /// the filesystem doesn't record creation times.
+    ///
+    /// Combined with [`LogRotation::max_size`], files are rotated if either limit is exceeded.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Set how often files are checked by [`crate::Machine::rotate_logs_periodically`].
+    ///
+    /// Defaults to 1 minute.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// If `path` is due for rotation.
+    async fn is_due(&self, path: &Path) -> io::Result<bool> {
+        if self.max_size.is_none() && self.max_age.is_none() {
+            return Ok(true);
+        }
+
+        let metadata = fs::metadata(path).await?;
+        if self
+            .max_size
+            .is_some_and(|max_size| metadata.len() > max_size)
+        {
+            return Ok(true);
+        }
+        if let Some(max_age) = self.max_age {
+            // The last rotation, or the creation of the file if it was never rotated.
+            let since = match fs::metadata(rotated_path(path, 1)).await {
+                Ok(rotated) => rotated.modified()?,
+                // Without creation times (e.g on filesystems without `btime`), fall back to the
+                // last modification, which only ages if nothing was logged since.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => match metadata.created() {
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => metadata.modified()?,
+                    created => created?,
+                },
+                Err(e) => return Err(e),
+            };
+            let age = SystemTime::now().duration_since(since).unwrap_or_default();
+            if age > max_age {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Rotate `path` if it's due according to `policy`, returning if it was rotated.
+///
+/// Missing files are ignored.
+#[instrument(skip_all, fields(path = %path.display()))]
+pub(crate) async fn rotate(path: &Path, policy: &LogRotation) -> Result<bool, Error> {
+    match policy.is_due(path).await {
+        Ok(true) => (),
+        Ok(false) => return Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    }
+
+    if policy.retention > 0 {
+        let oldest = rotated_path(path, policy.retention);
+        match fs::remove_file(&oldest).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        for n in (1..policy.retention).rev() {
+            match fs::rename(rotated_path(path, n), rotated_path(path, n + 1)).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        fs::copy(path, rotated_path(path, 1)).await?;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await?
+        .set_len(0)
+        .await?;
+    info!("Log file rotated");
+
+    Ok(true)
+}
+
+/// Rotate `paths` every [`LogRotation::interval`].
+pub(crate) fn rotate_periodically(paths: Vec<PathBuf>, policy: LogRotation) -> TaskHandle {
+    TaskHandle::new(tokio::spawn(async move {
+        loop {
+            sleep(policy.interval).await;
+            for path in &paths {
+                if let Err(e) = rotate(path, &policy).await {
+                    warn!(error = %e, "Failed to rotate `{}`", path.display());
+                }
+            }
+        }
+    }))
+}
+
+/// The path of the `n`th rotation of `path`.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+
+    rotated.into()
+}
+
+/// A line of Firecracker output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogLine {
+    /// A log record of the VMM.
+    Vmm(LogRecord),
+    /// Any other line, e.g guest serial console output.
+    Other(String),
+}
+
+/// A log record of the VMM.
+///
+/// Firecracker formats them as `<timestamp> [<instance>:<thread>(:<level>)(:<file>:<line>)]
+/// <message>`, the level and origin being included depending on
+/// [`crate::config::Builder::log_show_level`] and [`crate::config::Builder::log_show_origin`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The timestamp, as formatted by Firecracker.
+    pub timestamp: String,
+    /// The Firecracker instance ID.
+    pub instance_id: String,
+    /// The name of the thread, e.g `main` or `fc_vcpu 0`.
+    pub thread: String,
+    /// The level, if shown.
+    pub level: Option<LogLevel>,
+    /// The source file and line, if shown.
+    pub origin: Option<String>,
+    /// The message.
+    pub message: String,
+}
+
+impl LogLine {
+    /// Parse a line of Firecracker output.
+    pub fn parse(line: &str) -> Self {
+        Self::parse_record(line)
+            .map(Self::Vmm)
+            .unwrap_or_else(|| Self::Other(line.to_owned()))
+    }
+
+    fn parse_record(line: &str) -> Option<LogRecord> {
+        let (timestamp, rest) = line.split_once(' ')?;
+        if !timestamp.starts_with(|c: char| c.is_ascii_digit()) || !timestamp.contains('T') {
+            return None;
+        }
+        let (header, message) = rest.strip_prefix('[')?.split_once("] ")?;
+        let mut fields = header.split(':');
+        let instance_id = fields.next()?;
+        let thread = fields.next()?;
+        let mut rest: Vec<_> = fields.collect();
+        let level = match rest.first().and_then(|level| parse_level(level)) {
+            Some(level) => {
+                rest.remove(0);
+                Some(level)
+            }
+            None => None,
+        };
+        let origin = (!rest.is_empty()).then(|| rest.join(":"));
+
+        Some(LogRecord {
+            timestamp: timestamp.to_owned(),
+            instance_id: instance_id.to_owned(),
+            thread: thread.to_owned(),
+            level,
+            origin,
+            message: message.to_owned(),
+        })
+    }
+}
+
+fn parse_level(level: &str) -> Option<LogLevel> {
+    match level {
+        "ERROR" => Some(LogLevel::Error),
+        "WARN" => Some(LogLevel::Warning),
+        "INFO" => Some(LogLevel::Info),
+        "DEBUG" => Some(LogLevel::Debug),
+        "TRACE" => Some(LogLevel::Trace),
+        _ => None,
+    }
+}
+
+/// Reads [`LogLine`]s, e.g from the Firecracker log named pipe.
+#[derive(Debug)]
+pub struct LogReader<R> {
+    lines: Lines<R>,
+}
+
+impl<R> LogReader<R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    /// Create a new reader.
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+        }
+    }
+
+    /// Read the next line, or `None` at the end of the input.
+    pub async fn next_line(&mut self) -> Result<Option<LogLine>, Error> {
+        Ok(self
+            .lines
+            .next_line()
+            .await?
+            .map(|line| LogLine::parse(&line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rotation() {
+        let dir = std::env::temp_dir().join(format!("firec-logs-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("firecracker.log");
+        let policy = LogRotation::new(2).max_size(3);
+
+        for content in ["one", "three", "four", "five"] {
+            fs::write(&path, content).await.unwrap();
+            rotate(&path, &policy).await.unwrap();
+        }
+        assert!(fs::read(&path).await.unwrap().is_empty());
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).await.unwrap(),
+            "five"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).await.unwrap(),
+            "four"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        // Below the maximum size.
+        fs::write(&path, "one").await.unwrap();
+        assert!(!rotate(&path, &policy).await.unwrap());
+
+        fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            LogLine::parse(
+                "2023-06-13T10:12:35.123456789 [my-vm:fc_vcpu 0:WARN:src/vmm/src/lib.rs:42] Oops"
+            ),
+            LogLine::Vmm(LogRecord {
+                timestamp: "2023-06-13T10:12:35.123456789".to_owned(),
+                instance_id: "my-vm".to_owned(),
+                thread: "fc_vcpu 0".to_owned(),
+                level: Some(LogLevel::Warning),
+                origin: Some("src/vmm/src/lib.rs:42".to_owned()),
+                message: "Oops".to_owned(),
+            })
+        );
+        match LogLine::parse("2023-06-13T10:12:35.1 [my-vm:main] Running Firecracker v1.4.0") {
+            LogLine::Vmm(record) => {
+                assert_eq!(record.thread, "main");
+                assert_eq!(record.level, None);
+                assert_eq!(record.origin, None);
+                assert_eq!(record.message, "Running Firecracker v1.4.0");
+            }
+            line => panic!("unexpected line: {line:?}"),
+        }
+        assert_eq!(
+            LogLine::parse("[    0.000000] Linux version 5.10"),
+            LogLine::Other("[    0.000000] Linux version 5.10".to_owned())
+        );
+    }
+}
@@ -1,34 +1,225 @@
 //! A VMM machine.
 
-use std::{io::ErrorKind, path::Path, process::Stdio, time::Duration};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io::{self, ErrorKind, Read},
+    net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::{Duration, Instant, SystemTime},
+};
 
 use crate::{
-    config::{Config, JailerMode},
-    Error,
+    agent::{self, ExecOutput, ExecRequest},
+    api_stats::ApiStats,
+    audit::AuditAction,
+    balloon::{self, AutoscalePolicy, BalloonStats},
+    client::ApiClient,
+    clock::{self, ResumeInfo, CLOCK_SYNC_TIMEOUT},
+    compat,
+    config::{
+        network::TapMode, ApplyReport, Arch, ArtifactRefresh, Config, ConfigChange, Drive,
+        DriveProvisioning, JailerMode, Seccomp, SocketPermissions, VmConfig, VmId, Workspace,
+        WorkspaceLayout, WorkspaceQuota, DEFAULT_KERNEL_IMAGE_NAME,
+    },
+    devmapper,
+    dirty_pages::{self, DirtyPageObserver, DirtyPageRate, Sampler, SAMPLE_SNAPSHOT_NAME},
+    discovery::{self, VmRecord},
+    events::{self, ExitReason, MachineEvent, MachineEventKind},
+    fork::{self, ForkOptions, FORK_SNAPSHOT_NAME, REIDENTIFY_TIMEOUT},
+    fs::DiskUsage,
+    heartbeat::{self, HeartbeatPolicy, LastHeartbeat},
+    hooks::{self, EventHooks},
+    host, images, in_operation,
+    kernel::{self, KernelFormat},
+    logs::{self, LogRotation},
+    metrics::{self, MetricsSample, MetricsSource, SampleParser},
+    nat,
+    plan::{Plan, PlannedOperation},
+    snapshot::{self, Archive, ArchiveStore, Snapshot, SnapshotType, FINAL_SNAPSHOT_NAME},
+    spawner::{ChildProcess, ProcessSpawner},
+    start::{SetupStep, StartTimer},
+    tap,
+    task::TaskHandle,
+    version::{FirecrackerVersion, VersionResponse},
+    vsock::{self, Proxy},
+    watchdog::{self, Process, SharedProcess},
+    AgentError, Error, StartFailure, StartFailureReason, StartOptions, StartPhase,
 };
-use futures_util::TryFutureExt;
-use serde::Serialize;
-use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, ProcessStatus, System, SystemExt};
+use futures_util::{future::try_join_all, try_join};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, System, SystemExt};
 use tokio::{
-    fs::{self, copy, DirBuilder},
+    net::{TcpListener, UnixListener},
     process::Command,
+    sync::{broadcast, watch, Mutex as AsyncMutex},
     task,
     time::sleep,
 };
-use tracing::{info, instrument, trace, warn};
-
-use hyper::{Body, Client, Method, Request};
-use hyperlocal::{UnixClientExt, UnixConnector, Uri};
+use tracing::{debug, info, instrument, trace, warn, Span};
+use uuid::Uuid;
+
+use hyper::Method;
+
+/// Time given to a running VM to shut down when deleted, see [`DeleteOptions::grace_period`].
+pub const DEFAULT_DELETE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// Timeout of the guest command notified of a drive swap, see [`SwapDriveOptions::notify`].
+const DRIVE_NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time given to the VMM process to exit after it has been killed.
+const FORCE_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Name prefix of Firecracker's vCPU threads, followed by the vCPU index.
+const VCPU_THREAD_PREFIX: &str = "fc_vcpu ";
+/// Number of jailer stdout and stderr lines attached to a [`StartFailure`].
+const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Serializes the lifecycle operations of a VM, across all [`Machine`]s with the same ID.
+type OperationLock = Arc<AsyncMutex<()>>;
+
+fn operation_lock(vm_id: &VmId) -> OperationLock {
+    static LOCKS: OnceLock<Mutex<HashMap<VmId, Weak<AsyncMutex<()>>>>> = OnceLock::new();
+
+    let mut locks = LOCKS.get_or_init(Default::default).lock().unwrap();
+    locks.retain(|_, lock| lock.strong_count() > 0);
+    if let Some(lock) = locks.get(vm_id).and_then(Weak::upgrade) {
+        return lock;
+    }
+    let lock = OperationLock::default();
+    locks.insert(vm_id.clone(), Arc::downgrade(&lock));
 
-const JAILER_START_TIMEOUT: Duration = Duration::from_secs(10);
+    lock
+}
 
 /// A VMM machine.
 #[derive(Debug)]
 pub struct Machine<'m> {
     config: Config<'m>,
-    /// Pid of a started jailer/firecracker process, or None if not started yet
-    pid: Option<u32>,
-    client: Client<UnixConnector>,
+    process: SharedProcess,
+    /// Exit status of the Firecracker process, once it has been reaped.
+    exit_status: Option<watch::Receiver<Option<ExitStatus>>>,
+    client: ApiClient,
+    events: broadcast::Sender<MachineEvent>,
+    metrics: broadcast::Sender<MetricsSample>,
+    last_heartbeat: LastHeartbeat,
+    /// When the VM was paused, until it's resumed.
+    paused_at: Mutex<Option<SystemTime>>,
+    last_resume: Mutex<Option<ResumeInfo>>,
+    operation_lock: OperationLock,
+}
+
+/// Options for [`Machine::delete_with`].
+#[derive(Debug, Clone)]
+pub struct DeleteOptions {
+    pub(crate) archive: Option<Arc<dyn ArchiveStore>>,
+    pub(crate) grace_period: Duration,
+}
+
+impl Default for DeleteOptions {
+    fn default() -> Self {
+        Self {
+            archive: None,
+            grace_period: DEFAULT_DELETE_GRACE_PERIOD,
+        }
+    }
+}
+
+impl DeleteOptions {
+    /// Wait up to `grace_period` for a running VM to shut down before killing it.
+    ///
+    /// Deletion goes on as soon as the VMM process exits. Defaults to
+    /// [`DEFAULT_DELETE_GRACE_PERIOD`].
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Archive the VM to `store` before deleting it, see [`crate::snapshot`].
+    pub fn archive<S>(mut self, store: S) -> Self
+    where
+        S: ArchiveStore + 'static,
+    {
+        self.archive = Some(Arc::new(store));
+        self
+    }
+}
+
+/// Options for [`Machine::swap_drive_with`].
+#[derive(Debug, Clone, Default)]
+pub struct SwapDriveOptions {
+    pub(crate) pause: bool,
+    pub(crate) notify: Option<(String, Vec<String>)>,
+}
+
+impl SwapDriveOptions {
+    /// Pause the VM while Firecracker switches to the new backing file, so that no I/O is in
+    /// flight.
+    pub fn pause(mut self, pause: bool) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    /// Run `cmd` with `args` in the guest through the agent (see [`crate::agent`]) once the drive
+    /// is swapped, e.g to remount it.
+    ///
+    /// The command gets the drive ID in `FIREC_DRIVE_ID`, and its guest device (see
+    /// [`Config::drive_guest_device`]) in `FIREC_DRIVE_DEVICE`.
+    pub fn notify<C, I, A>(mut self, cmd: C, args: I) -> Self
+    where
+        C: Into<String>,
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        self.notify = Some((cmd.into(), args.into_iter().map(Into::into).collect()));
+        self
+    }
+}
+
+/// What [`Machine::delete_with`] cleaned up.
+///
+/// Resources that were never created or already removed are skipped, and failures to remove
+/// them are reported as warnings instead of failing the deletion.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteSummary {
+    /// If the VM was running, and was stopped.
+    pub stopped: bool,
+    /// If the VM was archived, see [`DeleteOptions::archive`].
+    pub archived: bool,
+    /// If the workspace quota was torn down.
+    pub quota_removed: bool,
+    /// The number of NAT rules removed.
+    pub nat_rules_removed: usize,
+    /// The TAP devices removed.
+    pub taps_removed: Vec<String>,
+    /// The IDs of the drives whose device-mapper devices were removed.
+    pub drive_devices_removed: Vec<String>,
+    /// If the VM directory was removed, `false` if it was already gone.
+    pub vm_dir_removed: bool,
+    /// The cleanup steps that failed.
+    pub warnings: Vec<String>,
+}
+
+impl DeleteSummary {
+    /// Log and record the failure of a cleanup step.
+    pub(crate) fn warn(&mut self, message: String, err: &Error) {
+        warn!(error = %err, "{message}");
+        self.warnings.push(format!("{message}: {err}"));
+    }
+}
+
+/// Information about a running Firecracker process, as returned by `GET /`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct InstanceInfo {
+    /// The ID of the VM.
+    pub id: String,
+    /// The state of the VM, e.g `Running` or `Paused`.
+    pub state: String,
+    /// The version of Firecracker.
+    pub vmm_version: String,
+    /// The name of the application.
+    pub app_name: String,
 }
 
 /// VM state
@@ -38,140 +229,325 @@ pub enum MachineState {
     SHUTOFF,
     /// Machine is running
     RUNNING,
+    /// The VMM process disappeared without the machine being stopped
+    CRASHED,
 }
 
 impl<'m> Machine<'m> {
     /// Create a new machine.
     ///
     /// The machine is not started yet.
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(vm_id = %config.vm_id()))]
     pub async fn create(config: Config<'m>) -> Result<Machine<'m>, Error> {
-        let vm_id = *config.vm_id();
-        info!("Creating new machine with VM ID `{vm_id}`");
-        trace!("{vm_id}: Configuration: {:?}", config);
-
-        let jailer_workspace_dir = config.jailer().workspace_dir();
-        trace!(
-            "{vm_id}: Ensuring Jailer workspace directory exist at `{}`",
-            jailer_workspace_dir.display()
-        );
-        DirBuilder::new()
-            .recursive(true)
-            .create(jailer_workspace_dir)
-            .await?;
+        let vm_id = config.vm_id().clone();
+        in_operation(vm_id, "create", async {
+            info!("Creating new machine");
+            trace!("Configuration: {:?}", config);
 
-        let dest = config.kernel_image_path();
-        if dest.exists() {
-            trace!(
-                "{vm_id}: Skipping existing kernel image at `{}`",
-                dest.display()
-            );
-        } else {
+            let fs = config.fs();
+            let jailer_workspace_dir = config.jailer().workspace_dir();
             trace!(
-                "{vm_id}: Copying kernel image from `{}` to `{}`",
-                config.src_kernel_image_path.display(),
-                dest.display()
+                "Ensuring Jailer workspace directory exist at `{}`",
+                jailer_workspace_dir.display()
             );
-            copy(config.src_kernel_image_path(), dest).await?;
-        }
+            fs.create_dir_all(jailer_workspace_dir).await?;
+            if let Some(quota) = config.jailer().workspace_quota() {
+                setup_workspace_quota(&config, quota).await?;
+            }
+            setup_workspace_layout(&config).await?;
 
-        if let (Some(src_initrd_path), Some(initrd_path)) =
-            (config.src_initrd_path(), config.initrd_path()?)
-        {
-            if initrd_path.exists() {
-                trace!(
-                    "{vm_id}: Skipping existing initrd at `{}`",
-                    initrd_path.display()
-                );
+            let dest = config.kernel_image_path();
+            if !kernel_needs_copy(&config, &dest).await? {
+                trace!("Skipping existing kernel image at `{}`", dest.display());
             } else {
-                trace!(
-                    "{vm_id}: Copying initrd from `{}` to `{}`",
-                    src_initrd_path.display(),
-                    initrd_path.display()
-                );
-                copy(src_initrd_path, initrd_path).await?;
+                copy_kernel(&config, &dest).await?;
             }
-        }
 
-        for drive in &config.drives {
-            let drive_filename = drive
-                .src_path()
-                .file_name()
-                .ok_or(Error::InvalidDrivePath)?;
-            let dest = jailer_workspace_dir.join(drive_filename);
-            if dest.exists() {
-                trace!("{vm_id}: Skipping existing drive at `{}`", dest.display());
-            } else {
+            if let (Some(src_initrd_path), Some(initrd_path)) =
+                (config.src_initrd_path(), config.initrd_path()?)
+            {
+                if !needs_copy(&config, src_initrd_path, &initrd_path).await? {
+                    trace!("Skipping existing initrd at `{}`", initrd_path.display());
+                } else {
+                    trace!(
+                        "Copying initrd from `{}` to `{}`",
+                        src_initrd_path.display(),
+                        initrd_path.display()
+                    );
+                    fs.copy(src_initrd_path, &initrd_path).await?;
+                }
+            }
+
+            if let (Seccomp::Custom(src), Some(dest)) =
+                (config.seccomp(), config.seccomp_filter_path())
+            {
+                if !needs_copy(&config, src, &dest).await? {
+                    trace!("Skipping existing seccomp filter at `{}`", dest.display());
+                } else {
+                    trace!(
+                        "Copying seccomp filter from `{}` to `{}`",
+                        src.display(),
+                        dest.display()
+                    );
+                    fs.copy(src, &dest).await?;
+                }
+            }
+
+            if let (Some(metadata), Some(dest)) =
+                (config.mmds_metadata(), config.mmds_metadata_path())
+            {
+                trace!("Writing MMDS metadata to `{}`", dest.display());
+                fs.write(&dest, serde_json::to_vec(metadata)?).await?;
+            }
+
+            // Older Firecracker versions require the log file to exist.
+            if let (None, Some(log_path)) = (config.log_fifo(), config.log_path()) {
+                let dest = config.host_path(log_path);
+                if !fs.exists(&dest).await? {
+                    trace!("Creating log file at `{}`", dest.display());
+                    if let Some(log_dir) = dest.parent() {
+                        fs.create_dir_all(log_dir).await?;
+                    }
+                    fs.write(&dest, Vec::new()).await?;
+                }
+            }
+
+            if config.check_root_filesystem() {
+                if let Some(root) = config.drives().iter().find(|d| d.is_root_device()) {
+                    images::check_root_filesystem(root).await?;
+                }
+            }
+            for drive in &config.drives {
+                let dest = config.drive_path(drive)?;
+                if *drive.provisioning() != DriveProvisioning::Copy {
+                    devmapper::setup(&config, drive).await?;
+                } else if !needs_copy(&config, drive.src_path(), &dest).await? {
+                    trace!("Skipping existing drive at `{}`", dest.display());
+                } else {
+                    trace!(
+                        "Copying drive `{}` from `{}` to `{}`",
+                        drive.drive_id(),
+                        drive.src_path().display(),
+                        dest.display()
+                    );
+                    fs.copy(drive.src_path(), &dest).await?;
+                }
+            }
+
+            if config.jailer().chown_artifacts() {
+                chown_artifacts(&config).await?;
+            }
+
+            tap::setup(&config).await?;
+            if let Some(nat) = config.nat() {
+                nat::setup(&config, nat).await?;
+            }
+            VmRecord::new(&config).write(&config).await?;
+
+            if let Some(socket_dir) = config.host_socket_path().parent() {
                 trace!(
-                    "{vm_id}: Copying drive `{}` from `{}` to `{}`",
-                    drive.drive_id(),
-                    drive.src_path().display(),
-                    dest.display()
+                    "Ensuring socket directory exist at `{}`",
+                    socket_dir.display()
                 );
-                copy(&drive.src_path(), dest).await?;
+                fs.create_dir_all(socket_dir).await?;
             }
-        }
-
-        if let Some(socket_dir) = config.host_socket_path().parent() {
-            trace!(
-                "{vm_id}: Ensuring socket directory exist at `{}`",
-                socket_dir.display()
-            );
-            DirBuilder::new().recursive(true).create(socket_dir).await?;
-        }
 
-        // TODO: Handle fifos. See https://github.com/firecracker-microvm/firecracker-go-sdk/blob/f0a967ef386caec37f6533dce5797038edf8c226/jailer.go#L435
+            // TODO: Handle fifos. See https://github.com/firecracker-microvm/firecracker-go-sdk/blob/f0a967ef386caec37f6533dce5797038edf8c226/jailer.go#L435
+
+            // `request` doesn't provide API to connect to unix sockets so we we use the low-level
+            // approach using hyper: https://github.com/seanmonstar/reqwest/issues/39
+            let client = ApiClient::new(&config);
+            let operation_lock = operation_lock(config.vm_id());
+
+            let machine = Self {
+                config,
+                process: SharedProcess::default(),
+                exit_status: None,
+                client,
+                events: events::channel(),
+                metrics: metrics::channel(),
+                last_heartbeat: LastHeartbeat::default(),
+                paused_at: Mutex::default(),
+                last_resume: Mutex::default(),
+                operation_lock,
+            };
 
-        // `request` doesn't provide API to connect to unix sockets so we we use the low-level
-        // approach using hyper: https://github.com/seanmonstar/reqwest/issues/39
-        let client = Client::unix();
+            Ok(machine)
+        })
+        .await
+    }
 
-        let machine = Self {
-            config,
-            pid: None,
-            client,
-        };
+    /// What [`Machine::create`] and [`Machine::start_with`] would do with `config` and
+    /// `options`, without changing anything on the host, see [`crate::plan`].
+    #[instrument(skip_all, fields(vm_id = %config.vm_id()))]
+    pub async fn plan(config: &Config<'_>, options: &StartOptions) -> Result<Plan, Error> {
+        options.validate(config)?;
 
-        Ok(machine)
+        Ok(Plan {
+            create: plan_create(config).await?,
+            start: plan_start(config, options)?,
+        })
     }
 
     /// Connect to already created machine.
     ///
     /// The machine should be created first via call to `create`
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(vm_id = %config.vm_id()))]
     pub async fn connect(config: Config<'m>, pid: Option<u32>) -> Machine<'m> {
-        let vm_id = *config.vm_id();
-        info!("Connecting to machine with VM ID `{vm_id}`");
-        trace!("{vm_id}: Configuration: {:?}, pid: {:?}", config, pid);
+        info!("Connecting to machine");
+        trace!(?pid, "Configuration: {:?}", config);
 
-        let client = Client::unix();
+        let client = ApiClient::new(&config);
+        let operation_lock = operation_lock(config.vm_id());
 
         Self {
             config,
-            pid,
+            process: Arc::new(Mutex::new(Process {
+                pid,
+                ..Default::default()
+            })),
+            exit_status: None,
             client,
+            events: events::channel(),
+            metrics: metrics::channel(),
+            last_heartbeat: LastHeartbeat::default(),
+            paused_at: Mutex::default(),
+            last_resume: Mutex::default(),
+            operation_lock,
+        }
+    }
+
+    /// Adopt a running Firecracker process started by other tooling, from the host path of its
+    /// API socket.
+    ///
+    /// The VM must be jailed, in the usual `<chroot base dir>/<exec file name>/<VM ID>/root`
+    /// workspace. Its configuration is rebuilt from the API on a best-effort basis: the kernel,
+    /// initrd and drives are the files in the chroot, the jailer uid and gid are the owners of
+    /// the socket, and the logger, metrics, MMDS and NAT configurations are unknown. The machine
+    /// can be managed (shut down, paused, snapshotted, ...), but restarting it may not work.
+    ///
+    /// The kernel, initrd and drives must be at the root of the chroot, as with
+    /// [`Machine::create`].
+    #[instrument(skip_all, fields(socket_path = %socket_path.as_ref().display()))]
+    pub async fn adopt<P>(socket_path: P) -> Result<Machine<'static>, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let socket_path = socket_path.as_ref();
+        let not_jailed = || Error::NotJailedSocket(socket_path.to_owned());
+        let workspace_dir = socket_path
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.file_name() == Some("root".as_ref()))
+            .ok_or_else(not_jailed)?;
+        let vm_dir = workspace_dir.parent().ok_or_else(not_jailed)?;
+        let exec_dir = vm_dir.parent().ok_or_else(not_jailed)?;
+        let (Some(vm_id), Some(exec_file), Some(chroot_base_dir)) = (
+            vm_dir.file_name().and_then(|name| name.to_str()),
+            exec_dir.file_name(),
+            exec_dir.parent(),
+        ) else {
+            return Err(not_jailed());
+        };
+        let vm_id = VmId::new(vm_id)?;
+        let chroot_socket_path = Path::new("/").join(
+            socket_path
+                .strip_prefix(workspace_dir)
+                .expect("workspace is an ancestor of the socket"),
+        );
+        let metadata = tokio::fs::metadata(socket_path).await?;
+        let jailed = || {
+            Config::builder(
+                Some(vm_id.clone()),
+                workspace_dir.join(DEFAULT_KERNEL_IMAGE_NAME),
+            )
+            .jailer_cfg()
+            .chroot_base_dir(chroot_base_dir.to_owned())
+            .exec_file(PathBuf::from(exec_file))
+            .uid(metadata.uid())
+            .gid(metadata.gid())
+            .build()
+            .socket_path(chroot_socket_path.clone())
+        };
+
+        let client = ApiClient::new(&jailed().build()?);
+        let body = client.send(Method::GET, "/", None).await?;
+        let info: InstanceInfo = serde_json::from_str(&body.unwrap_or_default())?;
+        if info.id != vm_id.as_str() {
+            return Err(Error::VmIdMismatch(vm_id));
         }
+        let body = client.send(Method::GET, "/vm/config", None).await?;
+        let live: VmConfig = serde_json::from_str(&body.unwrap_or_default())?;
+        let config = live.configure(jailed()).build()?;
+        let pid = discovery::firecracker_pid(&vm_id, workspace_dir).await?;
+        info!(state = info.state, pid, "Adopting VM");
+
+        Ok(Machine::connect(config, pid).await)
     }
 
     /// Start the machine.
-    #[instrument(skip_all)]
+    ///
+    /// This is the same as [`Machine::start_with`] with the default [`StartOptions`].
     pub async fn start(&mut self) -> Result<(), Error> {
-        if self.state() == MachineState::RUNNING {
-            return Err(Error::ProcessAlreadyRunning);
-        }
-        let vm_id = self.config.vm_id().to_string();
-        info!("Starting machine with VM ID `{vm_id}`");
+        self.start_with(StartOptions::default()).await
+    }
+
+    /// Start the machine with the given options.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn start_with(&mut self, options: StartOptions) -> Result<(), Error> {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        self.do_start(options).await
+    }
+
+    async fn do_start(&mut self, options: StartOptions) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "start", async {
+            // Another `Machine` might have started the VMM.
+            if self.state() == MachineState::RUNNING || self.client.ping().await.is_ok() {
+                return Err(Error::ProcessAlreadyRunning);
+            }
+
+            let result = self.try_start(options).await;
+            self.emit(match &result {
+                Ok(()) => MachineEventKind::Started,
+                Err(e) => MachineEventKind::StartFailed {
+                    error: e.to_string(),
+                },
+            });
+
+            result
+        })
+        .await
+    }
 
-        self.cleanup_before_starting().await?;
+    async fn try_start(&mut self, options: StartOptions) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().to_string();
+        info!("Starting machine");
+        options.validate(&self.config)?;
+        let timer = StartTimer::new(&options);
+        if let Some(overhead_factor) = self.config.admission_check() {
+            host::admit(&self.config, overhead_factor).await?;
+        }
+        // Don't reuse connections to a previous VMM process.
+        self.client = ApiClient::new(&self.config);
+
+        let spawn_timeout = options.spawn_timeout;
+        let mut daemon_output = timer
+            .run(StartPhase::Spawn, spawn_timeout, async {
+                self.cleanup_before_starting().await?;
+                match self.config.jailer().mode() {
+                    JailerMode::Daemon if self.config.jailer().capture_daemon_output() => {
+                        Ok(Some(self.create_daemon_output_files().await?))
+                    }
+                    _ => Ok(None),
+                }
+            })
+            .await?;
 
+        let jailer_args = jailer_args(&self.config)?;
         // FIXME: Assuming jailer for now.
         let jailer = self.config.jailer_cfg.as_mut().expect("no jailer config");
-        let jailer_bin = jailer.jailer_binary().to_owned();
-        let jailer_exec_path = jailer
-            .exec_file()
-            .to_str()
-            .ok_or(Error::InvalidJailerExecPath)?
-            .to_owned();
+        let program = jailer.program();
         let jailer_exec_name = jailer
             .exec_file()
             .file_name()
@@ -179,15 +555,21 @@ impl<'m> Machine<'m> {
             .ok_or(Error::InvalidJailerExecPath)?
             .to_owned();
         let (mut cmd, daemonize_arg, stdin, stdout, stderr) = match &mut jailer.mode {
-            JailerMode::Daemon => (
-                Command::new(jailer.jailer_binary()),
-                Some("--daemonize"),
-                Stdio::null(),
-                Stdio::null(),
-                Stdio::null(),
-            ),
+            JailerMode::Daemon => {
+                let (stdout, stderr) = daemon_output
+                    .take()
+                    .unwrap_or_else(|| (Stdio::null(), Stdio::null()));
+
+                (
+                    program_command(&program),
+                    Some("--daemonize"),
+                    Stdio::null(),
+                    stdout,
+                    stderr,
+                )
+            }
             JailerMode::Attached(stdio) => (
-                Command::new(jailer_bin),
+                program_command(&program),
                 None,
                 stdio.stdin.take().unwrap_or_else(Stdio::inherit),
                 stdio.stdout.take().unwrap_or_else(Stdio::inherit),
@@ -198,13 +580,8 @@ impl<'m> Machine<'m> {
                     .clone()
                     .unwrap_or_else(|| vm_id.to_string().into());
                 let mut cmd = Command::new("tmux");
-                cmd.args([
-                    "new-session",
-                    "-d",
-                    "-s",
-                    &session_name,
-                    jailer.jailer_binary().to_str().unwrap(),
-                ]);
+                cmd.args(["new-session", "-d", "-s", &session_name])
+                    .args(&program);
 
                 (cmd, None, Stdio::null(), Stdio::null(), Stdio::null())
             }
@@ -214,61 +591,87 @@ impl<'m> Machine<'m> {
             cmd.arg(daemonize_arg);
         }
         let cmd = cmd
-            .args([
-                "--id",
-                &vm_id,
-                "--exec-file",
-                &jailer_exec_path,
-                "--uid",
-                &jailer.uid().to_string(),
-                "--gid",
-                &jailer.gid().to_string(),
-                "--chroot-base-dir",
-                jailer
-                    .chroot_base_dir()
-                    .to_str()
-                    .ok_or(Error::InvalidChrootBasePath)?,
-                // `firecracker` binary args.
-                "--",
-                "--api-sock",
-                self.config
-                    .socket_path
-                    .to_str()
-                    .ok_or(Error::InvalidSocketPath)?,
-            ])
+            .args(&jailer_args)
             .stdin(stdin)
             .stdout(stdout)
             .stderr(stderr);
-        trace!("{vm_id}: Running command: {:?}", cmd);
-        let mut child = cmd.spawn()?;
+        trace!("Running command: {:?}", cmd);
+        let mut child = self.config.spawner().spawn(cmd)?;
         if child.id().is_none() {
-            let exit_status = child.wait().await?;
+            let exit_status = timer
+                .run(StartPhase::Spawn, spawn_timeout, async {
+                    Ok(child.wait().await?)
+                })
+                .await?;
             return Err(Error::ProcessExitedImmediatelly { exit_status });
         }
-        self.pid = Some(self.wait_for_jailer(&jailer_exec_name).await?);
-
-        if let Err(e) = self
-            .setup_vm()
-            .and_then(|_| async {
-                trace!("{vm_id}: Booting the VM instance...");
-
-                self.send_action(Action::InstanceStart).await
-            })
+        let socket_ready_timeout = timer
+            .limit(Some(options.socket_ready_timeout))
+            .unwrap_or(options.socket_ready_timeout);
+        match self
+            .wait_for_jailer(&jailer_exec_name, child.as_mut(), socket_ready_timeout)
             .await
         {
-            warn!(
-                "{vm_id}: Failed to boot VM instance: {}. Force shutting down..",
-                e
-            );
-            self.force_shutdown().await.unwrap_or_else(|e| {
+            Ok(pid) => {
+                *self.process.lock().unwrap() = Process {
+                    pid: Some(pid),
+                    ..Default::default()
+                }
+            }
+            Err(reason) => return Err(self.start_failure(reason, child.as_mut()).await),
+        }
+        self.exit_status = self.reap(child);
+        if let Some(permissions) = self.config.api_socket_permissions() {
+            let socket_path = self.config.host_socket_path();
+            if let Err(e) = self.set_socket_permissions(&socket_path, permissions).await {
+                warn!(error = %e, "Failed to set API socket permissions. Force shutting down..");
+                self.do_force_shutdown().await.unwrap_or_else(|e| {
+                    warn!(error = %e, "Failed to force shutdown");
+                });
+
+                return Err(e);
+            }
+        }
+
+        let boot = async {
+            self.check_firecracker_version().await?;
+            match &options.snapshot {
+                Some(snapshot) => self.restore_vm(&timer, snapshot, &options).await?,
+                None => {
+                    // Boxed, as the concurrent setup makes for a large future.
+                    Box::pin(self.setup_vm(&timer)).await?;
+                    self.extra_setup(&timer, &options).await?;
+                    trace!("Booting the VM instance...");
+
+                    timer
+                        .run(
+                            StartPhase::InstanceStart,
+                            options.instance_start_timeout,
+                            self.send_action(Action::InstanceStart),
+                        )
+                        .await?
+                }
+            }
+            self.secure_vsock_socket().await?;
+            self.pin_vcpus().await
+        };
+
+        if let Err(e) = boot.await {
+            warn!(error = %e, "Failed to boot VM instance. Force shutting down..");
+            self.do_force_shutdown().await.unwrap_or_else(|e| {
                 // We want to return to original error so only log the error from shutdown.
-                warn!("{vm_id}: Failed to force shutdown: {}", e);
+                warn!(error = %e, "Failed to force shutdown");
             });
 
             return Err(e);
         }
+        if options.snapshot.is_some() && options.sync_clock {
+            if let Err(e) = self.sync_guest_clock().await {
+                warn!(error = %e, "Failed to sync the guest clock");
+            }
+        }
 
-        trace!("{vm_id}: VM started successfully.");
+        trace!("VM started successfully.");
 
         Ok(())
     }
@@ -276,365 +679,3134 @@ impl<'m> Machine<'m> {
     /// Forcefully shutdown the machine.
     ///
     /// This will be done by killing VM process.
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
     pub async fn force_shutdown(&mut self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        info!("{vm_id}: Killing VM...");
-
-        let pid = self.pid.ok_or(Error::ProcessNotStarted)?;
-        match self.config.jailer_cfg().expect("no jailer config").mode() {
-            JailerMode::Daemon | JailerMode::Attached(_) => {
-                let killed = task::spawn_blocking(move || {
-                    let mut sys = System::new();
-                    if sys.refresh_process_specifics(Pid::from_u32(pid), ProcessRefreshKind::new())
-                    {
-                        match sys.process(Pid::from_u32(pid)) {
-                            Some(process) => Ok(process.kill()),
-                            None => Err(Error::ProcessNotRunning(pid)),
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        self.do_force_shutdown().await
+    }
+
+    async fn do_force_shutdown(&mut self) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "force_shutdown", async {
+            let vm_id = self.config.vm_id();
+            info!("Killing VM...");
+
+            let pid = self.pid().ok_or(Error::ProcessNotStarted)?;
+            self.process.lock().unwrap().stopping = true;
+            match self.config.jailer_cfg().expect("no jailer config").mode() {
+                JailerMode::Daemon | JailerMode::Attached(_) => {
+                    self.config
+                        .audit(AuditAction::Signal {
+                            pid,
+                            signal: "SIGKILL".to_owned(),
+                        })
+                        .await?;
+                    let killed = task::spawn_blocking(move || {
+                        let mut sys = System::new();
+                        if sys.refresh_process_specifics(
+                            Pid::from_u32(pid),
+                            ProcessRefreshKind::new(),
+                        ) {
+                            match sys.process(Pid::from_u32(pid)) {
+                                Some(process) => Ok(process.kill()),
+                                None => Err(Error::ProcessNotRunning(pid)),
+                            }
+                        } else {
+                            Err(Error::ProcessNotRunning(pid))
                         }
-                    } else {
-                        Err(Error::ProcessNotRunning(pid))
-                    }
-                })
-                .await??;
+                    })
+                    .await??;
 
-                if !killed {
-                    return Err(Error::ProcessNotKilled(pid));
+                    if !killed {
+                        return Err(Error::ProcessNotKilled(pid));
+                    }
+                    trace!(pid, "Successfully sent KILL signal to VM.");
+                }
+                JailerMode::Tmux(session_name) => {
+                    let session_name = session_name
+                        .clone()
+                        .unwrap_or_else(|| vm_id.to_string().into());
+                    // In case of tmux, we need to kill the tmux session.
+                    let cmd = &mut Command::new("tmux");
+                    cmd.args(["kill-session", "-t", &session_name]);
+                    trace!("Running command: {:?}", cmd);
+                    self.config.spawner().spawn(cmd)?.wait().await?;
                 }
-                trace!("{vm_id}: Successfully sent KILL signal to VM (pid: `{pid}`).");
             }
-            JailerMode::Tmux(session_name) => {
-                let session_name = session_name
-                    .clone()
-                    .unwrap_or_else(|| vm_id.to_string().into());
-                // In case of tmux, we need to kill the tmux session.
-                let cmd = &mut Command::new("tmux");
-                cmd.args(["kill-session", "-t", &session_name]);
-                trace!("{vm_id}: Running command: {:?}", cmd);
-                cmd.spawn()?.wait().await?;
+            self.process.lock().unwrap().pid = None;
+            self.emit(MachineEventKind::Killed);
+            Ok(())
+        })
+        .await
+    }
+
+    /// Stop the machine.
+    ///
+    /// Requests a clean shutdown and waits up to `grace_period` for the VMM process to exit,
+    /// killing it if it doesn't.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn stop(&mut self, grace_period: Duration) -> Result<(), Error> {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        self.do_stop(grace_period).await
+    }
+
+    async fn do_stop(&mut self, grace_period: Duration) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "stop", async {
+            info!("Stopping VM...");
+
+            if self.state() == MachineState::RUNNING {
+                match self.do_shutdown().await {
+                    Ok(()) => {
+                        if !self.wait_for_shutoff(grace_period).await {
+                            warn!("VM did not shut down in time");
+                        }
+                    }
+                    Err(err) => warn!(error = %err, "Shutdown error"),
+                }
+
+                if self.state() == MachineState::RUNNING {
+                    let pid = self.pid().ok_or(Error::ProcessNotStarted)?;
+                    self.do_force_shutdown().await?;
+                    if !self.wait_for_shutoff(FORCE_SHUTDOWN_TIMEOUT).await {
+                        return Err(Error::ProcessNotKilled(pid));
+                    }
+                } else {
+                    self.emit(MachineEventKind::Stopped {
+                        exit_reason: self.exit_reason(),
+                    });
+                }
             }
-        }
-        self.pid = None;
-        Ok(())
+            self.process.lock().unwrap().pid = None;
+
+            Ok(())
+        })
+        .await
     }
 
     /// Shutdown requests a clean shutdown of the VM by sending CtrlAltDelete on the virtual keyboard.
-    #[instrument(skip_all)]
+    ///
+    /// This is a no-op if the VMM is not running.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
     pub async fn shutdown(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        info!("{vm_id}: Sending CTRL+ALT+DEL to VM...");
-        self.send_action(Action::SendCtrlAltDel).await?;
-        trace!("{vm_id}: CTRL+ALT+DEL sent to VM successfully.");
-        Ok(())
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        self.do_shutdown().await
+    }
+
+    async fn do_shutdown(&self) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "shutdown", async {
+            info!("Sending CTRL+ALT+DEL to VM...");
+            self.process.lock().unwrap().stopping = true;
+            match self.send_action(Action::SendCtrlAltDel).await {
+                Ok(()) => trace!("CTRL+ALT+DEL sent to VM successfully."),
+                Err(e)
+                    if matches!(
+                        e.root_cause(),
+                        Error::SocketNotFound { .. } | Error::VmmNotListening { .. }
+                    ) =>
+                {
+                    trace!("VM is not running, nothing to shut down.")
+                }
+                Err(e) => return Err(e),
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Restart the machine.
+    ///
+    /// Requests a clean shutdown and waits up to `grace_period` for the VMM process to exit,
+    /// killing it if it doesn't. The machine is then started again, reusing the existing chroot
+    /// (artifacts are not copied again).
+    ///
+    /// If the machine is not running, it's simply started.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn restart(&mut self, grace_period: Duration) -> Result<(), Error> {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "restart", async {
+            info!("Restarting VM...");
+            self.do_stop(grace_period).await?;
+
+            self.do_start(StartOptions::default()).await
+        })
+        .await
     }
 
     /// Delete the machine.
     ///
     /// Deletes the machine, cleaning up all associated resources.
     ///
-    /// If machine is running, it is shut down before resources are deleted.
-    #[instrument(skip_all)]
-    pub async fn delete(mut self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id().to_string();
-        info!("{vm_id}: Deleting VM...");
+    /// If machine is running, it is shut down before resources are deleted. This is the same as
+    /// [`Machine::delete_with`] with the default [`DeleteOptions`].
+    pub async fn delete(self) -> Result<DeleteSummary, Error> {
+        self.delete_with(DeleteOptions::default()).await
+    }
 
-        let jailer_workspace_dir = self.config.jailer_cfg().unwrap().workspace_dir().to_owned();
+    /// Delete the machine with the given options.
+    ///
+    /// A running VM is shut down, and killed if it's still running after the grace period (see
+    /// [`DeleteOptions::grace_period`]).
+    ///
+    /// If an archive store is set (see [`DeleteOptions::archive`]), a running VM is paused and
+    /// snapshotted first, and the archive is stored before anything is torn down. If archiving
+    /// fails, the VM is resumed and nothing is deleted.
+    ///
+    /// Deleting a partially created machine, e.g one that failed to start, or one whose VM
+    /// directory is already gone, succeeds: what's left is cleaned up, and failures to clean up
+    /// are reported in the returned summary. Only failing to remove the VM directory is an error.
+    pub async fn delete_with(mut self, options: DeleteOptions) -> Result<DeleteSummary, Error> {
+        self.delete_in_place(options).await
+    }
 
-        if MachineState::RUNNING == self.state() {
-            if let Err(err) = self.shutdown().await {
-                warn!("{vm_id}: Shutdown error: {err}");
-            } else {
-                info!("{vm_id}: Waiting for the VM process to shut down...");
-                sleep(Duration::from_secs(10)).await;
+    /// Delete the machine, see [`Machine::delete_with`], keeping it around so that the deletion
+    /// can be retried if it fails.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub(crate) async fn delete_in_place(
+        &mut self,
+        options: DeleteOptions,
+    ) -> Result<DeleteSummary, Error> {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "delete", async {
+            info!("Deleting VM...");
+            let mut summary = DeleteSummary::default();
+
+            let running = MachineState::RUNNING == self.state();
+            if let Some(store) = &options.archive {
+                self.archive(store.as_ref(), running).await?;
+                summary.archived = true;
             }
 
-            if let Err(err) = self.force_shutdown().await {
-                warn!("{vm_id}: Forced shutdown error: {err}");
-            }
-        }
+            // An archived VM is paused, so it can't be shut down gracefully.
+            if running && options.archive.is_some() {
+                if let Err(err) = self.do_force_shutdown().await {
+                    summary.warn("Forced shutdown error".to_owned(), &err);
+                }
+            } else if running {
+                if let Err(err) = self.do_shutdown().await {
+                    summary.warn("Shutdown error".to_owned(), &err);
+                } else {
+                    info!("Waiting for the VM process to shut down...");
+                    if !self.wait_for_shutoff(options.grace_period).await {
+                        warn!("VM did not shut down in time");
+                    }
+                }
 
-        trace!("{vm_id}: Deleting VM resources...");
-        // The jailer workspace dir is `root` dir under the VM dir and we want to delete everything
-        // related to the VM so we need to delete the VM dir, and not just the workspace dir under
-        // it.
-        let vm_dir = jailer_workspace_dir
-            .parent()
-            .expect("VM workspace dir must have a parent");
-        trace!(
-            "{vm_id}: Deleting VM jailer directory at `{}`",
-            vm_dir.display()
-        );
-        fs::remove_dir_all(vm_dir).await?;
-        trace!("{vm_id}: VM deleted successfully.");
+                if self.state() == MachineState::RUNNING {
+                    if let Err(err) = self.do_force_shutdown().await {
+                        summary.warn("Forced shutdown error".to_owned(), &err);
+                    }
+                }
+            }
+            summary.stopped = running && self.state() != MachineState::RUNNING;
+
+            trace!("Deleting VM resources...");
+            // The jailer workspace dir is `root` dir under the VM dir and we want to delete everything
+            // related to the VM so we need to delete the VM dir, and not just the workspace dir under
+            // it.
+            // Removing the VM dir with the workspace still bind-mounted would empty it, so the
+            // directories are kept for another attempt if it can't be unmounted.
+            let unmounted = match teardown_workspace_layout(&self.config).await {
+                Ok(()) => true,
+                Err(err) => {
+                    summary.warn("Failed to unmount the workspace".to_owned(), &err);
+                    false
+                }
+            };
+            if let Some(quota) = self.config.jailer().workspace_quota() {
+                match teardown_workspace_quota(&self.config, quota).await {
+                    Ok(removed) => summary.quota_removed = removed,
+                    Err(err) => {
+                        summary.warn("Failed to tear down workspace quota".to_owned(), &err)
+                    }
+                }
+            }
+            if let Err(err) = nat::teardown(&self.config, &mut summary).await {
+                summary.warn("Failed to remove NAT rules".to_owned(), &err);
+            }
+            tap::teardown(&self.config, &mut summary).await;
+            devmapper::teardown(&self.config, &mut summary).await;
+            let vm_dir = self.config.vm_dir();
+            if unmounted {
+                trace!("Deleting VM jailer directory at `{}`", vm_dir.display());
+                match self.config.fs().remove_dir_all(vm_dir).await {
+                    Ok(()) => summary.vm_dir_removed = true,
+                    Err(e) if e.kind() == ErrorKind::NotFound => {
+                        trace!("VM jailer directory already removed");
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if let (true, WorkspaceLayout::Flat(_)) =
+                (unmounted, self.config.jailer().workspace_layout())
+            {
+                let jailer_vm_dir = self.config.jailer().chroot_dir().parent();
+                if let Some(dir) = jailer_vm_dir {
+                    match self.config.fs().remove_dir_all(dir).await {
+                        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+            }
+            trace!("VM deleted successfully.");
+            self.emit(MachineEventKind::Deleted);
 
-        Ok(())
+            Ok(summary)
+        })
+        .await
     }
 
-    /// Get the configuration of the machine.
-    pub fn config(&self) -> &Config<'m> {
-        &self.config
+    /// Pause the running VM.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn pause(&self) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "pause", async {
+            snapshot::set_vm_state(&self.client, "Paused").await?;
+            *self.paused_at.lock().unwrap() = Some(SystemTime::now());
+            info!("VM paused");
+
+            Ok(())
+        })
+        .await
     }
 
-    /// Checks the machine actual state
+    /// Resume the paused VM.
     ///
-    /// Returns SHUTOFF is machine is not running
-    pub fn state(&self) -> MachineState {
-        if let Some(pid) = self.pid {
-            let mut sys = System::new();
-            // TODO set self.pid=None somewhere if process doesn't exists anymore
-            if sys.refresh_process_specifics(Pid::from_u32(pid), ProcessRefreshKind::new()) {
-                sys.process(Pid::from_u32(pid))
-                    .map_or(MachineState::SHUTOFF, |proc| {
-                        // sometime FC is not reaped by jailer for some time, so lets ignore
-                        // zombies for state purpose
-                        if proc.status() != ProcessStatus::Zombie {
-                            MachineState::RUNNING
-                        } else {
-                            MachineState::SHUTOFF
-                        }
-                    })
-            } else {
-                MachineState::SHUTOFF
-            }
-        } else {
-            MachineState::SHUTOFF
-        }
+    /// The guest clock isn't adjusted for the time spent paused, see
+    /// [`Machine::sync_guest_clock`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn resume(&self) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "resume", async {
+            snapshot::set_vm_state(&self.client, "Resumed").await?;
+            let paused_at = self.paused_at.lock().unwrap().take();
+            *self.last_resume.lock().unwrap() = Some(ResumeInfo::new(paused_at, false));
+            info!("VM resumed");
+
+            Ok(())
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
-    async fn wait_for_jailer(&self, jailer_exec_name: &str) -> Result<u32, Error> {
-        let vm_id = self.config.vm_id();
-        // Wait jailer to start up and create the socket.
-        info!("{vm_id}: Waiting for the jailer to start up...");
+    /// Snapshot the paused VM (see [`Machine::pause`]) to the snapshot `name` in its chroot.
+    ///
+    /// Existing snapshot files of the same name are overwritten.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), name = name))]
+    pub async fn create_snapshot(
+        &self,
+        snapshot_type: SnapshotType,
+        name: &str,
+    ) -> Result<Snapshot, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "create_snapshot", async {
+            let snapshot = Snapshot::in_chroot(&self.config, name);
+            let (snapshot_path, mem_file_path) = snapshot.chroot_paths(&self.config)?;
+            snapshot::create(&self.client, snapshot_type, &snapshot_path, &mem_file_path).await?;
+            info!(?snapshot_type, "Snapshot created");
+
+            Ok(snapshot)
+        })
+        .await
+    }
 
-        // get try to get FC version to verify if jailer already started
-        let request_version = || async {
-            if self
-                .client
-                .request(
-                    Request::builder()
-                        .method(Method::GET)
-                        .uri(Uri::new(self.config.host_socket_path(), "/version"))
-                        .header("Accept", "application/json")
-                        .header("Content-Type", "application/json")
-                        .body(Body::empty())?,
-                )
-                .await?
-                .status()
-                .is_success()
+    /// Clone the running VM into a new machine with `config`, see [`crate::fork`].
+    ///
+    /// The VM is paused while it's snapshotted to [`FORK_SNAPSHOT_NAME`] in its chroot, which
+    /// must be on the same filesystem as the chroot of the clone. The memory file is shared with
+    /// the clone, so it must be kept until the clone is deleted. `config` must describe the same
+    /// machine, with network interfaces of the same IDs, and its own TAP devices and MAC
+    /// addresses (see [`crate::config::network::Interface::reidentify`]).
+    ///
+    /// The clone is deleted if it fails to start or be re-identified.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), clone_id = %config.vm_id()))]
+    pub async fn fork<'f>(
+        &self,
+        config: Config<'f>,
+        options: ForkOptions,
+    ) -> Result<Machine<'f>, Error> {
+        let vm_id = self.config.vm_id().clone();
+        let snapshot = in_operation(vm_id, "fork", async {
+            self.pause().await?;
+            match self
+                .create_snapshot(SnapshotType::Full, FORK_SNAPSHOT_NAME)
+                .await
             {
-                Ok(())
-            } else {
-                Err(Error::ProcessNotStarted)
+                Ok(snapshot) => {
+                    self.resume().await?;
+                    Ok(snapshot)
+                }
+                Err(err) => Err(self.resume_after(err).await),
             }
-        };
-        let start = std::time::Instant::now();
-        let elapsed = || std::time::Instant::now() - start;
-        while request_version().await.is_err() {
-            if elapsed() < JAILER_START_TIMEOUT {
-                sleep(Duration::from_millis(100)).await;
-            } else {
-                return Err(Error::JailerStartTimedOut);
+        })
+        .await?;
+
+        let mut clone = Machine::create(config).await?;
+        let res = async {
+            let shared = snapshot::share(&snapshot, &clone.config, FORK_SNAPSHOT_NAME).await?;
+            let start_options = options
+                .start_options
+                .clone()
+                .restore(shared)
+                .network_overrides();
+            clone.start_with(start_options).await?;
+            clone.reidentify(&self.config, &options).await
+        }
+        .await;
+        if let Err(err) = res {
+            if let Err(e) = clone.delete().await {
+                warn!(error = %e, "Failed to delete clone that failed to start");
             }
+            return Err(err);
         }
-        // get PID of started firecracker
-        let mut sys = System::new();
-        sys.refresh_specifics(
-            sysinfo::RefreshKind::new().with_processes(ProcessRefreshKind::everything()),
-        );
-        let processes: Vec<_> = sys
-            .processes_by_name(jailer_exec_name)
-            .filter(|&process| process.cmd().contains(&vm_id.to_string()))
-            .collect();
+        info!("VM forked");
 
-        match processes.len() {
-            1 => Ok(processes[0].pid().as_u32()),
-            _ => Err(Error::FailedToStart),
-        }
+        Ok(clone)
     }
 
-    #[instrument(skip_all)]
-    async fn send_request(&self, url: hyper::Uri, body: String) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: sending request to url={url}, body={body}");
-
-        let request = Request::builder()
-            .method(Method::PUT)
-            .uri(url.clone())
-            .header("Accept", "application/json")
-            .header("Content-Type", "application/json")
-            .body(Body::from(body))?;
-
-        let resp = self.client.request(request).await?;
-
-        let status = resp.status();
-        if status.is_success() {
-            trace!("{vm_id}: request to url={url} successful");
-        } else {
-            let body = hyper::body::to_bytes(resp.into_body()).await?;
-            let body = if body.is_empty() {
-                trace!("{vm_id}: request to url={url} failed: status={status}");
-                None
-            } else {
-                let body = String::from_utf8_lossy(&body).into_owned();
-                trace!("{vm_id}: request to url={url} failed: status={status}, body={body}");
-                Some(body)
-            };
-            return Err(Error::FirecrackerAPIError { status, body });
-        }
+    /// Give the guest of a clone of the VM of `original` the network identity of its
+    /// configuration, see [`Machine::fork`].
+    async fn reidentify(&self, original: &Config<'_>, options: &ForkOptions) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "reidentify", async {
+            let uds_path = self.config.host_vsock_uds_path();
+            let port = self.config.agent_port();
+            let max_output = self.config.agent_max_output();
+            for interface in self.config.network_interfaces() {
+                let old_mac = original
+                    .network_interfaces()
+                    .iter()
+                    .find(|old| old.vm_if_name() == interface.vm_if_name())
+                    .and_then(|old| old.vm_mac_address());
+                let (Some(old_mac), Some(new_mac)) = (old_mac, interface.vm_mac_address()) else {
+                    warn!(
+                        iface_id = interface.vm_if_name(),
+                        "No MAC address to re-identify the guest interface with"
+                    );
+                    continue;
+                };
+                if old_mac.eq_ignore_ascii_case(new_mac) {
+                    continue;
+                }
+                let uds_path = uds_path.as_ref().ok_or(Error::VsockNotConfigured)?;
+                let request = fork::set_mac_request(old_mac, new_mac);
+                check_exec(
+                    agent::exec(uds_path, port, request, REIDENTIFY_TIMEOUT, max_output).await?,
+                )?;
+                debug!(
+                    iface_id = interface.vm_if_name(),
+                    new_mac, "Guest MAC address changed"
+                );
+            }
+            if let Some((cmd, args)) = &options.readdress {
+                let uds_path = uds_path.as_ref().ok_or(Error::VsockNotConfigured)?;
+                let request = fork::readdress_request(cmd, args);
+                check_exec(
+                    agent::exec(uds_path, port, request, REIDENTIFY_TIMEOUT, max_output).await?,
+                )?;
+            }
+            if let Some(metadata) = &options.mmds_metadata {
+                self.set_mmds_metadata(metadata).await?;
+            }
 
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 
-    async fn send_action(&self, action: Action) -> Result<(), Error> {
-        let url: hyper::Uri = Uri::new(self.config.host_socket_path(), "/actions").into();
-        let json = serde_json::to_string(&action)?;
-        self.send_request(url, json).await?;
+    /// Estimate how fast the running VM dirties its memory, over `window`.
+    ///
+    /// Requires dirty page tracking, see [`crate::dirty_pages`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn dirty_page_rate(&self, window: Duration) -> Result<DirtyPageRate, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "dirty_page_rate", async {
+            let sampler = self.dirty_page_sampler()?;
+            sampler.sample().await?;
+            sleep(window).await;
+            let dirty_bytes = sampler.sample().await?;
+
+            Ok(DirtyPageRate {
+                dirty_bytes,
+                interval: window,
+            })
+        })
+        .await
+    }
 
-        Ok(())
+    /// Estimate how fast the running VM dirties its memory every `interval`, in the background.
+    ///
+    /// Requires dirty page tracking, see [`crate::dirty_pages`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub fn observe_dirty_pages(&self, interval: Duration) -> Result<DirtyPageObserver, Error> {
+        Ok(dirty_pages::observe(self.dirty_page_sampler()?, interval))
     }
 
-    /// Prepare the machine for running.
-    #[instrument(skip_all)]
-    async fn setup_vm(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        info!("{vm_id}: Setting the VM...");
-        self.setup_resources().await?;
-        self.setup_boot_source().await?;
-        self.setup_drives().await?;
-        self.setup_network().await?;
-        self.setup_vsock().await?;
-        trace!("{vm_id}: VM successfully setup.");
+    fn dirty_page_sampler(&self) -> Result<Sampler, Error> {
+        if !self.config.machine_cfg().track_dirty_pages() {
+            return Err(Error::DirtyPageTrackingDisabled);
+        }
+        let snapshot = Snapshot::in_chroot(&self.config, SAMPLE_SNAPSHOT_NAME);
+
+        Ok(Sampler {
+            vm_id: self.config.vm_id().clone(),
+            client: self.client.clone(),
+            fs: self.config.fs.clone(),
+            chroot_paths: snapshot.chroot_paths(&self.config)?,
+            snapshot,
+        })
+    }
 
-        Ok(())
+    /// The exit status of the Firecracker process, once it has terminated.
+    ///
+    /// This is only available in [`JailerMode::Attached`] mode, where the spawned jailer process
+    /// execs into Firecracker. In the other modes, the spawned process only launches the VMM
+    /// and `None` is always returned.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit_status.as_ref().and_then(|rx| *rx.borrow())
     }
 
-    #[instrument(skip_all)]
-    async fn setup_resources(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: Configuring machine resources...");
-        let json = serde_json::to_string(self.config.machine_cfg())?;
-        let url: hyper::Uri = Uri::new(self.config.host_socket_path(), "/machine-config").into();
-        self.send_request(url, json).await?;
-        trace!("{vm_id}: Machine resources configured successfully.");
+    /// Why the Firecracker process exited, classified from its exit status.
+    ///
+    /// Only available in [`JailerMode::Attached`] mode, as with [`Machine::exit_status`].
+    pub fn exit_reason(&self) -> Option<ExitReason> {
+        self.exit_status().map(ExitReason::from_exit_status)
+    }
 
-        Ok(())
+    /// Make Firecracker pick up changes to the backing file of a drive.
+    ///
+    /// Firecracker re-opens the file and updates the size of the block device seen by the guest.
+    /// Only works while the VM is running.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), drive_id))]
+    pub async fn update_drive(&self, drive_id: &str) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "update_drive", async {
+            let drive = self.drive(drive_id)?;
+            let path = format!("/drives/{drive_id}");
+            let json = serde_json::to_string(&serde_json::json!({
+                "drive_id": drive_id,
+                "path_on_host": self.config.drive_name(drive)?,
+            }))?;
+            self.client.send(Method::PATCH, &path, Some(json)).await?;
+            info!("Drive updated");
+
+            Ok(())
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
-    async fn setup_boot_source(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: Configuring boot source...");
-        let boot_source = self.config.boot_source()?;
-        let json = serde_json::to_string(&boot_source)?;
-        let url: hyper::Uri = Uri::new(self.config.host_socket_path(), "/boot-source").into();
-        self.send_request(url, json).await?;
-        trace!("{vm_id}: Boot source configured successfully.");
+    /// Replace the backing file of the drive with the given ID by a copy of `src_path`.
+    ///
+    /// The copy replaces the drive file in the chroot and, if the VM is running, Firecracker is
+    /// made to pick it up through [`Machine::update_drive`]. The guest sees the new content as
+    /// is, so the drive shouldn't be mounted in the guest.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), drive_id))]
+    pub async fn replace_drive<P>(&mut self, drive_id: &str, src_path: P) -> Result<(), Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        let vm_id = self.config.vm_id().clone();
+        let src_path = src_path.into();
+        in_operation(vm_id, "replace_drive", async {
+            let drive = self.drive(drive_id)?;
+            let dest = self.config.drive_path(drive)?;
+            let mode = if drive.is_read_only() { 0o400 } else { 0o600 };
+            let fs = self.config.fs();
+            trace!(
+                "Copying drive from `{}` to `{}`",
+                src_path.display(),
+                dest.display()
+            );
+            fs.copy(&src_path, &dest).await?;
+            let jailer = self.config.jailer();
+            if jailer.chown_artifacts() {
+                fs.set_owner(&dest, jailer.uid(), jailer.gid(), mode)
+                    .await?;
+            }
 
-        Ok(())
+            let drive = self
+                .config
+                .drives
+                .iter_mut()
+                .find(|drive| drive.drive_id() == drive_id)
+                .expect("drive exists");
+            drive.src_path = src_path.into();
+            VmRecord::new(&self.config).write(&self.config).await?;
+            if self.state() == MachineState::RUNNING {
+                self.update_drive(drive_id).await?;
+            }
+            info!("Drive replaced");
+
+            Ok(())
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
-    async fn setup_drives(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: Configuring drives...");
-        for drive in &self.config.drives {
-            let path = format!("/drives/{}", drive.drive_id());
-            let url: hyper::Uri = Uri::new(self.config.host_socket_path(), &path).into();
-            // Send modified drive object, with drive file in chroot location
-            let mut drive_obj = drive.clone();
-            let drive_filename = drive
-                .src_path()
+    /// Back the drive with the given ID by a copy of `new_image`, while the VM runs.
+    ///
+    /// This is the same as [`Machine::swap_drive_with`] with the default [`SwapDriveOptions`].
+    pub async fn swap_drive<P>(&mut self, drive_id: &str, new_image: P) -> Result<(), Error>
+    where
+        P: Into<PathBuf>,
+    {
+        self.swap_drive_with(drive_id, new_image, SwapDriveOptions::default())
+            .await
+    }
+
+    /// Back the drive with the given ID by a copy of `new_image`, with the given options.
+    ///
+    /// Unlike [`Machine::replace_drive`], the copy is placed next to the current backing file in
+    /// the chroot, and Firecracker is switched to it through `PATCH /drives/{id}` before the
+    /// current file is removed, so it's never modified under a running VM. If the VM isn't
+    /// running, the drive is only switched in the configuration. The guest sees the new content
+    /// as is, so the drive shouldn't be mounted in the guest, or be remounted after the swap (see
+    /// [`SwapDriveOptions::notify`]).
+    ///
+    /// If the VM is paused for the switch and can't be resumed afterwards, the swap is kept and
+    /// the error returned. Drives provisioned with device-mapper can't be swapped.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), drive_id))]
+    pub async fn swap_drive_with<P>(
+        &mut self,
+        drive_id: &str,
+        new_image: P,
+        options: SwapDriveOptions,
+    ) -> Result<(), Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        let vm_id = self.config.vm_id().clone();
+        let new_image = new_image.into();
+        in_operation(vm_id, "swap_drive", async {
+            let drive = self.drive(drive_id)?;
+            if *drive.provisioning() != DriveProvisioning::Copy {
+                return Err(Error::DriveNotSwappable(drive_id.to_owned()));
+            }
+            let old_path = self.config.drive_path(drive)?;
+            let image_name = new_image
                 .file_name()
+                .and_then(|name| name.to_str())
                 .ok_or(Error::InvalidDrivePath)?;
-            drive_obj.src_path = Path::new(&drive_filename).into();
-            let json = serde_json::to_string(&drive_obj)?;
-            self.send_request(url, json).await?;
+            // Unique, so that it never collides with the current backing file.
+            let id = Uuid::new_v4().simple().to_string();
+            let dest_name = format!("{drive_id}-{}-{image_name}", &id[..8]);
+            let dest = self.config.jailer().workspace_dir().join(&dest_name);
+            let mode = if drive.is_read_only() { 0o400 } else { 0o600 };
+
+            let fs = self.config.fs();
+            trace!(
+                "Copying drive from `{}` to `{}`",
+                new_image.display(),
+                dest.display()
+            );
+            fs.copy(&new_image, &dest).await?;
+            let jailer = self.config.jailer();
+            if jailer.chown_artifacts() {
+                fs.set_owner(&dest, jailer.uid(), jailer.gid(), mode)
+                    .await?;
+            }
+
+            let running = self.state() == MachineState::RUNNING;
+            let mut resumed = Ok(());
+            if running {
+                if let Err(err) = self.patch_drive(drive_id, &dest_name, options.pause).await {
+                    if let Err(e) = fs.remove_file(&dest).await {
+                        warn!(error = %e, "Failed to remove the new drive file");
+                    }
+                    return Err(err);
+                }
+                // Firecracker uses the new file from now on, so failing to resume mustn't roll
+                // the swap back: it's reported once the swap is recorded.
+                if options.pause {
+                    resumed = self.resume().await;
+                }
+            }
+
+            let drive = self
+                .config
+                .drives
+                .iter_mut()
+                .find(|drive| drive.drive_id() == drive_id)
+                .expect("drive exists");
+            drive.swap(new_image, dest_name);
+            VmRecord::new(&self.config).write(&self.config).await?;
+            trace!("Removing old drive file at `{}`", old_path.display());
+            if let Err(err) = self.config.fs().remove_file(&old_path).await {
+                warn!(error = %err, "Failed to remove the old drive file");
+            }
+            info!("Drive swapped");
+            resumed?;
+
+            if let (true, Some((cmd, args))) = (running, &options.notify) {
+                let device = self.config.drive_guest_device(drive_id).unwrap_or_default();
+                let env = [
+                    ("FIREC_DRIVE_ID", drive_id),
+                    ("FIREC_DRIVE_DEVICE", &device),
+                ];
+                let output = self.exec(cmd, args, env, DRIVE_NOTIFY_TIMEOUT).await?;
+                check_exec(output)?;
+                info!("Guest notified of the drive swap");
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Switch the drive with the given ID to the file `name` in the chroot, pausing the VM first
+    /// if `pause` is set.
+    ///
+    /// The VM is resumed if the drive couldn't be switched, but left paused otherwise.
+    async fn patch_drive(&self, drive_id: &str, name: &str, pause: bool) -> Result<(), Error> {
+        if pause {
+            self.pause().await?;
         }
-        trace!("{vm_id}: Drives configured successfully.");
+        let path = format!("/drives/{drive_id}");
+        let json = serde_json::to_string(&serde_json::json!({
+            "drive_id": drive_id,
+            "path_on_host": name,
+        }))?;
+        match self.client.send(Method::PATCH, &path, Some(json)).await {
+            Ok(_) => Ok(()),
+            Err(err) if pause => Err(self.resume_after(err).await),
+            Err(err) => Err(err),
+        }
+    }
 
-        Ok(())
+    /// Resize the drive with the given ID to `new_size` bytes.
+    ///
+    /// If the VM isn't running, the drive's ext4 filesystem is resized as well through
+    /// [`crate::images::resize_ext4`]. Otherwise the drive can only be grown: its file is
+    /// extended and the guest is notified through [`Machine::update_drive`], but the filesystem
+    /// has to be grown from within the guest (e.g with `resize2fs /dev/vdb`).
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), drive_id, new_size))]
+    pub async fn resize_drive(&self, drive_id: &str, new_size: u64) -> Result<(), Error> {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "resize_drive", async {
+            let drive = self.drive(drive_id)?;
+            let path = self.config.drive_path(drive)?;
+            if self.state() != MachineState::RUNNING {
+                return images::resize_ext4(&self.config, &path, new_size).await;
+            }
+
+            if new_size < self.config.fs().file_len(&path).await? {
+                return Err(Error::DriveShrinkWhileRunning(drive_id.to_owned()));
+            }
+            images::set_len(&self.config, &path, new_size).await?;
+            self.update_drive(drive_id).await
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
-    async fn setup_network(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: Configuring network...");
-        for network in self.config.network_interfaces() {
-            let json = serde_json::to_string(network)?;
-            let path = format!("/network-interfaces/{}", network.vm_if_name());
-            let url: hyper::Uri = Uri::new(self.config.host_socket_path(), &path).into();
-            self.send_request(url, json).await?;
-        }
-        trace!("{vm_id}: All networks configured successfully.");
-        Ok(())
+    /// Get the latest statistics reported by the balloon device.
+    ///
+    /// Requires the balloon statistics to be enabled in [`crate::config::Balloon`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn balloon_stats(&self) -> Result<BalloonStats, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "balloon_stats", async {
+            self.config
+                .balloon_cfg()
+                .ok_or(Error::BalloonNotConfigured)?;
+            balloon::stats(&self.client).await
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
-    async fn setup_vsock(&self) -> Result<(), Error> {
-        let vsock_cfg = match self.config.vsock_cfg() {
-            Some(vsock) => vsock,
-            None => return Ok(()),
-        };
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: Configuring vsock...");
-        let url: hyper::Uri = Uri::new(self.config.host_socket_path(), "/vsock").into();
-        let json = serde_json::to_string(vsock_cfg)?;
-        self.send_request(url, json).await?;
-        trace!("{vm_id}: vsock configured successfully.");
+    /// Set the target size of the balloon.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), amount_mib))]
+    pub async fn update_balloon(&self, amount_mib: u32) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "update_balloon", async {
+            self.config
+                .balloon_cfg()
+                .ok_or(Error::BalloonNotConfigured)?;
+            balloon::update(&self.client, amount_mib).await
+        })
+        .await
+    }
 
-        Ok(())
+    /// Replace the content of the MMDS data store of the running machine with `metadata`.
+    ///
+    /// See [`crate::config::Builder::mmds_metadata`] to set it before the machine starts.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn set_mmds_metadata(&self, metadata: &serde_json::Value) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "set_mmds_metadata", async {
+            let json = serde_json::to_string(metadata)?;
+            self.client.send(Method::PUT, "/mmds", Some(json)).await?;
+            info!("MMDS metadata set");
+
+            Ok(())
+        })
+        .await
     }
 
-    #[instrument(skip_all)]
-    async fn cleanup_before_starting(&self) -> Result<(), Error> {
-        let vm_id = self.config.vm_id();
-        trace!("{vm_id}: Deleting intermediate VM resources before starting...");
-        let socket_path = self.config.host_socket_path();
-        trace!("{vm_id}: Removing socket file {}...", socket_path.display());
-        match fs::remove_file(&socket_path).await {
-            Ok(_) => trace!("{vm_id}: Deleted `{}`", socket_path.display()),
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                trace!("{vm_id}: `{}` not found", socket_path.display())
+    /// Automatically inflate and deflate the balloon of the running machine.
+    ///
+    /// The balloon is resized in the background according to `policy`, until the returned handle
+    /// is stopped or dropped. Requires the balloon statistics to be enabled in
+    /// [`crate::config::Balloon`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub fn autoscale_memory(&self, policy: AutoscalePolicy) -> Result<TaskHandle, Error> {
+        self.config
+            .balloon_cfg()
+            .ok_or(Error::BalloonNotConfigured)?;
+        let mem_size_mib = u32::try_from(self.config.machine_cfg().mem_size_mib())?;
+
+        Ok(balloon::autoscale(
+            self.client.clone(),
+            policy,
+            mem_size_mib,
+        ))
+    }
+
+    /// Make the machine match `config`.
+    ///
+    /// If the VM is running, `config` is compared with its live configuration (see
+    /// [`Config::diff`]) and the hot-applicable changes are applied. Either way, `config` replaces
+    /// the configuration of the machine, so the remaining changes take effect on the next
+    /// [`Machine::restart`]. Artifacts are only copied to the chroot by [`Machine::create`]
+    /// though, so new drives or kernels have to be put there first.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn apply(&mut self, config: Config<'m>) -> Result<ApplyReport, Error> {
+        let _guard = self.operation_lock.clone().lock_owned().await;
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "apply", async {
+            if config.vm_id() != self.config.vm_id() {
+                return Err(Error::VmIdMismatch(config.vm_id().clone()));
             }
-            Err(e) => return Err(e.into()),
+
+            let mut report = ApplyReport::default();
+            if self.state() == MachineState::RUNNING {
+                let diff = config.diff(&self.live_config().await?)?;
+                for change in diff.changes() {
+                    if self.hot_apply(&config, change).await? {
+                        info!(?change, "Applied configuration change");
+                        report.applied.push(change.clone());
+                    } else {
+                        report.restart_required.push(change.clone());
+                    }
+                }
+            }
+            self.config = config;
+            VmRecord::new(&self.config).write(&self.config).await?;
+
+            Ok(report)
+        })
+        .await
+    }
+
+    /// Apply `change` to the running VM, returning `false` if it's not hot-applicable.
+    async fn hot_apply(&self, config: &Config<'_>, change: &ConfigChange) -> Result<bool, Error> {
+        match change {
+            ConfigChange::DriveRateLimiter(drive_id) => {
+                let drive = config
+                    .drives
+                    .iter()
+                    .find(|drive| drive.drive_id() == drive_id)
+                    .ok_or_else(|| Error::DriveNotFound(drive_id.clone()))?;
+                // Zero-sized buckets remove the limits.
+                let unlimited = serde_json::json!({ "size": 0, "refill_time": 0 });
+                let rate_limiter = match drive.rate_limiter() {
+                    Some(rate_limiter) => serde_json::to_value(rate_limiter)?,
+                    None => serde_json::json!({ "bandwidth": unlimited, "ops": unlimited }),
+                };
+                let json = serde_json::to_string(&serde_json::json!({
+                    "drive_id": drive_id,
+                    "rate_limiter": rate_limiter,
+                }))?;
+                let path = format!("/drives/{drive_id}");
+                self.client.send(Method::PATCH, &path, Some(json)).await?;
+            }
+            ConfigChange::BalloonAmount(amount_mib) => {
+                balloon::update(&self.client, *amount_mib).await?
+            }
+            ConfigChange::BalloonStatsInterval(interval_s) => {
+                balloon::update_stats_interval(&self.client, *interval_s).await?
+            }
+            _ => return Ok(false),
         }
 
-        let jailer_workspace_dir = self.config.jailer().workspace_dir();
+        Ok(true)
+    }
 
-        // Remove the vsock socket file if it exists.
-        if let Some(path) = self.config.vsock_cfg().map(|v| v.uds_path()) {
-            let relative_path = path.strip_prefix("/").unwrap_or(path);
-            let path = jailer_workspace_dir.join(relative_path);
-            trace!("{vm_id}: Removing vsock socket file {}...", path.display());
-            match fs::remove_file(&path).await {
-                Ok(_) => trace!("{vm_id}: Deleted `{}`", path.display()),
-                Err(e) if e.kind() == ErrorKind::NotFound => {
-                    trace!("{vm_id}: `{}` not found", path.display())
+    /// The live configuration of the running VM, to compare with [`Config::diff`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn live_config(&self) -> Result<VmConfig, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "live_config", async {
+            let body = self
+                .client
+                .send(Method::GET, "/vm/config", None)
+                .await?
+                .unwrap_or_default();
+
+            Ok(serde_json::from_str(&body)?)
+        })
+        .await
+    }
+
+    /// Information about the running Firecracker process.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn instance_info(&self) -> Result<InstanceInfo, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "instance_info", async {
+            let body = self
+                .client
+                .send(Method::GET, "/", None)
+                .await?
+                .unwrap_or_default();
+
+            Ok(serde_json::from_str(&body)?)
+        })
+        .await
+    }
+
+    /// The version of the running Firecracker process.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn firecracker_version(&self) -> Result<FirecrackerVersion, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "firecracker_version", async {
+            let body = self
+                .client
+                .send(Method::GET, "/version", None)
+                .await?
+                .unwrap_or_default();
+            let response: VersionResponse = serde_json::from_str(&body)?;
+
+            response.firecracker_version.parse()
+        })
+        .await
+    }
+
+    /// The host thread IDs of the vCPU threads, indexed by vCPU.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn vcpu_threads(&self) -> Result<Vec<u32>, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "vcpu_threads", async {
+            let pid = self.pid().ok_or(Error::ProcessNotStarted)?;
+            let mut threads = Vec::new();
+            let mut tasks = tokio::fs::read_dir(format!("/proc/{pid}/task")).await?;
+            while let Some(task) = tasks.next_entry().await? {
+                let comm = match tokio::fs::read_to_string(task.path().join("comm")).await {
+                    Ok(comm) => comm,
+                    // The thread exited in the meantime.
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                let index = comm
+                    .trim_end()
+                    .strip_prefix(VCPU_THREAD_PREFIX)
+                    .and_then(|index| index.parse::<usize>().ok());
+                let tid = task.file_name().to_str().and_then(|tid| tid.parse().ok());
+                if let (Some(index), Some(tid)) = (index, tid) {
+                    threads.push((index, tid));
+                }
+            }
+            threads.sort_unstable();
+
+            Ok(threads.into_iter().map(|(_, tid)| tid).collect())
+        })
+        .await
+    }
+
+    /// Rotate the log files of the machine that are due according to `policy`.
+    ///
+    /// This covers the Firecracker log file (unless it's a named pipe) and the captured jailer
+    /// output (see [`crate::config::JailerBuilder::capture_daemon_output`]). Returns the files
+    /// that were rotated.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn rotate_logs(&self, policy: &LogRotation) -> Result<Vec<PathBuf>, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "rotate_logs", async {
+            let mut rotated = Vec::new();
+            for path in self.log_files() {
+                if logs::rotate(&path, policy).await? {
+                    rotated.push(path);
                 }
-                Err(e) => return Err(e.into()),
             }
+
+            Ok(rotated)
+        })
+        .await
+    }
+
+    /// Rotate the log files of the machine in the background, see [`Machine::rotate_logs`].
+    pub fn rotate_logs_periodically(&self, policy: LogRotation) -> TaskHandle {
+        logs::rotate_periodically(self.log_files(), policy)
+    }
+
+    fn log_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        if let (None, Some(log_path)) = (self.config.log_fifo(), self.config.log_path()) {
+            files.push(self.config.host_path(log_path));
+        }
+        if self.config.jailer().capture_daemon_output() {
+            files.push(self.config.jailer_stdout_path());
+            files.push(self.config.jailer_stderr_path());
         }
 
-        let dev_dir = jailer_workspace_dir.join("dev");
-        trace!("{vm_id}: Deleting `{}`", dev_dir.display());
-        match fs::remove_dir_all(&dev_dir).await {
-            Ok(_) => trace!("{vm_id}: Deleted `{}`", dev_dir.display()),
-            Err(e) if e.kind() == ErrorKind::NotFound => {
-                trace!("{vm_id}: `{}` not found", dev_dir.display())
+        files
+    }
+
+    /// The host-side layout of the machine's jailer workspace.
+    pub fn workspace(&self) -> Result<Workspace, Error> {
+        self.config.workspace()
+    }
+
+    /// The disk space used by the machine's VM directory, per artifact.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn disk_usage(&self) -> Result<DiskUsage, Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "disk_usage", async {
+            let workspace = self.workspace()?;
+            let fs = self.config.fs();
+            let usage_of = |path: Option<PathBuf>| async move {
+                match path {
+                    Some(path) => fs.disk_usage(&path).await,
+                    None => Ok(0),
+                }
+            };
+
+            let total = fs.disk_usage(&workspace.vm_dir).await?;
+            let kernel_image = fs.disk_usage(&workspace.kernel_image).await?;
+            let initrd = usage_of(workspace.initrd).await?;
+            let mut drives = Vec::with_capacity(workspace.drives.len());
+            for (drive_id, path) in workspace.drives {
+                drives.push((drive_id, fs.disk_usage(&path).await?));
             }
-            Err(e) => return Err(e.into()),
+            let mut logs = fs.disk_usage(&workspace.diagnostics_dir).await?;
+            for path in [workspace.log, workspace.metrics] {
+                logs += usage_of(path).await?;
+            }
+            let accounted =
+                kernel_image + initrd + logs + drives.iter().map(|(_, u)| u).sum::<u64>();
+
+            Ok(DiskUsage {
+                total,
+                kernel_image,
+                initrd,
+                drives,
+                logs,
+                other: total.saturating_sub(accounted),
+            })
+        })
+        .await
+    }
+
+    /// Subscribe to the lifecycle events of the machine.
+    pub fn subscribe(&self) -> broadcast::Receiver<MachineEvent> {
+        self.events.subscribe()
+    }
+
+    /// Run `hooks` on the lifecycle events of the machine, until the returned handle is dropped.
+    pub fn run_hooks(&self, hooks: EventHooks) -> TaskHandle {
+        hooks::run(self.subscribe(), hooks)
+    }
+
+    fn emit(&self, kind: MachineEventKind) {
+        // Nobody might be listening, which is fine.
+        let _ = self
+            .events
+            .send(MachineEvent::new(self.config.vm_id().clone(), kind));
+    }
+
+    /// Collect the Firecracker metrics of the machine in the background, every `interval`.
+    ///
+    /// The samples are published to [`Machine::subscribe_metrics`]. Fails with
+    /// [`Error::MetricsNotConfigured`] unless a metrics file or named pipe is configured.
+    pub fn collect_metrics(&self, interval: Duration) -> Result<TaskHandle, Error> {
+        let source = match (self.config.metrics_fifo(), self.config.metrics_path()) {
+            (Some(fifo), _) => MetricsSource::Fifo(self.config.host_path(fifo)),
+            (None, Some(path)) => MetricsSource::File(self.config.host_path(path)),
+            (None, None) => return Err(Error::MetricsNotConfigured),
+        };
+
+        Ok(metrics::collect(
+            source,
+            self.client.clone(),
+            SampleParser::new(&self.config),
+            interval,
+            self.metrics.clone(),
+        ))
+    }
+
+    /// Subscribe to the metrics of the machine, see [`Machine::collect_metrics`].
+    pub fn subscribe_metrics(&self) -> broadcast::Receiver<MetricsSample> {
+        self.metrics.subscribe()
+    }
+
+    /// Get the configuration of the machine.
+    pub fn config(&self) -> &Config<'m> {
+        &self.config
+    }
+
+    /// Checks the machine actual state
+    ///
+    /// Returns SHUTOFF is machine is not running, or CRASHED if the watchdog (see
+    /// [`Machine::watch`]) noticed the VMM disappeared.
+    pub fn state(&self) -> MachineState {
+        let process = self.process.lock().unwrap();
+        if process.crashed {
+            return MachineState::CRASHED;
         }
+        if self.exit_status().is_some() {
+            return MachineState::SHUTOFF;
+        }
+        match process.pid {
+            Some(pid) if watchdog::is_running(pid) => MachineState::RUNNING,
+            _ => MachineState::SHUTOFF,
+        }
+    }
 
-        Ok(())
+    /// Pid of the started jailer/firecracker process, if any.
+    ///
+    /// The pid is cleared once the watchdog (see [`Machine::watch`]) notices the process is gone.
+    pub fn pid(&self) -> Option<u32> {
+        self.process.lock().unwrap().pid
     }
-}
 
-#[derive(Debug, Serialize)]
-#[serde(tag = "action_type", rename_all = "PascalCase")]
-enum Action {
-    InstanceStart,
-    SendCtrlAltDel,
-    #[allow(unused)]
-    FlushMetrics,
+    /// The host path of the Firecracker API socket.
+    pub fn socket_path(&self) -> PathBuf {
+        self.config.host_socket_path()
+    }
+
+    /// The jailer workspace directory, i.e the chroot of the VMM.
+    pub fn workspace_dir(&self) -> &Path {
+        self.config.jailer().workspace_dir()
+    }
+
+    /// Receive guest heartbeats over vsock in the background, see [`crate::heartbeat`].
+    ///
+    /// A [`MachineEventKind::Unhealthy`] event is emitted when the guest misses heartbeats.
+    pub async fn monitor_heartbeat(&self, policy: HeartbeatPolicy) -> Result<TaskHandle, Error> {
+        let listener = self.vsock_listen(policy.port()).await?;
+
+        Ok(heartbeat::monitor(
+            self.config.vm_id().clone(),
+            listener,
+            policy,
+            self.last_heartbeat.clone(),
+            self.events.clone(),
+        ))
+    }
+
+    /// Expose the guest vsock `guest_port` on a host TCP listener bound to `addr`.
+    ///
+    /// Lets guests without network interfaces serve host clients. See [`crate::vsock`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), %addr, guest_port = guest_port))]
+    pub async fn proxy_tcp_to_vsock(
+        &self,
+        addr: SocketAddr,
+        guest_port: u32,
+    ) -> Result<Proxy, Error> {
+        let uds_path = self
+            .config
+            .host_vsock_uds_path()
+            .ok_or(Error::VsockNotConfigured)?;
+        let listener = TcpListener::bind(addr).await?;
+
+        Ok(Proxy::tcp_to_vsock(listener, uds_path, guest_port))
+    }
+
+    /// Forward guest connections to vsock `port` (on CID 2) to the host TCP `target`.
+    ///
+    /// Lets guests without network interfaces reach host services. See [`crate::vsock`].
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), port = port, %target))]
+    pub async fn proxy_vsock_to_tcp(&self, port: u32, target: SocketAddr) -> Result<Proxy, Error> {
+        let listener = self.vsock_listen(port).await?;
+
+        Ok(Proxy::vsock_to_tcp(listener, target))
+    }
+
+    /// Copy `host_path` to `guest_path` through the guest agent, see [`crate::agent`].
+    ///
+    /// The content is streamed and its checksum verified by the agent. Returns the number of bytes
+    /// copied.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn push_file<P>(&self, host_path: P, guest_path: &str) -> Result<u64, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "push_file", async {
+            let uds_path = self
+                .config
+                .host_vsock_uds_path()
+                .ok_or(Error::VsockNotConfigured)?;
+
+            agent::push(
+                &uds_path,
+                self.config.agent_port(),
+                host_path.as_ref(),
+                guest_path,
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Copy `guest_path` to `host_path` through the guest agent, see [`crate::agent`].
+    ///
+    /// The content is streamed and its checksum verified; `host_path` is only replaced once it
+    /// is. Returns the number of bytes copied.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn pull_file<P>(&self, guest_path: &str, host_path: P) -> Result<u64, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "pull_file", async {
+            let uds_path = self
+                .config
+                .host_vsock_uds_path()
+                .ok_or(Error::VsockNotConfigured)?;
+
+            agent::pull(
+                &uds_path,
+                self.config.agent_port(),
+                guest_path,
+                host_path.as_ref(),
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Run `cmd` with `args` and the additional `env` variables in the guest, through the guest
+    /// agent (see [`crate::agent`]).
+    ///
+    /// Waits for the command to exit, for at most `timeout`, and returns its exit code and
+    /// captured output.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id(), cmd = cmd))]
+    pub async fn exec<A, S, E, K, V>(
+        &self,
+        cmd: &str,
+        args: A,
+        env: E,
+        timeout: Duration,
+    ) -> Result<ExecOutput, Error>
+    where
+        A: IntoIterator<Item = S>,
+        S: Into<String>,
+        E: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let vm_id = self.config.vm_id().clone();
+        let request = ExecRequest {
+            cmd: cmd.to_owned(),
+            args: args.into_iter().map(Into::into).collect(),
+            env: env.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+            timeout_ms: timeout.as_millis().try_into().unwrap_or(u64::MAX),
+        };
+        in_operation(vm_id, "exec", async {
+            let uds_path = self
+                .config
+                .host_vsock_uds_path()
+                .ok_or(Error::VsockNotConfigured)?;
+
+            agent::exec(
+                &uds_path,
+                self.config.agent_port(),
+                request,
+                timeout,
+                self.config.agent_max_output(),
+            )
+            .await
+        })
+        .await
+    }
+
+    /// Listen for guest-initiated vsock connections to `port`.
+    async fn vsock_listen(&self, port: u32) -> Result<UnixListener, Error> {
+        let mut uds_path = self
+            .config
+            .host_vsock_uds_path()
+            .ok_or(Error::VsockNotConfigured)?
+            .into_os_string();
+        uds_path.push(format!("_{port}"));
+        let uds_path = PathBuf::from(uds_path);
+        let jailer = self.config.jailer();
+
+        if self.config.fs().exists(&uds_path).await? {
+            self.config
+                .audit(AuditAction::RemoveFile {
+                    path: uds_path.clone(),
+                })
+                .await?;
+        }
+        self.config
+            .audit(AuditAction::SetOwner {
+                path: uds_path.clone(),
+                uid: jailer.uid(),
+                gid: jailer.gid(),
+                mode: vsock::LISTENER_MODE,
+            })
+            .await?;
+
+        // Removing the stale socket and setting the owner of the new one block.
+        let (uid, gid) = (jailer.uid(), jailer.gid());
+        Ok(task::spawn_blocking(move || vsock::listen(&uds_path, uid, gid)).await??)
+    }
+
+    /// The latency and payload size statistics of the API calls made through this machine, see
+    /// [`crate::api_stats`].
+    pub fn api_stats(&self) -> ApiStats {
+        self.client.stats()
+    }
+
+    /// Forget the API call statistics recorded so far, e.g before measuring an operation.
+    pub fn reset_api_stats(&self) {
+        self.client.reset_stats()
+    }
+
+    /// When and how the machine was last resumed, by [`Machine::resume`] or by restoring it
+    /// from a snapshot, see [`crate::clock`].
+    pub fn last_resume(&self) -> Option<ResumeInfo> {
+        *self.last_resume.lock().unwrap()
+    }
+
+    /// Set the guest clock to the host time through the guest agent, see [`crate::clock`].
+    ///
+    /// Meant to be called after [`Machine::resume`] or restoring the VM, as the guest clock
+    /// doesn't advance while it's suspended.
+    #[instrument(skip_all, fields(vm_id = %self.config.vm_id()))]
+    pub async fn sync_guest_clock(&self) -> Result<(), Error> {
+        let vm_id = self.config.vm_id().clone();
+        in_operation(vm_id, "sync_guest_clock", async {
+            let uds_path = self
+                .config
+                .host_vsock_uds_path()
+                .ok_or(Error::VsockNotConfigured)?;
+            let request = clock::sync_request(SystemTime::now());
+            let output = agent::exec(
+                &uds_path,
+                self.config.agent_port(),
+                request,
+                CLOCK_SYNC_TIMEOUT,
+                self.config.agent_max_output(),
+            )
+            .await?;
+            check_exec(output)?;
+            let suspended_for = self.last_resume().and_then(|info| info.suspended_for());
+            info!(?suspended_for, "Guest clock synced");
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// When the last guest heartbeat was received, see [`Machine::monitor_heartbeat`].
+    pub fn last_heartbeat(&self) -> Option<SystemTime> {
+        *self.last_heartbeat.lock().unwrap()
+    }
+
+    /// Watch the VMM process in the background, every `interval`.
+    ///
+    /// If the process exits or its API socket goes away without the machine being stopped, the
+    /// machine is marked as [`MachineState::CRASHED`] and a [`MachineEventKind::Crashed`] event is
+    /// emitted.
+    pub fn watch(&self, interval: Duration) -> TaskHandle {
+        watchdog::watch(
+            self.config.vm_id().clone(),
+            self.client.clone(),
+            self.process.clone(),
+            self.exit_status.clone(),
+            self.events.clone(),
+            interval,
+        )
+    }
+
+    /// Wait for the machine to be shut off, for at most `timeout`.
+    ///
+    /// Returns `false` if the machine is still running after `timeout`.
+    async fn wait_for_shutoff(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.state() == MachineState::RUNNING {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        true
+    }
+
+    #[instrument(skip_all)]
+    async fn wait_for_jailer(
+        &self,
+        jailer_exec_name: &str,
+        child: &mut dyn ChildProcess,
+        timeout: Duration,
+    ) -> Result<u32, StartFailureReason> {
+        let vm_id = self.config.vm_id().to_string();
+        // The ID might be in the command line of unrelated processes, or of VMs jailed elsewhere,
+        // so only processes chrooted in the workspace, or whose root can't be read, are kept.
+        let chroot_dir = self.config.jailer().chroot_dir().to_owned();
+        let chroot_dir = tokio::fs::canonicalize(&chroot_dir)
+            .await
+            .unwrap_or(chroot_dir);
+        // Wait jailer to start up and create the socket.
+        info!("Waiting for the jailer to start up...");
+
+        // get try to get FC version to verify if jailer already started
+        let start = Instant::now();
+        let elapsed = || Instant::now() - start;
+        while self.client.ping().await.is_err() {
+            // In daemon mode, the spawned process exits successfully once it has daemonized.
+            if let Ok(Some(exit_status)) = child.try_wait() {
+                if !exit_status.success() {
+                    return Err(StartFailureReason::ProcessExited);
+                }
+            }
+            if elapsed() < timeout {
+                sleep(Duration::from_millis(100)).await;
+            } else {
+                return Err(StartFailureReason::TimedOut);
+            }
+        }
+        if let Some(pid) = self.jailed_pid().await {
+            return Ok(pid);
+        }
+
+        // get PID of started firecracker, scanning the processes off the runtime threads as it
+        // blocks.
+        let jailer_exec_name = jailer_exec_name.to_owned();
+        let pids = task::spawn_blocking(move || {
+            let mut sys = System::new();
+            sys.refresh_specifics(
+                sysinfo::RefreshKind::new().with_processes(ProcessRefreshKind::everything()),
+            );
+            sys.processes_by_name(&jailer_exec_name)
+                .filter(|&process| process.cmd().contains(&vm_id))
+                .map(|process| process.pid().as_u32())
+                .filter(|&pid| root_dir(pid).is_none_or(|root| root == chroot_dir))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|_| StartFailureReason::ProcessLookupFailed { candidates: 0 })?;
+
+        match pids[..] {
+            [pid] => Ok(pid),
+            _ => Err(StartFailureReason::ProcessLookupFailed {
+                candidates: pids.len(),
+            }),
+        }
+    }
+
+    /// The PID of the Firecracker process from the cgroup of the jail, if the jailer sets one up,
+    /// or from the PID file the jailer writes in the chroot.
+    async fn jailed_pid(&self) -> Option<u32> {
+        let jailer = self.config.jailer();
+        if let Some(path) = jailer.cgroup_procs_path(self.config.vm_id()) {
+            match tokio::fs::read_to_string(&path).await {
+                Ok(procs) => {
+                    let pids: Vec<u32> = procs.lines().filter_map(|l| l.parse().ok()).collect();
+                    if let [pid] = pids[..] {
+                        trace!(pid, "Found PID in cgroup");
+                        return Some(pid);
+                    }
+                }
+                Err(e) => trace!(error = %e, "Failed to read `{}`", path.display()),
+            }
+        }
+
+        let pid_file = jailer.pid_file_path()?;
+        match self.config.fs().read(&pid_file).await {
+            Ok(contents) => {
+                let pid = String::from_utf8_lossy(&contents).trim().parse().ok()?;
+                trace!(pid, "Found PID in PID file");
+                Some(pid)
+            }
+            Err(e) => {
+                trace!(error = %e, "Failed to read `{}`", pid_file.display());
+                None
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn pin_vcpus(&self) -> Result<(), Error> {
+        let cpus = self.config.vcpu_affinity();
+        if cpus.is_empty() {
+            return Ok(());
+        }
+
+        for (tid, cpu) in self
+            .vcpu_threads()
+            .await?
+            .into_iter()
+            .zip(cpus.iter().cycle())
+        {
+            trace!(tid, cpu, "Pinning vCPU thread");
+            run_command(
+                &self.config,
+                Command::new("taskset")
+                    .args(["-p", "-c", &cpu.to_string()])
+                    .arg(tid.to_string()),
+            )
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    fn drive(&self, drive_id: &str) -> Result<&Drive<'m>, Error> {
+        self.config
+            .drives
+            .iter()
+            .find(|drive| drive.drive_id() == drive_id)
+            .ok_or_else(|| Error::DriveNotFound(drive_id.to_owned()))
+    }
+
+    async fn send_request(&self, path: &str, body: String) -> Result<(), Error> {
+        self.client.send(Method::PUT, path, Some(body)).await?;
+
+        Ok(())
+    }
+
+    async fn send_action(&self, action: Action) -> Result<(), Error> {
+        let json = serde_json::to_string(&action)?;
+        self.send_request("/actions", json).await?;
+
+        Ok(())
+    }
+
+    /// Prepare the machine for running.
+    #[instrument(skip_all)]
+    async fn setup_vm(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        info!("Setting the VM...");
+        // All of these are independent pre-boot resources, so we don't need to wait for one to be
+        // configured before sending the next.
+        try_join!(
+            timer.step(SetupStep::MachineConfig, self.setup_resources(timer)),
+            timer.step(SetupStep::BootSource, self.setup_boot_source(timer)),
+            timer.step(SetupStep::Drives, self.setup_drives(timer)),
+            async {
+                timer
+                    .step(SetupStep::Network, self.setup_network(timer))
+                    .await?;
+                timer.step(SetupStep::Mmds, self.setup_mmds(timer)).await
+            },
+            timer.step(SetupStep::Vsock, self.setup_vsock(timer)),
+            timer.step(SetupStep::Balloon, self.setup_balloon(timer)),
+            timer.step(SetupStep::Logger, self.setup_logger(timer)),
+        )?;
+        trace!("VM successfully setup.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn restore_vm(
+        &self,
+        timer: &StartTimer<'_>,
+        snapshot: &Snapshot,
+        options: &StartOptions,
+    ) -> Result<(), Error> {
+        info!("Restoring the VM from snapshot...");
+        timer
+            .step(SetupStep::Logger, self.setup_logger(timer))
+            .await?;
+        self.extra_setup(timer, options).await?;
+        let json = serde_json::to_string(&snapshot_load_payload(&self.config, snapshot, options)?)?;
+        timer
+            .run(
+                StartPhase::InstanceStart,
+                options.instance_start_timeout,
+                self.send_request("/snapshot/load", json),
+            )
+            .await?;
+        // The snapshot is written when the guest is paused, which is when its clock stopped.
+        let suspended_at = tokio::fs::metadata(&snapshot.state_path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        *self.last_resume.lock().unwrap() = Some(ResumeInfo::new(suspended_at, true));
+        trace!("VM successfully restored.");
+
+        Ok(())
+    }
+
+    /// Snapshot the VM if it's `running` and store the archive, see [`Machine::delete_with`].
+    async fn archive(&self, store: &dyn ArchiveStore, running: bool) -> Result<(), Error> {
+        let snapshot = if running {
+            self.pause().await?;
+            match self
+                .create_snapshot(SnapshotType::Full, FINAL_SNAPSHOT_NAME)
+                .await
+            {
+                Ok(snapshot) => Some(snapshot),
+                Err(err) => return Err(self.resume_after(err).await),
+            }
+        } else {
+            None
+        };
+
+        let fs = self.config.fs();
+        let mut files = vec![self.config.record_path()];
+        let logs = [
+            self.config.log_fifo().or(self.config.log_path()),
+            self.config.metrics_fifo().or(self.config.metrics_path()),
+        ];
+        for path in logs.into_iter().flatten() {
+            let path = self.config.host_path(path);
+            if fs.exists(&path).await? {
+                files.push(path);
+            }
+        }
+        let archive = Archive {
+            vm_id: self.config.vm_id().clone(),
+            snapshot,
+            files,
+        };
+        info!("Archiving VM...");
+        if let Err(err) = store.store(&archive).await {
+            return Err(match running {
+                true => self.resume_after(err).await,
+                false => err,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Make the extra setup calls of `options`, in order.
+    #[instrument(skip_all)]
+    async fn extra_setup(
+        &self,
+        timer: &StartTimer<'_>,
+        options: &StartOptions,
+    ) -> Result<(), Error> {
+        for call in &options.extra_setup {
+            let request = self
+                .client
+                .send(call.method.clone(), &call.path, call.body.clone());
+            timer.setup(&call.path, request).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the started VMM has the version the configuration pins, if any.
+    async fn check_firecracker_version(&self) -> Result<(), Error> {
+        let Some(expected) = self.config.firecracker_version() else {
+            return Ok(());
+        };
+        let actual = self.firecracker_version().await?;
+        if actual != expected {
+            return Err(Error::FirecrackerVersionMismatch { expected, actual });
+        }
+
+        Ok(())
+    }
+
+    /// Resume the VM after `err` happened, returning `err`.
+    async fn resume_after(&self, err: Error) -> Error {
+        if let Err(e) = self.resume().await {
+            warn!(error = %e, "Failed to resume VM");
+        }
+
+        err
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_resources(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        trace!("Configuring machine resources...");
+        let json = serde_json::to_string(self.config.machine_cfg())?;
+        let path = "/machine-config";
+        timer.setup(path, self.send_request(path, json)).await?;
+        trace!("Machine resources configured successfully.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_boot_source(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        trace!("Configuring boot source...");
+        let boot_source = self.config.boot_source()?;
+        let json = serde_json::to_string(&boot_source)?;
+        let path = "/boot-source";
+        timer.setup(path, self.send_request(path, json)).await?;
+        trace!("Boot source configured successfully.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_drives(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        trace!("Configuring drives...");
+        try_join_all(self.config.drives.iter().map(|drive| async move {
+            let path = format!("/drives/{}", drive.drive_id());
+            let json = serde_json::to_string(&drive_payload(&self.config, drive)?)?;
+            timer.setup(&path, self.send_request(&path, json)).await
+        }))
+        .await?;
+        trace!("Drives configured successfully.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_network(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        trace!("Configuring network...");
+        try_join_all(
+            self.config
+                .network_interfaces()
+                .iter()
+                .map(|network| async move {
+                    let json = serde_json::to_string(network)?;
+                    let path = format!("/network-interfaces/{}", network.vm_if_name());
+                    timer.setup(&path, self.send_request(&path, json)).await
+                }),
+        )
+        .await?;
+        trace!("All networks configured successfully.");
+        Ok(())
+    }
+
+    /// Configure MMDS, which must happen after the network interfaces it uses are configured.
+    #[instrument(skip_all)]
+    async fn setup_mmds(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        let mmds_cfg = match self.config.mmds_cfg() {
+            Some(mmds) => mmds,
+            None => return Ok(()),
+        };
+        trace!("Configuring MMDS...");
+        let required = mmds_cfg.version().min_firecracker_version();
+        let version = match self.config.firecracker_version() {
+            Some(version) => version,
+            None => self.firecracker_version().await?,
+        };
+        if version < required {
+            return Err(Error::UnsupportedFeature {
+                feature: format!("MMDS {:?}", mmds_cfg.version()),
+                required,
+                version,
+            });
+        }
+        let path = "/mmds/config";
+        let json = serde_json::to_string(mmds_cfg)?;
+        timer.setup(path, self.send_request(path, json)).await?;
+        trace!("MMDS configured successfully.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_logger(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        let logger = match self.config.logger() {
+            Some(logger) => logger,
+            None => return Ok(()),
+        };
+        trace!("Configuring logger...");
+        let path = "/logger";
+        let json = serde_json::to_string(&logger)?;
+        timer.setup(path, self.send_request(path, json)).await?;
+        trace!("Logger configured successfully.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_balloon(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        let balloon_cfg = match self.config.balloon_cfg() {
+            Some(balloon) => balloon,
+            None => return Ok(()),
+        };
+        trace!("Configuring balloon...");
+        let path = "/balloon";
+        let json = serde_json::to_string(balloon_cfg)?;
+        timer.setup(path, self.send_request(path, json)).await?;
+        trace!("Balloon configured successfully.");
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    async fn setup_vsock(&self, timer: &StartTimer<'_>) -> Result<(), Error> {
+        let vsock_cfg = match self.config.vsock_cfg() {
+            Some(vsock) => vsock,
+            None => return Ok(()),
+        };
+        trace!("Configuring vsock...");
+        let path = "/vsock";
+        let json = serde_json::to_string(vsock_cfg)?;
+        timer.setup(path, self.send_request(path, json)).await?;
+        trace!("vsock configured successfully.");
+
+        Ok(())
+    }
+
+    /// Apply the configured permissions to the vsock socket, once Firecracker created it.
+    async fn secure_vsock_socket(&self) -> Result<(), Error> {
+        let (Some(path), Some(permissions)) = (
+            self.config.host_vsock_uds_path(),
+            self.config.vsock_socket_permissions(),
+        ) else {
+            return Ok(());
+        };
+
+        self.set_socket_permissions(&path, permissions).await
+    }
+
+    async fn set_socket_permissions(
+        &self,
+        path: &Path,
+        permissions: SocketPermissions,
+    ) -> Result<(), Error> {
+        let jailer = self.config.jailer();
+        let (uid, gid) = permissions
+            .owner_ids()
+            .unwrap_or((jailer.uid(), jailer.gid()));
+        trace!(
+            "Setting permissions of `{}` to {:o}",
+            path.display(),
+            permissions.mode()
+        );
+        self.config
+            .fs()
+            .set_owner(path, uid, gid, permissions.mode())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Create the files capturing the output of the jailer before it daemonizes.
+    async fn create_daemon_output_files(&self) -> Result<(Stdio, Stdio), Error> {
+        let stdout_path = self.config.jailer_stdout_path();
+        let stderr_path = self.config.jailer_stderr_path();
+        if let Some(dir) = stdout_path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        trace!(
+            "Capturing jailer output to `{}` and `{}`",
+            stdout_path.display(),
+            stderr_path.display()
+        );
+        // Open in append mode, so the files can be rotated by truncating them.
+        let open = |path| async move {
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.set_len(0).await?;
+
+            Ok::<_, Error>(file.into_std().await)
+        };
+        let stdout = open(&stdout_path).await?;
+        let stderr = open(&stderr_path).await?;
+
+        Ok((stdout.into(), stderr.into()))
+    }
+
+    /// Reap the spawned process in the background so it doesn't linger as a zombie.
+    ///
+    /// Returns a receiver for the exit status if the process is the VMM itself.
+    fn reap(
+        &self,
+        mut child: Box<dyn ChildProcess>,
+    ) -> Option<watch::Receiver<Option<ExitStatus>>> {
+        let (tx, rx) = watch::channel(None);
+        let span = Span::current();
+        task::spawn(async move {
+            match child.wait().await {
+                Ok(exit_status) => {
+                    trace!(parent: &span, %exit_status, "Process exited");
+                    let _ = tx.send(Some(exit_status));
+                }
+                Err(e) => warn!(parent: &span, error = %e, "Failed to wait for process"),
+            }
+        });
+
+        matches!(self.config.jailer().mode(), JailerMode::Attached(_)).then_some(rx)
+    }
+
+    /// Collect evidence about a failed start.
+    async fn start_failure(
+        &self,
+        reason: StartFailureReason,
+        child: &mut dyn ChildProcess,
+    ) -> Error {
+        warn!(%reason, "Failed to start VMM");
+        let fs = self.config.fs();
+        let exit_status = child.try_wait().unwrap_or_else(|e| {
+            warn!(error = %e, "Failed to query process exit status");
+            None
+        });
+        let socket_exists = fs
+            .exists(&self.config.host_socket_path())
+            .await
+            .unwrap_or(false);
+        let chroot_listing = fs
+            .read_dir(self.config.jailer().workspace_dir())
+            .await
+            .unwrap_or_else(|e| {
+                warn!(error = %e, "Failed to list chroot directory");
+                Vec::new()
+            });
+
+        Error::StartFailed(Box::new(StartFailure {
+            reason,
+            exit_status,
+            stdout_tail: self
+                .read_jailer_output_tail(&self.config.jailer_stdout_path())
+                .await,
+            stderr_tail: self
+                .read_jailer_output_tail(&self.config.jailer_stderr_path())
+                .await,
+            socket_exists,
+            chroot_listing,
+        }))
+    }
+
+    /// Read the last lines of the captured jailer output at `path`, if any.
+    async fn read_jailer_output_tail(&self, path: &Path) -> Vec<String> {
+        if !matches!(self.config.jailer().mode(), JailerMode::Daemon)
+            || !self.config.jailer().capture_daemon_output()
+        {
+            return Vec::new();
+        }
+
+        match tokio::fs::read_to_string(path).await {
+            Ok(content) => {
+                let lines: Vec<_> = content.lines().map(str::to_owned).collect();
+                let skip = lines.len().saturating_sub(OUTPUT_TAIL_LINES);
+                lines.into_iter().skip(skip).collect()
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to read `{}`", path.display());
+                Vec::new()
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn cleanup_before_starting(&self) -> Result<(), Error> {
+        trace!("Deleting intermediate VM resources before starting...");
+        let socket_path = self.config.host_socket_path();
+        trace!("Removing socket file {}...", socket_path.display());
+        let fs = self.config.fs();
+        match fs.remove_file(&socket_path).await {
+            Ok(_) => trace!("Deleted `{}`", socket_path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                trace!("`{}` not found", socket_path.display())
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let jailer_workspace_dir = self.config.jailer().workspace_dir();
+
+        // Remove the vsock socket file if it exists.
+        if let Some(path) = self.config.host_vsock_uds_path() {
+            trace!("Removing vsock socket file {}...", path.display());
+            match fs.remove_file(&path).await {
+                Ok(_) => trace!("Deleted `{}`", path.display()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    trace!("`{}` not found", path.display())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let dev_dir = jailer_workspace_dir.join("dev");
+        trace!("Deleting `{}`", dev_dir.display());
+        match fs.remove_dir_all(&dev_dir).await {
+            Ok(_) => trace!("Deleted `{}`", dev_dir.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                trace!("`{}` not found", dev_dir.display())
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "action_type", rename_all = "PascalCase")]
+pub(crate) enum Action {
+    InstanceStart,
+    SendCtrlAltDel,
+    FlushMetrics,
+}
+
+/// A command executing `program`, the first element being the binary.
+fn program_command(program: &[OsString]) -> Command {
+    let mut cmd = Command::new(&program[0]);
+    cmd.args(&program[1..]);
+
+    cmd
+}
+
+/// Run a helper command to completion through the configured spawner.
+pub(crate) async fn run_command(config: &Config<'_>, cmd: &mut Command) -> Result<(), Error> {
+    run_command_with(config.spawner(), cmd).await
+}
+
+/// Run a helper command like [`run_command`], through `spawner`.
+pub(crate) async fn run_command_with(
+    spawner: &dyn ProcessSpawner,
+    cmd: &mut Command,
+) -> Result<(), Error> {
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    trace!("Running command: {:?}", cmd);
+    let exit_status = spawner.spawn(cmd)?.wait().await?;
+    check_exit_status(cmd, exit_status)
+}
+
+/// Run a helper command like [`run_command`], returning its standard output.
+pub(crate) async fn command_output(
+    config: &Config<'_>,
+    cmd: &mut Command,
+) -> Result<String, Error> {
+    let (mut reader, writer) = io::pipe()?;
+    cmd.stdin(Stdio::null())
+        .stdout(writer)
+        .stderr(Stdio::null());
+    trace!("Running command: {:?}", cmd);
+    let mut child = config.spawner().spawn(cmd)?;
+    // Close our end of the pipe, so that reading stops when the process exits.
+    cmd.stdout(Stdio::null());
+    let output = task::spawn_blocking(move || {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).map(|_| output)
+    });
+    let exit_status = child.wait().await?;
+    check_exit_status(cmd, exit_status)?;
+
+    Ok(output.await.map_err(io::Error::other)??)
+}
+
+/// The operations of [`Machine::create`], see [`Machine::plan`].
+async fn plan_create(config: &Config<'_>) -> Result<Vec<PlannedOperation>, Error> {
+    let fs = config.fs();
+    let jailer = config.jailer();
+    let mut ops = vec![PlannedOperation::CreateDir {
+        path: jailer.workspace_dir().to_owned(),
+    }];
+    if let Some(quota) = jailer.workspace_quota() {
+        ops.push(PlannedOperation::SetupWorkspaceQuota {
+            quota: format!("{quota:?}"),
+        });
+    }
+    if *jailer.workspace_layout() != WorkspaceLayout::Jailer {
+        ops.push(PlannedOperation::BindMount {
+            src: jailer.workspace_dir().to_owned(),
+            dest: jailer.chroot_dir().to_owned(),
+        });
+    }
+
+    let src = config.src_kernel_image_path();
+    let dest = config.kernel_image_path();
+    if kernel_needs_copy(config, &dest).await? {
+        let decompress = config.decompress_kernel() && kernel_needs_decompression(config).await;
+        ops.push(planned_copy(src, dest, decompress).await);
+    }
+    if let (Some(src), Some(dest)) = (config.src_initrd_path(), config.initrd_path()?) {
+        if needs_copy(config, src, &dest).await? {
+            ops.push(planned_copy(src, dest, false).await);
+        }
+    }
+    if let (Seccomp::Custom(src), Some(dest)) = (config.seccomp(), config.seccomp_filter_path()) {
+        if needs_copy(config, src, &dest).await? {
+            ops.push(planned_copy(src, dest, false).await);
+        }
+    }
+    if let (Some(metadata), Some(path)) = (config.mmds_metadata(), config.mmds_metadata_path()) {
+        ops.push(PlannedOperation::WriteFile {
+            path,
+            size: serde_json::to_vec(metadata)?.len() as u64,
+        });
+    }
+    if let (None, Some(log_path)) = (config.log_fifo(), config.log_path()) {
+        let path = config.host_path(log_path);
+        if !fs.exists(&path).await? {
+            ops.push(PlannedOperation::WriteFile { path, size: 0 });
+        }
+    }
+
+    if config.check_root_filesystem() {
+        if let Some(root) = config.drives().iter().find(|d| d.is_root_device()) {
+            ops.push(PlannedOperation::CheckRootFilesystem {
+                path: root.src_path().to_owned(),
+            });
+        }
+    }
+    for drive in config.drives() {
+        let dest = config.drive_path(drive)?;
+        if *drive.provisioning() != DriveProvisioning::Copy {
+            ops.push(PlannedOperation::ProvisionDrive {
+                drive_id: drive.drive_id().to_owned(),
+                provisioning: format!("{:?}", drive.provisioning()),
+                dest,
+            });
+        } else if needs_copy(config, drive.src_path(), &dest).await? {
+            ops.push(planned_copy(drive.src_path(), dest, false).await);
+        }
+    }
+    if jailer.chown_artifacts() {
+        ops.push(PlannedOperation::ChownArtifacts {
+            uid: jailer.uid(),
+            gid: jailer.gid(),
+        });
+    }
+
+    for iface in config.network_interfaces() {
+        if let TapMode::Bridge {
+            bridge,
+            create_bridge,
+        } = iface.tap_mode()
+        {
+            ops.push(PlannedOperation::CreateTap {
+                name: iface.host_if_name().to_owned(),
+                bridge: bridge.to_string(),
+                create_bridge: *create_bridge,
+            });
+        }
+    }
+    if let Some(nat) = config.nat() {
+        ops.push(PlannedOperation::SetupNat {
+            guest_subnet: nat.guest_subnet().to_owned(),
+            host_interface: nat.host_interface().to_owned(),
+        });
+    }
+    ops.push(PlannedOperation::WriteFile {
+        path: config.record_path(),
+        size: serde_json::to_vec(&VmRecord::new(config))?.len() as u64,
+    });
+    if let Some(socket_dir) = config.host_socket_path().parent() {
+        ops.push(PlannedOperation::CreateDir {
+            path: socket_dir.to_owned(),
+        });
+    }
+
+    Ok(ops)
+}
+
+/// The operations of [`Machine::start_with`], see [`Machine::plan`].
+fn plan_start(config: &Config<'_>, options: &StartOptions) -> Result<Vec<PlannedOperation>, Error> {
+    let jailer = config.jailer();
+    let mut command: Vec<_> = jailer
+        .program()
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    match jailer.mode() {
+        JailerMode::Daemon => command.push("--daemonize".to_owned()),
+        JailerMode::Attached(_) => {}
+        JailerMode::Tmux(session_name) => {
+            let session_name = session_name
+                .as_deref()
+                .map_or_else(|| config.vm_id().to_string(), ToOwned::to_owned);
+            let tmux = ["tmux", "new-session", "-d", "-s", &session_name];
+            command.splice(0..0, tmux.map(ToOwned::to_owned));
+        }
+    }
+    command.extend(jailer_args(config)?);
+    let mut ops = vec![PlannedOperation::SpawnJailer { command }];
+
+    let mut calls = Vec::new();
+    let logger = match config.logger() {
+        Some(logger) if !options.skips(SetupStep::Logger) => Some((
+            Method::PUT,
+            "/logger".to_owned(),
+            Some(serde_json::to_string(&logger)?),
+        )),
+        _ => None,
+    };
+    let extra_setup = options
+        .extra_setup
+        .iter()
+        .map(|call| (call.method.clone(), call.path.clone(), call.body.clone()));
+
+    if let Some(snapshot) = &options.snapshot {
+        calls.extend(logger);
+        calls.extend(extra_setup);
+        let load = snapshot_load_payload(config, snapshot, options)?;
+        calls.push((
+            Method::PUT,
+            "/snapshot/load".to_owned(),
+            Some(load.to_string()),
+        ));
+    } else {
+        let mut put = |path: String, json: String| calls.push((Method::PUT, path, Some(json)));
+        if !options.skips(SetupStep::MachineConfig) {
+            put(
+                "/machine-config".to_owned(),
+                serde_json::to_string(config.machine_cfg())?,
+            );
+        }
+        if !options.skips(SetupStep::BootSource) {
+            put(
+                "/boot-source".to_owned(),
+                serde_json::to_string(&config.boot_source()?)?,
+            );
+        }
+        if !options.skips(SetupStep::Drives) {
+            for drive in config.drives() {
+                put(
+                    format!("/drives/{}", drive.drive_id()),
+                    serde_json::to_string(&drive_payload(config, drive)?)?,
+                );
+            }
+        }
+        if !options.skips(SetupStep::Network) {
+            for iface in config.network_interfaces() {
+                put(
+                    format!("/network-interfaces/{}", iface.vm_if_name()),
+                    serde_json::to_string(iface)?,
+                );
+            }
+        }
+        if let Some(mmds_cfg) = config
+            .mmds_cfg()
+            .filter(|_| !options.skips(SetupStep::Mmds))
+        {
+            put("/mmds/config".to_owned(), serde_json::to_string(mmds_cfg)?);
+        }
+        if let Some(vsock_cfg) = config
+            .vsock_cfg()
+            .filter(|_| !options.skips(SetupStep::Vsock))
+        {
+            put("/vsock".to_owned(), serde_json::to_string(vsock_cfg)?);
+        }
+        if let Some(balloon_cfg) = config
+            .balloon_cfg()
+            .filter(|_| !options.skips(SetupStep::Balloon))
+        {
+            put("/balloon".to_owned(), serde_json::to_string(balloon_cfg)?);
+        }
+        calls.extend(logger);
+        calls.extend(extra_setup);
+        let json = serde_json::to_string(&Action::InstanceStart)?;
+        calls.push((Method::PUT, "/actions".to_owned(), Some(json)));
+    }
+
+    for (method, path, body) in calls {
+        let body = match (config.firecracker_version(), body) {
+            (Some(version), Some(body)) => {
+                let body = compat::adapt(version, &method, &path, body);
+                Some(compat::conform(version, &method, &path, body)?)
+            }
+            (_, body) => body,
+        };
+        ops.push(PlannedOperation::ApiCall {
+            method: method.to_string(),
+            path,
+            body: body.map(|body| serde_json::from_str(&body)).transpose()?,
+        });
+    }
+
+    Ok(ops)
+}
+
+/// A planned copy of `src` to `dest`.
+async fn planned_copy(src: &Path, dest: PathBuf, decompress: bool) -> PlannedOperation {
+    PlannedOperation::Copy {
+        src: src.to_owned(),
+        size: tokio::fs::metadata(src)
+            .await
+            .ok()
+            .map(|metadata| metadata.len()),
+        dest,
+        decompress,
+    }
+}
+
+/// If the kernel image would be decompressed when copied, see [`copy_kernel`].
+async fn kernel_needs_decompression(config: &Config<'_>) -> bool {
+    let Some(arch) = config.target_arch().or_else(Arch::host) else {
+        return false;
+    };
+
+    match kernel::read_header(config.src_kernel_image_path()).await {
+        Ok(header) => !KernelFormat::detect(&header).is_bootable(arch),
+        Err(_) => false,
+    }
+}
+
+/// The payload configuring `drive`, with the drive file at its location in the chroot.
+fn drive_payload<'c>(config: &Config<'_>, drive: &Drive<'c>) -> Result<Drive<'c>, Error> {
+    let mut drive_obj = drive.clone();
+    drive_obj.src_path = PathBuf::from(config.drive_name(drive)?).into();
+
+    Ok(drive_obj)
+}
+
+/// The payload loading `snapshot`, see [`Machine::restore_vm`].
+fn snapshot_load_payload(
+    config: &Config<'_>,
+    snapshot: &Snapshot,
+    options: &StartOptions,
+) -> Result<serde_json::Value, Error> {
+    let (snapshot_path, mem_file_path) = snapshot.chroot_paths(config)?;
+    let mut load = serde_json::json!({
+        "snapshot_path": snapshot_path,
+        "mem_backend": {
+            "backend_type": "File",
+            "backend_path": mem_file_path,
+        },
+        "enable_diff_snapshots": config.machine_cfg().track_dirty_pages(),
+        "resume_vm": true,
+    });
+    if options.network_overrides {
+        load["network_overrides"] = config
+            .network_interfaces()
+            .iter()
+            .map(|interface| {
+                serde_json::json!({
+                    "iface_id": interface.vm_if_name(),
+                    "host_dev_name": interface.host_if_name(),
+                })
+            })
+            .collect();
+    }
+
+    Ok(load)
+}
+
+/// The arguments of the jailer, followed by those of Firecracker.
+fn jailer_args(config: &Config<'_>) -> Result<Vec<String>, Error> {
+    let jailer = config.jailer();
+    let mut args = vec![
+        "--id".to_owned(),
+        config.vm_id().to_string(),
+        "--exec-file".to_owned(),
+        jailer
+            .exec_file()
+            .to_str()
+            .ok_or(Error::InvalidJailerExecPath)?
+            .to_owned(),
+        "--uid".to_owned(),
+        jailer.uid().to_string(),
+        "--gid".to_owned(),
+        jailer.gid().to_string(),
+        "--chroot-base-dir".to_owned(),
+        jailer
+            .chroot_base_dir()
+            .to_str()
+            .ok_or(Error::InvalidChrootBasePath)?
+            .to_owned(),
+    ];
+    if let Some(net_ns) = config.net_ns() {
+        args.extend(["--netns".to_owned(), net_ns.to_owned()]);
+    }
+    args.extend(jailer.extra_jailer_args().iter().map(|arg| arg.to_string()));
+    // `firecracker` binary args.
+    args.push("--".to_owned());
+    args.extend(config.vmm_args()?);
+
+    Ok(args)
+}
+
+fn check_exit_status(cmd: &Command, exit_status: ExitStatus) -> Result<(), Error> {
+    if !exit_status.success() {
+        return Err(Error::CommandFailed {
+            command: format!("{:?}", cmd.as_std()),
+            exit_status,
+        });
+    }
+
+    Ok(())
+}
+
+/// Fail unless a command run by the guest agent exited successfully.
+fn check_exec(output: ExecOutput) -> Result<(), Error> {
+    if !output.success() {
+        return Err(AgentError::CommandFailed {
+            exit_code: output.exit_code,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The root directory of the process `pid`, if it can be read.
+pub(crate) fn root_dir(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{pid}/root")).ok()
+}
+
+/// If the artifact at `src` must be copied to `dest` in the chroot, see
+/// [`Config::artifact_refresh`].
+async fn needs_copy(config: &Config<'_>, src: &Path, dest: &Path) -> Result<bool, Error> {
+    let fs = config.fs();
+    let needs_copy = match config.artifact_refresh() {
+        ArtifactRefresh::IfMissing => !fs.exists(dest).await?,
+        ArtifactRefresh::IfChanged => {
+            !fs.exists(dest).await? || !fs.same_contents(src, dest).await?
+        }
+        ArtifactRefresh::Always => true,
+    };
+
+    Ok(needs_copy)
+}
+
+/// If the kernel image must be copied to `dest` in the chroot, see [`needs_copy`].
+///
+/// A decompressed kernel never has the contents of its source, so with
+/// [`ArtifactRefresh::IfChanged`] the checksum of the source it was decompressed from is compared
+/// instead, see [`source_checksum_path`].
+async fn kernel_needs_copy(config: &Config<'_>, dest: &Path) -> Result<bool, Error> {
+    let src = config.src_kernel_image_path();
+    if config.artifact_refresh() != ArtifactRefresh::IfChanged
+        || !config.decompress_kernel()
+        || !kernel_needs_decompression(config).await
+    {
+        return needs_copy(config, src, dest).await;
+    }
+
+    let fs = config.fs();
+    let checksum_path = source_checksum_path(dest);
+    if !fs.exists(dest).await? || !fs.exists(&checksum_path).await? {
+        return Ok(true);
+    }
+    let src = src.to_owned();
+    let checksum = task::spawn_blocking(move || crate::fs::sha256(&src)).await??;
+
+    Ok(fs.read(&checksum_path).await? != checksum)
+}
+
+/// Where the SHA-256 of the source of the decompressed kernel at `dest` is recorded.
+fn source_checksum_path(dest: &Path) -> PathBuf {
+    let mut path = dest.as_os_str().to_owned();
+    path.push(".src.sha256");
+
+    path.into()
+}
+
+/// Copy the kernel image to `dest` in the chroot, checking that it can boot and decompressing it
+/// if configured to.
+async fn copy_kernel(config: &Config<'_>, dest: &Path) -> Result<(), Error> {
+    let fs = config.fs();
+    let src = config.src_kernel_image_path();
+    // Without a known architecture, leave it to Firecracker.
+    let Some(arch) = config.target_arch().or_else(Arch::host) else {
+        trace!(
+            "Copying kernel image from `{}` to `{}`",
+            src.display(),
+            dest.display()
+        );
+        fs.copy(src, dest).await?;
+        return Ok(());
+    };
+
+    // The source is on the local host, like the sources of `ChrootFs::copy`.
+    let header = kernel::read_header(src).await?;
+    if config.decompress_kernel() && !KernelFormat::detect(&header).is_bootable(arch) {
+        trace!(
+            "Decompressing kernel image from `{}` to `{}`",
+            src.display(),
+            dest.display()
+        );
+        let (image, checksum) = task::spawn_blocking({
+            let src = src.to_owned();
+            move || -> Result<_, Error> {
+                let image = std::fs::read(&src)?;
+                let checksum = Sha256::digest(&image).to_vec();
+                Ok((kernel::decompress(&src, image, arch)?, checksum))
+            }
+        })
+        .await??;
+        fs.write(dest, image).await?;
+        fs.write(&source_checksum_path(dest), checksum).await?;
+    } else {
+        kernel::validate(src, &header, arch)?;
+        trace!(
+            "Copying kernel image from `{}` to `{}`",
+            src.display(),
+            dest.display()
+        );
+        fs.copy(src, dest).await?;
+    }
+
+    Ok(())
+}
+
+/// Give the artifacts in the chroot to the jailer uid/gid, with minimal permissions.
+#[instrument(skip_all)]
+async fn chown_artifacts(config: &Config<'_>) -> Result<(), Error> {
+    let mut artifacts = vec![(config.kernel_image_path(), 0o400)];
+    if config.src_initrd_path().is_some() {
+        artifacts.extend(config.initrd_path()?.map(|path| (path, 0o400)));
+    }
+    if let Seccomp::Custom(_) = config.seccomp() {
+        artifacts.extend(config.seccomp_filter_path().map(|path| (path, 0o400)));
+    }
+    if config.mmds_metadata().is_some() {
+        artifacts.extend(config.mmds_metadata_path().map(|path| (path, 0o400)));
+    }
+    if let (None, Some(log_path)) = (config.log_fifo(), config.log_path()) {
+        artifacts.push((config.host_path(log_path), 0o600));
+    }
+    for drive in &config.drives {
+        let mode = if drive.is_read_only() { 0o400 } else { 0o600 };
+        artifacts.push((config.drive_path(drive)?, mode));
+    }
+
+    let jailer = config.jailer();
+    for (path, mode) in artifacts {
+        trace!("Setting owner of `{}` (mode {mode:o})", path.display());
+        config
+            .fs()
+            .set_owner(&path, jailer.uid(), jailer.gid(), mode)
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn setup_workspace_quota(
+    config: &Config<'_>,
+    quota: &WorkspaceQuota<'_>,
+) -> Result<(), Error> {
+    let workspace_dir = config.jailer().workspace_dir();
+    match quota {
+        WorkspaceQuota::LoopFile { size } => {
+            let image = config.workspace_image_path();
+            if !config.fs().exists(&image).await? {
+                info!(size, "Creating workspace image at `{}`", image.display());
+                run_command(
+                    config,
+                    Command::new("truncate")
+                        .arg("-s")
+                        .arg(size.to_string())
+                        .arg(&image),
+                )
+                .await?;
+                run_command(
+                    config,
+                    Command::new("mkfs.ext4").args(["-q", "-F"]).arg(&image),
+                )
+                .await?;
+            }
+
+            let mounted = run_command(
+                config,
+                Command::new("mountpoint").arg("-q").arg(workspace_dir),
+            )
+            .await
+            .is_ok();
+            if !mounted {
+                trace!("Mounting workspace image on `{}`", workspace_dir.display());
+                run_command(
+                    config,
+                    Command::new("mount")
+                        .args(["-o", "loop"])
+                        .arg(&image)
+                        .arg(workspace_dir),
+                )
+                .await?;
+            }
+        }
+        WorkspaceQuota::Project {
+            id,
+            size,
+            mount_point,
+        } => {
+            info!(id, size, "Setting up workspace project quota");
+            let project = format!("project -s -p {} {id}", workspace_dir.display());
+            let limit = format!("limit -p bhard={size} {id}");
+            for command in [project, limit] {
+                run_command(
+                    config,
+                    Command::new("xfs_quota")
+                        .args(["-x", "-c", &command])
+                        .arg(mount_point.as_ref()),
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Bind-mount the workspace at the chroot directory of the jailer, unless it's already there, see
+/// [`WorkspaceLayout`].
+#[instrument(skip_all)]
+async fn setup_workspace_layout(config: &Config<'_>) -> Result<(), Error> {
+    let jailer = config.jailer();
+    if *jailer.workspace_layout() == WorkspaceLayout::Jailer {
+        return Ok(());
+    }
+    let (workspace_dir, chroot_dir) = (jailer.workspace_dir(), jailer.chroot_dir());
+    config.fs().create_dir_all(chroot_dir).await?;
+    if is_mount_point(config, chroot_dir).await {
+        return Ok(());
+    }
+    trace!(
+        "Bind-mounting workspace `{}` at `{}`",
+        workspace_dir.display(),
+        chroot_dir.display()
+    );
+    run_command(
+        config,
+        Command::new("mount")
+            .arg("--bind")
+            .arg(workspace_dir)
+            .arg(chroot_dir),
+    )
+    .await
+}
+
+/// Unmount the workspace bind-mounted by [`setup_workspace_layout`], if mounted.
+#[instrument(skip_all)]
+async fn teardown_workspace_layout(config: &Config<'_>) -> Result<(), Error> {
+    let jailer = config.jailer();
+    let chroot_dir = jailer.chroot_dir();
+    if *jailer.workspace_layout() == WorkspaceLayout::Jailer
+        || !is_mount_point(config, chroot_dir).await
+    {
+        return Ok(());
+    }
+    trace!("Unmounting workspace from `{}`", chroot_dir.display());
+    run_command(config, Command::new("umount").arg(chroot_dir)).await
+}
+
+/// If `dir` is a mount point.
+async fn is_mount_point(config: &Config<'_>, dir: &Path) -> bool {
+    run_command(config, Command::new("mountpoint").arg("-q").arg(dir))
+        .await
+        .is_ok()
+}
+
+/// Tear down the workspace quota, returning `false` if there was nothing to tear down.
+#[instrument(skip_all)]
+async fn teardown_workspace_quota(
+    config: &Config<'_>,
+    quota: &WorkspaceQuota<'_>,
+) -> Result<bool, Error> {
+    match quota {
+        WorkspaceQuota::LoopFile { .. } => {
+            let workspace_dir = config.jailer().workspace_dir();
+            if !is_mount_point(config, workspace_dir).await {
+                return Ok(false);
+            }
+            trace!(
+                "Unmounting workspace image from `{}`",
+                workspace_dir.display()
+            );
+            run_command(config, Command::new("umount").arg(workspace_dir)).await?;
+        }
+        WorkspaceQuota::Project {
+            id, mount_point, ..
+        } => {
+            trace!(id, "Removing workspace project quota");
+            let limit = format!("limit -p bhard=0 {id}");
+            run_command(
+                config,
+                Command::new("xfs_quota")
+                    .args(["-x", "-c", &limit])
+                    .arg(mount_point.as_ref()),
+            )
+            .await?;
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        io,
+        os::unix::process::ExitStatusExt,
+        path::Path,
+        process::ExitStatus,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use futures_util::{future::BoxFuture, FutureExt};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        fs::ChrootFs,
+        spawner::{ChildProcess, ProcessSpawner},
+    };
+
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct RecordingSpawner(Arc<Mutex<Vec<Vec<String>>>>);
+
+    #[derive(Debug)]
+    struct ExitedChild;
+
+    impl ProcessSpawner for RecordingSpawner {
+        fn spawn(&self, cmd: &mut Command) -> io::Result<Box<dyn ChildProcess>> {
+            let cmd = cmd.as_std();
+            let line = std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            self.0.lock().unwrap().push(line);
+
+            Ok(Box::new(ExitedChild))
+        }
+    }
+
+    impl ChildProcess for ExitedChild {
+        fn id(&self) -> Option<u32> {
+            None
+        }
+
+        fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+            async { Ok(ExitStatus::from_raw(1 << 8)) }.boxed()
+        }
+
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            Ok(Some(ExitStatus::from_raw(1 << 8)))
+        }
+    }
+
+    /// A kernel image that can boot on x86_64, for the tests creating machines.
+    pub(crate) fn kernel_image() -> &'static Path {
+        static KERNEL: OnceLock<PathBuf> = OnceLock::new();
+        KERNEL.get_or_init(|| {
+            let path = std::env::temp_dir().join(format!("firec-kernel-{}", Uuid::new_v4()));
+            std::fs::write(&path, b"\x7fELF").unwrap();
+            path
+        })
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) enum FsOp {
+        CreateDir(PathBuf),
+        Copy(PathBuf, PathBuf),
+        Write(PathBuf),
+        SetOwner(PathBuf, u32, u32, u32),
+    }
+
+    /// Records the changes it's asked to make, fails to remove directories and never finishes
+    /// creating them if set to.
+    #[derive(Debug, Default, Clone)]
+    pub(crate) struct RecordingFs(Arc<Mutex<Vec<FsOp>>>, Arc<AtomicBool>, Arc<AtomicBool>);
+
+    impl RecordingFs {
+        pub(crate) fn fail_dir_removals(&self, fail: bool) {
+            self.1.store(fail, Ordering::Relaxed);
+        }
+
+        pub(crate) fn stall_dir_creations(&self, stall: bool) {
+            self.2.store(stall, Ordering::Relaxed);
+        }
+    }
+
+    impl ChrootFs for RecordingFs {
+        fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(FsOp::CreateDir(path.to_owned()));
+            if self.2.load(Ordering::Relaxed) {
+                return futures_util::future::pending().boxed();
+            }
+            async { Ok(()) }.boxed()
+        }
+
+        fn exists<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<bool>> {
+            async { Ok(false) }.boxed()
+        }
+
+        fn file_len<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+            async { Ok(0) }.boxed()
+        }
+
+        fn copy<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(FsOp::Copy(src.to_owned(), dest.to_owned()));
+            async { Ok(0) }.boxed()
+        }
+
+        fn same_contents<'a>(
+            &'a self,
+            _src: &'a Path,
+            _dest: &'a Path,
+        ) -> BoxFuture<'a, io::Result<bool>> {
+            async { Ok(false) }.boxed()
+        }
+
+        fn hard_link<'a>(
+            &'a self,
+            _src: &'a Path,
+            _dest: &'a Path,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn make_block_device<'a>(
+            &'a self,
+            _path: &'a Path,
+            _major: u32,
+            _minor: u32,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn read<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+            async { Ok(b"\x7fELF".to_vec()) }.boxed()
+        }
+
+        fn write<'a>(
+            &'a self,
+            path: &'a Path,
+            _contents: Vec<u8>,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            self.0.lock().unwrap().push(FsOp::Write(path.to_owned()));
+            async { Ok(()) }.boxed()
+        }
+
+        fn set_owner<'a>(
+            &'a self,
+            path: &'a Path,
+            uid: u32,
+            gid: u32,
+            mode: u32,
+        ) -> BoxFuture<'a, io::Result<()>> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(FsOp::SetOwner(path.to_owned(), uid, gid, mode));
+            async { Ok(()) }.boxed()
+        }
+
+        fn remove_file<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn remove_dir_all<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+            let fail = self.1.load(Ordering::Relaxed);
+            async move {
+                if fail {
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn read_dir<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+            async { Ok(Vec::new()) }.boxed()
+        }
+
+        fn disk_usage<'a>(&'a self, _path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+            async { Ok(0) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_decompressed_kernel() {
+        use std::io::Write;
+
+        use flate2::{write::GzEncoder, Compression};
+
+        // Short, so that the API socket path fits in a Unix socket address.
+        let id = Uuid::new_v4().simple().to_string();
+        let dir = std::env::temp_dir().join(format!("firec-{}", &id[..8]));
+        let src = dir.join("Image.gz");
+        let gzip = |image: &[u8]| {
+            let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+            gzip.write_all(image).unwrap();
+            gzip.finish().unwrap()
+        };
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(&src, gzip(b"\x7fELF one")).unwrap();
+        let config = Config::builder(Some(VmId::new("vm").unwrap()), &src)
+            .jailer_cfg()
+            .chroot_base_dir(dir.join("jailer"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .build()
+            .target_arch(Arch::X86_64)
+            .decompress_kernel(true)
+            .artifact_refresh(ArtifactRefresh::IfChanged)
+            .initrd_path(Path::new("/tmp/initrd"))
+            .build()
+            .unwrap();
+        let dest = config.kernel_image_path();
+        std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+
+        assert!(kernel_needs_copy(&config, &dest).await.unwrap());
+        copy_kernel(&config, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"\x7fELF one");
+        assert!(!kernel_needs_copy(&config, &dest).await.unwrap());
+
+        std::fs::write(&src, gzip(b"\x7fELF two")).unwrap();
+        assert!(kernel_needs_copy(&config, &dest).await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn create_and_start_with_fakes() {
+        let id = Uuid::new_v4();
+        let spawner = RecordingSpawner::default();
+        let fs = RecordingFs::default();
+
+        let config = Config::builder(Some(id.into()), kernel_image())
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .jailer_binary(Path::new("/usr/bin/jailer"))
+            .uid(123)
+            .gid(456)
+            .mode(JailerMode::Daemon)
+            .extra_jailer_args(["--resource-limit", "fsize=2048"])
+            .extra_vmm_args(["--level", "Debug"])
+            .build()
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .socket_path(Path::new("/firecracker.socket"))
+            .process_spawner(spawner.clone())
+            .chroot_fs(fs.clone())
+            .build()
+            .unwrap();
+
+        let mut machine = Machine::create(config).await.unwrap();
+
+        let root = PathBuf::from(format!("/chroot/firecracker/{id}/root"));
+        assert_eq!(
+            *fs.0.lock().unwrap(),
+            [
+                FsOp::CreateDir(root.clone()),
+                FsOp::Copy(kernel_image().into(), root.join("kernel")),
+                FsOp::Copy("/tmp/rootfs.ext4".into(), root.join("rootfs.ext4")),
+                FsOp::SetOwner(root.join("kernel"), 123, 456, 0o400),
+                FsOp::SetOwner(root.join("rootfs.ext4"), 123, 456, 0o600),
+                FsOp::Write(root.with_file_name("firec.json")),
+                FsOp::CreateDir(root),
+            ]
+        );
+
+        let err = machine.start().await.unwrap_err();
+        assert_eq!(err.vm_id(), Some(&id.into()));
+        match err.root_cause() {
+            Error::ProcessExitedImmediatelly { exit_status } => {
+                assert_eq!(exit_status.code(), Some(1))
+            }
+            err => panic!("unexpected error: {err:?}"),
+        }
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Failed to start VM `{id}`: ")));
+        let id = id.to_string();
+        assert_eq!(
+            *spawner.0.lock().unwrap(),
+            [[
+                "/usr/bin/jailer",
+                "--daemonize",
+                "--id",
+                &id,
+                "--exec-file",
+                "/usr/bin/firecracker",
+                "--uid",
+                "123",
+                "--gid",
+                "456",
+                "--chroot-base-dir",
+                "/chroot",
+                "--resource-limit",
+                "fsize=2048",
+                "--",
+                "--api-sock",
+                "/firecracker.socket",
+                "--level",
+                "Debug",
+            ]]
+        );
+    }
+
+    #[tokio::test]
+    async fn plan_with_fakes() {
+        let id = Uuid::new_v4();
+        let fs = RecordingFs::default();
+        let config = Config::builder(Some(id.into()), kernel_image())
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .jailer_binary(Path::new("/usr/bin/jailer"))
+            .uid(123)
+            .gid(456)
+            .mode(JailerMode::Daemon)
+            .build()
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .chroot_fs(fs.clone())
+            .build()
+            .unwrap();
+
+        let plan = Machine::plan(&config, &StartOptions::default())
+            .await
+            .unwrap();
+        assert!(fs.0.lock().unwrap().is_empty());
+
+        let root = PathBuf::from(format!("/chroot/firecracker/{id}/root"));
+        assert_eq!(
+            plan.create[0],
+            PlannedOperation::CreateDir { path: root.clone() }
+        );
+        assert!(plan.create.iter().any(|op| matches!(
+            op,
+            PlannedOperation::Copy { src, dest, .. }
+                if src == Path::new("/tmp/rootfs.ext4") && *dest == root.join("rootfs.ext4")
+        )));
+        assert!(plan
+            .create
+            .contains(&PlannedOperation::ChownArtifacts { uid: 123, gid: 456 }));
+
+        match &plan.start[0] {
+            PlannedOperation::SpawnJailer { command } => {
+                assert_eq!(command[..2], ["/usr/bin/jailer", "--daemonize"]);
+                assert!(command.contains(&id.to_string()));
+            }
+            op => panic!("unexpected operation: {op:?}"),
+        }
+        let paths: Vec<_> = plan.start[1..]
+            .iter()
+            .map(|op| match op {
+                PlannedOperation::ApiCall { method, path, .. } => format!("{method} {path}"),
+                op => panic!("unexpected operation: {op:?}"),
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "PUT /machine-config",
+                "PUT /boot-source",
+                "PUT /drives/root",
+                "PUT /actions",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn audit_log_with_fakes() {
+        let id = Uuid::new_v4();
+        let audit_dir = std::env::temp_dir().join(format!("firec-audit-{id}"));
+        let config = Config::builder(Some(id.into()), kernel_image())
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .jailer_binary(Path::new("/usr/bin/jailer"))
+            .mode(JailerMode::Daemon)
+            .build()
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .process_spawner(RecordingSpawner::default())
+            .chroot_fs(RecordingFs::default())
+            .audit_log_dir(audit_dir.as_path())
+            .build()
+            .unwrap();
+        let audit_log_path = config.audit_log_path().unwrap();
+        assert_eq!(audit_log_path, audit_dir.join(format!("{id}.jsonl")));
+
+        let mut machine = Machine::create(config).await.unwrap();
+        machine.start().await.unwrap_err();
+
+        let entries = crate::audit::read(&audit_log_path).await.unwrap();
+        std::fs::remove_dir_all(&audit_dir).unwrap();
+        assert!(entries.iter().all(|entry| entry.vm_id == id.into()));
+        let root = PathBuf::from(format!("/chroot/firecracker/{id}/root"));
+        assert_eq!(
+            entries[0].action,
+            AuditAction::CreateDir { path: root.clone() }
+        );
+        assert!(entries.iter().any(|entry| entry.action
+            == AuditAction::Copy {
+                src: "/tmp/rootfs.ext4".into(),
+                dest: root.join("rootfs.ext4"),
+            }));
+        assert!(entries.iter().any(|entry| matches!(
+            &entry.action,
+            AuditAction::Command { command } if command[0] == "/usr/bin/jailer"
+        )));
+    }
+
+    #[tokio::test]
+    async fn delete_never_started() {
+        let id = Uuid::new_v4();
+        let config = Config::builder(Some(id.into()), kernel_image())
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .build()
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .process_spawner(RecordingSpawner::default())
+            .chroot_fs(RecordingFs::default())
+            .build()
+            .unwrap();
+
+        let machine = Machine::create(config).await.unwrap();
+        let summary = machine.delete().await.unwrap();
+        assert_eq!(
+            summary,
+            DeleteSummary {
+                vm_dir_removed: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn swap_drive_not_running() {
+        let id = Uuid::new_v4();
+        let fs = RecordingFs::default();
+        let config = Config::builder(Some(id.into()), kernel_image())
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .build()
+            .add_drive("root", Path::new("/tmp/rootfs.ext4"))
+            .is_root_device(true)
+            .build()
+            .add_drive("data", Path::new("/tmp/data.ext4"))
+            .build()
+            .process_spawner(RecordingSpawner::default())
+            .chroot_fs(fs.clone())
+            .build()
+            .unwrap();
+
+        let mut machine = Machine::create(config).await.unwrap();
+        fs.0.lock().unwrap().clear();
+        machine
+            .swap_drive("data", "/tmp/data-v2.ext4")
+            .await
+            .unwrap();
+
+        let drive = machine.drive("data").unwrap();
+        assert_eq!(drive.src_path(), Path::new("/tmp/data-v2.ext4"));
+        let dest = machine.config().drive_path(drive).unwrap();
+        let name = dest.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("data-") && name.ends_with("-data-v2.ext4"));
+        assert_eq!(
+            fs.0.lock().unwrap()[0],
+            FsOp::Copy("/tmp/data-v2.ext4".into(), dest)
+        );
+        let err = machine
+            .swap_drive("missing", "/tmp/data-v2.ext4")
+            .await
+            .unwrap_err();
+        assert!(matches!(err.root_cause(), Error::DriveNotFound(_)));
+    }
 }
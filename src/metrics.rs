@@ -0,0 +1,437 @@
+//! Firecracker metrics collection and fleet-level aggregation.
+//!
+//! Firecracker flushes its metrics as JSON lines to the metrics file or named pipe (see
+//! [`crate::config::Builder::metrics_path`]), every minute and whenever asked to. Most metrics are
+//! counters of what happened since the previous flush. [`crate::Machine::collect_metrics`] asks
+//! for a flush periodically and publishes the parsed [`MetricsSample`]s, which a
+//! [`MetricsAggregator`] rolls up across machines.
+
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::Method;
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, BufReader},
+    net::unix::pipe,
+    sync::broadcast,
+    time::interval,
+};
+use tracing::{instrument, trace, warn};
+
+use crate::{
+    client::ApiClient,
+    config::{Config, VmId},
+    events::{MachineEvent, MachineEventKind},
+    machine::Action,
+    task::TaskHandle,
+    Error,
+};
+
+/// Number of samples buffered for each subscriber before it starts lagging.
+const METRICS_CAPACITY: usize = 64;
+
+/// Block device counters, summed over all the drives of a machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BlockMetrics {
+    /// Number of read operations.
+    pub read_count: u64,
+    /// Number of write operations.
+    pub write_count: u64,
+    /// Number of bytes read.
+    pub read_bytes: u64,
+    /// Number of bytes written.
+    pub write_bytes: u64,
+}
+
+/// Network device counters, summed over all the interfaces of a machine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct NetMetrics {
+    /// Number of bytes received.
+    #[serde(rename = "rx_bytes_count")]
+    pub rx_bytes: u64,
+    /// Number of bytes sent.
+    #[serde(rename = "tx_bytes_count")]
+    pub tx_bytes: u64,
+    /// Number of packets received.
+    #[serde(rename = "rx_packets_count")]
+    pub rx_packets: u64,
+    /// Number of packets sent.
+    #[serde(rename = "tx_packets_count")]
+    pub tx_packets: u64,
+}
+
+/// A metrics flush of a machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSample {
+    /// The ID of the machine.
+    pub vm_id: VmId,
+    /// When the metrics were flushed.
+    pub timestamp: SystemTime,
+    /// The time since the previous flush, which the counters cover, if known.
+    pub period: Option<Duration>,
+    /// The guest memory, in MiB.
+    pub memory_mib: u64,
+    /// The number of vCPUs.
+    pub vcpus: usize,
+    /// The block device counters.
+    pub block: BlockMetrics,
+    /// The network device counters.
+    pub net: NetMetrics,
+}
+
+impl MetricsSample {
+    /// Read operations per second over the period of the sample.
+    pub fn read_iops(&self) -> f64 {
+        self.rate(self.block.read_count)
+    }
+
+    /// Write operations per second over the period of the sample.
+    pub fn write_iops(&self) -> f64 {
+        self.rate(self.block.write_count)
+    }
+
+    /// Block operations per second over the period of the sample.
+    pub fn iops(&self) -> f64 {
+        self.read_iops() + self.write_iops()
+    }
+
+    /// `count` per second over the period of the sample, or `0` if it's unknown.
+    fn rate(&self, count: u64) -> f64 {
+        match self.period {
+            Some(period) if !period.is_zero() => count as f64 / period.as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+}
+
+/// A metrics flush, as written by Firecracker.
+#[derive(Debug, Deserialize)]
+struct Flush {
+    utc_timestamp_ms: u64,
+    #[serde(default)]
+    block: BlockMetrics,
+    #[serde(default)]
+    net: NetMetrics,
+}
+
+/// Parses the metrics flushes of a machine into samples.
+#[derive(Debug)]
+pub(crate) struct SampleParser {
+    vm_id: VmId,
+    memory_mib: u64,
+    vcpus: usize,
+    last_timestamp_ms: Option<u64>,
+}
+
+impl SampleParser {
+    pub(crate) fn new(config: &Config<'_>) -> Self {
+        let machine = config.machine_cfg();
+
+        Self {
+            vm_id: config.vm_id().clone(),
+            memory_mib: u64::try_from(machine.mem_size_mib()).unwrap_or_default(),
+            vcpus: machine.vcpu_count(),
+            last_timestamp_ms: None,
+        }
+    }
+
+    fn parse(&mut self, line: &str) -> Option<MetricsSample> {
+        let flush: Flush = match serde_json::from_str(line) {
+            Ok(flush) => flush,
+            Err(err) => {
+                trace!(error = %err, "Skipping metrics line");
+                return None;
+            }
+        };
+        let period = self
+            .last_timestamp_ms
+            .replace(flush.utc_timestamp_ms)
+            .map(|last| Duration::from_millis(flush.utc_timestamp_ms.saturating_sub(last)));
+
+        Some(MetricsSample {
+            vm_id: self.vm_id.clone(),
+            timestamp: UNIX_EPOCH + Duration::from_millis(flush.utc_timestamp_ms),
+            period,
+            memory_mib: self.memory_mib,
+            vcpus: self.vcpus,
+            block: flush.block,
+            net: flush.net,
+        })
+    }
+}
+
+/// Where Firecracker writes its metrics.
+#[derive(Debug)]
+pub(crate) enum MetricsSource {
+    File(PathBuf),
+    Fifo(PathBuf),
+}
+
+pub(crate) fn channel() -> broadcast::Sender<MetricsSample> {
+    broadcast::channel(METRICS_CAPACITY).0
+}
+
+pub(crate) fn collect(
+    source: MetricsSource,
+    client: ApiClient,
+    parser: SampleParser,
+    every: Duration,
+    samples: broadcast::Sender<MetricsSample>,
+) -> TaskHandle {
+    TaskHandle::new(tokio::spawn(async move {
+        let vm_id = parser.vm_id.clone();
+        if let Err(err) = run_collector(&vm_id, source, client, parser, every, samples).await {
+            warn!(%vm_id, error = %err, "Metrics collection stopped");
+        }
+    }))
+}
+
+#[instrument(skip_all, fields(vm_id = %vm_id))]
+async fn run_collector(
+    vm_id: &VmId,
+    source: MetricsSource,
+    client: ApiClient,
+    mut parser: SampleParser,
+    every: Duration,
+    samples: broadcast::Sender<MetricsSample>,
+) -> Result<(), Error> {
+    let mut publish = |line: &str| {
+        if let Some(sample) = parser.parse(line) {
+            // Nobody might be listening, which is fine.
+            let _ = samples.send(sample);
+        }
+    };
+    let mut ticks = interval(every);
+    match source {
+        MetricsSource::File(path) => {
+            // Only the flushes from now on are of interest.
+            let mut offset = tokio::fs::metadata(&path).await?.len();
+            loop {
+                ticks.tick().await;
+                flush(&client).await;
+                for line in read_appended(&path, &mut offset).await?.lines() {
+                    publish(line);
+                }
+            }
+        }
+        MetricsSource::Fifo(path) => {
+            let mut lines = BufReader::new(pipe::OpenOptions::new().open_receiver(&path)?).lines();
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => flush(&client).await,
+                    line = lines.next_line() => match line? {
+                        Some(line) => publish(&line),
+                        None => return Ok(()),
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Ask Firecracker to flush its metrics.
+async fn flush(client: &ApiClient) {
+    let result = match serde_json::to_string(&Action::FlushMetrics) {
+        Ok(json) => client.send(Method::PUT, "/actions", Some(json)).await,
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = result {
+        warn!(error = %err, "Failed to flush metrics");
+    }
+}
+
+/// The complete lines appended to the file at `path` since `offset`, which is advanced past them.
+///
+/// The file is read from the start again if it was truncated, e.g by log rotation.
+async fn read_appended(path: &Path, offset: &mut u64) -> Result<String, Error> {
+    let mut file = File::open(path).await?;
+    if file.metadata().await?.len() < *offset {
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset)).await?;
+    let mut appended = String::new();
+    file.read_to_string(&mut appended).await?;
+    // Leave a partially written line for the next read.
+    appended.truncate(appended.rfind('\n').map_or(0, |end| end + 1));
+    *offset += appended.len() as u64;
+
+    Ok(appended)
+}
+
+/// Keeps the latest metrics sample of many machines, see [`MetricsAggregator::snapshot`].
+///
+/// Machines are forgotten once they are stopped, killed, crashed or deleted. The aggregation
+/// stops when the aggregator is dropped.
+#[derive(Debug)]
+pub struct MetricsAggregator {
+    latest: Arc<Mutex<BTreeMap<VmId, MetricsSample>>>,
+    _task: TaskHandle,
+}
+
+impl MetricsAggregator {
+    /// Aggregate the samples of `metrics`, using `events` to forget the machines that are gone.
+    ///
+    /// See [`crate::Orchestrator::aggregate_metrics`] to aggregate all the machines of an
+    /// orchestrator.
+    pub fn new(
+        mut metrics: broadcast::Receiver<MetricsSample>,
+        mut events: broadcast::Receiver<MachineEvent>,
+    ) -> Self {
+        let latest = Arc::new(Mutex::new(BTreeMap::new()));
+        let task_latest = latest.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    sample = metrics.recv() => match sample {
+                        Ok(sample) => {
+                            task_latest.lock().unwrap().insert(sample.vm_id.clone(), sample);
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    event = events.recv() => match event {
+                        Ok(MachineEvent {
+                            vm_id,
+                            kind:
+                                MachineEventKind::Stopped { .. }
+                                | MachineEventKind::Killed
+                                | MachineEventKind::Crashed { .. }
+                                | MachineEventKind::Deleted,
+                            ..
+                        }) => {
+                            task_latest.lock().unwrap().remove(&vm_id);
+                        }
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                }
+            }
+        });
+
+        Self {
+            latest,
+            _task: TaskHandle::new(task),
+        }
+    }
+
+    /// The latest metrics of all the machines.
+    pub fn snapshot(&self) -> FleetMetrics {
+        FleetMetrics {
+            timestamp: SystemTime::now(),
+            machines: self.latest.lock().unwrap().values().cloned().collect(),
+        }
+    }
+}
+
+/// The latest metrics of many machines, see [`MetricsAggregator::snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FleetMetrics {
+    /// When the snapshot was taken.
+    pub timestamp: SystemTime,
+    /// The latest sample of each machine, sorted by VM ID.
+    pub machines: Vec<MetricsSample>,
+}
+
+impl FleetMetrics {
+    /// Total guest memory, in MiB.
+    pub fn total_memory_mib(&self) -> u64 {
+        self.machines.iter().map(|sample| sample.memory_mib).sum()
+    }
+
+    /// Total number of vCPUs.
+    pub fn total_vcpus(&self) -> usize {
+        self.machines.iter().map(|sample| sample.vcpus).sum()
+    }
+
+    /// Aggregate block operations per second.
+    pub fn iops(&self) -> f64 {
+        self.machines.iter().map(MetricsSample::iops).sum()
+    }
+
+    /// Aggregate block read operations per second.
+    pub fn read_iops(&self) -> f64 {
+        self.machines.iter().map(MetricsSample::read_iops).sum()
+    }
+
+    /// Aggregate block write operations per second.
+    pub fn write_iops(&self) -> f64 {
+        self.machines.iter().map(MetricsSample::write_iops).sum()
+    }
+
+    /// The `n` machines with the most block operations per second, busiest first.
+    pub fn top_by_iops(&self, n: usize) -> Vec<&MetricsSample> {
+        self.top_by(n, MetricsSample::iops)
+    }
+
+    /// The `n` machines with the highest `key`, highest first.
+    pub fn top_by<K, F>(&self, n: usize, key: F) -> Vec<&MetricsSample>
+    where
+        K: PartialOrd,
+        F: Fn(&MetricsSample) -> K,
+    {
+        let mut top: Vec<_> = self.machines.iter().collect();
+        top.sort_by(|a, b| {
+            key(b)
+                .partial_cmp(&key(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        top.truncate(n);
+
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollups() {
+        let mut parser = SampleParser {
+            vm_id: VmId::new("vm-a").unwrap(),
+            memory_mib: 512,
+            vcpus: 2,
+            last_timestamp_ms: None,
+        };
+        assert_eq!(parser.parse("not json"), None);
+        let first = parser
+            .parse(r#"{"utc_timestamp_ms":1000,"block":{"read_count":5}}"#)
+            .unwrap();
+        assert_eq!(first.period, None);
+        assert_eq!(first.iops(), 0.0);
+        let busy = parser
+            .parse(
+                r#"{"utc_timestamp_ms":3000,"block":{"read_count":10,"write_count":30},
+                "net":{"rx_bytes_count":7}}"#,
+            )
+            .unwrap();
+        assert_eq!(busy.period, Some(Duration::from_secs(2)));
+        assert_eq!(busy.net.rx_bytes, 7);
+        assert_eq!(busy.iops(), 20.0);
+
+        let idle = MetricsSample {
+            vm_id: VmId::new("vm-b").unwrap(),
+            memory_mib: 1024,
+            vcpus: 1,
+            ..first
+        };
+        let fleet = FleetMetrics {
+            timestamp: SystemTime::now(),
+            machines: vec![idle.clone(), busy.clone()],
+        };
+        assert_eq!(fleet.total_memory_mib(), 1536);
+        assert_eq!(fleet.total_vcpus(), 3);
+        assert_eq!(fleet.write_iops(), 15.0);
+        assert_eq!(fleet.top_by_iops(1), [&busy]);
+        assert_eq!(fleet.top_by(2, |sample| sample.memory_mib), [&idle, &busy]);
+    }
+}
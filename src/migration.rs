@@ -0,0 +1,222 @@
+//! Host-to-host VM migration.
+//!
+//! A VM is migrated by pausing it, taking a full snapshot and transferring the snapshot to the
+//! target host through a [`MigrationTransport`], with [`migrate`]. On the target host, a machine
+//! with the same configuration (at least the same drives, network interfaces and vsock) is
+//! created (see [`crate::Machine::create`]) and restored from the snapshot with [`restore`].
+//!
+//! The drives aren't transferred: they must already be available on the target host, e.g on
+//! shared storage.
+//!
+//! Two transports are provided:
+//!
+//! * [`StorageTransport`] goes through a [`Storage`], e.g an object store.
+//! * [`CommandTransport`] runs a command, e.g `rsync` or `scp`, to copy the snapshot files
+//!   directly into the chroot of the target machine, which must then be created beforehand.
+
+use std::{ffi::OsString, fmt::Debug, sync::Arc};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::process::Command;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    config::Config,
+    machine::run_command,
+    snapshot::{Snapshot, SnapshotType},
+    storage::{self, Storage},
+    Error, Machine, StartOptions,
+};
+
+/// Name of the snapshot taken by [`migrate`].
+pub const MIGRATION_SNAPSHOT_NAME: &str = "migration";
+
+/// Moves the snapshot of a migrating VM to the target host.
+pub trait MigrationTransport: Debug + Send + Sync {
+    /// Send `snapshot` of the VM with `config` from the source host.
+    fn send<'a>(
+        &'a self,
+        config: &'a Config<'_>,
+        snapshot: &'a Snapshot,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Receive the snapshot into the chroot of the created machine with `config`, on the target
+    /// host.
+    fn receive<'a>(&'a self, config: &'a Config<'_>) -> BoxFuture<'a, Result<Snapshot, Error>>;
+}
+
+/// Migrate the running VM of `machine` to another host, through `transport`.
+///
+/// The VM is left paused once the snapshot is sent, so it can still be resumed (see
+/// [`Machine::resume`]) if the target fails to restore it. It should otherwise be deleted. If
+/// anything fails, the VM is resumed.
+#[instrument(skip_all, fields(vm_id = %machine.config().vm_id()))]
+pub async fn migrate(
+    machine: &Machine<'_>,
+    transport: &dyn MigrationTransport,
+) -> Result<(), Error> {
+    info!("Migrating VM...");
+    machine.pause().await?;
+    let result = async {
+        let snapshot = machine
+            .create_snapshot(SnapshotType::Full, MIGRATION_SNAPSHOT_NAME)
+            .await?;
+        transport.send(machine.config(), &snapshot).await
+    }
+    .await;
+    if let Err(err) = result {
+        if let Err(e) = machine.resume().await {
+            warn!(error = %e, "Failed to resume VM");
+        }
+        return Err(err);
+    }
+    info!("VM snapshot sent");
+
+    Ok(())
+}
+
+/// Restore the VM migrated with [`migrate`] to the created `machine`, through `transport`.
+#[instrument(skip_all, fields(vm_id = %machine.config().vm_id()))]
+pub async fn restore(
+    machine: &mut Machine<'_>,
+    transport: &dyn MigrationTransport,
+) -> Result<(), Error> {
+    let snapshot = transport.receive(machine.config()).await?;
+    machine
+        .start_with(StartOptions::default().restore(snapshot))
+        .await?;
+    info!("VM migrated");
+
+    Ok(())
+}
+
+/// Migrates VMs through a [`Storage`], under `<prefix>/<VM ID>`.
+#[derive(Debug, Clone)]
+pub struct StorageTransport {
+    storage: Arc<dyn Storage>,
+    prefix: String,
+}
+
+impl StorageTransport {
+    /// Migrate through `storage`, under `prefix`.
+    pub fn new<S, P>(storage: S, prefix: P) -> Self
+    where
+        S: Storage + 'static,
+        P: Into<String>,
+    {
+        Self {
+            storage: Arc::new(storage),
+            prefix: prefix.into(),
+        }
+    }
+
+    fn vm_prefix(&self, config: &Config<'_>) -> String {
+        format!("{}/{}", self.prefix.trim_end_matches('/'), config.vm_id())
+    }
+}
+
+impl MigrationTransport for StorageTransport {
+    fn send<'a>(
+        &'a self,
+        config: &'a Config<'_>,
+        snapshot: &'a Snapshot,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let prefix = self.vm_prefix(config);
+            storage::push_snapshot(
+                self.storage.as_ref(),
+                snapshot,
+                &prefix,
+                MIGRATION_SNAPSHOT_NAME,
+            )
+            .await
+        }
+        .boxed()
+    }
+
+    fn receive<'a>(&'a self, config: &'a Config<'_>) -> BoxFuture<'a, Result<Snapshot, Error>> {
+        async move {
+            let prefix = self.vm_prefix(config);
+            storage::pull_snapshot(
+                self.storage.as_ref(),
+                config,
+                &prefix,
+                MIGRATION_SNAPSHOT_NAME,
+            )
+            .await
+        }
+        .boxed()
+    }
+}
+
+/// Migrates VMs by running a command on the source host to copy the snapshot files.
+///
+/// The command is run with the given arguments, followed by the paths of the snapshot state and
+/// memory files and the destination, e.g `rsync --sparse <state> <memory> target:/srv/jailer/...`.
+/// The destination should be the jailer workspace directory of the target machine (see
+/// [`crate::config::Jailer::workspace_dir`]).
+#[derive(Debug, Clone)]
+pub struct CommandTransport {
+    program: Vec<OsString>,
+    destination: OsString,
+}
+
+impl CommandTransport {
+    /// Copy the snapshot files to `destination` by running `program`.
+    pub fn new<P, D>(program: P, destination: D) -> Self
+    where
+        P: Into<OsString>,
+        D: Into<OsString>,
+    {
+        Self {
+            program: vec![program.into()],
+            destination: destination.into(),
+        }
+    }
+
+    /// Add an argument to the command, before the snapshot files.
+    pub fn arg<A>(mut self, arg: A) -> Self
+    where
+        A: Into<OsString>,
+    {
+        self.program.push(arg.into());
+        self
+    }
+}
+
+impl MigrationTransport for CommandTransport {
+    fn send<'a>(
+        &'a self,
+        config: &'a Config<'_>,
+        snapshot: &'a Snapshot,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let mut cmd = Command::new(&self.program[0]);
+            cmd.args(&self.program[1..])
+                .arg(&snapshot.state_path)
+                .arg(&snapshot.memory_path)
+                .arg(&self.destination);
+            run_command(config, &mut cmd).await
+        }
+        .boxed()
+    }
+
+    fn receive<'a>(&'a self, config: &'a Config<'_>) -> BoxFuture<'a, Result<Snapshot, Error>> {
+        async move {
+            // The files were copied by the source host.
+            let snapshot = Snapshot::in_chroot(config, MIGRATION_SNAPSHOT_NAME);
+            let jailer = config.jailer();
+            if jailer.chown_artifacts() {
+                for path in [&snapshot.state_path, &snapshot.memory_path] {
+                    config
+                        .fs()
+                        .set_owner(path, jailer.uid(), jailer.gid(), 0o600)
+                        .await?;
+                }
+            }
+
+            Ok(snapshot)
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,245 @@
+//! Host NAT and port-forward rules, see [`crate::config::Nat`].
+
+use std::io;
+
+use tokio::process::Command;
+use tracing::{instrument, trace, warn};
+
+use crate::{
+    config::{Config, Firewall, Nat, VmId},
+    machine::run_command,
+    DeleteSummary, Error,
+};
+
+/// File name of the record of the commands undoing the rules, in the VM directory.
+pub(crate) const NAT_RULES_FILE_NAME: &str = "nat.json";
+
+/// A firewall command, program first.
+type Rule = Vec<String>;
+
+/// Set up the NAT rules of `nat` and record how to remove them.
+#[instrument(skip_all)]
+pub(crate) async fn setup(config: &Config<'_>, nat: &Nat<'_>) -> Result<(), Error> {
+    let mut undo = Vec::new();
+    for (rule, undo_rule) in rules(config.vm_id(), nat) {
+        trace!(?rule, "Adding NAT rule");
+        if let Err(e) = run(config, &rule).await {
+            remove(config, undo.into_iter().rev()).await;
+            return Err(e);
+        }
+        undo.extend(undo_rule);
+    }
+    undo.reverse();
+
+    config
+        .fs()
+        .write(&config.nat_rules_path(), serde_json::to_vec(&undo)?)
+        .await?;
+
+    Ok(())
+}
+
+/// Remove the NAT rules recorded by [`setup`], if any.
+#[instrument(skip_all)]
+pub(crate) async fn teardown(
+    config: &Config<'_>,
+    summary: &mut DeleteSummary,
+) -> Result<(), Error> {
+    let undo: Vec<Rule> = match tokio::fs::read(config.nat_rules_path()).await {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for rule in undo {
+        trace!(?rule, "Removing NAT rule");
+        match run(config, &rule).await {
+            Ok(()) => summary.nat_rules_removed += 1,
+            Err(err) => summary.warn(
+                format!("Failed to remove NAT rule `{}`", rule.join(" ")),
+                &err,
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+async fn remove(config: &Config<'_>, undo: impl IntoIterator<Item = Rule>) {
+    for rule in undo {
+        trace!(?rule, "Removing NAT rule");
+        if let Err(err) = run(config, &rule).await {
+            warn!(error = %err, "Failed to remove NAT rule");
+        }
+    }
+}
+
+async fn run(config: &Config<'_>, rule: &[String]) -> Result<(), Error> {
+    run_command(config, Command::new(&rule[0]).args(&rule[1..])).await
+}
+
+/// The rules to add, each with the rule undoing it, if any.
+fn rules(vm_id: &VmId, nat: &Nat<'_>) -> Vec<(Rule, Option<Rule>)> {
+    match nat.firewall() {
+        Firewall::Iptables => iptables_rules(vm_id, nat),
+        Firewall::Nftables => nftables_rules(vm_id, nat),
+    }
+}
+
+fn iptables_rules(vm_id: &VmId, nat: &Nat<'_>) -> Vec<(Rule, Option<Rule>)> {
+    let subnet = nat.guest_subnet();
+    let iface = nat.host_interface();
+    let mut rules = vec![
+        (
+            "nat",
+            "POSTROUTING",
+            format!("-s {subnet} -o {iface} -j MASQUERADE"),
+        ),
+        (
+            "filter",
+            "FORWARD",
+            format!("-s {subnet} -o {iface} -j ACCEPT"),
+        ),
+        (
+            "filter",
+            "FORWARD",
+            format!("-d {subnet} -i {iface} -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT"),
+        ),
+    ];
+    for forward in nat.port_forwards() {
+        let protocol = forward.protocol.as_str();
+        let guest_ip = forward.guest_ip;
+        rules.push((
+            "nat",
+            "PREROUTING",
+            format!(
+                "-i {iface} -p {protocol} --dport {} -j DNAT --to-destination {guest_ip}:{}",
+                forward.host_port, forward.guest_port
+            ),
+        ));
+        rules.push((
+            "filter",
+            "FORWARD",
+            format!(
+                "-d {guest_ip} -p {protocol} --dport {} -j ACCEPT",
+                forward.guest_port
+            ),
+        ));
+    }
+
+    // Tag the rules with the VM ID, so they can be told apart from others.
+    let comment = format!("firec:{vm_id}");
+    rules
+        .into_iter()
+        .map(|(table, chain, spec)| {
+            let rule = |op: &str| -> Rule {
+                ["iptables", "-t", table, op, chain]
+                    .into_iter()
+                    .chain(spec.split(' '))
+                    .chain(["-m", "comment", "--comment", &comment])
+                    .map(String::from)
+                    .collect()
+            };
+            (rule("-A"), Some(rule("-D")))
+        })
+        .collect()
+}
+
+fn nftables_rules(vm_id: &VmId, nat: &Nat<'_>) -> Vec<(Rule, Option<Rule>)> {
+    let table: String = format!("firec_{vm_id}")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let subnet = nat.guest_subnet();
+    let iface = nat.host_interface();
+    let nft = |args: &str| -> Rule {
+        ["nft"]
+            .into_iter()
+            .chain(args.split(' '))
+            .map(String::from)
+            .collect()
+    };
+
+    // Deleting the table deletes all its chains and rules.
+    let mut rules = vec![(
+        nft(&format!("add table ip {table}")),
+        Some(nft(&format!("delete table ip {table}"))),
+    )];
+    for command in [
+        format!("add chain ip {table} postrouting {{ type nat hook postrouting priority 100 ; }}"),
+        format!("add chain ip {table} prerouting {{ type nat hook prerouting priority -100 ; }}"),
+        format!("add chain ip {table} forward {{ type filter hook forward priority 0 ; }}"),
+        format!("add rule ip {table} postrouting ip saddr {subnet} oifname {iface} masquerade"),
+        format!("add rule ip {table} forward ip saddr {subnet} oifname {iface} accept"),
+        format!(
+            "add rule ip {table} forward ip daddr {subnet} iifname {iface} \
+             ct state related,established accept"
+        ),
+    ] {
+        rules.push((nft(&command), None));
+    }
+    for forward in nat.port_forwards() {
+        let protocol = forward.protocol.as_str();
+        let guest_ip = forward.guest_ip;
+        rules.push((
+            nft(&format!(
+                "add rule ip {table} prerouting iifname {iface} {protocol} dport {} \
+                 dnat to {guest_ip}:{}",
+                forward.host_port, forward.guest_port
+            )),
+            None,
+        ));
+        rules.push((
+            nft(&format!(
+                "add rule ip {table} forward ip daddr {guest_ip} {protocol} dport {} accept",
+                forward.guest_port
+            )),
+            None,
+        ));
+    }
+
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::config::PortForward;
+
+    #[test]
+    fn iptables() {
+        let vm_id = VmId::new("vm").unwrap();
+        let nat = Nat::new(Firewall::Iptables, "172.16.0.0/30", "eth0")
+            .port_forward(PortForward::tcp(2222, Ipv4Addr::new(172, 16, 0, 2), 22));
+        let rules = rules(&vm_id, &nat);
+        assert_eq!(rules.len(), 5);
+
+        let (add, delete) = &rules[3];
+        assert_eq!(
+            add.join(" "),
+            "iptables -t nat -A PREROUTING -i eth0 -p tcp --dport 2222 -j DNAT \
+             --to-destination 172.16.0.2:22 -m comment --comment firec:vm"
+        );
+        assert_eq!(
+            delete.as_ref().unwrap().join(" "),
+            add.join(" ").replace(" -A ", " -D ")
+        );
+    }
+
+    #[test]
+    fn nftables() {
+        let vm_id = VmId::new("vm-1").unwrap();
+        let nat = Nat::new(Firewall::Nftables, "172.16.0.0/30", "eth0");
+        let rules = rules(&vm_id, &nat);
+
+        let undo: Vec<_> = rules.iter().filter_map(|(_, undo)| undo.as_ref()).collect();
+        assert_eq!(undo.len(), 1);
+        assert_eq!(undo[0].join(" "), "nft delete table ip firec_vm_1");
+        assert_eq!(
+            rules[1].0.join(" "),
+            "nft add chain ip firec_vm_1 postrouting \
+             { type nat hook postrouting priority 100 ; }"
+        );
+    }
+}
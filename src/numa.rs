@@ -0,0 +1,201 @@
+//! Host NUMA topology and machine placement.
+//!
+//! [`HostTopology::place`] picks a NUMA node and host CPUs for a new machine, which can then be
+//! passed to [`crate::config::JailerBuilder::numa_node`] and
+//! [`crate::config::Builder::vcpu_affinity`].
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::Error;
+
+/// Where NUMA nodes are exposed by the kernel.
+const NODES_DIR: &str = "/sys/devices/system/node";
+
+/// A NUMA node of the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+    /// The node ID.
+    pub id: u32,
+    /// The CPUs of the node.
+    pub cpus: Vec<u32>,
+    /// Total memory of the node in MiB.
+    pub total_memory_mib: u64,
+    /// Free memory of the node in MiB.
+    pub free_memory_mib: u64,
+}
+
+/// The NUMA topology of the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostTopology {
+    /// The NUMA nodes, ordered by ID.
+    pub nodes: Vec<NumaNode>,
+}
+
+/// The NUMA node and host CPUs of a machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    /// The NUMA node.
+    pub numa_node: u32,
+    /// The host CPUs the vCPUs are pinned to.
+    pub cpus: Vec<u32>,
+}
+
+impl HostTopology {
+    /// Read the topology of the host from sysfs.
+    pub async fn read() -> Result<Self, Error> {
+        Self::read_from(Path::new(NODES_DIR)).await
+    }
+
+    async fn read_from(nodes_dir: &Path) -> Result<Self, Error> {
+        let mut nodes = Vec::new();
+        let mut entries = tokio::fs::read_dir(nodes_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let id = match entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix("node"))
+                .and_then(|id| id.parse().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            let node_dir = entry.path();
+            let cpulist = tokio::fs::read_to_string(node_dir.join("cpulist")).await?;
+            let meminfo = tokio::fs::read_to_string(node_dir.join("meminfo")).await?;
+            let meminfo_path = || node_dir.join("meminfo");
+
+            nodes.push(NumaNode {
+                id,
+                cpus: parse_cpu_list(&cpulist)
+                    .ok_or_else(|| invalid_data(node_dir.join("cpulist")))?,
+                total_memory_mib: parse_meminfo(&meminfo, "MemTotal:")
+                    .ok_or_else(|| invalid_data(meminfo_path()))?,
+                free_memory_mib: parse_meminfo(&meminfo, "MemFree:")
+                    .ok_or_else(|| invalid_data(meminfo_path()))?,
+            });
+        }
+        nodes.sort_by_key(|node| node.id);
+
+        Ok(Self { nodes })
+    }
+
+    /// Pick a placement for a new machine, given the placements of the existing ones.
+    ///
+    /// The node with the most free memory among those that can fit `mem_size_mib` is chosen, and
+    /// its `vcpu_count` CPUs with the fewest vCPUs already pinned to them. Returns `None` if no
+    /// node has enough free memory.
+    pub fn place(
+        &self,
+        mem_size_mib: u64,
+        vcpu_count: usize,
+        fleet: &[Placement],
+    ) -> Option<Placement> {
+        let node = self
+            .nodes
+            .iter()
+            .filter(|node| node.free_memory_mib >= mem_size_mib && !node.cpus.is_empty())
+            .max_by_key(|node| (node.free_memory_mib, std::cmp::Reverse(node.id)))?;
+
+        let mut load: HashMap<u32, usize> = HashMap::new();
+        for cpu in fleet.iter().flat_map(|placement| &placement.cpus) {
+            *load.entry(*cpu).or_default() += 1;
+        }
+        let mut cpus = node.cpus.clone();
+        cpus.sort_by_key(|cpu| (load.get(cpu).copied().unwrap_or_default(), *cpu));
+        cpus.truncate(vcpu_count);
+
+        Some(Placement {
+            numa_node: node.id,
+            cpus,
+        })
+    }
+}
+
+/// Parse a kernel CPU list, e.g `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Option<Vec<u32>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => cpus.extend(start.parse::<u32>().ok()?..=end.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+
+    Some(cpus)
+}
+
+/// Get a value of a node `meminfo` file in MiB, e.g for `Node 0 MemFree:   1024 kB`.
+fn parse_meminfo(meminfo: &str, key: &str) -> Option<u64> {
+    meminfo.lines().find_map(|line| {
+        let mut fields = line.split_whitespace().skip(2);
+        if fields.next()? != key {
+            return None;
+        }
+        fields.next()?.parse::<u64>().ok().map(|kib| kib / 1024)
+    })
+}
+
+fn invalid_data(path: PathBuf) -> Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid NUMA node data in `{}`", path.display()),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("\n"), Some(vec![]));
+        assert_eq!(parse_cpu_list("0-x"), None);
+
+        let meminfo = "Node 1 MemTotal:       32780060 kB\nNode 1 MemFree:        2048 kB\n";
+        assert_eq!(parse_meminfo(meminfo, "MemTotal:"), Some(32011));
+        assert_eq!(parse_meminfo(meminfo, "MemFree:"), Some(2));
+        assert_eq!(parse_meminfo(meminfo, "MemUsed:"), None);
+    }
+
+    #[test]
+    fn placement() {
+        let topology = HostTopology {
+            nodes: vec![
+                NumaNode {
+                    id: 0,
+                    cpus: vec![0, 1, 2, 3],
+                    total_memory_mib: 8192,
+                    free_memory_mib: 4096,
+                },
+                NumaNode {
+                    id: 1,
+                    cpus: vec![4, 5, 6, 7],
+                    total_memory_mib: 8192,
+                    free_memory_mib: 1024,
+                },
+            ],
+        };
+        let fleet = [Placement {
+            numa_node: 0,
+            cpus: vec![0, 1],
+        }];
+
+        assert_eq!(
+            topology.place(2048, 2, &fleet),
+            Some(Placement {
+                numa_node: 0,
+                cpus: vec![2, 3],
+            })
+        );
+        assert_eq!(topology.place(512, 1, &[]).unwrap().numa_node, 0);
+        assert_eq!(topology.place(8192, 1, &fleet), None);
+    }
+}
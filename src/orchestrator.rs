@@ -0,0 +1,728 @@
+//! Management of a fleet of machines.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as SyncMutex},
+    time::Duration,
+};
+
+use futures_util::{stream, StreamExt};
+use tokio::{
+    sync::{broadcast, Mutex, OwnedMutexGuard},
+    time::{self, MissedTickBehavior},
+};
+use tracing::{info, instrument, warn};
+
+use crate::{
+    config::{Config, VmId},
+    events::{self, MachineEvent, MachineEventKind},
+    hooks::{self, EventHooks},
+    host,
+    metrics::{self, MetricsAggregator, MetricsSample},
+    task::TaskHandle,
+    DeleteOptions, DeleteSummary, Error, Machine, MachineState,
+};
+
+/// Limits enforced by an [`Orchestrator`] across all of its machines.
+///
+/// No limits are enforced by default.
+#[derive(Debug, Clone, Default)]
+pub struct OrchestratorLimits {
+    pub(crate) max_machines: Option<usize>,
+    pub(crate) max_memory_mib: Option<u64>,
+    pub(crate) memory_overcommit: Option<f64>,
+    pub(crate) vcpu_overcommit: Option<f64>,
+    pub(crate) host_resources: Option<(u64, usize)>,
+    pub(crate) tenant_profiles: HashMap<String, String>,
+}
+
+impl OrchestratorLimits {
+    /// Set the maximum number of machines.
+    pub fn max_machines(mut self, max_machines: usize) -> Self {
+        self.max_machines = Some(max_machines);
+        self
+    }
+
+    /// Set the maximum total memory of all machines, in MiB.
+    pub fn max_memory_mib(mut self, max_memory_mib: u64) -> Self {
+        self.max_memory_mib = Some(max_memory_mib);
+        self
+    }
+
+    /// Limit the total memory of all machines to the host memory multiplied by `ratio`.
+    pub fn memory_overcommit(mut self, ratio: f64) -> Self {
+        self.memory_overcommit = Some(ratio);
+        self
+    }
+
+    /// Limit the total number of vCPUs of all machines to the number of host CPUs multiplied by
+    /// `ratio`.
+    pub fn vcpu_overcommit(mut self, ratio: f64) -> Self {
+        self.vcpu_overcommit = Some(ratio);
+        self
+    }
+
+    /// Set the host memory, in MiB, and number of CPUs the overcommit ratios apply to.
+    ///
+    /// Defaults to the total memory of the host and the CPUs available to this process.
+    pub fn host_resources(mut self, memory_mib: u64, cpus: usize) -> Self {
+        self.host_resources = Some((memory_mib, cpus));
+        self
+    }
+
+    /// Require the machines of `tenant` to be configured with the isolation profile named
+    /// `profile`, see [`Orchestrator::create_for_tenant`].
+    pub fn tenant_profile<T, P>(mut self, tenant: T, profile: P) -> Self
+    where
+        T: Into<String>,
+        P: Into<String>,
+    {
+        self.tenant_profiles.insert(tenant.into(), profile.into());
+        self
+    }
+
+    /// The memory and vCPU budgets, from the host resources and overcommit ratios.
+    fn budgets(&self) -> (Option<u64>, Option<usize>) {
+        let (memory_mib, cpus) = match (self.memory_overcommit, self.vcpu_overcommit) {
+            (None, None) => (0, 0),
+            _ => self.host_resources.unwrap_or_else(host::total_resources),
+        };
+        let memory_budget = self
+            .memory_overcommit
+            .map(|ratio| (memory_mib as f64 * ratio) as u64);
+        let memory_budget = match (memory_budget, self.max_memory_mib) {
+            (Some(budget), Some(max)) => Some(budget.min(max)),
+            (budget, max) => budget.or(max),
+        };
+        let vcpu_budget = self
+            .vcpu_overcommit
+            .map(|ratio| (cpus as f64 * ratio) as usize);
+
+        (memory_budget, vcpu_budget)
+    }
+}
+
+/// Aggregated statistics of the machines managed by an [`Orchestrator`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrchestratorStats {
+    /// Number of machines.
+    pub machines: usize,
+    /// Number of running machines.
+    pub running: usize,
+    /// Total memory of all machines, in MiB.
+    pub memory_mib: u64,
+    /// Total number of vCPUs of all machines.
+    pub vcpus: usize,
+}
+
+/// The resources committed to the machines of an [`Orchestrator`], and its budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OrchestratorCapacity {
+    /// Memory of all machines, in MiB.
+    pub committed_memory_mib: u64,
+    /// Maximum memory of all machines, in MiB, if limited.
+    pub memory_budget_mib: Option<u64>,
+    /// Number of vCPUs of all machines.
+    pub committed_vcpus: usize,
+    /// Maximum number of vCPUs of all machines, if limited.
+    pub vcpu_budget: Option<usize>,
+}
+
+impl OrchestratorCapacity {
+    /// Memory left in the budget, in MiB, if limited.
+    pub fn free_memory_mib(&self) -> Option<u64> {
+        self.memory_budget_mib
+            .map(|budget| budget.saturating_sub(self.committed_memory_mib))
+    }
+
+    /// Number of vCPUs left in the budget, if limited.
+    pub fn free_vcpus(&self) -> Option<usize> {
+        self.vcpu_budget
+            .map(|budget| budget.saturating_sub(self.committed_vcpus))
+    }
+
+    /// Check that a machine with the given resources fits in the budget.
+    fn check(&self, memory_mib: u64, vcpus: usize) -> Result<(), Error> {
+        if let Some(free_mib) = self.free_memory_mib() {
+            if memory_mib > free_mib {
+                return Err(Error::CapacityExceeded(format!(
+                    "{memory_mib} MiB requested but only {free_mib} MiB of memory left"
+                )));
+            }
+        }
+        if let Some(free_vcpus) = self.free_vcpus() {
+            if vcpus > free_vcpus {
+                return Err(Error::CapacityExceeded(format!(
+                    "{vcpus} vCPUs requested but only {free_vcpus} vCPUs left"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A machine slot, which is `None` while the machine is being created or once it's deleted.
+type Slot = Arc<Mutex<Option<Machine<'static>>>>;
+
+#[derive(Debug)]
+struct Entry {
+    machine: Slot,
+    memory_mib: u64,
+    vcpus: usize,
+}
+
+/// The shortest period between the creations of a batch, see [`Orchestrator::start_many`].
+const MIN_BATCH_PERIOD: Duration = Duration::from_nanos(1);
+/// The longest period between the creations of a batch, see [`Orchestrator::start_many`].
+const MAX_BATCH_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The period between the creations of a batch started at `rate` per second, if limited.
+fn batch_period(rate: Option<f64>) -> Option<Duration> {
+    let rate = rate.filter(|rate| *rate > 0.0 && rate.is_finite())?;
+    let period = Duration::try_from_secs_f64(1.0 / rate).unwrap_or(MAX_BATCH_PERIOD);
+
+    Some(period.clamp(MIN_BATCH_PERIOD, MAX_BATCH_PERIOD))
+}
+
+/// The slot reserved for a machine being created, removed from the machines when dropped unless
+/// the machine was created.
+struct Reservation<'a> {
+    machines: &'a SyncMutex<HashMap<VmId, Entry>>,
+    vm_id: &'a VmId,
+    slot: Option<Slot>,
+}
+
+impl Reservation<'_> {
+    /// Keep the slot, now holding the created machine.
+    fn keep(mut self) {
+        self.slot = None;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let Some(slot) = self.slot.take() else {
+            return;
+        };
+        let mut machines = self.machines.lock().unwrap();
+        if let Some(entry) = machines.get(self.vm_id) {
+            if Arc::ptr_eq(&entry.machine, &slot) {
+                machines.remove(self.vm_id);
+            }
+        }
+    }
+}
+
+/// Manages many machines, keyed by their VM ID.
+///
+/// Operations on a machine are serialized, while operations on different machines run
+/// concurrently. Events of all machines are forwarded to [`Orchestrator::subscribe`], and their
+/// metrics to [`Orchestrator::subscribe_metrics`].
+#[derive(Debug)]
+pub struct Orchestrator {
+    machines: SyncMutex<HashMap<VmId, Entry>>,
+    limits: OrchestratorLimits,
+    memory_budget_mib: Option<u64>,
+    vcpu_budget: Option<usize>,
+    events: broadcast::Sender<MachineEvent>,
+    metrics: broadcast::Sender<MetricsSample>,
+}
+
+impl Default for Orchestrator {
+    fn default() -> Self {
+        Self::new(OrchestratorLimits::default())
+    }
+}
+
+impl Orchestrator {
+    /// Create a new orchestrator, enforcing the given limits.
+    pub fn new(limits: OrchestratorLimits) -> Self {
+        let (memory_budget_mib, vcpu_budget) = limits.budgets();
+
+        Self {
+            machines: SyncMutex::new(HashMap::new()),
+            limits,
+            memory_budget_mib,
+            vcpu_budget,
+            events: events::channel(),
+            metrics: metrics::channel(),
+        }
+    }
+
+    /// Create a machine.
+    ///
+    /// Fails with [`Error::CapacityExceeded`] if this would exceed the limits of the orchestrator.
+    #[instrument(skip_all, fields(vm_id = %config.vm_id()))]
+    pub async fn create(&self, config: Config<'static>) -> Result<VmId, Error> {
+        let vm_id = config.vm_id().clone();
+        let memory_mib = u64::try_from(config.machine_cfg().mem_size_mib())?;
+        let vcpus = config.machine_cfg().vcpu_count();
+
+        // Reserve the slot before creating the machine, so concurrent creations account for it.
+        let slot = Slot::default();
+        let mut guard = slot.clone().try_lock_owned().expect("new slot is locked");
+        {
+            let mut machines = self.machines.lock().unwrap();
+            if machines.contains_key(&vm_id) {
+                return Err(Error::MachineAlreadyExists(vm_id));
+            }
+            self.check_limits(&machines, memory_mib, vcpus)?;
+            machines.insert(
+                vm_id.clone(),
+                Entry {
+                    machine: slot.clone(),
+                    memory_mib,
+                    vcpus,
+                },
+            );
+        }
+        // Released if the creation fails or is cancelled.
+        let reservation = Reservation {
+            machines: &self.machines,
+            vm_id: &vm_id,
+            slot: Some(slot),
+        };
+
+        let machine = Machine::create(config).await?;
+        forward(machine.subscribe(), self.events.clone());
+        forward(machine.subscribe_metrics(), self.metrics.clone());
+        *guard = Some(machine);
+        reservation.keep();
+        info!("Machine created");
+        let _ = self
+            .events
+            .send(MachineEvent::new(vm_id.clone(), MachineEventKind::Created));
+
+        Ok(vm_id)
+    }
+
+    /// Create a machine of `tenant`, see [`Orchestrator::create`].
+    ///
+    /// Fails with [`Error::IsolationProfileViolation`] unless `config` was built with the
+    /// isolation profile of the tenant, see [`OrchestratorLimits::tenant_profile`].
+    pub async fn create_for_tenant(
+        &self,
+        tenant: &str,
+        config: Config<'static>,
+    ) -> Result<VmId, Error> {
+        let Some(required) = self.limits.tenant_profiles.get(tenant) else {
+            return Err(Error::IsolationProfileViolation {
+                profile: String::new(),
+                reason: format!("no isolation profile for tenant `{tenant}`"),
+            });
+        };
+        let profile = config.isolation_profile().map(|profile| profile.name());
+        if profile != Some(required.as_str()) {
+            return Err(Error::IsolationProfileViolation {
+                profile: required.clone(),
+                reason: format!("required for the machines of tenant `{tenant}`"),
+            });
+        }
+
+        self.create(config).await
+    }
+
+    /// Create and start a batch of machines, returning the result of each, in the order of
+    /// `configs`.
+    ///
+    /// At most `concurrency` machines are created and started at a time, and at most `rate`
+    /// per second begin their creation, which provisions their chroots, so a large batch doesn't
+    /// saturate the disks of the host. Rates that aren't positive or are infinite don't limit
+    /// the batch, and rates below one per day are rounded up to it. Each machine is subject to the limits of the orchestrator,
+    /// see [`Orchestrator::create`]. Machines that fail to start are left created.
+    #[instrument(skip_all, fields(count = configs.len(), concurrency, rate))]
+    pub async fn start_many(
+        &self,
+        configs: Vec<Config<'static>>,
+        concurrency: usize,
+        rate: Option<f64>,
+    ) -> Vec<(VmId, Result<(), Error>)> {
+        let ticker = batch_period(rate).map(|period| {
+            let mut ticker = time::interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Mutex::new(ticker)
+        });
+        let ticker = &ticker;
+        let results: Vec<_> = stream::iter(configs)
+            .map(|config| async move {
+                let vm_id = config.vm_id().clone();
+                if let Some(ticker) = ticker {
+                    ticker.lock().await.tick().await;
+                }
+                let res = async {
+                    self.create(config).await?;
+                    self.start(&vm_id).await
+                }
+                .await;
+                if let Err(e) = &res {
+                    warn!(%vm_id, error = %e, "Failed to start machine of the batch");
+                }
+
+                (vm_id, res)
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await;
+        let started = results.iter().filter(|(_, res)| res.is_ok()).count();
+        info!(started, failed = results.len() - started, "Batch started");
+
+        results
+    }
+
+    /// Start the machine with the given ID.
+    pub async fn start(&self, vm_id: &VmId) -> Result<(), Error> {
+        self.lock(vm_id).await?.as_mut().unwrap().start().await
+    }
+
+    /// Stop the machine with the given ID, see [`Machine::stop`].
+    pub async fn stop(&self, vm_id: &VmId, grace_period: Duration) -> Result<(), Error> {
+        self.lock(vm_id)
+            .await?
+            .as_mut()
+            .unwrap()
+            .stop(grace_period)
+            .await
+    }
+
+    /// Delete the machine with the given ID, see [`Machine::delete`].
+    ///
+    /// The machine is kept if it couldn't be deleted, so the deletion can be retried.
+    pub async fn delete(&self, vm_id: &VmId) -> Result<DeleteSummary, Error> {
+        let mut guard = self.lock(vm_id).await?;
+        let summary = guard
+            .as_mut()
+            .unwrap()
+            .delete_in_place(DeleteOptions::default())
+            .await?;
+        *guard = None;
+        self.machines.lock().unwrap().remove(vm_id);
+
+        Ok(summary)
+    }
+
+    /// The IDs of all machines, sorted.
+    pub fn list(&self) -> Vec<VmId> {
+        let mut vm_ids: Vec<_> = self.machines.lock().unwrap().keys().cloned().collect();
+        vm_ids.sort();
+
+        vm_ids
+    }
+
+    /// Collect the metrics of the machine with the given ID, see [`Machine::collect_metrics`].
+    pub async fn collect_metrics(
+        &self,
+        vm_id: &VmId,
+        interval: Duration,
+    ) -> Result<TaskHandle, Error> {
+        self.lock(vm_id)
+            .await?
+            .as_ref()
+            .unwrap()
+            .collect_metrics(interval)
+    }
+
+    /// The state of the machine with the given ID.
+    ///
+    /// Waits for any ongoing operation on the machine to complete.
+    pub async fn state(&self, vm_id: &VmId) -> Result<MachineState, Error> {
+        Ok(self.lock(vm_id).await?.as_ref().unwrap().state())
+    }
+
+    /// Aggregated statistics of all machines.
+    ///
+    /// Waits for any ongoing operation on the machines to complete.
+    pub async fn stats(&self) -> OrchestratorStats {
+        let entries: Vec<_> = self
+            .machines
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| (entry.machine.clone(), entry.memory_mib, entry.vcpus))
+            .collect();
+
+        let mut stats = OrchestratorStats::default();
+        for (slot, memory_mib, vcpus) in entries {
+            let running = match &*slot.lock().await {
+                Some(machine) => machine.state() == MachineState::RUNNING,
+                // Deleted in the meantime.
+                None => continue,
+            };
+            stats.machines += 1;
+            stats.running += usize::from(running);
+            stats.memory_mib += memory_mib;
+            stats.vcpus += vcpus;
+        }
+
+        stats
+    }
+
+    /// The resources committed to all machines, including those being created, and the budget.
+    pub fn capacity(&self) -> OrchestratorCapacity {
+        self.capacity_of(&self.machines.lock().unwrap())
+    }
+
+    /// Subscribe to the lifecycle events of all machines.
+    pub fn subscribe(&self) -> broadcast::Receiver<MachineEvent> {
+        self.events.subscribe()
+    }
+
+    /// Run `hooks` on the lifecycle events of all machines, until the returned handle is dropped.
+    pub fn run_hooks(&self, hooks: EventHooks) -> TaskHandle {
+        hooks::run(self.subscribe(), hooks)
+    }
+
+    /// Subscribe to the metrics of all machines.
+    pub fn subscribe_metrics(&self) -> broadcast::Receiver<MetricsSample> {
+        self.metrics.subscribe()
+    }
+
+    /// Aggregate the metrics of all machines, see [`MetricsAggregator`].
+    pub fn aggregate_metrics(&self) -> MetricsAggregator {
+        MetricsAggregator::new(self.subscribe_metrics(), self.subscribe())
+    }
+
+    /// Lock the machine with the given ID, for the duration of an operation.
+    ///
+    /// The returned slot is guaranteed to hold the machine.
+    async fn lock(&self, vm_id: &VmId) -> Result<OwnedMutexGuard<Option<Machine<'static>>>, Error> {
+        let slot = self
+            .machines
+            .lock()
+            .unwrap()
+            .get(vm_id)
+            .map(|entry| entry.machine.clone())
+            .ok_or_else(|| Error::MachineNotFound(vm_id.clone()))?;
+        let guard = slot.lock_owned().await;
+        if guard.is_none() {
+            // Deleted while we were waiting for the lock, or failed to be created.
+            return Err(Error::MachineNotFound(vm_id.clone()));
+        }
+
+        Ok(guard)
+    }
+
+    fn capacity_of(&self, machines: &HashMap<VmId, Entry>) -> OrchestratorCapacity {
+        OrchestratorCapacity {
+            committed_memory_mib: machines.values().map(|entry| entry.memory_mib).sum(),
+            memory_budget_mib: self.memory_budget_mib,
+            committed_vcpus: machines.values().map(|entry| entry.vcpus).sum(),
+            vcpu_budget: self.vcpu_budget,
+        }
+    }
+
+    fn check_limits(
+        &self,
+        machines: &HashMap<VmId, Entry>,
+        memory_mib: u64,
+        vcpus: usize,
+    ) -> Result<(), Error> {
+        if let Some(max_machines) = self.limits.max_machines {
+            if machines.len() >= max_machines {
+                return Err(Error::CapacityExceeded(format!(
+                    "maximum of {max_machines} machines reached"
+                )));
+            }
+        }
+
+        self.capacity_of(machines).check(memory_mib, vcpus)
+    }
+}
+
+/// Forward the events or metrics of a machine until it's dropped.
+fn forward<T>(mut receiver: broadcast::Receiver<T>, sender: broadcast::Sender<T>)
+where
+    T: Clone + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(item) => {
+                    let _ = sender.send(item);
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::machine::tests::{kernel_image, RecordingFs, RecordingSpawner};
+
+    fn config_with_fakes(fs: &RecordingFs) -> Config<'static> {
+        config_with_id(fs, Uuid::new_v4().into())
+    }
+
+    fn config_with_id(fs: &RecordingFs, vm_id: VmId) -> Config<'static> {
+        Config::builder(Some(vm_id), kernel_image())
+            .jailer_cfg()
+            .chroot_base_dir(Path::new("/chroot"))
+            .exec_file(Path::new("/usr/bin/firecracker"))
+            .build()
+            .machine_cfg()
+            .mem_size_mib(512)
+            .vcpu_count(2)
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .process_spawner(RecordingSpawner::default())
+            .chroot_fs(fs.clone())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_and_delete() {
+        let orchestrator = Orchestrator::new(OrchestratorLimits::default().max_machines(2));
+        let fs = RecordingFs::default();
+        let vm_id = orchestrator.create(config_with_fakes(&fs)).await.unwrap();
+        let other = orchestrator.create(config_with_fakes(&fs)).await.unwrap();
+        assert_eq!(orchestrator.list().len(), 2);
+        assert!(matches!(
+            orchestrator.create(config_with_fakes(&fs)).await,
+            Err(Error::CapacityExceeded(_))
+        ));
+        assert_eq!(
+            orchestrator.stats().await,
+            OrchestratorStats {
+                machines: 2,
+                running: 0,
+                memory_mib: 1024,
+                vcpus: 4,
+            }
+        );
+
+        let summary = orchestrator.delete(&vm_id).await.unwrap();
+        assert!(summary.vm_dir_removed);
+        assert_eq!(orchestrator.list(), [other]);
+        assert_eq!(orchestrator.capacity().committed_memory_mib, 512);
+        assert!(matches!(
+            orchestrator.state(&vm_id).await,
+            Err(Error::MachineNotFound(_))
+        ));
+        assert!(matches!(
+            orchestrator.delete(&vm_id).await,
+            Err(Error::MachineNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_delete_keeps_machine() {
+        let orchestrator = Orchestrator::default();
+        let fs = RecordingFs::default();
+        let vm_id = orchestrator.create(config_with_fakes(&fs)).await.unwrap();
+
+        fs.fail_dir_removals(true);
+        assert!(orchestrator.delete(&vm_id).await.is_err());
+        assert_eq!(orchestrator.list(), std::slice::from_ref(&vm_id));
+        assert_eq!(
+            orchestrator.state(&vm_id).await.unwrap(),
+            MachineState::SHUTOFF
+        );
+
+        // The deletion can be retried.
+        fs.fail_dir_removals(false);
+        assert!(orchestrator.delete(&vm_id).await.unwrap().vm_dir_removed);
+        assert!(orchestrator.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_create_releases_slot() {
+        let orchestrator = Orchestrator::new(OrchestratorLimits::default().max_machines(1));
+        let fs = RecordingFs::default();
+        let vm_id: VmId = Uuid::new_v4().into();
+
+        fs.stall_dir_creations(true);
+        let create = orchestrator.create(config_with_id(&fs, vm_id.clone()));
+        assert!(time::timeout(Duration::from_millis(50), create)
+            .await
+            .is_err());
+        assert!(orchestrator.list().is_empty());
+        assert_eq!(orchestrator.capacity().committed_memory_mib, 0);
+        assert_eq!(orchestrator.capacity().committed_vcpus, 0);
+
+        fs.stall_dir_creations(false);
+        let created = orchestrator
+            .create(config_with_id(&fs, vm_id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(created, vm_id);
+        assert_eq!(orchestrator.list(), [vm_id]);
+    }
+
+    #[test]
+    fn budgets() {
+        let limits = OrchestratorLimits::default()
+            .max_memory_mib(4096)
+            .memory_overcommit(1.5)
+            .vcpu_overcommit(4.0)
+            .host_resources(2048, 2);
+        let capacity = OrchestratorCapacity {
+            committed_memory_mib: 2048,
+            committed_vcpus: 6,
+            ..Orchestrator::new(limits).capacity()
+        };
+        assert_eq!(capacity.memory_budget_mib, Some(3072));
+        assert_eq!(capacity.vcpu_budget, Some(8));
+        assert_eq!(capacity.free_memory_mib(), Some(1024));
+        assert!(capacity.check(1024, 2).is_ok());
+        assert!(capacity.check(1025, 1).is_err());
+        assert!(capacity.check(512, 3).is_err());
+
+        let unlimited = Orchestrator::default().capacity();
+        assert_eq!(unlimited.free_vcpus(), None);
+        assert!(unlimited.check(u64::MAX, usize::MAX).is_ok());
+    }
+
+    #[tokio::test]
+    async fn start_many() {
+        let orchestrator = Orchestrator::new(OrchestratorLimits::default().max_machines(0));
+        let configs: Vec<_> = ["batch-1", "batch-2", "batch-3"]
+            .into_iter()
+            .map(|id| {
+                Config::builder(Some(VmId::new(id).unwrap()), Path::new("/tmp/vmlinux"))
+                    .jailer_cfg()
+                    .build()
+                    .initrd_path(Path::new("/tmp/initrd"))
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let results = orchestrator.start_many(configs, 2, Some(20.0)).await;
+        // The first creation begins right away, the others 50ms apart.
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        let vm_ids: Vec<_> = results.iter().map(|(vm_id, _)| vm_id.as_str()).collect();
+        assert_eq!(vm_ids, ["batch-1", "batch-2", "batch-3"]);
+        assert!(results
+            .iter()
+            .all(|(_, res)| matches!(res, Err(Error::CapacityExceeded(_)))));
+    }
+
+    #[tokio::test]
+    async fn batch_rates() {
+        assert_eq!(batch_period(None), None);
+        assert_eq!(batch_period(Some(0.0)), None);
+        assert_eq!(batch_period(Some(-1.0)), None);
+        assert_eq!(batch_period(Some(f64::NAN)), None);
+        assert_eq!(batch_period(Some(f64::INFINITY)), None);
+        assert_eq!(batch_period(Some(4.0)), Some(Duration::from_millis(250)));
+        assert_eq!(batch_period(Some(1e300)), Some(MIN_BATCH_PERIOD));
+        assert_eq!(batch_period(Some(1e-300)), Some(MAX_BATCH_PERIOD));
+
+        let orchestrator = Orchestrator::default();
+        for rate in [f64::INFINITY, f64::MAX, f64::MIN_POSITIVE, 1e-300] {
+            assert!(orchestrator
+                .start_many(Vec::new(), 1, Some(rate))
+                .await
+                .is_empty());
+        }
+    }
+}
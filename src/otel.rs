@@ -0,0 +1,35 @@
+//! OpenTelemetry integration.
+//!
+//! All operations of the crate are instrumented with [`tracing`] spans (`create`, `start_with`,
+//! `setup_vm`, every API call, `shutdown`, ...), carrying the `vm_id` as a field. With a
+//! [`tracing_opentelemetry`] layer installed, these spans are exported as OpenTelemetry spans
+//! with `vm_id` attributes.
+//!
+//! Spans nest under the current [`tracing`] span. Callers that only have an OpenTelemetry
+//! [`Context`], e.g extracted from an incoming request of a distributed scheduler, can use
+//! [`with_context`] to link the spans of an operation to it.
+
+use std::future::Future;
+
+use opentelemetry::Context;
+use tracing::{info_span, instrument::Instrumented, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Run `fut` with its spans linked to the OpenTelemetry context `cx`.
+///
+/// ```no_run
+/// # async fn example(machine: &mut firec::Machine<'_>) -> Result<(), firec::Error> {
+/// let cx = opentelemetry::Context::current();
+/// firec::otel::with_context(cx, machine.start()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn with_context<F>(cx: Context, fut: F) -> Instrumented<F>
+where
+    F: Future,
+{
+    let span = info_span!("firec");
+    let _ = span.set_parent(cx);
+
+    fut.instrument(span)
+}
@@ -0,0 +1,117 @@
+//! Dry runs of machine creation and start.
+//!
+//! [`crate::Machine::plan`] lists what [`crate::Machine::create`] and
+//! [`crate::Machine::start_with`] would do for a configuration, without changing anything on the
+//! host: the directories and files they would create, the artifacts they would copy, the jailer
+//! command line and the API calls, with their payloads. Plans serialize to JSON, e.g for audit
+//! logs.
+//!
+//! Copies already up to date in the chroot (see [`crate::config::ArtifactRefresh`]) are left out,
+//! as they would be skipped. The API calls are those of a successful start, in the order they
+//! would be sent, although some are sent concurrently.
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+/// What creating and starting a machine would do, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Plan {
+    /// The operations of [`crate::Machine::create`].
+    pub create: Vec<PlannedOperation>,
+    /// The operations of [`crate::Machine::start_with`].
+    pub start: Vec<PlannedOperation>,
+}
+
+/// An operation of a [`Plan`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlannedOperation {
+    /// Create a directory, along with its parents.
+    CreateDir {
+        /// The host path of the directory.
+        path: PathBuf,
+    },
+    /// Limit the disk usage of the workspace.
+    SetupWorkspaceQuota {
+        /// The quota, as configured.
+        quota: String,
+    },
+    /// Bind-mount the workspace at the chroot directory of the jailer, see
+    /// [`crate::config::WorkspaceLayout`].
+    BindMount {
+        /// The host path of the workspace.
+        src: PathBuf,
+        /// The chroot directory of the jailer.
+        dest: PathBuf,
+    },
+    /// Copy a file into the chroot.
+    Copy {
+        /// The host path of the source.
+        src: PathBuf,
+        /// The host path of the copy.
+        dest: PathBuf,
+        /// The size of the source in bytes, if it can be read.
+        size: Option<u64>,
+        /// If the file is decompressed while copied, e.g a compressed kernel image.
+        decompress: bool,
+    },
+    /// Write a file.
+    WriteFile {
+        /// The host path of the file.
+        path: PathBuf,
+        /// The size of the content in bytes.
+        size: u64,
+    },
+    /// Check that the root filesystem image is usable.
+    CheckRootFilesystem {
+        /// The host path of the image.
+        path: PathBuf,
+    },
+    /// Provision a drive with device-mapper instead of copying it.
+    ProvisionDrive {
+        /// The ID of the drive.
+        drive_id: String,
+        /// The provisioning, as configured.
+        provisioning: String,
+        /// The host path of the drive in the chroot.
+        dest: PathBuf,
+    },
+    /// Give the artifacts in the chroot to the jailer user.
+    ChownArtifacts {
+        /// The user ID.
+        uid: u32,
+        /// The group ID.
+        gid: u32,
+    },
+    /// Create a TAP device attached to a bridge.
+    CreateTap {
+        /// The name of the TAP device.
+        name: String,
+        /// The name of the bridge.
+        bridge: String,
+        /// If the bridge is created when it doesn't exist.
+        create_bridge: bool,
+    },
+    /// Set up NAT for the guest subnet.
+    SetupNat {
+        /// The guest subnet.
+        guest_subnet: String,
+        /// The host interface traffic goes out of.
+        host_interface: String,
+    },
+    /// Run the jailer.
+    SpawnJailer {
+        /// The command line.
+        command: Vec<String>,
+    },
+    /// Call the Firecracker API.
+    ApiCall {
+        /// The HTTP method.
+        method: String,
+        /// The endpoint.
+        path: String,
+        /// The JSON payload, if any.
+        body: Option<serde_json::Value>,
+    },
+}
@@ -0,0 +1,410 @@
+//! Warm pools of started machines.
+//!
+//! A [`Pool`] hands out machines that were started ahead of time, booted or restored from a
+//! snapshot (see [`PoolPolicy::snapshot`]), so that acquiring one doesn't wait for the VM to
+//! start. [`Pool::refill`] keeps the pool at its target size in the background, starting a limited
+//! number of machines at a time and spacing their starts, so that refilling doesn't overload the
+//! host. When the pool is empty, [`Pool::acquire`] starts a machine on demand.
+//!
+//! Pooled machines are all alike, but [`Pool::acquire_with`] applies [`Overrides`] to the machine
+//! it hands out, e.g to give it tenant-specific MMDS metadata or data drive.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    sync::{Notify, Semaphore},
+    task::JoinSet,
+    time::sleep,
+};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    config::Config,
+    snapshot::{self, Snapshot},
+    task::TaskHandle,
+    Error, Machine, MachineState, StartOptions,
+};
+
+/// Name of the pool snapshot in the chroot of pooled machines.
+const POOL_SNAPSHOT_NAME: &str = "pool";
+
+type ConfigFactory = Arc<dyn Fn() -> Result<Config<'static>, Error> + Send + Sync>;
+
+/// How a [`Pool`] is kept filled.
+#[derive(Debug, Clone)]
+pub struct PoolPolicy {
+    pub(crate) target_size: usize,
+    pub(crate) max_concurrent_starts: usize,
+    pub(crate) start_spacing: Duration,
+    pub(crate) start_options: StartOptions,
+    pub(crate) snapshot: Option<Snapshot>,
+}
+
+impl PoolPolicy {
+    /// Keep `target_size` machines ready.
+    ///
+    /// By default, one machine is started at a time, a second apart.
+    pub fn new(target_size: usize) -> Self {
+        Self {
+            target_size,
+            max_concurrent_starts: 1,
+            start_spacing: Duration::from_secs(1),
+            start_options: StartOptions::default(),
+            snapshot: None,
+        }
+    }
+
+    /// Set the maximum number of machines started concurrently when refilling.
+    pub fn max_concurrent_starts(mut self, max_concurrent_starts: usize) -> Self {
+        self.max_concurrent_starts = max_concurrent_starts.max(1);
+        self
+    }
+
+    /// Set the minimum time between two machine starts when refilling.
+    pub fn start_spacing(mut self, start_spacing: Duration) -> Self {
+        self.start_spacing = start_spacing;
+        self
+    }
+
+    /// Set the options machines are started with.
+    pub fn start_options(mut self, start_options: StartOptions) -> Self {
+        self.start_options = start_options;
+        self
+    }
+
+    /// Restore the machines from `snapshot` instead of booting them.
+    ///
+    /// The snapshot is shared with all the machines, see [`snapshot::share`].
+    pub fn snapshot(mut self, snapshot: Snapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+}
+
+/// Last-mile customization of a pooled machine, see [`Pool::acquire_with`].
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub(crate) drives: Vec<(String, PathBuf)>,
+    pub(crate) balloon_mib: Option<u32>,
+    pub(crate) mmds_metadata: Option<serde_json::Value>,
+}
+
+impl Overrides {
+    /// Replace the backing file of the drive `drive_id` by a copy of `src_path`, see
+    /// [`Machine::replace_drive`].
+    ///
+    /// The drive must be part of the pooled configuration, as drives can't be attached to a
+    /// running VM, e.g as an empty placeholder.
+    pub fn drive<S, P>(mut self, drive_id: S, src_path: P) -> Self
+    where
+        S: Into<String>,
+        P: Into<PathBuf>,
+    {
+        self.drives.push((drive_id.into(), src_path.into()));
+        self
+    }
+
+    /// Set the target size of the balloon, in MiB, see [`Machine::update_balloon`].
+    pub fn balloon_mib(mut self, balloon_mib: u32) -> Self {
+        self.balloon_mib = Some(balloon_mib);
+        self
+    }
+
+    /// Set the MMDS metadata, see [`Machine::set_mmds_metadata`].
+    pub fn mmds_metadata(mut self, metadata: serde_json::Value) -> Self {
+        self.mmds_metadata = Some(metadata);
+        self
+    }
+}
+
+/// The health of a [`Pool`], see [`Pool::health`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolHealth {
+    /// Number of ready machines.
+    pub size: usize,
+    /// Number of machines being started to refill the pool.
+    pub starting: usize,
+    /// Number of machines the pool is kept at.
+    pub target_size: usize,
+    /// Number of acquisitions served by a ready machine.
+    pub hits: u64,
+    /// Number of acquisitions that had to start a machine.
+    pub misses: u64,
+    /// Number of machines that failed to start.
+    pub failed_starts: u64,
+    /// Total time spent in successful acquisitions.
+    pub total_acquire_latency: Duration,
+}
+
+impl PoolHealth {
+    /// The share of acquisitions served by a ready machine, if any.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let acquisitions = self.hits + self.misses;
+        (acquisitions > 0).then(|| self.hits as f64 / acquisitions as f64)
+    }
+
+    /// The average time of successful acquisitions, if any.
+    pub fn average_acquire_latency(&self) -> Option<Duration> {
+        let acquisitions = u32::try_from(self.hits + self.misses).ok()?;
+        (acquisitions > 0).then(|| self.total_acquire_latency / acquisitions)
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    ready: VecDeque<Machine<'static>>,
+    health: PoolHealth,
+}
+
+struct Inner {
+    factory: ConfigFactory,
+    policy: PoolPolicy,
+    state: Mutex<State>,
+    /// Notified when the pool may need refilling.
+    changed: Notify,
+}
+
+/// A warm pool of started machines, see the [module documentation](self).
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("policy", &self.inner.policy)
+            .field("health", &self.health())
+            .finish()
+    }
+}
+
+impl Pool {
+    /// Create an empty pool of machines configured by `factory`, filled according to `policy`.
+    ///
+    /// Each call to `factory` must return the configuration of a new machine, with a VM ID of
+    /// its own.
+    pub fn new<F>(factory: F, policy: PoolPolicy) -> Self
+    where
+        F: Fn() -> Result<Config<'static>, Error> + Send + Sync + 'static,
+    {
+        let health = PoolHealth {
+            target_size: policy.target_size,
+            ..Default::default()
+        };
+
+        Self {
+            inner: Arc::new(Inner {
+                factory: Arc::new(factory),
+                policy,
+                state: Mutex::new(State {
+                    ready: VecDeque::new(),
+                    health,
+                }),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Take a running machine from the pool, or start one if the pool is empty.
+    ///
+    /// Ready machines that stopped in the meantime are deleted and skipped.
+    #[instrument(skip_all)]
+    pub async fn acquire(&self) -> Result<Machine<'static>, Error> {
+        let started = Instant::now();
+        let (machine, hit) = loop {
+            let pooled = self.inner.state.lock().unwrap().ready.pop_front();
+            self.inner.changed.notify_one();
+            match pooled {
+                Some(machine) if machine.state() == MachineState::RUNNING => break (machine, true),
+                Some(machine) => {
+                    warn!(vm_id = %machine.config().vm_id(), "Discarding stopped pooled machine");
+                    if let Err(err) = machine.delete().await {
+                        warn!(error = %err, "Failed to delete stopped pooled machine");
+                    }
+                }
+                None => break (self.inner.start().await?, false),
+            }
+        };
+
+        let mut state = self.inner.state.lock().unwrap();
+        match hit {
+            true => state.health.hits += 1,
+            false => state.health.misses += 1,
+        }
+        state.health.total_acquire_latency += started.elapsed();
+        debug!(vm_id = %machine.config().vm_id(), hit, "Machine acquired");
+
+        Ok(machine)
+    }
+
+    /// Take a running machine from the pool, see [`Pool::acquire`], and apply `overrides` to it.
+    ///
+    /// The drives are replaced first, then the balloon is resized and the MMDS metadata set last,
+    /// so that the guest can wait for its metadata to know it was handed out. The machine is
+    /// deleted if any override fails.
+    #[instrument(skip_all)]
+    pub async fn acquire_with(&self, overrides: &Overrides) -> Result<Machine<'static>, Error> {
+        let mut machine = self.acquire().await?;
+        if let Err(e) = customize(&mut machine, overrides).await {
+            if let Err(err) = machine.delete().await {
+                warn!(error = %err, "Failed to delete machine that failed to be customized");
+            }
+            return Err(e);
+        }
+
+        Ok(machine)
+    }
+
+    /// Keep the pool at its target size in the background, until the returned handle is dropped.
+    ///
+    /// Dropping the handle aborts the starts in progress, which may leave their machines behind.
+    pub fn refill(&self) -> TaskHandle {
+        TaskHandle::new(tokio::spawn(run_refiller(self.inner.clone())))
+    }
+
+    /// The health of the pool.
+    pub fn health(&self) -> PoolHealth {
+        let state = self.inner.state.lock().unwrap();
+
+        PoolHealth {
+            size: state.ready.len(),
+            ..state.health.clone()
+        }
+    }
+
+    /// Delete the ready machines.
+    ///
+    /// All machines are deleted even if some fail to be, the first failure being returned. The
+    /// refiller, if any, should be stopped first, or it refills the pool.
+    #[instrument(skip_all)]
+    pub async fn drain(&self) -> Result<(), Error> {
+        let ready: Vec<_> = self.inner.state.lock().unwrap().ready.drain(..).collect();
+        let mut result = Ok(());
+        for machine in ready {
+            let vm_id = machine.config().vm_id().clone();
+            if let Err(err) = machine.delete().await {
+                warn!(%vm_id, error = %err, "Failed to delete pooled machine");
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl Inner {
+    /// Start a machine.
+    #[instrument(skip_all)]
+    async fn start(&self) -> Result<Machine<'static>, Error> {
+        let mut machine = Machine::create((self.factory)()?).await?;
+        let result = async {
+            let mut options = self.policy.start_options.clone();
+            if let Some(snapshot) = &self.policy.snapshot {
+                let shared =
+                    snapshot::share(snapshot, machine.config(), POOL_SNAPSHOT_NAME).await?;
+                options = options.restore(shared);
+            }
+
+            machine.start_with(options).await
+        }
+        .await;
+        if let Err(e) = result {
+            if let Err(err) = machine.delete().await {
+                warn!(error = %err, "Failed to delete machine that failed to start");
+            }
+            return Err(e);
+        }
+
+        Ok(machine)
+    }
+
+    /// Number of machines to start to reach the target size.
+    fn deficit(&self) -> usize {
+        let state = self.state.lock().unwrap();
+
+        self.policy
+            .target_size
+            .saturating_sub(state.ready.len() + state.health.starting)
+    }
+}
+
+async fn customize(machine: &mut Machine<'static>, overrides: &Overrides) -> Result<(), Error> {
+    for (drive_id, src_path) in &overrides.drives {
+        machine.replace_drive(drive_id, src_path).await?;
+    }
+    if let Some(balloon_mib) = overrides.balloon_mib {
+        machine.update_balloon(balloon_mib).await?;
+    }
+    if let Some(metadata) = &overrides.mmds_metadata {
+        machine.set_mmds_metadata(metadata).await?;
+    }
+
+    Ok(())
+}
+
+async fn run_refiller(inner: Arc<Inner>) {
+    let starts = Arc::new(Semaphore::new(inner.policy.max_concurrent_starts));
+    let mut running = JoinSet::new();
+    loop {
+        while inner.deficit() > 0 {
+            let permit = starts.clone().acquire_owned().await.expect("never closed");
+            // Acquisitions may have refilled the pool while we were waiting.
+            if inner.deficit() == 0 {
+                break;
+            }
+            inner.state.lock().unwrap().health.starting += 1;
+            let task_inner = inner.clone();
+            running.spawn(async move {
+                let result = task_inner.start().await;
+                let mut state = task_inner.state.lock().unwrap();
+                state.health.starting -= 1;
+                match result {
+                    Ok(machine) => state.ready.push_back(machine),
+                    Err(err) => {
+                        warn!(error = %err, "Failed to start pooled machine");
+                        state.health.failed_starts += 1;
+                    }
+                }
+                drop(state);
+                task_inner.changed.notify_one();
+                drop(permit);
+            });
+            sleep(inner.policy.start_spacing).await;
+        }
+
+        tokio::select! {
+            _ = inner.changed.notified() => (),
+            Some(_) = running.join_next() => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health() {
+        let health = PoolHealth {
+            hits: 3,
+            misses: 1,
+            total_acquire_latency: Duration::from_millis(400),
+            ..Default::default()
+        };
+        assert_eq!(health.hit_rate(), Some(0.75));
+        assert_eq!(
+            health.average_acquire_latency(),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(PoolHealth::default().hit_rate(), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! Recording and replaying of Firecracker API calls.
+//!
+//! When enabled through [`crate::config::Builder::record_api_calls`], every API call made by
+//! [`crate::Machine`] is appended as a JSON line to [`crate::config::Config::api_record_path`].
+//! The recorded sequence can later be re-issued against a (fresh) Firecracker instance with
+//! [`replay`], which comes in handy when reproducing issues to report to Firecracker developers.
+
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{Body, Method, Request};
+use hyperlocal::Uri;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use crate::{client::api_client, Error};
+
+/// A recorded Firecracker API call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiCall {
+    /// Time of the call, in milliseconds since the UNIX epoch.
+    pub timestamp_ms: u128,
+    /// The HTTP method.
+    pub method: String,
+    /// The endpoint path (e.g `/machine-config`).
+    pub path: String,
+    /// The request body.
+    pub body: Option<String>,
+    /// The HTTP response status code.
+    pub status: u16,
+    /// The response body.
+    pub response_body: Option<String>,
+}
+
+impl ApiCall {
+    pub(crate) fn new(
+        method: &Method,
+        path: &str,
+        body: Option<String>,
+        status: u16,
+        response_body: Option<String>,
+    ) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+
+        Self {
+            timestamp_ms,
+            method: method.to_string(),
+            path: path.to_owned(),
+            body,
+            status,
+            response_body,
+        }
+    }
+}
+
+/// Append `call` to the record file at `path`.
+pub(crate) async fn append(path: &Path, call: &ApiCall) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut line = serde_json::to_vec(call)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+
+    Ok(())
+}
+
+/// Read the API calls recorded in the file at `path`.
+pub async fn read(path: &Path) -> Result<Vec<ApiCall>, Error> {
+    let content = fs::read_to_string(path).await?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(Into::into))
+        .collect()
+}
+
+/// Re-issue the API calls recorded in `record_path` against the Firecracker API socket at
+/// `socket_path`.
+///
+/// Calls are issued in the recorded order, regardless of whether they succeed. Returns the calls
+/// as they were made during the replay, i.e with the new status and response body.
+pub async fn replay(socket_path: &Path, record_path: &Path) -> Result<Vec<ApiCall>, Error> {
+    let client = api_client();
+    let mut replayed = Vec::new();
+
+    for call in read(record_path).await? {
+        let method = Method::from_bytes(call.method.as_bytes())
+            .map_err(|e| Error::Http(hyper::http::Error::from(e)))?;
+        let body = call
+            .body
+            .clone()
+            .map(Body::from)
+            .unwrap_or_else(Body::empty);
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(Uri::new(socket_path, &call.path))
+            .header("Accept", "application/json")
+            .header("Content-Type", "application/json")
+            .body(body)?;
+
+        let resp = client.request(request).await?;
+        let status = resp.status().as_u16();
+        let response_body = hyper::body::to_bytes(resp.into_body()).await?;
+        let response_body =
+            (!response_body.is_empty()).then(|| String::from_utf8_lossy(&response_body).into());
+
+        replayed.push(ApiCall::new(
+            &method,
+            &call.path,
+            call.body,
+            status,
+            response_body,
+        ));
+    }
+
+    Ok(replayed)
+}
@@ -0,0 +1,456 @@
+//! Experimental management of VMs on remote hosts over SSH.
+//!
+//! [`SshHost`] implements the [`ProcessSpawner`], [`ChrootFs`] and [`ApiTransport`]
+//! abstractions by running commands on another host through the `ssh` client, so the same
+//! [`crate::Machine`] API can create and start VMs there without an agent (see
+//! [`SshHost::configure`]):
+//!
+//! * The jailer and helper commands are run with `ssh`.
+//! * Artifacts are copied to the remote chroot with `rsync`, which must be installed on both
+//!   hosts. Other chroot operations use coreutils on the remote host.
+//! * The API socket is reached through `socat` on the remote host.
+//!
+//! Key-based authentication must be set up, as `ssh` runs in batch mode. The remote user needs
+//! the same privileges as firec needs locally.
+//!
+//! Limitations: the standard I/O of remote commands isn't forwarded, so the output of the
+//! jailer can't be captured and helpers relying on command output (e.g vCPU pinning) fail.
+//! Process IDs are those of the remote host, so [`crate::Machine::force_shutdown`] and the crash
+//! watchdog, which rely on local processes, don't work; shut VMs down through the API instead.
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+    process::{Output, Stdio},
+    time::UNIX_EPOCH,
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::trace;
+
+use crate::{
+    config::Builder,
+    fs::{self, ChrootFs},
+    spawner::{ChildProcess, ProcessSpawner},
+    transport::{ApiStream, ApiTransport, CommandTransport},
+};
+
+/// A host reachable over SSH, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct SshHost {
+    destination: String,
+    ssh_args: Vec<String>,
+}
+
+impl SshHost {
+    /// Use the SSH `destination`, e.g `root@host` or an alias of the SSH configuration.
+    pub fn new<D>(destination: D) -> Self
+    where
+        D: Into<String>,
+    {
+        Self {
+            destination: destination.into(),
+            ssh_args: vec!["-o".to_owned(), "BatchMode=yes".to_owned()],
+        }
+    }
+
+    /// Add an argument to the `ssh` command line, e.g `-p` and `2222`.
+    pub fn ssh_arg<A>(mut self, arg: A) -> Self
+    where
+        A: Into<String>,
+    {
+        self.ssh_args.push(arg.into());
+        self
+    }
+
+    /// The SSH destination.
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    /// Manage the VM of `builder` on this host, setting its process spawner, chroot filesystem
+    /// and API transport.
+    pub fn configure<'c>(&self, builder: Builder<'c>) -> Builder<'c> {
+        builder
+            .process_spawner(self.clone())
+            .chroot_fs(self.clone())
+            .api_transport(self.clone())
+    }
+
+    /// An `ssh` command running `remote_cmd`, a shell command line, on the host.
+    fn ssh(&self, remote_cmd: &str) -> Command {
+        let mut cmd = Command::new("ssh");
+        cmd.args(&self.ssh_args)
+            .arg(&self.destination)
+            .arg("--")
+            .arg(remote_cmd)
+            .kill_on_drop(true);
+
+        cmd
+    }
+
+    /// Run `remote_cmd` on the host, with `stdin` as its standard input.
+    async fn output(&self, remote_cmd: &str, stdin: Option<Vec<u8>>) -> io::Result<Output> {
+        trace!(destination = %self.destination, remote_cmd, "Running remote command");
+        let mut cmd = self.ssh(remote_cmd);
+        cmd.stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        if let Some(contents) = stdin {
+            let mut child_stdin = child.stdin.take().expect("piped stdin");
+            child_stdin.write_all(&contents).await?;
+            // Close it, so that the command sees the end of the input.
+            drop(child_stdin);
+        }
+
+        child.wait_with_output().await
+    }
+
+    /// Run `remote_cmd` on the host, failing if it exits unsuccessfully.
+    async fn run(&self, remote_cmd: &str, stdin: Option<Vec<u8>>) -> io::Result<Vec<u8>> {
+        let output = self.output(remote_cmd, stdin).await?;
+        if !output.status.success() {
+            return Err(remote_error(remote_cmd, &output));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+impl ProcessSpawner for SshHost {
+    /// The standard I/O of `cmd` isn't forwarded, and environment variables are passed through
+    /// `env`.
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Box<dyn ChildProcess>> {
+        let std_cmd = cmd.as_std();
+        let mut remote_cmd = Vec::new();
+        let envs: Vec<_> = std_cmd
+            .get_envs()
+            .filter_map(|(key, value)| Some((key, value?)))
+            .collect();
+        if !envs.is_empty() {
+            remote_cmd.push("env".to_owned());
+            for (key, value) in envs {
+                let mut var = key.to_owned();
+                var.push("=");
+                var.push(value);
+                remote_cmd.push(quote(&var));
+            }
+        }
+        remote_cmd.push(quote(std_cmd.get_program()));
+        remote_cmd.extend(std_cmd.get_args().map(quote));
+
+        let mut ssh = self.ssh(&remote_cmd.join(" "));
+        ssh.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        trace!(destination = %self.destination, "Spawning remote command: {:?}", ssh);
+
+        Ok(Box::new(ssh.spawn()?))
+    }
+}
+
+impl ChrootFs for SshHost {
+    fn create_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            self.run(&format!("mkdir -p {}", quote(path)), None).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn exists<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            let remote_cmd = format!("test -e {}", quote(path));
+            let output = self.output(&remote_cmd, None).await?;
+            match output.status.code() {
+                Some(0) => Ok(true),
+                Some(1) => Ok(false),
+                _ => Err(remote_error(&remote_cmd, &output)),
+            }
+        }
+        .boxed()
+    }
+
+    fn file_len<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let remote_cmd = format!("stat -c %s {}", quote(path));
+            let len = self.run(&remote_cmd, None).await?;
+            String::from_utf8_lossy(&len)
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::other("unexpected `stat` output"))
+        }
+        .boxed()
+    }
+
+    /// `src` is a local path, copied with `rsync`, preserving holes and the modification time.
+    fn copy<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let len = tokio::fs::metadata(src).await?.len();
+            let ssh = std::iter::once("ssh".to_owned())
+                .chain(self.ssh_args.iter().map(quote))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let output = Command::new("rsync")
+                .args(["--sparse", "--times", "--protect-args", "--rsh", &ssh])
+                .arg(src)
+                .arg(format!("{}:{}", self.destination, dest.display()))
+                .stdin(Stdio::null())
+                .output()
+                .await?;
+            if !output.status.success() {
+                return Err(remote_error("rsync", &output));
+            }
+
+            Ok(len)
+        }
+        .boxed()
+    }
+
+    /// `src` is a local path.
+    fn same_contents<'a>(
+        &'a self,
+        src: &'a Path,
+        dest: &'a Path,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        async move {
+            let metadata = tokio::fs::metadata(src).await?;
+            let modified = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs();
+            let remote_stat = self
+                .run(&format!("stat -c '%s %Y' {}", quote(dest)), None)
+                .await?;
+            let remote_stat = String::from_utf8_lossy(&remote_stat);
+            let mut remote_stat = remote_stat.split_whitespace().map(str::parse::<u64>);
+            let (Some(Ok(len)), Some(Ok(remote_modified))) =
+                (remote_stat.next(), remote_stat.next())
+            else {
+                return Err(io::Error::other("unexpected `stat` output"));
+            };
+            if len != metadata.len() {
+                return Ok(false);
+            }
+            if modified == remote_modified {
+                return Ok(true);
+            }
+
+            let remote_sum = self
+                .run(&format!("sha256sum {}", quote(dest)), None)
+                .await?;
+            let src = src.to_owned();
+            let local_sum = tokio::task::spawn_blocking(move || fs::sha256(&src))
+                .await
+                .map_err(io::Error::other)??;
+            let local_sum: String = local_sum.iter().map(|b| format!("{b:02x}")).collect();
+
+            Ok(remote_sum.starts_with(local_sum.as_bytes()))
+        }
+        .boxed()
+    }
+
+    fn hard_link<'a>(&'a self, src: &'a Path, dest: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            self.run(&format!("ln {} {}", quote(src), quote(dest)), None)
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn make_block_device<'a>(
+        &'a self,
+        path: &'a Path,
+        major: u32,
+        minor: u32,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let remote_cmd = format!("mknod -m 600 {} b {major} {minor}", quote(path));
+            self.run(&remote_cmd, None).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move { self.run(&format!("cat {}", quote(path)), None).await }.boxed()
+    }
+
+    fn write<'a>(&'a self, path: &'a Path, contents: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            self.run(&format!("cat > {}", quote(path)), Some(contents))
+                .await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_owner<'a>(
+        &'a self,
+        path: &'a Path,
+        uid: u32,
+        gid: u32,
+        mode: u32,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let path = quote(path);
+            let remote_cmd = format!("chown {uid}:{gid} {path} && chmod {mode:o} {path}");
+            self.run(&remote_cmd, None).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            self.run(&format!("rm {}", quote(path)), None).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            self.run(&format!("rm -r {}", quote(path)), None).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<PathBuf>>> {
+        async move {
+            let remote_cmd = format!("find {} -mindepth 1 -maxdepth 1 -print0", quote(path));
+            let output = self.run(&remote_cmd, None).await?;
+            let mut entries: Vec<_> = output
+                .split(|&b| b == 0)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| PathBuf::from(String::from_utf8_lossy(entry).into_owned()))
+                .collect();
+            entries.sort();
+
+            Ok(entries)
+        }
+        .boxed()
+    }
+
+    fn disk_usage<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<u64>> {
+        async move {
+            let path = quote(path);
+            let remote_cmd = format!("if [ -e {path} ]; then du -s -B1 {path}; else echo 0; fi");
+            let output = self.run(&remote_cmd, None).await?;
+            String::from_utf8_lossy(&output)
+                .split_whitespace()
+                .next()
+                .and_then(|usage| usage.parse().ok())
+                .ok_or_else(|| io::Error::other("unexpected `du` output"))
+        }
+        .boxed()
+    }
+}
+
+impl ApiTransport for SshHost {
+    fn connect<'a>(
+        &'a self,
+        socket_path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn ApiStream>>> {
+        async move {
+            // The path is escaped for the socat address syntax, then the address for the remote
+            // shell.
+            let address = format!("UNIX-CONNECT:{}", socat_escape(socket_path));
+            let transport = CommandTransport::new("ssh")
+                .args(self.ssh_args.iter().cloned())
+                .args([
+                    self.destination.clone(),
+                    "--".to_owned(),
+                    format!("socat - {}", quote(&address)),
+                ]);
+
+            transport.connect(socket_path).await
+        }
+        .boxed()
+    }
+}
+
+/// Quote `arg` for a POSIX shell.
+fn quote<S>(arg: &S) -> String
+where
+    S: AsRef<OsStr> + ?Sized,
+{
+    let arg = arg.as_ref().to_string_lossy();
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return arg.into_owned();
+    }
+
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Escape `path` for a socat address, where `:` and `,` separate parameters.
+fn socat_escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.to_string_lossy().chars() {
+        if r#"\:,!"'()[]{}"#.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// The error of a failed remote command, `NotFound` if a file is missing.
+fn remote_error(remote_cmd: &str, output: &Output) -> io::Error {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let kind = if stderr.contains("No such file or directory") {
+        io::ErrorKind::NotFound
+    } else {
+        io::ErrorKind::Other
+    };
+
+    io::Error::new(
+        kind,
+        format!(
+            "`{remote_cmd}` failed with {}: {}",
+            output.status,
+            stderr.trim()
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("/srv/jailer/firecracker"), "/srv/jailer/firecracker");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote(""), "''");
+
+        let address = format!(
+            "UNIX-CONNECT:{}",
+            socat_escape(Path::new("/srv/vm,1/it's:{socket}"))
+        );
+        assert_eq!(
+            quote(&address),
+            r"'UNIX-CONNECT:/srv/vm\,1/it\'\''s\:\{socket\}'"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! VM snapshots and archives.
+//!
+//! A snapshot of a paused VM (see [`crate::Machine::create_snapshot`]) consists of a state file
+//! and a memory file, both written to the chroot of the VM. A machine can then be started from
+//! the snapshot instead of booting (see [`crate::StartOptions::restore`]), once the files are
+//! back in its chroot.
+//!
+//! Many VMs can be restored from the same snapshot without copying its memory file, see
+//! [`share`].
+//!
+//! [`crate::Machine::delete_with`] can take a final snapshot and hand it, along with the logs,
+//! to an [`ArchiveStore`] before the VM is torn down, so it can be resumed later.
+
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{debug, instrument};
+
+use crate::{
+    client::ApiClient,
+    config::{Config, VmId},
+    Error,
+};
+
+/// File extension of snapshot state files.
+pub const STATE_FILE_EXTENSION: &str = "vmstate";
+
+/// File extension of snapshot memory files.
+pub const MEMORY_FILE_EXTENSION: &str = "mem";
+
+/// Name of the snapshot taken by [`crate::Machine::delete_with`] when archiving.
+pub const FINAL_SNAPSHOT_NAME: &str = "final";
+
+/// The type of a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SnapshotType {
+    /// The memory file contains all the guest memory.
+    #[default]
+    Full,
+    /// The memory file only contains the memory dirtied since the previous snapshot.
+    ///
+    /// Requires [`crate::config::MachineBuilder::track_dirty_pages`].
+    Diff,
+}
+
+/// The files of a snapshot, as host paths.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The VM state file.
+    pub state_path: PathBuf,
+    /// The guest memory file.
+    pub memory_path: PathBuf,
+}
+
+impl Snapshot {
+    /// The files of the snapshot `name` in the chroot of `config`.
+    pub fn in_chroot(config: &Config<'_>, name: &str) -> Self {
+        let workspace_dir = config.jailer().workspace_dir();
+
+        Self {
+            state_path: workspace_dir.join(format!("{name}.{STATE_FILE_EXTENSION}")),
+            memory_path: workspace_dir.join(format!("{name}.{MEMORY_FILE_EXTENSION}")),
+        }
+    }
+
+    /// The paths of the snapshot files as seen by Firecracker, inside the chroot of `config`.
+    pub(crate) fn chroot_paths(&self, config: &Config<'_>) -> Result<(PathBuf, PathBuf), Error> {
+        let workspace_dir = config.jailer().workspace_dir();
+        let relative = |path: &Path| {
+            path.strip_prefix(workspace_dir)
+                .map(|path| Path::new("/").join(path))
+                .map_err(|_| Error::OutsideChroot(path.to_owned()))
+        };
+
+        Ok((relative(&self.state_path)?, relative(&self.memory_path)?))
+    }
+}
+
+/// Set the state of the VM to `Paused` or `Resumed`.
+pub(crate) async fn set_vm_state(client: &ApiClient, state: &str) -> Result<(), Error> {
+    let json = serde_json::to_string(&serde_json::json!({ "state": state }))?;
+    client.send(Method::PATCH, "/vm", Some(json)).await?;
+
+    Ok(())
+}
+
+/// Snapshot the paused VM to the given paths inside the chroot.
+pub(crate) async fn create(
+    client: &ApiClient,
+    snapshot_type: SnapshotType,
+    snapshot_path: &Path,
+    mem_file_path: &Path,
+) -> Result<(), Error> {
+    let json = serde_json::to_string(&serde_json::json!({
+        "snapshot_type": snapshot_type,
+        "snapshot_path": snapshot_path,
+        "mem_file_path": mem_file_path,
+    }))?;
+    client
+        .send(Method::PUT, "/snapshot/create", Some(json))
+        .await?;
+
+    Ok(())
+}
+
+/// Prepare the chroot of `config` to restore `snapshot` as the snapshot `name`, sharing its
+/// memory file.
+///
+/// The state file is copied, but the memory file is hard linked, so it must be on the same
+/// filesystem as the chroot. Firecracker maps the memory file privately, so guest writes are
+/// copy-on-write and all the VMs restored from the snapshot share the page cache of the
+/// unmodified memory. As the file is shared by VMs running as different jailer users, it's given
+/// to the jailer group of `config` and made readable by that group only (`0440`) when the jailer
+/// chowns artifacts (see [`crate::config::JailerBuilder::chown_artifacts`]): all the VMs
+/// restored from the snapshot must run with the same jailer group. The file must not be modified
+/// while VMs use it.
+///
+/// The machine must be created (see [`crate::Machine::create`]). The returned snapshot can be
+/// passed to [`crate::StartOptions::restore`].
+#[instrument(skip_all, fields(vm_id = %config.vm_id(), name = name))]
+pub async fn share(
+    snapshot: &Snapshot,
+    config: &Config<'_>,
+    name: &str,
+) -> Result<Snapshot, Error> {
+    let fs = config.fs();
+    let shared = Snapshot::in_chroot(config, name);
+    for path in [&shared.state_path, &shared.memory_path] {
+        if fs.exists(path).await? {
+            fs.remove_file(path).await?;
+        }
+    }
+
+    fs.hard_link(&snapshot.memory_path, &shared.memory_path)
+        .await?;
+    fs.copy(&snapshot.state_path, &shared.state_path).await?;
+    let jailer = config.jailer();
+    if jailer.chown_artifacts() {
+        // The link shares the owner and permissions of the source. Owned by root, so that
+        // only the group can read it.
+        fs.set_owner(&shared.memory_path, 0, jailer.gid(), 0o440)
+            .await?;
+        fs.set_owner(&shared.state_path, jailer.uid(), jailer.gid(), 0o400)
+            .await?;
+    }
+    debug!("Snapshot shared");
+
+    Ok(shared)
+}
+
+/// What is archived of a VM on deletion, see [`crate::DeleteOptions::archive`].
+#[derive(Debug, Clone)]
+pub struct Archive {
+    /// The ID of the VM.
+    pub vm_id: VmId,
+    /// The final snapshot, if the VM was running.
+    pub snapshot: Option<Snapshot>,
+    /// The other files to archive: the VM record and the Firecracker log and metrics files, if
+    /// they exist.
+    pub files: Vec<PathBuf>,
+}
+
+impl Archive {
+    /// All the files to archive, snapshot included.
+    pub fn all_files(&self) -> impl Iterator<Item = &Path> {
+        self.snapshot
+            .iter()
+            .flat_map(|snapshot| [snapshot.state_path.as_path(), &snapshot.memory_path])
+            .chain(self.files.iter().map(PathBuf::as_path))
+    }
+}
+
+/// Where VM archives are stored.
+///
+/// Implement this trait to upload archives to an object store. The files are removed once
+/// [`ArchiveStore::store`] returns.
+pub trait ArchiveStore: Debug + Send + Sync {
+    /// Store the files of `archive`.
+    fn store<'a>(&'a self, archive: &'a Archive) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Stores archives in a directory on the host, in a subdirectory per VM.
+#[derive(Debug, Clone)]
+pub struct DirectoryStore {
+    dir: PathBuf,
+}
+
+impl DirectoryStore {
+    /// Store archives in `dir`.
+    pub fn new<P>(dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self { dir: dir.into() }
+    }
+
+    /// The directory the archive of `vm_id` is stored in.
+    pub fn archive_dir(&self, vm_id: &VmId) -> PathBuf {
+        self.dir.join(vm_id.to_string())
+    }
+}
+
+impl ArchiveStore for DirectoryStore {
+    fn store<'a>(&'a self, archive: &'a Archive) -> BoxFuture<'a, Result<(), Error>> {
+        store_in_dir(self.archive_dir(&archive.vm_id), archive).boxed()
+    }
+}
+
+#[instrument(skip_all, fields(vm_id = %archive.vm_id))]
+async fn store_in_dir(dir: PathBuf, archive: &Archive) -> Result<(), Error> {
+    fs::create_dir_all(&dir).await?;
+    for path in archive.all_files() {
+        let Some(file_name) = path.file_name() else {
+            continue;
+        };
+        let dest = dir.join(file_name);
+        debug!("Archiving `{}` to `{}`", path.display(), dest.display());
+        crate::fs::copy_sparse(path, &dest).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+//! Process spawning abstraction.
+//!
+//! [`crate::Machine`] never spawns processes directly but goes through the [`ProcessSpawner`] set
+//! on the [`crate::config::Config`]. By default, [`LocalSpawner`] is used, which spawns processes
+//! on the local host. Custom implementations can be used to inspect the command line instead of
+//! running it (e.g in tests), or to run the processes somewhere else.
+
+use std::{fmt::Debug, io, process::ExitStatus};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::process::{Child, Command};
+
+/// Spawns processes on behalf of a [`crate::Machine`].
+pub trait ProcessSpawner: Debug + Send + Sync {
+    /// Spawn the given command.
+    ///
+    /// The command has its arguments and stdio already set up.
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Box<dyn ChildProcess>>;
+}
+
+/// A process spawned by a [`ProcessSpawner`].
+pub trait ChildProcess: Debug + Send + Sync {
+    /// The OS-assigned process identifier.
+    ///
+    /// Returns `None` if the process has already exited.
+    fn id(&self) -> Option<u32>;
+
+    /// Wait for the process to exit.
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>>;
+
+    /// Get the exit status of the process if it has already exited, without blocking.
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+}
+
+/// Spawns processes on the local host.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalSpawner;
+
+impl ProcessSpawner for LocalSpawner {
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Box<dyn ChildProcess>> {
+        Ok(Box::new(cmd.spawn()?))
+    }
+}
+
+impl ChildProcess for Child {
+    fn id(&self) -> Option<u32> {
+        Child::id(self)
+    }
+
+    fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+        Child::wait(self).boxed()
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+}
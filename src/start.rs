@@ -0,0 +1,385 @@
+//! Options controlling how a machine is started.
+
+use std::{
+    fmt,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use hyper::Method;
+use serde::Serialize;
+use tokio::time::timeout;
+use tracing::trace;
+
+use crate::{config::Config, snapshot::Snapshot, Error};
+
+/// Default time to wait for the API socket to become ready.
+const SOCKET_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options for [`crate::Machine::start_with`].
+///
+/// Each phase of the start can be given its own timeout, and an overall deadline bounds all of
+/// them. Except for waiting on the API socket, no timeouts are applied by default.
+#[derive(Debug, Clone)]
+pub struct StartOptions {
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) spawn_timeout: Option<Duration>,
+    pub(crate) socket_ready_timeout: Duration,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) instance_start_timeout: Option<Duration>,
+    pub(crate) snapshot: Option<Snapshot>,
+    pub(crate) extra_setup: Vec<SetupCall>,
+    pub(crate) skipped_steps: Vec<SetupStep>,
+    pub(crate) sync_clock: bool,
+    pub(crate) network_overrides: bool,
+}
+
+impl Default for StartOptions {
+    fn default() -> Self {
+        Self {
+            deadline: None,
+            spawn_timeout: None,
+            socket_ready_timeout: SOCKET_READY_TIMEOUT,
+            request_timeout: None,
+            instance_start_timeout: None,
+            snapshot: None,
+            extra_setup: Vec::new(),
+            skipped_steps: Vec::new(),
+            sync_clock: false,
+            network_overrides: false,
+        }
+    }
+}
+
+impl StartOptions {
+    /// Set the overall time budget of the start.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set the timeout for preparing the chroot and spawning the jailer.
+    pub fn spawn_timeout(mut self, spawn_timeout: Duration) -> Self {
+        self.spawn_timeout = Some(spawn_timeout);
+        self
+    }
+
+    /// Set the timeout for the Firecracker API socket to become ready.
+    ///
+    /// Defaults to 10 seconds. On timeout, [`Error::StartFailed`] is returned with diagnostics
+    /// about the failed start, rather than [`Error::StartTimedOut`].
+    pub fn socket_ready_timeout(mut self, socket_ready_timeout: Duration) -> Self {
+        self.socket_ready_timeout = socket_ready_timeout;
+        self
+    }
+
+    /// Set the timeout of each API request configuring the VM.
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Set the timeout for the `InstanceStart` action.
+    pub fn instance_start_timeout(mut self, instance_start_timeout: Duration) -> Self {
+        self.instance_start_timeout = Some(instance_start_timeout);
+        self
+    }
+
+    /// Restore the VM from `snapshot` and resume it, instead of booting it.
+    ///
+    /// The snapshot files must be in the chroot of the VM. Only the logger is configured, the
+    /// rest of the configuration is restored from the snapshot. The instance start timeout
+    /// applies to loading the snapshot.
+    pub fn restore(mut self, snapshot: Snapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+
+    /// Make the additional API `calls`, in order, after the built-in setup and before
+    /// `InstanceStart` (or before loading the snapshot, see [`StartOptions::restore`]).
+    ///
+    /// Useful for endpoints firec doesn't configure itself, e.g `/cpu-config`. Each call is a
+    /// setup phase, subject to the request timeout.
+    pub fn extra_setup(mut self, calls: Vec<SetupCall>) -> Self {
+        self.extra_setup = calls;
+        self
+    }
+
+    /// Skip the built-in setup `step`, e.g because it's done by an external controller or
+    /// through [`StartOptions::extra_setup`].
+    ///
+    /// The start fails with [`Error::InvalidStartOptions`] if a skipped step is required, e.g
+    /// the network when MMDS is configured, or the boot source when booting without an extra
+    /// `/boot-source` call.
+    pub fn skip(mut self, step: SetupStep) -> Self {
+        if !self.skipped_steps.contains(&step) {
+            self.skipped_steps.push(step);
+        }
+        self
+    }
+
+    /// If the built-in setup `step` is skipped.
+    pub fn skips(&self, step: SetupStep) -> bool {
+        self.skipped_steps.contains(&step)
+    }
+
+    /// Set the guest clock to the host time after restoring the VM from a snapshot, see
+    /// [`crate::Machine::sync_guest_clock`].
+    ///
+    /// Requires the guest agent. Failing to set the clock doesn't fail the start, it's only
+    /// logged.
+    pub fn sync_clock(mut self) -> Self {
+        self.sync_clock = true;
+        self
+    }
+
+    /// When restoring the VM from a snapshot, attach its network interfaces to the host TAP
+    /// devices of the configuration, by interface ID, instead of those of the snapshotted VM.
+    ///
+    /// Requires Firecracker 1.12 or later. Used by [`crate::Machine::fork`].
+    pub fn network_overrides(mut self) -> Self {
+        self.network_overrides = true;
+        self
+    }
+
+    /// Check that the skipped steps are compatible with the start of a VM with `config`.
+    pub(crate) fn validate(&self, config: &Config<'_>) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::InvalidStartOptions(reason.to_owned()));
+        if self.snapshot.is_some() {
+            // Only the logger is configured when restoring.
+            return Ok(());
+        }
+        if self.skips(SetupStep::Network)
+            && !self.skips(SetupStep::Mmds)
+            && config.mmds_cfg().is_some()
+        {
+            return invalid("MMDS can't be configured without the network interfaces it uses");
+        }
+        let extra_boot_source = self
+            .extra_setup
+            .iter()
+            .any(|call| call.path == SetupStep::BootSource.endpoint());
+        if self.skips(SetupStep::BootSource) && !extra_boot_source {
+            return invalid("a VM can't boot without a boot source");
+        }
+
+        Ok(())
+    }
+}
+
+/// A built-in setup step of [`crate::Machine::start_with`], see [`StartOptions::skip`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SetupStep {
+    /// The machine resources (`/machine-config`).
+    MachineConfig,
+    /// The kernel, initrd and kernel arguments (`/boot-source`).
+    BootSource,
+    /// The drives (`/drives/<id>`).
+    Drives,
+    /// The network interfaces (`/network-interfaces/<id>`).
+    Network,
+    /// MMDS (`/mmds/config`).
+    Mmds,
+    /// The vsock device (`/vsock`).
+    Vsock,
+    /// The balloon device (`/balloon`).
+    Balloon,
+    /// The logger (`/logger`).
+    Logger,
+}
+
+impl SetupStep {
+    /// The API endpoint configured by the step, without the device ID, if any.
+    pub fn endpoint(&self) -> &'static str {
+        match self {
+            Self::MachineConfig => "/machine-config",
+            Self::BootSource => "/boot-source",
+            Self::Drives => "/drives",
+            Self::Network => "/network-interfaces",
+            Self::Mmds => "/mmds/config",
+            Self::Vsock => "/vsock",
+            Self::Balloon => "/balloon",
+            Self::Logger => "/logger",
+        }
+    }
+}
+
+impl fmt::Display for SetupStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.endpoint())
+    }
+}
+
+/// An API call made while starting a machine, see [`StartOptions::extra_setup`].
+#[derive(Debug, Clone)]
+pub struct SetupCall {
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) body: Option<String>,
+}
+
+impl SetupCall {
+    /// A `PUT` of `body`, serialized as JSON, to `path`.
+    pub fn put<P, B>(path: P, body: &B) -> Result<Self, Error>
+    where
+        P: Into<String>,
+        B: Serialize + ?Sized,
+    {
+        Ok(Self {
+            method: Method::PUT,
+            path: path.into(),
+            body: Some(serde_json::to_string(body)?),
+        })
+    }
+
+    /// A `PATCH` of `body`, serialized as JSON, to `path`.
+    pub fn patch<P, B>(path: P, body: &B) -> Result<Self, Error>
+    where
+        P: Into<String>,
+        B: Serialize + ?Sized,
+    {
+        Ok(Self {
+            method: Method::PATCH,
+            path: path.into(),
+            body: Some(serde_json::to_string(body)?),
+        })
+    }
+
+    /// A call with an arbitrary HTTP `method` (e.g `PUT`) and raw JSON `body`.
+    pub fn raw<P>(method: &str, path: P, body: Option<String>) -> Result<Self, Error>
+    where
+        P: Into<String>,
+    {
+        Ok(Self {
+            method: Method::from_bytes(method.as_bytes()).map_err(hyper::http::Error::from)?,
+            path: path.into(),
+            body,
+        })
+    }
+}
+
+/// A phase of [`crate::Machine::start_with`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartPhase {
+    /// Preparing the chroot and spawning the jailer.
+    Spawn,
+    /// Configuring the VM through the given API endpoint.
+    Setup(String),
+    /// Booting the VM instance.
+    InstanceStart,
+}
+
+impl fmt::Display for StartPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn => write!(f, "spawn"),
+            Self::Setup(endpoint) => write!(f, "setup of `{endpoint}`"),
+            Self::InstanceStart => write!(f, "instance start"),
+        }
+    }
+}
+
+/// Keeps track of the time budget of a start.
+#[derive(Debug)]
+pub(crate) struct StartTimer<'o> {
+    options: &'o StartOptions,
+    deadline: Option<Instant>,
+}
+
+impl<'o> StartTimer<'o> {
+    pub(crate) fn new(options: &'o StartOptions) -> Self {
+        Self {
+            options,
+            deadline: options.deadline.map(|deadline| Instant::now() + deadline),
+        }
+    }
+
+    /// The time left for a phase, given its own timeout.
+    pub(crate) fn limit(&self, phase_timeout: Option<Duration>) -> Option<Duration> {
+        let remaining = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (phase_timeout, remaining) {
+            (Some(phase_timeout), Some(remaining)) => Some(phase_timeout.min(remaining)),
+            (phase_timeout, remaining) => phase_timeout.or(remaining),
+        }
+    }
+
+    /// Run `fut` as the given phase, failing with [`Error::StartTimedOut`] if it takes too long.
+    pub(crate) async fn run<T, F>(
+        &self,
+        phase: StartPhase,
+        phase_timeout: Option<Duration>,
+        fut: F,
+    ) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        match self.limit(phase_timeout) {
+            Some(limit) => timeout(limit, fut)
+                .await
+                .map_err(|_| Error::StartTimedOut { phase })?,
+            None => fut.await,
+        }
+    }
+
+    /// Run the built-in setup `step`, unless it's skipped.
+    pub(crate) async fn step<F>(&self, step: SetupStep, fut: F) -> Result<(), Error>
+    where
+        F: Future<Output = Result<(), Error>>,
+    {
+        if self.options.skips(step) {
+            trace!(%step, "Setup step skipped");
+            return Ok(());
+        }
+
+        fut.await
+    }
+
+    /// Run an API request configuring the VM.
+    pub(crate) async fn setup<T, F>(&self, endpoint: &str, fut: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        self.run(
+            StartPhase::Setup(endpoint.to_owned()),
+            self.options.request_timeout,
+            fut,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::MmdsVersion;
+
+    #[test]
+    fn skipped_steps() {
+        let config = Config::builder(None, Path::new("/tmp/vmlinux"))
+            .jailer_cfg()
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .mmds_cfg(MmdsVersion::V2, ["eth0"], None::<&str>)
+            .build()
+            .unwrap();
+
+        let options = StartOptions::default().skip(SetupStep::Network);
+        assert!(matches!(
+            options.validate(&config),
+            Err(Error::InvalidStartOptions(_))
+        ));
+        let options = options.skip(SetupStep::Mmds);
+        assert!(options.validate(&config).is_ok());
+
+        let options = StartOptions::default().skip(SetupStep::BootSource);
+        assert!(options.validate(&config).is_err());
+        let boot_source = serde_json::json!({"kernel_image_path": "vmlinux"});
+        let options =
+            options.extra_setup(vec![SetupCall::put("/boot-source", &boot_source).unwrap()]);
+        assert!(options.validate(&config).is_ok());
+    }
+}
@@ -0,0 +1,32 @@
+//! Persistent state of the helpers managing resources across VMs, such as [`crate::ipam::Ipam`].
+
+use std::{io, path::Path};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::Error;
+
+/// Read the JSON state at `path`, or the default state if there's none yet.
+pub(crate) async fn read<T>(path: &Path) -> Result<T, Error>
+where
+    T: DeserializeOwned + Default,
+{
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write `state` as JSON to `path`.
+pub(crate) async fn write<T>(path: &Path, state: &T) -> Result<(), Error>
+where
+    T: Serialize + ?Sized,
+{
+    // Write to a temporary file first, so the state is never left half-written.
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec_pretty(state)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    Ok(())
+}
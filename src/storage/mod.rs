@@ -0,0 +1,417 @@
+//! Remote storage of snapshots and images.
+//!
+//! A [`Storage`] holds files under string keys, e.g an object store bucket. On top of it:
+//!
+//! * [`push_snapshot`] and [`pull_snapshot`] move snapshots between a storage and the chroot of
+//!   a VM, so a VM snapshotted on one host can be restored on another (see
+//!   [`crate::StartOptions::restore`]).
+//! * [`ImageCache`] keeps local copies of the images (kernels, root filesystems) in a storage.
+//! * [`StorageArchive`] stores the archives of deleted VMs (see [`crate::DeleteOptions::archive`]).
+//!
+//! With the `s3` feature, [`S3Storage`] implements [`Storage`] on top of S3.
+//!
+//! With the `encryption` feature, [`push_snapshot_with`] and [`pull_snapshot_with`] encrypt and
+//! sign snapshots, as memory files can contain secrets of the guest (see [`SnapshotSeal`]).
+
+use std::{
+    fmt::Debug,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::{fs, sync::Mutex};
+use tracing::{debug, instrument};
+
+use crate::{
+    config::{Config, VmId},
+    snapshot::{Archive, ArchiveStore, Snapshot},
+    Error,
+};
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
+#[cfg(feature = "encryption")]
+mod seal;
+#[cfg(feature = "encryption")]
+pub use seal::{generate_signing_key, KeyProvider, SnapshotSeal, StaticKey};
+
+/// A store of files under string keys.
+pub trait Storage: Debug + Send + Sync {
+    /// Upload the file at `path` to `key`, replacing any existing object.
+    fn push<'a>(&'a self, path: &'a Path, key: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Download `key` to the file at `path`, replacing it if it exists.
+    fn pull<'a>(&'a self, key: &'a str, path: &'a Path) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// The keys of the files of the snapshot `name` under `prefix`.
+pub fn snapshot_keys(prefix: &str, name: &str) -> (String, String) {
+    let prefix = prefix.trim_end_matches('/');
+
+    (
+        format!("{prefix}/{name}.{}", crate::snapshot::STATE_FILE_EXTENSION),
+        format!("{prefix}/{name}.{}", crate::snapshot::MEMORY_FILE_EXTENSION),
+    )
+}
+
+/// Upload `snapshot` to `storage` as the snapshot `name` under `prefix`.
+#[instrument(skip_all, fields(prefix = prefix, name = name))]
+pub async fn push_snapshot(
+    storage: &dyn Storage,
+    snapshot: &Snapshot,
+    prefix: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let (state_key, memory_key) = snapshot_keys(prefix, name);
+    storage.push(&snapshot.state_path, &state_key).await?;
+    storage.push(&snapshot.memory_path, &memory_key).await?;
+    debug!("Snapshot pushed");
+
+    Ok(())
+}
+
+/// Download the snapshot `name` under `prefix` from `storage` into the chroot of `config`.
+///
+/// The machine must be created (see [`crate::Machine::create`]). The returned snapshot can be
+/// passed to [`crate::StartOptions::restore`].
+#[instrument(skip_all, fields(vm_id = %config.vm_id(), prefix = prefix, name = name))]
+pub async fn pull_snapshot(
+    storage: &dyn Storage,
+    config: &Config<'_>,
+    prefix: &str,
+    name: &str,
+) -> Result<Snapshot, Error> {
+    let (state_key, memory_key) = snapshot_keys(prefix, name);
+    let snapshot = Snapshot::in_chroot(config, name);
+    storage.pull(&state_key, &snapshot.state_path).await?;
+    storage.pull(&memory_key, &snapshot.memory_path).await?;
+    let jailer = config.jailer();
+    if jailer.chown_artifacts() {
+        for path in [&snapshot.state_path, &snapshot.memory_path] {
+            config
+                .fs()
+                .set_owner(path, jailer.uid(), jailer.gid(), 0o600)
+                .await?;
+        }
+    }
+    debug!("Snapshot pulled");
+
+    Ok(snapshot)
+}
+
+/// The key of the signature of the snapshot `name` under `prefix`.
+#[cfg(feature = "encryption")]
+pub fn signature_key(prefix: &str, name: &str) -> String {
+    format!("{}/{name}.sig", prefix.trim_end_matches('/'))
+}
+
+/// Like [`push_snapshot`], encrypting and signing the snapshot as configured in `seal`.
+///
+/// Encrypted files are written next to the snapshot files while they are uploaded. The signature
+/// covers the uploaded files and is stored under [`signature_key`].
+#[cfg(feature = "encryption")]
+#[instrument(skip_all, fields(prefix = prefix, name = name))]
+pub async fn push_snapshot_with(
+    storage: &dyn Storage,
+    snapshot: &Snapshot,
+    prefix: &str,
+    name: &str,
+    seal: &SnapshotSeal,
+) -> Result<(), Error> {
+    let key = seal.key(name).await?;
+    let (state_path, memory_path) = match &key {
+        Some(_) => (
+            sealed_path(&snapshot.state_path),
+            sealed_path(&snapshot.memory_path),
+        ),
+        None => (snapshot.state_path.clone(), snapshot.memory_path.clone()),
+    };
+    let res = async {
+        if let Some(key) = &key {
+            seal::encrypt(key.clone(), snapshot.state_path.clone(), state_path.clone()).await?;
+            seal::encrypt(
+                key.clone(),
+                snapshot.memory_path.clone(),
+                memory_path.clone(),
+            )
+            .await?;
+        }
+        let (state_key, memory_key) = snapshot_keys(prefix, name);
+        storage.push(&state_path, &state_key).await?;
+        storage.push(&memory_path, &memory_key).await?;
+        if seal.signs() {
+            let signature = seal.signature(name, &state_path, &memory_path).await?;
+            let signature_path = sealed_path(&snapshot.state_path.with_extension("sig"));
+            fs::write(&signature_path, signature).await?;
+            let res = storage
+                .push(&signature_path, &signature_key(prefix, name))
+                .await;
+            let _ = fs::remove_file(&signature_path).await;
+            res?;
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+    if key.is_some() {
+        let _ = fs::remove_file(&state_path).await;
+        let _ = fs::remove_file(&memory_path).await;
+    }
+    res?;
+    debug!(
+        encrypted = key.is_some(),
+        signed = seal.signs(),
+        "Snapshot pushed"
+    );
+
+    Ok(())
+}
+
+/// Like [`pull_snapshot`], verifying and decrypting the snapshot as configured in `seal`.
+///
+/// The signature is verified before anything is decrypted, and nothing is left in the chroot if
+/// either fails.
+#[cfg(feature = "encryption")]
+#[instrument(skip_all, fields(vm_id = %config.vm_id(), prefix = prefix, name = name))]
+pub async fn pull_snapshot_with(
+    storage: &dyn Storage,
+    config: &Config<'_>,
+    prefix: &str,
+    name: &str,
+    seal: &SnapshotSeal,
+) -> Result<Snapshot, Error> {
+    let key = seal.key(name).await?;
+    if key.is_none() && !seal.verifies() {
+        return pull_snapshot(storage, config, prefix, name).await;
+    }
+
+    let (state_key, memory_key) = snapshot_keys(prefix, name);
+    let snapshot = Snapshot::in_chroot(config, name);
+    let state_path = sealed_path(&snapshot.state_path);
+    let memory_path = sealed_path(&snapshot.memory_path);
+    let signature_path = sealed_path(&snapshot.state_path.with_extension("sig"));
+    let res = async {
+        storage.pull(&state_key, &state_path).await?;
+        storage.pull(&memory_key, &memory_path).await?;
+        if seal.verifies() {
+            storage
+                .pull(&signature_key(prefix, name), &signature_path)
+                .await?;
+            let signature = fs::read(&signature_path).await?;
+            seal.check_signature(name, &signature, &state_path, &memory_path)
+                .await?;
+        }
+        match key {
+            Some(key) => {
+                seal::decrypt(key.clone(), state_path.clone(), snapshot.state_path.clone()).await?;
+                seal::decrypt(key, memory_path.clone(), snapshot.memory_path.clone()).await?;
+            }
+            None => {
+                fs::rename(&state_path, &snapshot.state_path).await?;
+                fs::rename(&memory_path, &snapshot.memory_path).await?;
+            }
+        }
+
+        Ok::<_, Error>(())
+    }
+    .await;
+    for path in [&state_path, &memory_path, &signature_path] {
+        let _ = fs::remove_file(path).await;
+    }
+    if let Err(err) = res {
+        for path in [&snapshot.state_path, &snapshot.memory_path] {
+            let _ = fs::remove_file(path).await;
+        }
+        return Err(err);
+    }
+    let jailer = config.jailer();
+    if jailer.chown_artifacts() {
+        for path in [&snapshot.state_path, &snapshot.memory_path] {
+            config
+                .fs()
+                .set_owner(path, jailer.uid(), jailer.gid(), 0o600)
+                .await?;
+        }
+    }
+    debug!("Snapshot pulled and verified");
+
+    Ok(snapshot)
+}
+
+/// The path of the sealed counterpart of the file at `path`, next to it.
+#[cfg(feature = "encryption")]
+fn sealed_path(path: &Path) -> PathBuf {
+    let mut sealed = path.as_os_str().to_owned();
+    sealed.push(".sealed");
+
+    PathBuf::from(sealed)
+}
+
+/// Local copies of the images in a [`Storage`].
+///
+/// Images are downloaded once, on first use, and never refreshed: keys should be immutable, e.g
+/// contain a version or checksum.
+#[derive(Debug)]
+pub struct ImageCache {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl ImageCache {
+    /// Cache the images of `storage` in `dir`.
+    pub fn new<S, P>(storage: S, dir: P) -> Self
+    where
+        S: Storage + 'static,
+        P: Into<PathBuf>,
+    {
+        Self {
+            storage: Arc::new(storage),
+            dir: dir.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The local path of the image `key`, downloading it if it isn't cached yet.
+    ///
+    /// The path can be used as the source of a kernel or drive in the configuration. Keys with
+    /// `..` components are refused, so images can't be written outside of the cache directory.
+    #[instrument(skip_all, fields(key = key))]
+    pub async fn get(&self, key: &str) -> Result<PathBuf, Error> {
+        let path = self.path(key)?;
+        let _guard = self.lock.lock().await;
+        if fs::try_exists(&path).await? {
+            return Ok(path);
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Download next to the final path, so an interrupted download isn't mistaken for a cached
+        // image.
+        let mut part_path = path.clone().into_os_string();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+        debug!("Downloading image to `{}`", path.display());
+        if let Err(err) = self.storage.pull(key, &part_path).await {
+            let _ = fs::remove_file(&part_path).await;
+            return Err(err);
+        }
+        fs::rename(&part_path, &path).await?;
+
+        Ok(path)
+    }
+
+    /// The local path of the image `key`.
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key.trim_start_matches('/'));
+        let mut components = relative.components().peekable();
+        if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_))) {
+            return Err(Error::InvalidImageKey(key.to_owned()));
+        }
+
+        Ok(self.dir.join(relative))
+    }
+}
+
+/// Stores VM archives in a [`Storage`], under `<prefix>/<VM ID>/<file name>`.
+#[derive(Debug, Clone)]
+pub struct StorageArchive {
+    storage: Arc<dyn Storage>,
+    prefix: String,
+}
+
+impl StorageArchive {
+    /// Store archives in `storage` under `prefix`.
+    pub fn new<S, P>(storage: S, prefix: P) -> Self
+    where
+        S: Storage + 'static,
+        P: Into<String>,
+    {
+        Self {
+            storage: Arc::new(storage),
+            prefix: prefix.into(),
+        }
+    }
+
+    /// The key prefix of the archive of `vm_id`.
+    pub fn archive_prefix(&self, vm_id: &VmId) -> String {
+        format!("{}/{vm_id}", self.prefix.trim_end_matches('/'))
+    }
+}
+
+impl ArchiveStore for StorageArchive {
+    fn store<'a>(&'a self, archive: &'a Archive) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            let prefix = self.archive_prefix(&archive.vm_id);
+            for path in archive.all_files() {
+                let Some(file_name) = path.file_name() else {
+                    continue;
+                };
+                let key = format!("{prefix}/{}", file_name.to_string_lossy());
+                self.storage.push(path, &key).await?;
+            }
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    /// A storage holding the same content under every key, counting the pulls.
+    #[derive(Debug, Default)]
+    struct FakeStorage {
+        pulls: Arc<AtomicUsize>,
+    }
+
+    impl Storage for FakeStorage {
+        fn push<'a>(&'a self, _path: &'a Path, _key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn pull<'a>(&'a self, _key: &'a str, path: &'a Path) -> BoxFuture<'a, Result<(), Error>> {
+            self.pulls.fetch_add(1, Ordering::SeqCst);
+            async move { Ok(fs::write(path, b"image").await?) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn image_cache_keys() {
+        let dir = std::env::temp_dir().join(format!("firec-images-{}", Uuid::new_v4()));
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let cache = ImageCache::new(
+            FakeStorage {
+                pulls: pulls.clone(),
+            },
+            &dir,
+        );
+
+        let path = cache.get("/kernels/vmlinux-6.1").await.unwrap();
+        assert_eq!(path, dir.join("kernels/vmlinux-6.1"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"image");
+        cache.get("kernels/vmlinux-6.1").await.unwrap();
+        assert_eq!(pulls.load(Ordering::SeqCst), 1);
+
+        for key in ["../../etc/cron.d/x", "kernels/../../x", "./x", "", "/"] {
+            let err = cache.get(key).await.unwrap_err();
+            assert!(
+                matches!(&err, Error::InvalidImageKey(k) if k == key),
+                "{key}: {err:?}"
+            );
+        }
+        assert_eq!(pulls.load(Ordering::SeqCst), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use futures_util::{future::BoxFuture, FutureExt, StreamExt};
+use object_store::{
+    aws::AmazonS3Builder, buffered::BufWriter, path::Path as ObjectPath, ObjectStore,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufReader},
+};
+use tracing::{debug, instrument};
+
+use super::Storage;
+use crate::Error;
+
+/// A [`Storage`] backed by an S3 bucket.
+///
+/// Uploads are streamed as multipart uploads, so memory files of any size can be pushed.
+#[derive(Clone)]
+pub struct S3Storage {
+    store: Arc<dyn ObjectStore>,
+    bucket: String,
+}
+
+impl S3Storage {
+    /// Use `bucket`, configured from the standard `AWS_*` environment variables (region,
+    /// credentials, endpoint, ...).
+    pub fn from_env<B>(bucket: B) -> Result<Self, Error>
+    where
+        B: Into<String>,
+    {
+        Self::from_builder(AmazonS3Builder::from_env().with_bucket_name(bucket))
+    }
+
+    /// Use the bucket configured by `builder`, for full control over the S3 client.
+    pub fn from_builder(builder: AmazonS3Builder) -> Result<Self, Error> {
+        let store = builder.build()?;
+        let bucket = store.to_string();
+
+        Ok(Self {
+            store: Arc::new(store),
+            bucket,
+        })
+    }
+}
+
+impl fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Storage")
+            .field("bucket", &self.bucket)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Storage for S3Storage {
+    fn push<'a>(&'a self, path: &'a Path, key: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        push(self.store.clone(), path, key).boxed()
+    }
+
+    fn pull<'a>(&'a self, key: &'a str, path: &'a Path) -> BoxFuture<'a, Result<(), Error>> {
+        pull(&*self.store, key, path).boxed()
+    }
+}
+
+#[instrument(skip_all, fields(key = key))]
+async fn push(store: Arc<dyn ObjectStore>, path: &Path, key: &str) -> Result<(), Error> {
+    let mut file = BufReader::new(File::open(path).await?);
+    let mut writer = BufWriter::new(store, ObjectPath::from(key));
+    let len = match tokio::io::copy_buf(&mut file, &mut writer).await {
+        Ok(len) => len,
+        Err(err) => {
+            let _ = writer.abort().await;
+            return Err(err.into());
+        }
+    };
+    writer.shutdown().await?;
+    debug!(len, "Object uploaded");
+
+    Ok(())
+}
+
+#[instrument(skip_all, fields(key = key))]
+async fn pull(store: &dyn ObjectStore, key: &str, path: &Path) -> Result<(), Error> {
+    let mut stream = store.get(&ObjectPath::from(key)).await?.into_stream();
+    let mut file = File::create(path).await?;
+    let mut len = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        len += chunk.len();
+    }
+    file.sync_all().await?;
+    debug!(len, "Object downloaded");
+
+    Ok(())
+}
@@ -0,0 +1,356 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use crate::{fs, Error};
+
+/// Magic of encrypted files, followed by the salt of the file key and the encrypted chunks.
+const MAGIC: &[u8] = b"firec-sealed-v1\0";
+const SALT_LEN: usize = 32;
+/// Plaintext size of the encrypted chunks, all full except the last one.
+const CHUNK_LEN: usize = 1 << 20;
+const KEY_INFO: &[u8] = b"firec snapshot file key";
+const SIGNATURE_CONTEXT: &str = "firec-snapshot-v1";
+
+/// Provides the keys encrypting snapshots, see [`SnapshotSeal::encrypt`].
+pub trait KeyProvider: fmt::Debug + Send + Sync {
+    /// The 256-bit key of the snapshot `name`.
+    fn key<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, Error>>;
+}
+
+/// A [`KeyProvider`] with the same key for all the snapshots.
+#[derive(Clone)]
+pub struct StaticKey(Vec<u8>);
+
+impl StaticKey {
+    /// Use the 256-bit `key`.
+    pub fn new<K>(key: K) -> Self
+    where
+        K: Into<Vec<u8>>,
+    {
+        Self(key.into())
+    }
+}
+
+impl fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StaticKey").field(&"<redacted>").finish()
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn key<'a>(&'a self, _name: &'a str) -> BoxFuture<'a, Result<Vec<u8>, Error>> {
+        async move { Ok(self.0.clone()) }.boxed()
+    }
+}
+
+/// Protection of snapshots stored off-host, see [`super::push_snapshot_with`] and
+/// [`super::pull_snapshot_with`].
+///
+/// Files are encrypted with AES-256-GCM, in chunks, under a key derived from the key of the
+/// snapshot and a random salt per file. Signatures are Ed25519 signatures of the SHA-256 of the
+/// stored files (encrypted, if encryption is enabled) and of the snapshot name, stored next to
+/// them as `<name>.sig`.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotSeal {
+    pub(super) key_provider: Option<Arc<dyn KeyProvider>>,
+    signing_key: Option<Arc<Ed25519KeyPair>>,
+    verifying_key: Option<Vec<u8>>,
+}
+
+impl SnapshotSeal {
+    /// No encryption nor signatures, until configured.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encrypt pushed snapshots and decrypt pulled ones with the keys of `key_provider`.
+    pub fn encrypt<K>(mut self, key_provider: K) -> Self
+    where
+        K: KeyProvider + 'static,
+    {
+        self.key_provider = Some(Arc::new(key_provider));
+        self
+    }
+
+    /// Sign pushed snapshots with the Ed25519 key pair `pkcs8`, in PKCS#8 v2 DER, e.g from
+    /// [`generate_signing_key`].
+    pub fn sign(mut self, pkcs8: &[u8]) -> Result<Self, Error> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(Error::InvalidSigningKey)?;
+        self.signing_key = Some(Arc::new(key_pair));
+        Ok(self)
+    }
+
+    /// Require pulled snapshots to be signed by the Ed25519 public key `public_key`.
+    pub fn verify<K>(mut self, public_key: K) -> Self
+    where
+        K: Into<Vec<u8>>,
+    {
+        self.verifying_key = Some(public_key.into());
+        self
+    }
+
+    pub(super) fn signs(&self) -> bool {
+        self.signing_key.is_some()
+    }
+
+    pub(super) fn verifies(&self) -> bool {
+        self.verifying_key.is_some()
+    }
+
+    /// The key of the snapshot `name`, if encrypted.
+    pub(super) async fn key(&self, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let Some(key_provider) = &self.key_provider else {
+            return Ok(None);
+        };
+        let key = key_provider.key(name).await?;
+        if key.len() != 32 {
+            return Err(Error::InvalidSnapshotKey(key.len() * 8));
+        }
+
+        Ok(Some(key))
+    }
+
+    /// The signature file of the stored files of the snapshot `name`.
+    pub(super) async fn signature(
+        &self,
+        name: &str,
+        state_path: &Path,
+        memory_path: &Path,
+    ) -> Result<Vec<u8>, Error> {
+        let key_pair = self.signing_key.as_ref().expect("signing key");
+        let (state_sha256, memory_sha256) = digests(state_path, memory_path).await?;
+        let message = signed_message(name, &state_sha256, &memory_sha256);
+        let signature = SignatureFile {
+            state_sha256,
+            memory_sha256,
+            public_key: hex(key_pair.public_key().as_ref()),
+            signature: hex(key_pair.sign(message.as_bytes()).as_ref()),
+        };
+
+        Ok(serde_json::to_vec(&signature)?)
+    }
+
+    /// Verify the signature file `signature` of the stored files of the snapshot `name`.
+    pub(super) async fn check_signature(
+        &self,
+        name: &str,
+        signature: &[u8],
+        state_path: &Path,
+        memory_path: &Path,
+    ) -> Result<(), Error> {
+        let public_key = self.verifying_key.as_ref().expect("verifying key");
+        let signature: SignatureFile = serde_json::from_slice(signature)?;
+        let (state_sha256, memory_sha256) = digests(state_path, memory_path).await?;
+        if state_sha256 != signature.state_sha256 {
+            return Err(Error::ChecksumMismatch(state_path.to_owned()));
+        }
+        if memory_sha256 != signature.memory_sha256 {
+            return Err(Error::ChecksumMismatch(memory_path.to_owned()));
+        }
+        let message = signed_message(name, &state_sha256, &memory_sha256);
+        let signature_bytes = unhex(&signature.signature).ok_or(Error::InvalidSignature)?;
+        UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message.as_bytes(), &signature_bytes)
+            .map_err(|_| Error::InvalidSignature)
+    }
+}
+
+/// Generate an Ed25519 key pair for [`SnapshotSeal::sign`], in PKCS#8 v2 DER, along with its
+/// public key for [`SnapshotSeal::verify`].
+pub fn generate_signing_key() -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(Error::Crypto)?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(Error::InvalidSigningKey)?;
+
+    Ok((
+        pkcs8.as_ref().to_vec(),
+        key_pair.public_key().as_ref().to_vec(),
+    ))
+}
+
+/// The content of a signature file.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignatureFile {
+    state_sha256: String,
+    memory_sha256: String,
+    /// The public key of the signer, informative only.
+    public_key: String,
+    signature: String,
+}
+
+fn signed_message(name: &str, state_sha256: &str, memory_sha256: &str) -> String {
+    format!("{SIGNATURE_CONTEXT}\n{name}\n{state_sha256}\n{memory_sha256}")
+}
+
+async fn digests(state_path: &Path, memory_path: &Path) -> Result<(String, String), Error> {
+    let paths = (state_path.to_owned(), memory_path.to_owned());
+    let digests = task::spawn_blocking(move || -> io::Result<_> {
+        Ok((hex(&fs::sha256(&paths.0)?), hex(&fs::sha256(&paths.1)?)))
+    })
+    .await??;
+
+    Ok(digests)
+}
+
+/// Encrypt `src` to `dest` with `key`.
+pub(super) async fn encrypt(key: Vec<u8>, src: PathBuf, dest: PathBuf) -> Result<(), Error> {
+    task::spawn_blocking(move || blocking_encrypt(&key, &src, &dest)).await?
+}
+
+/// Decrypt `src` to `dest` with `key`.
+pub(super) async fn decrypt(key: Vec<u8>, src: PathBuf, dest: PathBuf) -> Result<(), Error> {
+    task::spawn_blocking(move || blocking_decrypt(&key, &src, &dest)).await?
+}
+
+fn blocking_encrypt(key: &[u8], src: &Path, dest: &Path) -> Result<(), Error> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).map_err(Error::Crypto)?;
+    let file_key = file_key(key, &salt)?;
+    let mut src = File::open(src)?;
+    let mut dest = io::BufWriter::new(File::create(dest)?);
+    dest.write_all(MAGIC)?;
+    dest.write_all(&salt)?;
+
+    let mut buf = Vec::with_capacity(CHUNK_LEN + aead::MAX_TAG_LEN);
+    for counter in 0.. {
+        buf.clear();
+        let len = Read::by_ref(&mut src)
+            .take(CHUNK_LEN as u64)
+            .read_to_end(&mut buf)?;
+        // A short chunk, possibly empty, ends the file, so truncation is detected.
+        let last = len < CHUNK_LEN;
+        file_key
+            .seal_in_place_append_tag(nonce(counter), Aad::from([last as u8]), &mut buf)
+            .map_err(Error::Crypto)?;
+        dest.write_all(&buf)?;
+        if last {
+            break;
+        }
+    }
+    dest.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    Ok(())
+}
+
+fn blocking_decrypt(key: &[u8], src_path: &Path, dest: &Path) -> Result<(), Error> {
+    let mut src = io::BufReader::new(File::open(src_path)?);
+    let mut header = [0; MAGIC.len() + SALT_LEN];
+    src.read_exact(&mut header)
+        .map_err(|_| Error::NotEncryptedSnapshot(src_path.to_owned()))?;
+    let (magic, salt) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(Error::NotEncryptedSnapshot(src_path.to_owned()));
+    }
+    let file_key = file_key(key, salt)?;
+    let mut dest = io::BufWriter::new(File::create(dest)?);
+
+    let mut buf = Vec::with_capacity(CHUNK_LEN + aead::MAX_TAG_LEN);
+    for counter in 0.. {
+        buf.clear();
+        let len = src
+            .by_ref()
+            .take((CHUNK_LEN + aead::MAX_TAG_LEN) as u64)
+            .read_to_end(&mut buf)?;
+        let last = len < CHUNK_LEN + aead::MAX_TAG_LEN;
+        let plaintext = file_key
+            .open_in_place(nonce(counter), Aad::from([last as u8]), &mut buf)
+            .map_err(|_| Error::SnapshotAuthenticationFailed(src_path.to_owned()))?;
+        dest.write_all(plaintext)?;
+        if last {
+            break;
+        }
+    }
+    dest.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    Ok(())
+}
+
+fn file_key(key: &[u8], salt: &[u8]) -> Result<LessSafeKey, Error> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(key);
+    let okm = prk
+        .expand(&[KEY_INFO], &aead::AES_256_GCM)
+        .map_err(Error::Crypto)?;
+
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// The nonce of chunk `counter`, unique as file keys are.
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sealing() {
+        let dir = std::env::temp_dir().join(format!("firec-seal-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let (plain, sealed, opened) = (dir.join("mem"), dir.join("sealed"), dir.join("opened"));
+        let contents: Vec<u8> = (0..CHUNK_LEN * 2 + 7).map(|i| i as u8).collect();
+        std::fs::write(&plain, &contents).unwrap();
+
+        let key = vec![7; 32];
+        encrypt(key.clone(), plain.clone(), sealed.clone())
+            .await
+            .unwrap();
+        decrypt(key, sealed.clone(), opened.clone()).await.unwrap();
+        assert_eq!(std::fs::read(&opened).unwrap(), contents);
+        assert!(matches!(
+            decrypt(vec![8; 32], sealed.clone(), opened.clone()).await,
+            Err(Error::SnapshotAuthenticationFailed(path)) if path == sealed
+        ));
+        assert!(matches!(
+            decrypt(vec![7; 32], plain.clone(), opened.clone()).await,
+            Err(Error::NotEncryptedSnapshot(path)) if path == plain
+        ));
+
+        let (pkcs8, public_key) = generate_signing_key().unwrap();
+        let seal = SnapshotSeal::new().sign(&pkcs8).unwrap().verify(public_key);
+        let signature = seal.signature("snap", &plain, &sealed).await.unwrap();
+        seal.check_signature("snap", &signature, &plain, &sealed)
+            .await
+            .unwrap();
+        assert!(matches!(
+            seal.check_signature("other", &signature, &plain, &sealed)
+                .await,
+            Err(Error::InvalidSignature)
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+//! Host TAP devices, see [`crate::config::network::TapMode`].
+
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::{instrument, trace, warn};
+
+use crate::{
+    config::{network::TapMode, Config},
+    machine::run_command,
+    DeleteSummary, Error,
+};
+
+/// Create the TAP devices of the network interfaces that aren't created by the user.
+#[instrument(skip_all)]
+pub(crate) async fn setup(config: &Config<'_>) -> Result<(), Error> {
+    let jailer = config.jailer();
+    let mut created = Vec::new();
+    for iface in config.network_interfaces() {
+        let (bridge, create_bridge) = match iface.tap_mode() {
+            TapMode::Existing => continue,
+            TapMode::Bridge {
+                bridge,
+                create_bridge,
+            } => (bridge, *create_bridge),
+        };
+        let tap = iface.host_if_name();
+        trace!(tap, %bridge, "Creating TAP device");
+
+        let result = async {
+            if create_bridge && ip(config, &["link", "show", bridge]).await.is_err() {
+                ip(config, &["link", "add", "name", bridge, "type", "bridge"]).await?;
+                ip(config, &["link", "set", bridge, "up"]).await?;
+            }
+            let (uid, gid) = (jailer.uid().to_string(), jailer.gid().to_string());
+            ip(
+                config,
+                &[
+                    "tuntap", "add", "dev", tap, "mode", "tap", "user", &uid, "group", &gid,
+                ],
+            )
+            .await?;
+            created.push(tap);
+            ip(config, &["link", "set", tap, "master", bridge]).await?;
+            ip(config, &["link", "set", tap, "up"]).await
+        }
+        .await;
+        if let Err(e) = result {
+            for tap in created {
+                remove(config, tap).await;
+            }
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the TAP devices created by [`setup`], skipping those already gone.
+#[instrument(skip_all)]
+pub(crate) async fn teardown(config: &Config<'_>, summary: &mut DeleteSummary) {
+    for iface in config.network_interfaces() {
+        if let TapMode::Bridge { .. } = iface.tap_mode() {
+            let tap = iface.host_if_name();
+            let sys_path = Path::new("/sys/class/net").join(tap);
+            if !tokio::fs::try_exists(&sys_path).await.unwrap_or(true) {
+                trace!(tap, "TAP device already removed");
+                continue;
+            }
+            trace!(tap, "Removing TAP device");
+            match ip(config, &["link", "del", tap]).await {
+                Ok(()) => summary.taps_removed.push(tap.to_owned()),
+                Err(err) => summary.warn(format!("Failed to remove TAP device `{tap}`"), &err),
+            }
+        }
+    }
+}
+
+async fn remove(config: &Config<'_>, tap: &str) {
+    trace!(tap, "Removing TAP device");
+    if let Err(err) = ip(config, &["link", "del", tap]).await {
+        warn!(tap, error = %err, "Failed to remove TAP device");
+    }
+}
+
+async fn ip(config: &Config<'_>, args: &[&str]) -> Result<(), Error> {
+    run_command(config, Command::new("ip").args(args)).await
+}
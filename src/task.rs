@@ -0,0 +1,38 @@
+//! Background tasks.
+
+use tokio::task::JoinHandle;
+
+/// Handle to a background task, such as [`crate::Machine::autoscale_memory`].
+///
+/// The task is stopped when the handle is dropped.
+#[derive(Debug)]
+pub struct TaskHandle {
+    task: JoinHandle<()>,
+}
+
+impl TaskHandle {
+    pub(crate) fn new(task: JoinHandle<()>) -> Self {
+        Self { task }
+    }
+
+    /// Stop the task.
+    pub fn stop(self) {
+        self.task.abort();
+    }
+
+    /// If the task has stopped.
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Wait for the task to finish on its own.
+    pub(crate) async fn join(mut self) {
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
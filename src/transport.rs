@@ -0,0 +1,194 @@
+//! Transports for the Firecracker API.
+//!
+//! By default, [`crate::Machine`] connects to the API socket of the VM on the local host, keeping
+//! connections alive across requests. With an [`ApiTransport`] set on the
+//! [`crate::config::Config`] (see [`crate::config::Builder::api_transport`]), the API is driven
+//! over any stream it connects instead, e.g a socket forwarded over SSH from another host, with
+//! one connection per request.
+//!
+//! [`CommandTransport`] uses the standard input and output of a command as the stream, e.g
+//! `ssh <host> socat - UNIX-CONNECT:{socket}`.
+
+use std::{
+    ffi::OsString,
+    fmt::Debug,
+    io,
+    path::Path,
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Join, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+/// A bidirectional stream to a Firecracker API socket.
+pub trait ApiStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T> ApiStream for T where T: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+/// Connects to the API socket of a VM, see the [module documentation](self).
+pub trait ApiTransport: Debug + Send + Sync {
+    /// Open a stream to the API socket at `socket_path`, as seen by the host running the VM.
+    fn connect<'a>(
+        &'a self,
+        socket_path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn ApiStream>>>;
+}
+
+/// The placeholder for the API socket path in the arguments of a [`CommandTransport`].
+pub const SOCKET_PLACEHOLDER: &str = "{socket}";
+
+/// An [`ApiTransport`] running a command per connection and using its standard input and
+/// output as the stream.
+///
+/// [`SOCKET_PLACEHOLDER`] is replaced by the API socket path in the arguments. The command is
+/// killed when the stream is dropped.
+#[derive(Debug, Clone)]
+pub struct CommandTransport {
+    program: OsString,
+    args: Vec<String>,
+}
+
+impl CommandTransport {
+    /// Run `program`, without arguments.
+    pub fn new<P>(program: P) -> Self
+    where
+        P: Into<OsString>,
+    {
+        Self {
+            program: program.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Add an argument, which can contain [`SOCKET_PLACEHOLDER`].
+    pub fn arg<A>(mut self, arg: A) -> Self
+    where
+        A: Into<String>,
+    {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Add arguments, which can contain [`SOCKET_PLACEHOLDER`].
+    pub fn args<I, A>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    fn command(&self, socket_path: &Path) -> Command {
+        let socket_path = socket_path.to_string_lossy();
+        let mut cmd = Command::new(&self.program);
+        cmd.args(
+            self.args
+                .iter()
+                .map(|arg| arg.replace(SOCKET_PLACEHOLDER, &socket_path)),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+
+        cmd
+    }
+}
+
+impl ApiTransport for CommandTransport {
+    fn connect<'a>(
+        &'a self,
+        socket_path: &'a Path,
+    ) -> BoxFuture<'a, io::Result<Box<dyn ApiStream>>> {
+        async move {
+            let mut child = self.command(socket_path).spawn()?;
+            let stdin = child.stdin.take().expect("piped stdin");
+            let stdout = child.stdout.take().expect("piped stdout");
+            let stream: Box<dyn ApiStream> = Box::new(CommandStream {
+                _child: child,
+                io: tokio::io::join(stdout, stdin),
+            });
+
+            Ok(stream)
+        }
+        .boxed()
+    }
+}
+
+/// The stdio of a command, keeping it alive.
+#[derive(Debug)]
+struct CommandStream {
+    _child: Child,
+    io: Join<ChildStdout, ChildStdin>,
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Method;
+
+    use super::*;
+    use crate::{client::ApiClient, config::Config};
+
+    #[tokio::test]
+    async fn command_transport() {
+        // Answer the first request, whatever it is.
+        let transport = CommandTransport::new("sh").args([
+            "-c",
+            r#"read -r line; printf 'HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}'; cat >/dev/null"#,
+            "{socket}",
+        ]);
+        let config = Config::builder(None, Path::new("/tmp/vmlinux"))
+            .jailer_cfg()
+            .build()
+            .initrd_path(Path::new("/tmp/initrd"))
+            .api_transport(transport)
+            .build()
+            .unwrap();
+        let args: Vec<_> = CommandTransport::new("sh")
+            .arg("{socket}")
+            .command(&config.host_socket_path())
+            .as_std()
+            .get_args()
+            .map(ToOwned::to_owned)
+            .collect();
+        assert_eq!(args, [config.host_socket_path().into_os_string()]);
+
+        let client = ApiClient::new(&config);
+        let body = client.send(Method::GET, "/version", None).await.unwrap();
+        assert_eq!(body.as_deref(), Some("{}"));
+    }
+}
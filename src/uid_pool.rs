@@ -0,0 +1,106 @@
+//! Jailer uid/gid allocation.
+//!
+//! The jailer drops privileges to the uid and gid it's given (see
+//! [`crate::config::JailerBuilder::uid`]). Running all VMs under the same uid lets a compromised
+//! VMM reach the other VMs, so [`UidGidPool`] hands out a unique pair per VM.
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{debug, instrument};
+
+use crate::{config::VmId, state, Error};
+
+/// File name of the allocations, in the state directory.
+pub const ALLOCATIONS_FILE_NAME: &str = "uids.json";
+
+/// The uid and gid of a VM.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UidGid {
+    /// The ID of the VM.
+    pub vm_id: VmId,
+    /// The uid.
+    pub uid: u32,
+    /// The gid.
+    pub gid: u32,
+}
+
+/// Allocates a unique uid/gid pair per VM from a range, persisted in a state directory.
+///
+/// The uid and gid of a pair are the same number. Allocations are serialized within a process.
+/// Only a single process should manage a state directory.
+#[derive(Debug)]
+pub struct UidGidPool {
+    range: Range<u32>,
+    allocations_path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl UidGidPool {
+    /// Allocate from `range`, keeping the allocations in `state_dir`.
+    ///
+    /// The range should not overlap with the uids and gids of the host users and groups.
+    pub fn new<P>(range: Range<u32>, state_dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            range,
+            allocations_path: state_dir.as_ref().join(ALLOCATIONS_FILE_NAME),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Allocate a uid/gid pair to `vm_id`.
+    ///
+    /// Returns the existing pair if the VM already has one.
+    #[instrument(skip_all, fields(vm_id = %vm_id))]
+    pub async fn allocate(&self, vm_id: &VmId) -> Result<UidGid, Error> {
+        let _guard = self.lock.lock().await;
+        let mut allocations: Vec<UidGid> = state::read(&self.allocations_path).await?;
+        if let Some(ids) = allocations.iter().find(|ids| &ids.vm_id == vm_id) {
+            return Ok(ids.clone());
+        }
+
+        let id = self
+            .range
+            .clone()
+            .find(|id| allocations.iter().all(|ids| ids.uid != *id))
+            .ok_or(Error::UidGidExhausted)?;
+        debug!(id, "Allocated uid/gid");
+        let ids = UidGid {
+            vm_id: vm_id.clone(),
+            uid: id,
+            gid: id,
+        };
+        allocations.push(ids.clone());
+        state::write(&self.allocations_path, &allocations).await?;
+
+        Ok(ids)
+    }
+
+    /// Release the uid/gid pair of `vm_id`, returning it if it had one.
+    #[instrument(skip_all, fields(vm_id = %vm_id))]
+    pub async fn release(&self, vm_id: &VmId) -> Result<Option<UidGid>, Error> {
+        let _guard = self.lock.lock().await;
+        let mut allocations: Vec<UidGid> = state::read(&self.allocations_path).await?;
+        let ids = match allocations.iter().position(|ids| &ids.vm_id == vm_id) {
+            Some(i) => allocations.remove(i),
+            None => return Ok(None),
+        };
+        debug!(id = ids.uid, "Released uid/gid");
+        state::write(&self.allocations_path, &allocations).await?;
+
+        Ok(Some(ids))
+    }
+
+    /// The current allocations.
+    pub async fn allocations(&self) -> Result<Vec<UidGid>, Error> {
+        let _guard = self.lock.lock().await;
+        state::read(&self.allocations_path).await
+    }
+}
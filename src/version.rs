@@ -0,0 +1,74 @@
+//! Firecracker versions.
+
+use std::{fmt, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::Error;
+
+/// A Firecracker release version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FirecrackerVersion {
+    /// The major version.
+    pub major: u32,
+    /// The minor version.
+    pub minor: u32,
+    /// The patch version.
+    pub patch: u32,
+}
+
+impl FirecrackerVersion {
+    /// Create a new version.
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for FirecrackerVersion {
+    type Err = Error;
+
+    /// Parse a version like `1.4.0`, `v1.4.0` or `1.5.0-dev`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidFirecrackerVersion(s.to_owned());
+        let version = s.strip_prefix('v').unwrap_or(s);
+        let version = version.split(['-', '+']).next().unwrap_or(version);
+        let mut parts = version.split('.').map(|part| part.parse::<u32>());
+        let mut next = || parts.next().ok_or_else(invalid)?.map_err(|_| invalid());
+
+        Ok(Self::new(next()?, next()?, next()?))
+    }
+}
+
+impl fmt::Display for FirecrackerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Response of `GET /version`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct VersionResponse {
+    pub(crate) firecracker_version: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        let v1_4 = FirecrackerVersion::new(1, 4, 0);
+        assert_eq!("1.4.0".parse::<FirecrackerVersion>().unwrap(), v1_4);
+        assert_eq!("v1.4.0".parse::<FirecrackerVersion>().unwrap(), v1_4);
+        assert_eq!("1.4.0-dev".parse::<FirecrackerVersion>().unwrap(), v1_4);
+        assert!("1.4".parse::<FirecrackerVersion>().is_err());
+        assert!("one.4.0".parse::<FirecrackerVersion>().is_err());
+
+        assert!(FirecrackerVersion::new(0, 25, 2) < FirecrackerVersion::new(1, 0, 0));
+        assert_eq!(v1_4.to_string(), "1.4.0");
+    }
+}
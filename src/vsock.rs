@@ -0,0 +1,342 @@
+//! Host-side vsock connections and TCP proxies.
+//!
+//! Firecracker exposes the guest vsock device on the host as a Unix socket (see
+//! [`crate::config::Builder::vsock_cfg`]):
+//!
+//! * Host-initiated connections connect to the socket and send `CONNECT <port>\n`, which
+//!   Firecracker acknowledges with `OK <host port>\n` once the guest accepted the connection.
+//! * Guest-initiated connections to CID 2 on `<port>` are forwarded by Firecracker to a Unix
+//!   socket listening at the socket path suffixed with `_<port>`.
+//!
+//! [`Proxy`] builds on these to expose guest services on host TCP listeners, and host services to
+//! guests, without any network interface in the guest.
+
+use std::{
+    fs::Permissions,
+    io,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{debug, instrument, trace, warn};
+
+use crate::task::TaskHandle;
+
+/// Maximum length of the `OK <host port>` acknowledgement of a host-initiated connection.
+const MAX_ACK_LEN: usize = 32;
+/// Permissions of the guest-initiated connection sockets, only the jailer user connects to them.
+pub(crate) const LISTENER_MODE: u32 = 0o600;
+
+/// Listen for guest-initiated connections on `uds_path` (the host vsock socket, with the `_PORT`
+/// suffix).
+pub(crate) fn listen(uds_path: &Path, uid: u32, gid: u32) -> io::Result<UnixListener> {
+    match std::fs::remove_file(uds_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    let listener = UnixListener::bind(uds_path)?;
+    // Firecracker connects to the socket as the jailer user.
+    std::os::unix::fs::chown(uds_path, Some(uid), Some(gid))?;
+    std::fs::set_permissions(uds_path, Permissions::from_mode(LISTENER_MODE))?;
+
+    Ok(listener)
+}
+
+/// Connect to the guest on vsock `port` through the host vsock socket at `uds_path`.
+pub(crate) async fn connect(uds_path: &Path, port: u32) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(uds_path).await?;
+    stream
+        .write_all(format!("CONNECT {port}\n").as_bytes())
+        .await?;
+
+    // Read the acknowledgement byte by byte, so no data from the guest is consumed.
+    let mut ack = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            byte if ack.len() < MAX_ACK_LEN => ack.push(byte),
+            _ => break,
+        }
+    }
+    if !ack.starts_with(b"OK ") {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Guest refused connection to vsock port {port}"),
+        ));
+    }
+
+    Ok(stream)
+}
+
+/// A TCP <-> vsock proxy, see [`crate::Machine::proxy_tcp_to_vsock`] and
+/// [`crate::Machine::proxy_vsock_to_tcp`].
+///
+/// Every accepted connection is proxied independently. Dropping the proxy stops it immediately,
+/// closing all connections; use [`Proxy::shutdown`] to let them finish.
+#[derive(Debug)]
+pub struct Proxy {
+    local_addr: Option<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    task: TaskHandle,
+}
+
+impl Proxy {
+    /// The address of the TCP listener, if the proxy listens on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Stop accepting connections and wait for the proxied connections to be closed.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        self.task.join().await;
+    }
+
+    /// Proxy connections accepted on `listener` to the guest vsock `port`.
+    pub(crate) fn tcp_to_vsock(listener: TcpListener, uds_path: PathBuf, port: u32) -> Self {
+        let local_addr = listener.local_addr().ok();
+        let (shutdown, rx) = watch::channel(false);
+        let task = tokio::spawn(run_tcp_to_vsock(listener, uds_path, port, rx));
+
+        Self {
+            local_addr,
+            shutdown,
+            task: TaskHandle::new(task),
+        }
+    }
+
+    /// Proxy guest connections accepted on `listener` to `target`.
+    pub(crate) fn vsock_to_tcp(listener: UnixListener, target: SocketAddr) -> Self {
+        let (shutdown, rx) = watch::channel(false);
+        let task = tokio::spawn(run_vsock_to_tcp(listener, target, rx));
+
+        Self {
+            local_addr: None,
+            shutdown,
+            task: TaskHandle::new(task),
+        }
+    }
+}
+
+#[instrument(skip_all, fields(port = port))]
+async fn run_tcp_to_vsock(
+    listener: TcpListener,
+    uds_path: PathBuf,
+    port: u32,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((mut tcp, peer)) => {
+                    trace!(%peer, "Accepted TCP connection");
+                    let uds_path = uds_path.clone();
+                    connections.spawn(async move {
+                        let mut vsock = connect(&uds_path, port).await?;
+                        copy_bidirectional(&mut tcp, &mut vsock).await
+                    });
+                }
+                Err(err) => warn!(error = %err, "Failed to accept TCP connection"),
+            },
+            Some(result) = connections.join_next() => log_closed(result),
+            _ = shutdown.changed() => break,
+        }
+    }
+    drain(connections).await;
+}
+
+#[instrument(skip_all, fields(%target))]
+async fn run_vsock_to_tcp(
+    listener: UnixListener,
+    target: SocketAddr,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((mut vsock, _)) => {
+                    trace!("Accepted vsock connection");
+                    connections.spawn(async move {
+                        let mut tcp = TcpStream::connect(target).await?;
+                        copy_bidirectional(&mut vsock, &mut tcp).await
+                    });
+                }
+                Err(err) => warn!(error = %err, "Failed to accept vsock connection"),
+            },
+            Some(result) = connections.join_next() => log_closed(result),
+            _ = shutdown.changed() => break,
+        }
+    }
+    drain(connections).await;
+}
+
+async fn drain(mut connections: JoinSet<io::Result<(u64, u64)>>) {
+    debug!(connections = connections.len(), "Shutting down proxy");
+    while let Some(result) = connections.join_next().await {
+        log_closed(result);
+    }
+}
+
+fn log_closed(result: Result<io::Result<(u64, u64)>, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok((sent, received))) => trace!(sent, received, "Connection closed"),
+        Ok(Err(err)) => debug!(error = %err, "Connection failed"),
+        Err(err) => warn!(error = %err, "Connection task failed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::MetadataExt, time::Duration};
+
+    use tokio::{io::AsyncBufReadExt, io::BufReader, task::JoinHandle, time::timeout};
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("firec-vsock-{}.sock", Uuid::new_v4()))
+    }
+
+    /// A fake Firecracker vsock socket, answering `ack` to the `CONNECT` line and then echoing
+    /// what it receives. Returns the `CONNECT` line.
+    fn fake_vsock(uds_path: &Path, ack: &'static [u8]) -> JoinHandle<String> {
+        let listener = UnixListener::bind(uds_path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut connect = String::new();
+            stream.read_line(&mut connect).await.unwrap();
+            stream.write_all(ack).await.unwrap();
+
+            let mut buf = [0; 64];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            connect
+        })
+    }
+
+    #[tokio::test]
+    async fn connect_handshake() {
+        let uds_path = socket_path();
+        let guest = fake_vsock(&uds_path, b"OK 1073741824\n");
+
+        let mut stream = connect(&uds_path, 52).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        // Nothing past the acknowledgement is consumed.
+        assert_eq!(&echo, b"ping");
+        drop(stream);
+        assert_eq!(guest.await.unwrap(), "CONNECT 52\n");
+
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_refused() {
+        for ack in [&b"ERR\n"[..], &[b'O'; 64]] {
+            let uds_path = socket_path();
+            let guest = fake_vsock(&uds_path, ack);
+
+            // Overlong acknowledgements are cut short rather than read until a newline that
+            // never comes.
+            let err = timeout(Duration::from_secs(5), connect(&uds_path, 52))
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            guest.abort();
+            std::fs::remove_file(&uds_path).unwrap();
+        }
+
+        // Firecracker closes the connection when nothing listens on the guest port.
+        let uds_path = socket_path();
+        let listener = UnixListener::bind(&uds_path).unwrap();
+        let guest = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut connect = [0; 11];
+            stream.read_exact(&mut connect).await.unwrap();
+        });
+        let err = connect(&uds_path, 52).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        guest.await.unwrap();
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn listen_replaces_stale_socket() {
+        let uds_path = socket_path();
+        std::fs::write(&uds_path, b"stale").unwrap();
+        let metadata = std::fs::metadata(&uds_path).unwrap();
+
+        let listener = listen(&uds_path, metadata.uid(), metadata.gid()).unwrap();
+        let metadata = std::fs::metadata(&uds_path).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, LISTENER_MODE);
+        let (client, accepted) = tokio::join!(UnixStream::connect(&uds_path), listener.accept());
+        client.unwrap();
+        accepted.unwrap();
+
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxies() {
+        // TCP clients to a guest service.
+        let uds_path = socket_path();
+        let guest = fake_vsock(&uds_path, b"OK 1073741824\n");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = Proxy::tcp_to_vsock(listener, uds_path.clone(), 80);
+        let mut tcp = TcpStream::connect(proxy.local_addr().unwrap())
+            .await
+            .unwrap();
+        tcp.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        tcp.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+        drop(tcp);
+        proxy.shutdown().await;
+        assert_eq!(guest.await.unwrap(), "CONNECT 80\n");
+        std::fs::remove_file(&uds_path).unwrap();
+
+        // Guest clients to a host service.
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = service.local_addr().unwrap();
+        let host = tokio::spawn(async move {
+            let (mut stream, _) = service.accept().await.unwrap();
+            let mut request = [0; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            request
+        });
+        let uds_path = socket_path();
+        let proxy = Proxy::vsock_to_tcp(UnixListener::bind(&uds_path).unwrap(), target);
+        let mut vsock = UnixStream::connect(&uds_path).await.unwrap();
+        vsock.write_all(b"ping").await.unwrap();
+        let mut response = [0; 4];
+        vsock.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"pong");
+        assert_eq!(&host.await.unwrap(), b"ping");
+        drop(vsock);
+        proxy.shutdown().await;
+        std::fs::remove_file(&uds_path).unwrap();
+    }
+}
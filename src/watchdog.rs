@@ -0,0 +1,124 @@
+//! Process liveness watchdog.
+
+use std::{
+    process::ExitStatus,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, ProcessStatus, System, SystemExt};
+use tokio::{
+    sync::{broadcast, watch},
+    task,
+    time::sleep,
+};
+use tracing::{instrument, warn};
+
+use crate::{
+    client::ApiClient,
+    config::VmId,
+    events::{ExitReason, MachineEvent, MachineEventKind},
+    task::TaskHandle,
+    Error,
+};
+
+/// The VMM process of a machine, shared with its watchdog.
+#[derive(Debug, Default)]
+pub(crate) struct Process {
+    /// Pid of a started jailer/firecracker process, or None if not started yet.
+    pub pid: Option<u32>,
+    /// Set while the process is being stopped, so its exit isn't reported as a crash.
+    pub stopping: bool,
+    /// Set once the process disappeared without being stopped.
+    pub crashed: bool,
+}
+
+pub(crate) type SharedProcess = Arc<Mutex<Process>>;
+
+/// If the process `pid` is running.
+///
+/// Zombies are not considered running, as Firecracker is sometimes not reaped by the jailer for
+/// some time.
+pub(crate) fn is_running(pid: u32) -> bool {
+    let mut sys = System::new();
+    sys.refresh_process_specifics(Pid::from_u32(pid), ProcessRefreshKind::new())
+        && sys
+            .process(Pid::from_u32(pid))
+            .is_some_and(|process| process.status() != ProcessStatus::Zombie)
+}
+
+pub(crate) fn watch(
+    vm_id: VmId,
+    client: ApiClient,
+    process: SharedProcess,
+    exit_status: Option<watch::Receiver<Option<ExitStatus>>>,
+    events: broadcast::Sender<MachineEvent>,
+    interval: Duration,
+) -> TaskHandle {
+    TaskHandle::new(tokio::spawn(run_watchdog(
+        vm_id,
+        client,
+        process,
+        exit_status,
+        events,
+        interval,
+    )))
+}
+
+#[instrument(skip_all, fields(vm_id = %vm_id))]
+async fn run_watchdog(
+    vm_id: VmId,
+    client: ApiClient,
+    process: SharedProcess,
+    exit_status: Option<watch::Receiver<Option<ExitStatus>>>,
+    events: broadcast::Sender<MachineEvent>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        let pid = match process.lock().unwrap().pid {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let running = task::spawn_blocking(move || is_running(pid))
+            .await
+            .unwrap_or(true);
+        let reason = if !running {
+            "process exited".to_string()
+        } else {
+            match client.ping().await {
+                Ok(()) => continue,
+                Err(err @ (Error::SocketNotFound { .. } | Error::VmmNotListening { .. })) => {
+                    err.to_string()
+                }
+                Err(err) => {
+                    warn!(error = %err, "VMM API is not responding");
+                    continue;
+                }
+            }
+        };
+
+        {
+            let mut process = process.lock().unwrap();
+            // The process was stopped or replaced in the meantime.
+            if process.pid != Some(pid) || process.stopping {
+                continue;
+            }
+            process.pid = None;
+            process.crashed = true;
+        }
+        let exit_reason = exit_status
+            .as_ref()
+            .and_then(|rx| *rx.borrow())
+            .map(ExitReason::from_exit_status);
+        warn!(pid, %reason, ?exit_reason, "VMM disappeared");
+        let _ = events.send(MachineEvent::new(
+            vm_id.clone(),
+            MachineEventKind::Crashed {
+                reason,
+                exit_reason,
+            },
+        ));
+    }
+}